@@ -0,0 +1,285 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use attributes::Attributes;
+
+// Tar archives consist of records of this many bytes. Every entry has a
+// header record, followed by its contents padded to a whole record. The
+// archive ends with two records of zeroes.
+static RECORD_BYTES: usize = 512;
+static NAME_BYTES: usize = 100;
+// Names and link targets which do not fit their header field are written as
+// a preceding GNU long name entry, which most tar implementations understand
+static LONG_NAME: &'static str = "././@LongLink";
+// Sizes from this one on do not fit in eleven octal digits, and are written in
+// GNU's base-256 format instead
+static LARGE_SIZE: u64 = 0o100000000000;
+// Mode of files and directories whose permissions are unknown
+static DEFAULT_FILE_MODE: u32 = 0o644;
+static DEFAULT_DIRECTORY_MODE: u32 = 0o755;
+
+static REGULAR_TYPE: u8 = b'0';
+static SYMLINK_TYPE: u8 = b'2';
+static DIRECTORY_TYPE: u8 = b'5';
+static LONG_NAME_TYPE: u8 = b'L';
+static LONG_LINK_TYPE: u8 = b'K';
+
+// Writes entries to a tar stream in the ustar format. The size of a file is
+// part of its header, so its contents must be known in full before they can
+// be written.
+pub struct TarWriter<'a> {
+    out: &'a mut Write,
+}
+
+impl<'a> TarWriter<'a> {
+    pub fn new(out: &'a mut Write) -> TarWriter<'a> {
+        TarWriter { out: out }
+    }
+
+    // Adds a regular file of the given size, whose contents are read from the
+    // given reader
+    pub fn append_file(&mut self,
+                       path: &Path,
+                       size: u64,
+                       attributes: Attributes,
+                       contents: &mut Read)
+                       -> io::Result<()> {
+        let mode = attributes.mode.unwrap_or(DEFAULT_FILE_MODE);
+
+        try!(self.write_header(path, REGULAR_TYPE, size, mode, attributes.modified, None));
+
+        let copied = try!(io::copy(&mut contents.take(size), &mut *self.out));
+
+        if copied != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "File shrunk while being archived"));
+        }
+
+        self.pad(size)
+    }
+
+    pub fn append_directory(&mut self, path: &Path) -> io::Result<()> {
+        self.write_header(path, DIRECTORY_TYPE, 0, DEFAULT_DIRECTORY_MODE, None, None)
+    }
+
+    pub fn append_symlink(&mut self, path: &Path, target: &str) -> io::Result<()> {
+        self.write_header(path, SYMLINK_TYPE, 0, 0o777, None, Some(target))
+    }
+
+    // Writes the end of archive marker. Nothing should be written after this.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out.write_all(&vec![0; 2 * RECORD_BYTES])
+    }
+
+    fn write_header(&mut self,
+                    path: &Path,
+                    entry_type: u8,
+                    size: u64,
+                    mode: u32,
+                    modified: Option<u64>,
+                    link_target: Option<&str>)
+                    -> io::Result<()> {
+        let mut name = path.to_string_lossy().replace("\\", "/");
+
+        if entry_type == DIRECTORY_TYPE && !name.ends_with("/") {
+            name.push('/');
+        }
+
+        if name.len() > NAME_BYTES {
+            try!(self.write_long_name(LONG_NAME_TYPE, &name));
+        }
+
+        if let Some(target) = link_target {
+            if target.len() > NAME_BYTES {
+                try!(self.write_long_name(LONG_LINK_TYPE, target));
+            }
+        }
+
+        let header = header(name.as_bytes(),
+                            entry_type,
+                            size,
+                            mode,
+                            modified.unwrap_or(0) / 1000,
+                            link_target.unwrap_or("").as_bytes());
+
+        self.out.write_all(&header)
+    }
+
+    fn write_long_name(&mut self, entry_type: u8, name: &str) -> io::Result<()> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+
+        let header = header(LONG_NAME.as_bytes(), entry_type, bytes.len() as u64, 0, 0, b"");
+
+        try!(self.out.write_all(&header));
+        try!(self.out.write_all(&bytes));
+
+        self.pad(bytes.len() as u64)
+    }
+
+    // Fills up the last record of contents of the given size with zeroes
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let remainder = (size % RECORD_BYTES as u64) as usize;
+
+        match remainder {
+            0 => Ok(()),
+            _ => self.out.write_all(&vec![0; RECORD_BYTES - remainder]),
+        }
+    }
+}
+
+// Builds a header record. Names and link targets are truncated to their
+// fields; longer ones are preceded by a long name entry.
+fn header(name: &[u8],
+          entry_type: u8,
+          size: u64,
+          mode: u32,
+          seconds: u64,
+          link_target: &[u8])
+          -> Vec<u8> {
+    let mut header = vec![0; RECORD_BYTES];
+
+    copy_truncated(&mut header[0..100], name);
+    write_octal(&mut header[100..108], mode as u64);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_size(&mut header[124..136], size);
+    write_octal(&mut header[136..148], seconds);
+    header[156] = entry_type;
+    copy_truncated(&mut header[157..257], link_target);
+    copy_truncated(&mut header[257..263], b"ustar\0");
+    copy_truncated(&mut header[263..265], b"00");
+
+    // the checksum is computed with its own field filled with spaces
+    for byte in header[148..156].iter_mut() {
+        *byte = b' ';
+    }
+
+    let checksum = header.iter().fold(0, |sum, &byte| sum + byte as u64);
+
+    write_octal(&mut header[148..155], checksum);
+
+    header
+}
+
+fn copy_truncated(field: &mut [u8], bytes: &[u8]) {
+    for (target, &byte) in field.iter_mut().zip(bytes.iter()) {
+        *target = byte;
+    }
+}
+
+// Writes the number as zero padded octal digits, followed by a nul byte
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:01$o}", value, field.len() - 1);
+
+    copy_truncated(field, digits.as_bytes());
+    field[field.len() - 1] = 0;
+}
+
+fn write_size(field: &mut [u8], size: u64) {
+    if size < LARGE_SIZE {
+        return write_octal(field, size);
+    }
+
+    let length = field.len();
+
+    for (index, byte) in field.iter_mut().enumerate() {
+        *byte = match length - index - 1 {
+            shift if shift < 8 => (size >> (8 * shift)) as u8,
+            _ => 0,
+        };
+    }
+
+    field[0] = 0x80;
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::iter::repeat;
+    use std::path::Path;
+    use std::str;
+
+    use attributes::Attributes;
+    use super::{TarWriter, header, write_size, RECORD_BYTES};
+
+    fn octal(field: &[u8]) -> u64 {
+        let digits = str::from_utf8(field).unwrap().trim_matches(|c| c == '\0' || c == ' ');
+
+        u64::from_str_radix(digits, 8).unwrap()
+    }
+
+    #[test]
+    fn checksum() {
+        let header = header(b"some/file", b'0', 1234, 0o640, 1_400_000_000, b"");
+        let sum = header.iter()
+                        .enumerate()
+                        .fold(0, |sum, (index, &byte)| {
+                            sum +
+                            match index {
+                                148...155 => b' ' as u64,
+                                _ => byte as u64,
+                            }
+                        });
+
+        assert_eq!(RECORD_BYTES, header.len());
+        assert_eq!(b"some/file", &header[0..9]);
+        assert_eq!(0o640, octal(&header[100..108]));
+        assert_eq!(1234, octal(&header[124..136]));
+        assert_eq!(1_400_000_000, octal(&header[136..148]));
+        assert_eq!(sum, octal(&header[148..156]));
+        assert_eq!(b"ustar\0", &header[257..263]);
+    }
+
+    #[test]
+    fn large_size() {
+        let mut field = [0; 12];
+        write_size(&mut field, 0x1234567890);
+
+        assert_eq!([0x80, 0, 0, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78, 0x90], field);
+    }
+
+    #[test]
+    fn entries() {
+        let mut buffer = Vec::new();
+        let long_name = format!("{}file", repeat("directory/").take(12).collect::<String>());
+
+        {
+            let mut writer = TarWriter::new(&mut buffer);
+            let attributes = Attributes {
+                modified: Some(1_400_000_000_000),
+                mode: None,
+            };
+
+            writer.append_file(Path::new("short"), 5, attributes, &mut Cursor::new(b"hello"))
+                  .unwrap();
+            writer.append_file(Path::new(&long_name), 0, attributes, &mut Cursor::new(b""))
+                  .unwrap();
+            writer.append_directory(Path::new("empty")).unwrap();
+            writer.finish().unwrap();
+        }
+
+        // header and padded contents, long name entry with its name, header
+        // of the empty file, directory header and end marker
+        assert_eq!(RECORD_BYTES * (2 + 2 + 1 + 1 + 2), buffer.len());
+        assert_eq!(b"hello", &buffer[RECORD_BYTES..RECORD_BYTES + 5]);
+        assert_eq!(b'L', buffer[2 * RECORD_BYTES + 156]);
+        assert_eq!(long_name.as_bytes(),
+                   &buffer[3 * RECORD_BYTES..3 * RECORD_BYTES + long_name.len()]);
+        assert_eq!(b"empty/", &buffer[5 * RECORD_BYTES..5 * RECORD_BYTES + 6]);
+        assert_eq!(b'5', buffer[5 * RECORD_BYTES + 156]);
+        assert!(buffer[6 * RECORD_BYTES..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn shrunk_file() {
+        let mut buffer = Vec::new();
+        let mut writer = TarWriter::new(&mut buffer);
+
+        assert!(writer.append_file(Path::new("file"),
+                                   10,
+                                   Attributes::unknown(),
+                                   &mut Cursor::new(b"short"))
+                      .is_err());
+    }
+}
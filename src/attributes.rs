@@ -0,0 +1,131 @@
+use std::io;
+use std::fs::{self, Metadata};
+use std::path::Path;
+
+use filetime::{set_file_times, FileTime};
+use xattr;
+
+// Only the permission bits of a mode are recorded, not the file type
+static PERMISSION_BITS: u32 = 0o7777;
+
+// Metadata of a version of a file which is restored along with its contents.
+// The modification time is in milliseconds since the epoch. The mode holds
+// Unix permission bits, and is never known on other platforms. Versions
+// backed up by older releases have no mode either.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Attributes {
+    pub modified: Option<u64>,
+    pub mode: Option<u32>,
+}
+
+impl Attributes {
+    pub fn unknown() -> Attributes {
+        Attributes { modified: None, mode: None }
+    }
+
+    // Sets the attributes on the file at the given path. Unknown attributes
+    // are left alone.
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        if let Some(mode) = self.mode {
+            try!(set_mode(path, mode));
+        }
+
+        if let Some(modified) = self.modified {
+            let time = FileTime::from_seconds_since_1970(modified / 1000,
+                                                         (modified % 1000) as u32 * 1_000_000);
+
+            try!(set_file_times(path, time, time));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub fn file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & PERMISSION_BITS)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & PERMISSION_BITS))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_: &Path, _: u32) -> io::Result<()> {
+    Ok(())
+}
+
+// Reads the names and values of the extended attributes of the file at the
+// given path. Attributes whose names are not valid UTF-8 are left out. There
+// are none on platforms without extended attributes.
+pub fn read_xattrs(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(xattrs);
+    }
+
+    for name in try!(xattr::list(path)) {
+        if let Some(value) = try!(xattr::get(path, &name)) {
+            if let Ok(name) = name.into_string() {
+                xattrs.push((name, value));
+            }
+        }
+    }
+
+    Ok(xattrs)
+}
+
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    match xattr::SUPPORTED_PLATFORM {
+        true => xattr::set(path, name, value),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{File, metadata};
+
+    use filetime::FileTime;
+    use tempdir::TempDir;
+
+    use super::{Attributes, file_mode};
+
+    #[test]
+    fn apply_attributes() {
+        let temp_dir = TempDir::new("attributes").unwrap();
+        let path = temp_dir.path().join("script");
+
+        File::create(&path).unwrap();
+
+        let attributes = Attributes { modified: Some(1234567890123), mode: Some(0o750) };
+
+        attributes.apply(&path).unwrap();
+
+        let meta = metadata(&path).unwrap();
+        let modified = FileTime::from_last_modification_time(&meta);
+
+        assert_eq!(1234567890, modified.seconds_relative_to_1970());
+        assert_eq!(123000000, modified.nanoseconds());
+
+        if cfg!(unix) {
+            assert_eq!(Some(0o750), file_mode(&meta));
+        }
+
+        // unknown attributes change nothing
+        Attributes::unknown().apply(&path).unwrap();
+
+        assert_eq!(modified, FileTime::from_last_modification_time(&metadata(&path).unwrap()));
+    }
+}
@@ -0,0 +1,1036 @@
+extern crate libc;
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions, read_dir, rename, copy, remove_file, metadata};
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "sftp")]
+use std::sync::{Arc, Mutex};
+
+use rustc_serialize::hex::{ToHex, FromHex};
+
+use error::{BonzoResult, BonzoError};
+use crypto::hash_block;
+use throttle::Throttle;
+use {write_to_disk, write_to_disk_throttled, read_file, create_parent_dir};
+#[cfg(feature = "sftp")]
+use sftp::SftpBackend;
+
+// Name of the bucketing scheme used to assign blocks to destinations. The first
+// two bytes of a block's hash are interpreted as an integer, which is then
+// taken modulo the number of destinations.
+pub static PREFIX_MODULO_BUCKETING: &'static str = "prefix-modulo";
+
+// Number of directory levels blocks are spread over when the index does not
+// say otherwise, as in backups made before the depth was configurable. Every
+// level is named after the next two hexadecimal digits of the block hash.
+pub static DEFAULT_SHARD_DEPTH: usize = 1;
+static MAX_SHARD_DEPTH: usize = 4;
+
+// Name of the file in every shard directory listing the hashes of the blocks
+// within, one hexadecimal hash per line
+pub static MANIFEST_FILENAME: &'static str = "manifest";
+
+// Name of the directory in every destination where blocks are written before
+// they are renamed into their shard. Anything in it was left behind by an
+// interrupted write.
+pub static PARTIAL_DIRECTORY: &'static str = "partial";
+
+// Prefix of the names of index snapshots. The prefix is followed by the time
+// the snapshot was taken, in milliseconds since the epoch.
+pub static INDEX_SNAPSHOT_PREFIX: &'static str = "index-";
+
+// Name of the encrypted index at the primary destination, and of the new
+// version of it while it is being written
+static INDEX_FILENAME: &'static str = "index";
+static NEW_INDEX_FILENAME: &'static str = "index-new";
+
+// Destinations starting with this are locations on a remote server rather
+// than local directories
+pub static SFTP_SCHEME: &'static str = "sftp://";
+
+// Storage for the blocks and the index of a backup. Both are handed over in
+// their processed form, so a backend never sees anything but compressed and
+// encrypted bytes. Blocks are identified by their hash.
+pub trait Backend {
+    // Stores a block. Once this returns, the block is complete; an interrupted
+    // write never leaves a truncated block under its hash.
+    fn write_block(&self, hash: &[u8], bytes: &[u8], throttle: Option<&Throttle>)
+                   -> BonzoResult<()>;
+
+    // Errs with the hash of the block when it cannot be found
+    fn read_block(&self, hash: &[u8]) -> BonzoResult<Vec<u8>>;
+
+    // Opens a block for reading along with its size in bytes, so that large
+    // blocks need not be read into memory as a whole. Errs like read_block.
+    fn open_block(&self, hash: &[u8]) -> BonzoResult<(Box<Read>, u64)> {
+        let bytes = try!(self.read_block(hash));
+        let length = bytes.len() as u64;
+
+        Ok((Box::new(io::Cursor::new(bytes)), length))
+    }
+
+    // Returns the number of bytes removed, or None when the block was not
+    // stored in the first place
+    fn remove_block(&self, hash: &[u8]) -> BonzoResult<Option<u64>>;
+
+    // Replaces the index, such that either the old or the new version is
+    // always complete
+    fn write_index(&self, bytes: &[u8], throttle: Option<&Throttle>) -> BonzoResult<()>;
+
+    fn read_index(&self) -> BonzoResult<Vec<u8>>;
+
+    // Stores one of the small files kept in the clear next to the index,
+    // such as the name of the cipher
+    fn write_metadata(&self, name: &str, bytes: &[u8]) -> BonzoResult<()>;
+
+    // Returns None when the file was never written, as in older backups
+    fn read_metadata(&self, name: &str) -> BonzoResult<Option<Vec<u8>>>;
+}
+
+// Keeps blocks and the index in a directory on the local filesystem, which
+// may of course be a mounted network share. Blocks are spread over nested
+// shard directories.
+#[derive(Clone)]
+pub struct LocalBackend {
+    directory: PathBuf,
+    shard_depth: usize,
+    // Whether every block is synced to disk before it is renamed into place
+    sync: bool,
+}
+
+impl LocalBackend {
+    pub fn new(directory: PathBuf, shard_depth: usize) -> LocalBackend {
+        LocalBackend {
+            directory: directory,
+            shard_depth: shard_depth,
+            sync: true,
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn block_path(&self, hash: &[u8]) -> PathBuf {
+        block_output_path(&self.directory, hash, self.shard_depth)
+    }
+
+    // Returns the path a new block is written to before it is moved into
+    // place. It is in the same directory tree as the block, so that it can be
+    // renamed.
+    fn partial_block_path(&self, hash: &[u8]) -> PathBuf {
+        self.directory.join(PARTIAL_DIRECTORY).join(hash.to_hex())
+    }
+
+    // Removes the blocks left behind in the partial directory by writes which
+    // were interrupted. Returns the number of blocks removed.
+    pub fn remove_partial_blocks(&self) -> BonzoResult<u64> {
+        let directory = self.directory.join(PARTIAL_DIRECTORY);
+        let mut count = 0;
+
+        if !directory.is_dir() {
+            return Ok(0);
+        }
+
+        for entry in try_io!(read_dir(&directory), &directory) {
+            let path = try_io!(entry, &directory).path();
+
+            try_io!(remove_file(&path), &path);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // Returns the path of a previously written block. Errs with the block hash
+    // and the directory when the block cannot be found.
+    pub fn existing_block_path(&self, hash: &[u8]) -> BonzoResult<PathBuf> {
+        let path = self.block_path(hash);
+
+        if !path.exists() {
+            return Err(BonzoError::MissingBlock(format!("Could not find block {} at destination {}",
+                                                        hash.to_hex(),
+                                                        self.directory.display())));
+        }
+
+        Ok(path)
+    }
+
+    // Determines how the index is put in place after it has been written
+    pub fn index_finalization(&self) -> IndexFinalization {
+        probe_index_finalization(&self.directory, &self.directory.join(INDEX_FILENAME))
+    }
+}
+
+impl Backend for LocalBackend {
+    // The block is first written to the partial directory and synced, and
+    // then renamed into its shard. Errs when the directory is not available,
+    // so that we never write blocks into the mount point of a disk which has
+    // gone offline.
+    fn write_block(&self, hash: &[u8], bytes: &[u8], throttle: Option<&Throttle>)
+                   -> BonzoResult<()> {
+        if !self.directory.is_dir() {
+            return Err(BonzoError::Other(format!("Backup destination {} is unavailable",
+                                                 self.directory.display())));
+        }
+
+        let path = self.block_path(hash);
+        let partial_path = self.partial_block_path(hash);
+
+        try!(create_parent_dir(&path));
+        try!(create_parent_dir(&partial_path));
+        try_io!(write_to_disk_throttled(&partial_path, bytes, throttle, self.sync),
+                &partial_path);
+
+        Ok(try_io!(rename(&partial_path, &path), &path))
+    }
+
+    fn read_block(&self, hash: &[u8]) -> BonzoResult<Vec<u8>> {
+        let path = try!(self.existing_block_path(hash));
+
+        Ok(try_io!(read_file(&path), path))
+    }
+
+    fn open_block(&self, hash: &[u8]) -> BonzoResult<(Box<Read>, u64)> {
+        let path = try!(self.existing_block_path(hash));
+        let file = try_io!(File::open(&path), &path);
+        let length = try_io!(file.metadata(), &path).len();
+
+        Ok((Box::new(file), length))
+    }
+
+    fn remove_block(&self, hash: &[u8]) -> BonzoResult<Option<u64>> {
+        let path = self.block_path(hash);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let bytes = try_io!(metadata(&path), &path).len();
+        try_io!(remove_file(&path), &path);
+
+        Ok(Some(bytes))
+    }
+
+    fn write_index(&self, bytes: &[u8], throttle: Option<&Throttle>) -> BonzoResult<()> {
+        let new_index = self.directory.join(NEW_INDEX_FILENAME);
+        let index = self.directory.join(INDEX_FILENAME);
+        let finalization = self.index_finalization();
+
+        try_io!(write_to_disk_throttled(&new_index, bytes, throttle, true), &new_index);
+
+        finalize_index(&new_index, &index, bytes, finalization)
+    }
+
+    fn read_index(&self) -> BonzoResult<Vec<u8>> {
+        let path = self.directory.join(INDEX_FILENAME);
+
+        Ok(try_io!(read_file(&path), path))
+    }
+
+    fn write_metadata(&self, name: &str, bytes: &[u8]) -> BonzoResult<()> {
+        let path = self.directory.join(name);
+
+        Ok(try_io!(write_to_disk(&path, bytes), path))
+    }
+
+    fn read_metadata(&self, name: &str) -> BonzoResult<Option<Vec<u8>>> {
+        let path = self.directory.join(name);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        Ok(Some(try_io!(read_file(&path), path)))
+    }
+}
+
+// Routes block writes and reads to one of several destination directories.
+// Since the destination of a block is derived from its hash alone, blocks can
+// be found again on restore without any extra bookkeeping in the index. The
+// first destination is the primary one; that is where the index is kept.
+#[derive(Clone)]
+pub struct MultiBackend {
+    destinations: Vec<PathBuf>,
+    backends: Vec<LocalBackend>,
+    shard_depth: usize,
+    sync: bool,
+}
+
+impl MultiBackend {
+    pub fn new(destinations: Vec<PathBuf>, shard_depth: usize) -> BonzoResult<MultiBackend> {
+        if destinations.is_empty() {
+            return Err(BonzoError::from_str("No backup destinations given"));
+        }
+
+        try!(check_shard_depth(shard_depth));
+
+        let backends = destinations.iter()
+                                   .map(|destination| {
+                                       LocalBackend::new(destination.clone(), shard_depth)
+                                   })
+                                   .collect();
+
+        Ok(MultiBackend {
+            destinations: destinations,
+            backends: backends,
+            shard_depth: shard_depth,
+            sync: true,
+        })
+    }
+
+    // Decides whether blocks and manifests are synced to disk one by one as
+    // they are written. When they are not, flush should be called before
+    // anything which refers to them is committed.
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+
+        for backend in self.backends.iter_mut() {
+            backend.sync = sync;
+        }
+    }
+
+    // Makes sure everything written so far has reached the disk. Without
+    // syncing block by block, this is done for all file systems at once.
+    pub fn flush(&self) {
+        if !self.sync {
+            unsafe {
+                libc::sync();
+            }
+        }
+    }
+
+    pub fn primary(&self) -> &Path {
+        &self.destinations[0]
+    }
+
+    pub fn destinations(&self) -> &[PathBuf] {
+        &self.destinations
+    }
+
+    // Returns the backend of the destination which holds the block with the
+    // given hash
+    fn bucket(&self, hash: &[u8]) -> &LocalBackend {
+        let bucket = hash.iter().take(2).fold(0usize, |acc, &byte| (acc << 8) | byte as usize);
+
+        &self.backends[bucket % self.backends.len()]
+    }
+
+    pub fn destination(&self, hash: &[u8]) -> &Path {
+        self.bucket(hash).directory()
+    }
+
+    pub fn block_path(&self, hash: &[u8]) -> PathBuf {
+        self.bucket(hash).block_path(hash)
+    }
+
+    // Removes the blocks left behind in the partial directories of every
+    // destination. Returns the number of blocks removed.
+    pub fn remove_partial_blocks(&self) -> BonzoResult<u64> {
+        let mut count = 0;
+
+        for backend in self.backends.iter() {
+            count += try!(backend.remove_partial_blocks());
+        }
+
+        Ok(count)
+    }
+
+    pub fn existing_block_path(&self, hash: &[u8]) -> BonzoResult<PathBuf> {
+        self.bucket(hash).existing_block_path(hash)
+    }
+
+    // The index is always kept at the primary destination
+    pub fn index_finalization(&self) -> IndexFinalization {
+        self.backends[0].index_finalization()
+    }
+
+    // Manifests are kept in the top level shard directories only, whatever
+    // the depth
+    fn manifest_path(&self, hash: &[u8]) -> PathBuf {
+        let hex = hash.to_hex();
+
+        self.destination(hash).join(&hex[0..2]).join(MANIFEST_FILENAME)
+    }
+
+    // Appends the hash of a freshly written block to the manifest of its shard
+    pub fn record_block(&self, hash: &[u8]) -> BonzoResult<()> {
+        let path = self.manifest_path(hash);
+        let mut file = try_io!(OpenOptions::new().create(true).append(true).open(&path),
+                               &path);
+
+        try_io!(writeln!(file, "{}", hash.to_hex()), &path);
+
+        if self.sync {
+            try_io!(file.sync_all(), &path);
+        }
+
+        Ok(())
+    }
+
+    // Removes the given hashes from their manifests. Every affected manifest
+    // is rewritten only once.
+    pub fn forget_blocks(&self, hashes: &[Vec<u8>]) -> BonzoResult<()> {
+        let mut removals: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+
+        for hash in hashes {
+            removals.entry(self.manifest_path(hash))
+                    .or_insert_with(HashSet::new)
+                    .insert(hash.to_hex());
+        }
+
+        for (path, removed) in removals {
+            if !path.exists() {
+                continue;
+            }
+
+            let remaining: Vec<String> = try_io!(read_manifest(&path), &path)
+                                             .into_iter()
+                                             .filter(|hex| !removed.contains(hex))
+                                             .collect();
+
+            try!(write_manifest(&path, &remaining));
+        }
+
+        Ok(())
+    }
+
+    // Lists the hashes of all stored blocks according to the manifests
+    pub fn manifest_blocks(&self) -> BonzoResult<Vec<Vec<u8>>> {
+        let mut hashes = Vec::new();
+
+        for shard in try!(self.shard_directories()) {
+            let path = shard.join(MANIFEST_FILENAME);
+
+            if !path.exists() {
+                continue;
+            }
+
+            for hex in try_io!(read_manifest(&path), &path) {
+                hashes.push(try!(decode_hash(&hex)));
+            }
+        }
+
+        Ok(hashes)
+    }
+
+    // Lists the hashes of all stored blocks by scanning every shard
+    // directory. This may be slow for large backups on remote filesystems.
+    pub fn scan_blocks(&self) -> BonzoResult<Vec<Vec<u8>>> {
+        let mut hashes = Vec::new();
+
+        for shard in try!(self.block_directories()) {
+            for entry in try_io!(read_dir(&shard), &shard) {
+                let path = try_io!(entry, &shard).path();
+                let name = path.file_name().and_then(|os_str| os_str.to_str()).unwrap_or("");
+
+                if name == MANIFEST_FILENAME || !path.is_file() {
+                    continue;
+                }
+
+                if let Ok(hash) = decode_hash(name) {
+                    hashes.push(hash);
+                }
+            }
+        }
+
+        Ok(hashes)
+    }
+
+    // Compares the manifests with the actual contents of the shard
+    // directories. Returns the number of blocks on disk which are missing
+    // from the manifests and the number of manifest entries without a block.
+    pub fn check_manifests(&self) -> BonzoResult<(u64, u64)> {
+        let listed: HashSet<Vec<u8>> = try!(self.manifest_blocks()).into_iter().collect();
+        let stored: HashSet<Vec<u8>> = try!(self.scan_blocks()).into_iter().collect();
+
+        Ok((stored.difference(&listed).count() as u64, listed.difference(&stored).count() as u64))
+    }
+
+    // Rewrites all manifests from a scan of the shard directories. Returns the
+    // number of blocks found.
+    pub fn rebuild_manifests(&self) -> BonzoResult<u64> {
+        let mut shards: HashMap<PathBuf, Vec<String>> = HashMap::new();
+
+        for shard in try!(self.shard_directories()) {
+            shards.insert(shard.join(MANIFEST_FILENAME), Vec::new());
+        }
+
+        let hashes = try!(self.scan_blocks());
+
+        for hash in hashes.iter() {
+            shards.entry(self.manifest_path(hash))
+                  .or_insert_with(Vec::new)
+                  .push(hash.to_hex());
+        }
+
+        for (path, list) in shards {
+            try!(write_manifest(&path, &list));
+        }
+
+        Ok(hashes.len() as u64)
+    }
+
+    // Lists the top level shard directories of every destination
+    fn shard_directories(&self) -> BonzoResult<Vec<PathBuf>> {
+        let mut shards = Vec::new();
+
+        for destination in self.destinations.iter() {
+            shards.extend(try!(shard_subdirectories(destination)));
+        }
+
+        Ok(shards)
+    }
+
+    // Lists the shard directories at the deepest level, which hold the blocks
+    fn block_directories(&self) -> BonzoResult<Vec<PathBuf>> {
+        let mut shards = try!(self.shard_directories());
+
+        for _ in 1..self.shard_depth {
+            let mut nested = Vec::new();
+
+            for shard in shards.iter() {
+                nested.extend(try!(shard_subdirectories(shard)));
+            }
+
+            shards = nested;
+        }
+
+        Ok(shards)
+    }
+}
+
+impl Backend for MultiBackend {
+    fn write_block(&self, hash: &[u8], bytes: &[u8], throttle: Option<&Throttle>)
+                   -> BonzoResult<()> {
+        self.bucket(hash).write_block(hash, bytes, throttle)
+    }
+
+    fn read_block(&self, hash: &[u8]) -> BonzoResult<Vec<u8>> {
+        self.bucket(hash).read_block(hash)
+    }
+
+    fn open_block(&self, hash: &[u8]) -> BonzoResult<(Box<Read>, u64)> {
+        self.bucket(hash).open_block(hash)
+    }
+
+    fn remove_block(&self, hash: &[u8]) -> BonzoResult<Option<u64>> {
+        self.bucket(hash).remove_block(hash)
+    }
+
+    fn write_index(&self, bytes: &[u8], throttle: Option<&Throttle>) -> BonzoResult<()> {
+        self.backends[0].write_index(bytes, throttle)
+    }
+
+    fn read_index(&self) -> BonzoResult<Vec<u8>> {
+        self.backends[0].read_index()
+    }
+
+    fn write_metadata(&self, name: &str, bytes: &[u8]) -> BonzoResult<()> {
+        self.backends[0].write_metadata(name, bytes)
+    }
+
+    fn read_metadata(&self, name: &str) -> BonzoResult<Option<Vec<u8>>> {
+        self.backends[0].read_metadata(name)
+    }
+}
+
+// Where the blocks and index of a backup are kept: one or more directories on
+// the local filesystem, or a single location on a remote server. Shard
+// manifests, index snapshots and the other maintenance which works on the
+// stored files directly is only available for local storage. A remote server
+// is shared by all threads over a single connection.
+#[derive(Clone)]
+pub enum Storage {
+    Local(MultiBackend),
+    #[cfg(feature = "sftp")]
+    Remote(Arc<Mutex<SftpBackend>>),
+}
+
+impl Storage {
+    // Opens the storage at the given destinations. A remote destination
+    // cannot be combined with any other.
+    pub fn open(destinations: Vec<PathBuf>, shard_depth: usize) -> BonzoResult<Storage> {
+        match destinations.iter().any(|destination| is_remote(destination)) {
+            true if destinations.len() > 1 => {
+                Err(BonzoError::from_str("A remote destination cannot be combined with others"))
+            }
+            true => open_remote(&destinations[0], shard_depth),
+            false => MultiBackend::new(destinations, shard_depth).map(Storage::Local),
+        }
+    }
+
+    pub fn local(&self) -> BonzoResult<&MultiBackend> {
+        match *self {
+            Storage::Local(ref backend) => Ok(backend),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(..) => {
+                Err(BonzoError::from_str("This is not supported for remote destinations"))
+            }
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.local().is_ok()
+    }
+
+    // The local directories of the backup, which are none for remote storage
+    pub fn destinations(&self) -> &[PathBuf] {
+        match self.local() {
+            Ok(backend) => backend.destinations(),
+            Err(..) => &[],
+        }
+    }
+
+    // See MultiBackend::set_sync. Blocks on a remote server are always
+    // written one by one.
+    pub fn set_sync(&mut self, sync: bool) {
+        if let Storage::Local(ref mut backend) = *self {
+            backend.set_sync(sync);
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Ok(backend) = self.local() {
+            backend.flush();
+        }
+    }
+}
+
+impl Backend for Storage {
+    fn write_block(&self, hash: &[u8], bytes: &[u8], throttle: Option<&Throttle>)
+                   -> BonzoResult<()> {
+        match *self {
+            Storage::Local(ref backend) => backend.write_block(hash, bytes, throttle),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(ref backend) => {
+                backend.lock().unwrap().write_block(hash, bytes, throttle)
+            }
+        }
+    }
+
+    fn read_block(&self, hash: &[u8]) -> BonzoResult<Vec<u8>> {
+        match *self {
+            Storage::Local(ref backend) => backend.read_block(hash),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(ref backend) => backend.lock().unwrap().read_block(hash),
+        }
+    }
+
+    fn open_block(&self, hash: &[u8]) -> BonzoResult<(Box<Read>, u64)> {
+        match *self {
+            Storage::Local(ref backend) => backend.open_block(hash),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(ref backend) => backend.lock().unwrap().open_block(hash),
+        }
+    }
+
+    fn remove_block(&self, hash: &[u8]) -> BonzoResult<Option<u64>> {
+        match *self {
+            Storage::Local(ref backend) => backend.remove_block(hash),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(ref backend) => backend.lock().unwrap().remove_block(hash),
+        }
+    }
+
+    fn write_index(&self, bytes: &[u8], throttle: Option<&Throttle>) -> BonzoResult<()> {
+        match *self {
+            Storage::Local(ref backend) => backend.write_index(bytes, throttle),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(ref backend) => backend.lock().unwrap().write_index(bytes, throttle),
+        }
+    }
+
+    fn read_index(&self) -> BonzoResult<Vec<u8>> {
+        match *self {
+            Storage::Local(ref backend) => backend.read_index(),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(ref backend) => backend.lock().unwrap().read_index(),
+        }
+    }
+
+    fn write_metadata(&self, name: &str, bytes: &[u8]) -> BonzoResult<()> {
+        match *self {
+            Storage::Local(ref backend) => backend.write_metadata(name, bytes),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(ref backend) => backend.lock().unwrap().write_metadata(name, bytes),
+        }
+    }
+
+    fn read_metadata(&self, name: &str) -> BonzoResult<Option<Vec<u8>>> {
+        match *self {
+            Storage::Local(ref backend) => backend.read_metadata(name),
+            #[cfg(feature = "sftp")]
+            Storage::Remote(ref backend) => backend.lock().unwrap().read_metadata(name),
+        }
+    }
+}
+
+// Whether the destination is a location on a remote server
+pub fn is_remote(destination: &Path) -> bool {
+    destination.to_str().map(|url| url.starts_with(SFTP_SCHEME)).unwrap_or(false)
+}
+
+#[cfg(feature = "sftp")]
+fn open_remote(destination: &Path, shard_depth: usize) -> BonzoResult<Storage> {
+    let url = destination.to_string_lossy();
+
+    SftpBackend::connect(&url, shard_depth)
+        .map(|backend| Storage::Remote(Arc::new(Mutex::new(backend))))
+}
+
+#[cfg(not(feature = "sftp"))]
+fn open_remote(destination: &Path, _: usize) -> BonzoResult<Storage> {
+    Err(BonzoError::Other(format!("Cannot use remote destination {} without SFTP support, \
+                                   which is enabled by building with --features sftp",
+                                  destination.display())))
+}
+
+// Blocks are spread over the given number of nested directory levels, named
+// after successive pairs of hexadecimal digits of their hash
+pub fn block_output_path(base_path: &Path, hash: &[u8], shard_depth: usize) -> PathBuf {
+    let hex = hash.to_hex();
+    let mut path = base_path.to_owned();
+
+    for level in 0..shard_depth {
+        path.push(&hex[2 * level..2 * level + 2]);
+    }
+
+    path.push(hex);
+
+    path
+}
+
+// Errs when blocks cannot be spread over the given number of directory levels
+pub fn check_shard_depth(shard_depth: usize) -> BonzoResult<()> {
+    if shard_depth == 0 || shard_depth > MAX_SHARD_DEPTH {
+        return Err(BonzoError::Other(format!("Shard depth must be between 1 and {}, not {}",
+                                             MAX_SHARD_DEPTH,
+                                             shard_depth)));
+    }
+
+    Ok(())
+}
+
+// Lists the directories within the given one which are named like shards
+fn shard_subdirectories(directory: &Path) -> BonzoResult<Vec<PathBuf>> {
+    let mut shards = Vec::new();
+
+    for entry in try_io!(read_dir(directory), directory) {
+        let path = try_io!(entry, directory).path();
+        let is_shard = path.file_name()
+                           .and_then(|os_str| os_str.to_str())
+                           .map(|name| name.len() == 2 && name.from_hex().is_ok())
+                           .unwrap_or(false);
+
+        if is_shard && path.is_dir() {
+            shards.push(path);
+        }
+    }
+
+    Ok(shards)
+}
+
+// Ways of putting a freshly written index in place of the previous one. A
+// rename is atomic, so there is never a moment without a complete index. When
+// renames are not possible, the index is copied and read back to verify it.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IndexFinalization {
+    Rename,
+    VerifiedCopy,
+}
+
+impl IndexFinalization {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            IndexFinalization::Rename => "rename",
+            IndexFinalization::VerifiedCopy => "verified-copy",
+        }
+    }
+}
+
+// Determines the safest way to replace the index in the given directory by
+// trying to rename a small file there. Renames are also ruled out when the
+// current index is on another device than its directory, as is the case when
+// it is a mount point.
+pub fn probe_index_finalization(directory: &Path, index: &Path) -> IndexFinalization {
+    let probe = directory.join("index-probe");
+    let renamed_probe = directory.join("index-probe-renamed");
+    let can_rename = write_to_disk(&probe, b"probe")
+                         .and_then(|_| rename(&probe, &renamed_probe))
+                         .is_ok();
+
+    let _ = remove_file(&probe);
+    let _ = remove_file(&renamed_probe);
+
+    match can_rename && same_device(directory, index) {
+        true => IndexFinalization::Rename,
+        false => IndexFinalization::VerifiedCopy,
+    }
+}
+
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (metadata(a), metadata(b)) {
+        (Ok(meta_a), Ok(meta_b)) => meta_a.dev() == meta_b.dev(),
+        _ => true,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_device(_: &Path, _: &Path) -> bool {
+    true
+}
+
+// Replaces the index with the new one, which should contain the given bytes
+pub fn finalize_index(new_index: &Path,
+                      index: &Path,
+                      bytes: &[u8],
+                      finalization: IndexFinalization)
+                      -> BonzoResult<()> {
+    if finalization == IndexFinalization::Rename {
+        return Ok(try_io!(rename(new_index, index), index));
+    }
+
+    try_io!(copy(new_index, index), new_index);
+
+    let mut written = Vec::new();
+    try_io!(File::open(index).and_then(|mut file| file.read_to_end(&mut written)), index);
+
+    if hash_block(&written) != hash_block(bytes) {
+        return Err(BonzoError::Other(format!("Copy of the index at {} is corrupt",
+                                             index.display())));
+    }
+
+    Ok(try_io!(remove_file(new_index), new_index))
+}
+
+// Stores a copy of the index, which should contain the given bytes, as a
+// snapshot taken at the given time. Afterwards, only the given number of most
+// recent snapshots are kept.
+pub fn snapshot_index(directory: &Path, bytes: &[u8], timestamp: u64, keep: usize)
+                      -> BonzoResult<()> {
+    let path = directory.join(format!("{}{}", INDEX_SNAPSHOT_PREFIX, timestamp));
+
+    try_io!(write_to_disk(&path, bytes), &path);
+
+    let snapshots = try!(index_snapshots(directory));
+    let obsolete = snapshots.len().saturating_sub(keep);
+
+    for &(_, ref path) in snapshots[..obsolete].iter() {
+        try_io!(remove_file(path), path);
+    }
+
+    Ok(())
+}
+
+// Lists the index snapshots in the given directory along with the times they
+// were taken, oldest first
+pub fn index_snapshots(directory: &Path) -> BonzoResult<Vec<(u64, PathBuf)>> {
+    let mut snapshots = Vec::new();
+
+    for entry in try_io!(read_dir(directory), directory) {
+        let path = try_io!(entry, directory).path();
+        let timestamp = path.file_name()
+                            .and_then(|os_str| os_str.to_str())
+                            .and_then(|name| {
+                                match name.starts_with(INDEX_SNAPSHOT_PREFIX) {
+                                    true => name[INDEX_SNAPSHOT_PREFIX.len()..].parse().ok(),
+                                    false => None,
+                                }
+                            });
+
+        if let Some(timestamp) = timestamp {
+            snapshots.push((timestamp, path));
+        }
+    }
+
+    snapshots.sort();
+
+    Ok(snapshots)
+}
+
+fn decode_hash(hex: &str) -> BonzoResult<Vec<u8>> {
+    hex.from_hex()
+       .map_err(|_| BonzoError::Other(format!("Invalid block hash in manifest: {}", hex)))
+}
+
+fn read_manifest(path: &Path) -> io::Result<Vec<String>> {
+    let mut contents = String::new();
+
+    try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents)));
+
+    Ok(contents.lines()
+               .filter(|line| !line.is_empty())
+               .map(|line| line.to_owned())
+               .collect())
+}
+
+// Replaces a manifest by first writing the new version next to it, so that a
+// crash never leaves a truncated manifest behind
+fn write_manifest(path: &Path, hashes: &[String]) -> BonzoResult<()> {
+    let temp_path = path.with_extension("new");
+    let mut contents = String::new();
+
+    for hex in hashes {
+        contents.push_str(hex);
+        contents.push('\n');
+    }
+
+    try_io!(write_to_disk(&temp_path, contents.as_bytes()), &temp_path);
+
+    Ok(try_io!(rename(&temp_path, path), path))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::fs::{create_dir_all, remove_file};
+
+    use tempdir::TempDir;
+    use write_to_disk;
+    use super::{Backend, LocalBackend, MultiBackend, IndexFinalization, probe_index_finalization,
+                finalize_index, snapshot_index, index_snapshots};
+
+    #[test]
+    fn bucketing() {
+        let destinations = vec![PathBuf::from("/a"), PathBuf::from("/b"), PathBuf::from("/c")];
+        let backend = MultiBackend::new(destinations, 1).unwrap();
+
+        assert_eq!(PathBuf::from("/a"), backend.destination(&[0, 0, 7]));
+        assert_eq!(PathBuf::from("/b"), backend.destination(&[0, 1, 7]));
+        assert_eq!(PathBuf::from("/c"), backend.destination(&[0, 2]));
+        assert_eq!(PathBuf::from("/b"), backend.destination(&[1, 0]));
+        assert_eq!(backend.destination(&[9, 9, 1]), backend.destination(&[9, 9, 2]));
+
+        assert!(MultiBackend::new(Vec::new(), 1).is_err());
+    }
+
+    #[test]
+    fn local_backend() {
+        let temp_dir = TempDir::new("local-backend").unwrap();
+        let backend = LocalBackend::new(temp_dir.path().to_owned(), 2);
+        let hash = vec![0x12, 0x34, 0x56];
+
+        assert!(backend.read_block(&hash).is_err());
+        assert!(backend.read_index().is_err());
+
+        backend.write_block(&hash, b"block", None).unwrap();
+        backend.write_index(b"index", None).unwrap();
+
+        assert!(temp_dir.path().join("12").join("34").join("123456").exists());
+        assert!(!temp_dir.path().join(super::PARTIAL_DIRECTORY).join("123456").exists());
+        assert_eq!(b"block".to_vec(), backend.read_block(&hash).unwrap());
+        assert_eq!(b"index".to_vec(), backend.read_index().unwrap());
+
+        assert_eq!(Some(5), backend.remove_block(&hash).unwrap());
+        assert_eq!(None, backend.remove_block(&hash).unwrap());
+
+        let missing = LocalBackend::new(temp_dir.path().join("offline"), 1);
+
+        assert!(missing.write_block(&hash, b"block", None).is_err());
+    }
+
+    #[test]
+    fn shard_depth() {
+        let temp_dir = TempDir::new("shard-depth").unwrap();
+        let backend = MultiBackend::new(vec![temp_dir.path().to_owned()], 2).unwrap();
+        let hash = vec![0xab, 0xcd, 0xef];
+
+        assert_eq!(temp_dir.path().join("ab").join("cd").join("abcdef"),
+                   backend.block_path(&hash));
+
+        let path = backend.block_path(&hash);
+
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write_to_disk(&path, b"block").unwrap();
+        backend.record_block(&hash).unwrap();
+
+        assert!(temp_dir.path().join("ab").join(super::MANIFEST_FILENAME).exists());
+        assert_eq!(vec![hash], backend.scan_blocks().unwrap());
+        assert_eq!((0, 0), backend.check_manifests().unwrap());
+
+        assert!(MultiBackend::new(vec![temp_dir.path().to_owned()], 0).is_err());
+        assert!(MultiBackend::new(vec![temp_dir.path().to_owned()], 5).is_err());
+    }
+
+    #[test]
+    fn manifests() {
+        let temp_dir = TempDir::new("manifest").unwrap();
+        let backend = MultiBackend::new(vec![temp_dir.path().to_owned()], 1).unwrap();
+        let hashes = vec![vec![1, 2, 3], vec![1, 5, 8], vec![200, 0, 1]];
+
+        for hash in hashes.iter() {
+            let path = backend.block_path(hash);
+
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write_to_disk(&path, b"block").unwrap();
+        }
+
+        backend.record_block(&hashes[0]).unwrap();
+
+        assert_eq!((2, 0), backend.check_manifests().unwrap());
+        assert_eq!(3, backend.rebuild_manifests().unwrap());
+        assert_eq!((0, 0), backend.check_manifests().unwrap());
+        assert_eq!(3, backend.manifest_blocks().unwrap().len());
+
+        remove_file(backend.block_path(&hashes[1])).unwrap();
+
+        assert_eq!((0, 1), backend.check_manifests().unwrap());
+
+        backend.forget_blocks(&hashes[1..2]).unwrap();
+
+        assert_eq!((0, 0), backend.check_manifests().unwrap());
+        assert_eq!(2, backend.scan_blocks().unwrap().len());
+    }
+
+    #[test]
+    fn index_finalization() {
+        let temp_dir = TempDir::new("finalize").unwrap();
+        let new_index = temp_dir.path().join("index-new");
+        let index = temp_dir.path().join("index");
+
+        assert_eq!(IndexFinalization::Rename,
+                   probe_index_finalization(temp_dir.path(), &index));
+        assert!(!temp_dir.path().join("index-probe").exists());
+
+        for &finalization in [IndexFinalization::Rename, IndexFinalization::VerifiedCopy].iter() {
+            write_to_disk(&new_index, finalization.name().as_bytes()).unwrap();
+            finalize_index(&new_index, &index, finalization.name().as_bytes(), finalization)
+                .unwrap();
+
+            assert!(!new_index.exists());
+            assert!(index.exists());
+        }
+
+        write_to_disk(&new_index, b"written").unwrap();
+
+        assert!(finalize_index(&new_index, &index, b"expected", IndexFinalization::VerifiedCopy)
+                    .is_err());
+    }
+
+    #[test]
+    fn index_snapshots_pruning() {
+        let temp_dir = TempDir::new("snapshots").unwrap();
+
+        write_to_disk(&temp_dir.path().join("index"), b"live").unwrap();
+        write_to_disk(&temp_dir.path().join("index-new"), b"new").unwrap();
+
+        for &timestamp in [30, 10, 20].iter() {
+            snapshot_index(temp_dir.path(), b"snapshot", timestamp, 2).unwrap();
+        }
+
+        let timestamps: Vec<u64> = index_snapshots(temp_dir.path())
+                                       .unwrap()
+                                       .into_iter()
+                                       .map(|(timestamp, _)| timestamp)
+                                       .collect();
+
+        assert_eq!(vec![20, 30], timestamps);
+        assert!(temp_dir.path().join("index").exists());
+        assert!(temp_dir.path().join("index-new").exists());
+    }
+}
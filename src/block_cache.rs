@@ -0,0 +1,124 @@
+use std::collections::{HashMap, BTreeMap};
+use std::sync::Arc;
+
+use BlockId;
+
+// Keeps recently restored blocks in memory, so that blocks shared by many
+// files are only read and decrypted once. The contents are kept decompressed,
+// and the total number of bytes held never exceeds the budget. When a new
+// block does not fit, the least recently used blocks are evicted. Blocks
+// larger than the whole budget are never cached.
+pub struct BlockCache {
+    budget: usize,
+    size: usize,
+    // Incremented on every use, so that lower values are less recent
+    clock: u64,
+    blocks: HashMap<BlockId, (u64, Arc<Vec<u8>>)>,
+    recency: BTreeMap<u64, BlockId>,
+}
+
+impl BlockCache {
+    pub fn new(budget: usize) -> BlockCache {
+        BlockCache {
+            budget: budget,
+            size: 0,
+            clock: 0,
+            blocks: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    // Returns the contents of the block when it is cached, marking it as most
+    // recently used
+    pub fn get(&mut self, block_id: BlockId) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+
+        match self.blocks.get_mut(&block_id) {
+            Some(&mut (ref mut last_used, ref bytes)) => {
+                self.recency.remove(&*last_used);
+                self.recency.insert(self.clock, block_id);
+                *last_used = self.clock;
+
+                Some(bytes.clone())
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, block_id: BlockId, bytes: Arc<Vec<u8>>) {
+        if bytes.len() > self.budget || self.blocks.contains_key(&block_id) {
+            return;
+        }
+
+        while self.size + bytes.len() > self.budget {
+            self.evict();
+        }
+
+        self.clock += 1;
+        self.size += bytes.len();
+        self.recency.insert(self.clock, block_id);
+        self.blocks.insert(block_id, (self.clock, bytes));
+    }
+
+    // Removes the least recently used block
+    fn evict(&mut self) {
+        let oldest = self.recency.iter().next().map(|(&last_used, &block_id)| {
+            (last_used, block_id)
+        });
+
+        if let Some((last_used, block_id)) = oldest {
+            self.recency.remove(&last_used);
+
+            if let Some((_, bytes)) = self.blocks.remove(&block_id) {
+                self.size -= bytes.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use BlockId;
+    use super::BlockCache;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = BlockCache::new(10);
+
+        cache.insert(BlockId(1), Arc::new(vec![1; 4]));
+        cache.insert(BlockId(2), Arc::new(vec![2; 4]));
+
+        // using the first block makes the second the one to go
+        assert_eq!(Some(Arc::new(vec![1; 4])), cache.get(BlockId(1)));
+
+        cache.insert(BlockId(3), Arc::new(vec![3; 4]));
+
+        assert!(cache.get(BlockId(1)).is_some());
+        assert!(cache.get(BlockId(2)).is_none());
+        assert!(cache.get(BlockId(3)).is_some());
+        assert_eq!(8, cache.size);
+    }
+
+    #[test]
+    fn budget() {
+        let mut cache = BlockCache::new(10);
+
+        cache.insert(BlockId(1), Arc::new(vec![1; 11]));
+
+        assert!(cache.get(BlockId(1)).is_none());
+        assert_eq!(0, cache.size);
+
+        cache.insert(BlockId(2), Arc::new(vec![2; 6]));
+        cache.insert(BlockId(3), Arc::new(vec![3; 6]));
+
+        assert!(cache.get(BlockId(2)).is_none());
+        assert_eq!(6, cache.size);
+
+        let mut disabled = BlockCache::new(0);
+        disabled.insert(BlockId(4), Arc::new(vec![4]));
+
+        assert!(disabled.get(BlockId(4)).is_none());
+    }
+}
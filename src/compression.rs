@@ -0,0 +1,369 @@
+use std::io::{self, Read, BufReader};
+
+use bzip2::Compress;
+use bzip2::reader::{BzCompressor, BzDecompressor};
+use flate2::Compression as GzipLevel;
+use flate2::read::{GzEncoder, GzDecoder};
+
+use error::{BonzoResult, BonzoError};
+
+// Every block starts with a single byte identifying the algorithm its
+// remainder is compressed with. Since the tag is part of the clear text, it is
+// covered by the encryption just like the data itself. Blocks written before
+// tagging was introduced are plain bzip2 streams, which always start with the
+// magic bytes "BZh". As no tag equals the first of those bytes, old blocks can
+// be read without any further bookkeeping. The algorithm used for new blocks
+// is chosen when a backup is initialized.
+static STORED_TAG: u8 = 0;
+static BZIP2_TAG: u8 = 1;
+static GZIP_TAG: u8 = 2;
+static LEGACY_BZIP2_MAGIC: &'static [u8] = b"BZh";
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Compression {
+    Stored,
+    Bzip2,
+    Gzip,
+}
+
+impl Compression {
+    pub fn tag(&self) -> u8 {
+        match *self {
+            Compression::Stored => STORED_TAG,
+            Compression::Bzip2 => BZIP2_TAG,
+            Compression::Gzip => GZIP_TAG,
+        }
+    }
+
+    // Name under which the algorithm is recorded in the index
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Compression::Stored => "none",
+            Compression::Bzip2 => "bzip2",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::Stored),
+            "bzip2" => Some(Compression::Bzip2),
+            "gzip" => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+}
+
+// Trades compression speed for size. Since blocks are decompressed the same
+// way regardless of the level, it can differ from one backup run to the
+// next.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CompressionLevel {
+    Fast,
+    Default,
+    Best,
+}
+
+impl CompressionLevel {
+    // Name under which the level is recorded in the index
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CompressionLevel::Fast => "fast",
+            CompressionLevel::Default => "default",
+            CompressionLevel::Best => "best",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CompressionLevel> {
+        match name {
+            "fast" => Some(CompressionLevel::Fast),
+            "default" => Some(CompressionLevel::Default),
+            "best" => Some(CompressionLevel::Best),
+            _ => None,
+        }
+    }
+
+    fn bzip2(&self) -> Compress {
+        match *self {
+            CompressionLevel::Fast => Compress::Fastest,
+            CompressionLevel::Default => Compress::Default,
+            CompressionLevel::Best => Compress::Best,
+        }
+    }
+
+    fn gzip(&self) -> GzipLevel {
+        match *self {
+            CompressionLevel::Fast => GzipLevel::Fast,
+            CompressionLevel::Default => GzipLevel::Default,
+            CompressionLevel::Best => GzipLevel::Best,
+        }
+    }
+}
+
+// Compresses the given bytes at the best level and prepends the algorithm
+// tag. When compression does not make the block any smaller, it is stored as
+// is instead.
+pub fn compress(clear_text: &[u8], algorithm: Compression) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+
+    try!(compress_into(clear_text, algorithm, CompressionLevel::Best, &mut buffer));
+
+    Ok(buffer)
+}
+
+// Like compress, but at the given level. Replaces the contents of the given
+// buffer instead of allocating a new one, so that its capacity can be reused
+// between blocks.
+pub fn compress_into(clear_text: &[u8],
+                     algorithm: Compression,
+                     level: CompressionLevel,
+                     buffer: &mut Vec<u8>)
+                     -> io::Result<()> {
+    buffer.clear();
+    buffer.push(algorithm.tag());
+
+    let compressed_size = match algorithm {
+        Compression::Stored => None,
+        Compression::Bzip2 => {
+            let mut compressor = BzCompressor::new(clear_text, level.bzip2());
+            Some(try!(compressor.read_to_end(buffer)))
+        }
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(clear_text, level.gzip());
+            Some(try!(encoder.read_to_end(buffer)))
+        }
+    };
+
+    match compressed_size {
+        Some(size) if size < clear_text.len() => {}
+        _ => {
+            buffer.clear();
+            buffer.push(Compression::Stored.tag());
+            buffer.extend(clear_text.iter().cloned());
+        }
+    }
+
+    Ok(())
+}
+
+// Compresses a block as it is read, yielding the same bytes as compress_into.
+// As it is not known up front whether compression pays off, reading fails
+// once the compressed bytes reach the size of the clear text. The block should
+// then be stored as is, which expanded reports.
+pub struct CompressReader<'a> {
+    inner: Box<Read + 'a>,
+    remaining: Option<usize>,
+    expanded: bool,
+}
+
+impl<'a> CompressReader<'a> {
+    pub fn new(clear_text: &'a [u8],
+               algorithm: Compression,
+               level: CompressionLevel)
+               -> CompressReader<'a> {
+        let tag = io::repeat(algorithm.tag()).take(1);
+        let inner: Box<Read + 'a> = match algorithm {
+            Compression::Stored => Box::new(tag.chain(clear_text)),
+            Compression::Bzip2 => {
+                Box::new(tag.chain(BzCompressor::new(clear_text, level.bzip2())))
+            }
+            Compression::Gzip => Box::new(tag.chain(GzEncoder::new(clear_text, level.gzip()))),
+        };
+
+        CompressReader {
+            inner: inner,
+            // the tag counts towards the limit as well
+            remaining: match algorithm {
+                Compression::Stored => None,
+                _ => Some(clear_text.len() + 1),
+            },
+            expanded: false,
+        }
+    }
+
+    pub fn expanded(&self) -> bool {
+        self.expanded
+    }
+}
+
+impl<'a> Read for CompressReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+
+        if let Some(remaining) = self.remaining {
+            if read >= remaining {
+                self.expanded = true;
+
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "Compression does not reduce the block size"));
+            }
+
+            self.remaining = Some(remaining - read);
+        }
+
+        Ok(read)
+    }
+}
+
+// Inverse of compress. Also accepts untagged blocks from older backups. Any
+// failure is reported as a decompression error, never as a partial block.
+pub fn decompress(bytes: &[u8]) -> BonzoResult<Vec<u8>> {
+    if bytes.starts_with(LEGACY_BZIP2_MAGIC) {
+        return bzip2_decompress(bytes).map_err(decompression_error);
+    }
+
+    let result = match bytes.first() {
+        Some(&tag) if tag == STORED_TAG => Ok(bytes[1..].to_vec()),
+        Some(&tag) if tag == BZIP2_TAG => bzip2_decompress(&bytes[1..]),
+        Some(&tag) if tag == GZIP_TAG => gzip_decompress(&bytes[1..]),
+        Some(&tag) => {
+            return Err(BonzoError::Decompression(format!("Unknown compression tag {}", tag)))
+        }
+        None => return Err(BonzoError::Decompression("Block is empty".to_owned())),
+    };
+
+    result.map_err(decompression_error)
+}
+
+fn decompression_error(error: io::Error) -> BonzoError {
+    BonzoError::Decompression(error.to_string())
+}
+
+fn bzip2_decompress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decompressor = BzDecompressor::new(BufReader::new(bytes));
+    let mut buffer = Vec::new();
+
+    try!(decompressor.read_to_end(&mut buffer));
+
+    Ok(buffer)
+}
+
+fn gzip_decompress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = try!(GzDecoder::new(bytes));
+    let mut buffer = Vec::new();
+
+    try!(decoder.read_to_end(&mut buffer));
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use bzip2::Compress;
+    use bzip2::reader::BzCompressor;
+    use error::BonzoErrorKind;
+    use super::{compress, compress_into, decompress, Compression, CompressionLevel,
+                CompressReader};
+
+    #[test]
+    fn tags() {
+        let text = b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        let compressed = compress(text, Compression::Bzip2).unwrap();
+
+        assert_eq!(Compression::Bzip2.tag(), compressed[0]);
+        assert_eq!(&text[..], &decompress(&compressed).unwrap()[..]);
+
+        let stored = compress(text, Compression::Stored).unwrap();
+
+        assert_eq!(Compression::Stored.tag(), stored[0]);
+        assert_eq!(&text[..], &stored[1..]);
+        assert_eq!(&text[..], &decompress(&stored).unwrap()[..]);
+
+        let gzipped = compress(text, Compression::Gzip).unwrap();
+
+        assert_eq!(Compression::Gzip.tag(), gzipped[0]);
+        assert_eq!(&text[..], &decompress(&gzipped).unwrap()[..]);
+    }
+
+    #[test]
+    fn names() {
+        for &algorithm in [Compression::Stored, Compression::Bzip2, Compression::Gzip].iter() {
+            assert_eq!(Some(algorithm), Compression::from_name(algorithm.name()));
+        }
+
+        assert_eq!(None, Compression::from_name("lzma"));
+
+        for &level in [CompressionLevel::Fast, CompressionLevel::Default, CompressionLevel::Best]
+                          .iter() {
+            assert_eq!(Some(level), CompressionLevel::from_name(level.name()));
+        }
+
+        assert_eq!(None, CompressionLevel::from_name("9"));
+    }
+
+    // every level yields blocks which decompress the same way
+    #[test]
+    fn levels() {
+        let text = b"cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc";
+
+        for &algorithm in [Compression::Bzip2, Compression::Gzip].iter() {
+            for &level in [CompressionLevel::Fast, CompressionLevel::Default].iter() {
+                let mut buffer = Vec::new();
+
+                compress_into(text, algorithm, level, &mut buffer).unwrap();
+
+                assert_eq!(algorithm.tag(), buffer[0]);
+                assert_eq!(&text[..], &decompress(&buffer).unwrap()[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn incompressible() {
+        let text = b"xyz";
+        let compressed = compress(text, Compression::Bzip2).unwrap();
+
+        assert_eq!(vec![Compression::Stored.tag(), b'x', b'y', b'z'], compressed);
+    }
+
+    #[test]
+    fn reused_buffer() {
+        let text = b"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+        let mut buffer = b"leftovers from a previous block".to_vec();
+
+        compress_into(text, Compression::Gzip, CompressionLevel::Best, &mut buffer).unwrap();
+
+        assert_eq!(compress(text, Compression::Gzip).unwrap(), buffer);
+
+        compress_into(b"xyz", Compression::Gzip, CompressionLevel::Best, &mut buffer).unwrap();
+
+        assert_eq!(vec![Compression::Stored.tag(), b'x', b'y', b'z'], buffer);
+    }
+
+    #[test]
+    fn reader() {
+        let text = b"dddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddd";
+
+        for &algorithm in [Compression::Stored, Compression::Bzip2, Compression::Gzip].iter() {
+            let mut reader = CompressReader::new(text, algorithm, CompressionLevel::Fast);
+            let mut buffer = Vec::new();
+            let mut streamed = Vec::new();
+
+            reader.read_to_end(&mut streamed).unwrap();
+            compress_into(text, algorithm, CompressionLevel::Fast, &mut buffer).unwrap();
+
+            assert_eq!(buffer, streamed);
+            assert!(!reader.expanded());
+        }
+
+        let mut reader = CompressReader::new(b"xyz", Compression::Gzip, CompressionLevel::Best);
+
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert!(reader.expanded());
+    }
+
+    #[test]
+    fn legacy() {
+        let text = b"blocks written by older versions are plain bzip2";
+        let mut compressor = BzCompressor::new(&text[..], Compress::Best);
+        let mut legacy = Vec::new();
+        compressor.read_to_end(&mut legacy).unwrap();
+
+        assert_eq!(&text[..], &decompress(&legacy).unwrap()[..]);
+        assert_eq!(BonzoErrorKind::Decompression, decompress(&[7, 1, 2]).unwrap_err().kind());
+        assert_eq!(BonzoErrorKind::Decompression,
+                   decompress(&[Compression::Gzip.tag(), 1, 2]).unwrap_err().kind());
+    }
+}
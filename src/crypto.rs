@@ -6,16 +6,55 @@ use self::rust_crypto::buffer::{RefReadBuffer, RefWriteBuffer, WriteBuffer, Read
                                 BufferResult};
 use self::rust_crypto::blockmodes::PkcsPadding;
 use self::rust_crypto::sha2::Sha256;
+use self::rust_crypto::blake2b::Blake2b;
 use self::rust_crypto::pbkdf2::pbkdf2;
 use self::rust_crypto::hmac::Hmac;
+use self::rust_crypto::mac::{Mac, MacResult};
 use self::rust_crypto::symmetriccipher::SymmetricCipherError;
+use self::rust_crypto::chacha20poly1305::ChaCha20Poly1305;
+use self::rust_crypto::aead::{AeadEncryptor, AeadDecryptor};
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use rand::{Rng, OsRng};
 
 use file_chunks::file_chunks;
 use std::path::Path;
-use std::io;
+use std::process::Command;
+use std::io::{self, Read, Write};
+use std::fs::File;
 use std::fmt;
 use std::error::Error;
 use std::convert::From;
+use std::iter::repeat;
+
+// Names of the supported ciphers. Backups which do not record their cipher
+// use AES.
+pub const AES_CIPHER: &'static str = "aes-256-cbc";
+pub const CHACHA_CIPHER: &'static str = "chacha20-poly1305";
+
+// Version of the format of encrypted blocks. It is the first byte of every
+// block written since IVs were randomized. Blocks of the first version carry
+// no authentication code.
+static UNAUTHENTICATED_FORMAT_VERSION: u8 = 1;
+static BLOCK_FORMAT_VERSION: u8 = 2;
+static CHACHA_FORMAT_VERSION: u8 = 1;
+const IV_SIZE: usize = 16;
+const HASH_SIZE: usize = 32;
+const MAC_SIZE: usize = 32;
+// Context from which the authentication key is derived, so that it differs
+// from the encryption key
+static MAC_KEY_CONTEXT: &'static [u8] = b"backbonzo block authentication";
+static AES_BLOCK_SIZE: usize = 16;
+static SALT_SIZE: usize = 16;
+pub static DEFAULT_ITERATIONS: u32 = 100000;
+const CHACHA_NONCE_SIZE: usize = 8;
+const POLY1305_TAG_SIZE: usize = 16;
+// Number of bytes read from or written to a stream at a time
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+// Blocks larger than this many bytes are encrypted and decrypted as streams
+pub static STREAMING_THRESHOLD: usize = 4 * 1024 * 1024;
+// Upper bound on the number of bytes any of the ciphers adds to a block
+pub static MAX_ENCRYPTION_OVERHEAD: usize = 1 + IV_SIZE + AES_BLOCK_SIZE + MAC_SIZE;
 
 macro_rules! do_while_match (($b: block, $e: pat) => (while let $e = $b {}));
 
@@ -45,28 +84,298 @@ impl fmt::Display for CryptoError {
 }
 
 pub trait CryptoScheme: Send + Sync + Copy + 'static {
+    // Name under which the cipher is recorded for a backup
+    fn name(&self) -> &'static str;
+
     fn hash_password(&self) -> String;
 
-    fn encrypt_block(&self, block: &[u8]) -> Result<Vec<u8>, CryptoError>;
+    // Appends the encrypted block to the given buffer, so that callers
+    // encrypting many blocks can reuse it
+    fn encrypt_block_into(&self, block: &[u8], out: &mut Vec<u8>) -> Result<(), CryptoError>;
+
+    fn encrypt_block(&self, block: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut result = Vec::new();
+
+        try!(self.encrypt_block_into(block, &mut result));
+
+        Ok(result)
+    }
 
     fn decrypt_block(&self, block: &[u8]) -> Result<Vec<u8>, CryptoError>;
+
+    // Returns the scheme with decryption of the unauthenticated formats of
+    // older versions enabled. Those are rejected by default, so that the
+    // authentication code cannot be stripped off a block by rewriting it in
+    // an older format.
+    fn with_legacy_formats(self) -> Self {
+        self
+    }
+
+    // Whether encrypt_stream and decrypt_stream work a buffer at a time. When
+    // they do not, streaming a block only adds copies of it.
+    fn streams(&self) -> bool {
+        false
+    }
+
+    // Encrypts everything read from the source into the sink, in the same
+    // format as encrypt_block. Ciphers which cannot encrypt piecewise read
+    // the whole source first.
+    fn encrypt_stream(&self, source: &mut Read, sink: &mut Write) -> Result<(), CryptoError> {
+        let mut block = Vec::new();
+
+        try!(source.read_to_end(&mut block).map_err(|_| CryptoError));
+
+        let encrypted = try!(self.encrypt_block(&block));
+
+        sink.write_all(&encrypted).map_err(|_| CryptoError)
+    }
+
+    // Decrypts a block read from the source into the sink. When this fails,
+    // whatever was written to the sink must be discarded.
+    fn decrypt_stream(&self, source: &mut Read, sink: &mut Write) -> Result<(), CryptoError> {
+        let mut block = Vec::new();
+
+        try!(source.read_to_end(&mut block).map_err(|_| CryptoError));
+
+        let clear_text = try!(self.decrypt_block(&block));
+
+        sink.write_all(&clear_text).map_err(|_| CryptoError)
+    }
+}
+
+// Obtains the key that blocks are encrypted with. This separates how the key
+// is acquired from how it is used.
+pub trait KeySource {
+    // Name under which the kind of key source is recorded in the index
+    fn name(&self) -> &'static str;
+
+    fn key(&self) -> io::Result<[u8; 32]>;
+}
+
+// Parameters for deriving a key from a passphrase. Backups created before
+// these were recorded used an all zero salt.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct KeyParams {
+    pub salt: [u8; 16],
+    pub iterations: u32,
+}
+
+impl KeyParams {
+    pub fn legacy() -> KeyParams {
+        KeyParams { salt: [0; 16], iterations: DEFAULT_ITERATIONS }
+    }
+
+    // Generates a random salt
+    pub fn generate(iterations: u32) -> io::Result<KeyParams> {
+        let mut salt = [0; 16];
+        let mut rng = try!(OsRng::new());
+
+        rng.fill_bytes(&mut salt);
+
+        Ok(KeyParams { salt: salt, iterations: iterations })
+    }
+
+    // The encoded form is the salt in hexadecimal followed by the iteration
+    // count on a separate line
+    pub fn encode(&self) -> String {
+        format!("{}\n{}\n", self.salt.to_hex(), self.iterations)
+    }
+
+    pub fn decode(encoded: &str) -> Option<KeyParams> {
+        let mut lines = encoded.lines();
+        let salt_bytes = match lines.next().and_then(|line| line.from_hex().ok()) {
+            Some(ref bytes) if bytes.len() == SALT_SIZE => bytes.clone(),
+            _ => return None,
+        };
+        let iterations = match lines.next().and_then(|line| line.parse().ok()) {
+            Some(iterations) if iterations > 0 => iterations,
+            _ => return None,
+        };
+        let mut salt = [0; 16];
+
+        for (target, byte) in salt.iter_mut().zip(salt_bytes.into_iter()) {
+            *target = byte;
+        }
+
+        Some(KeyParams { salt: salt, iterations: iterations })
+    }
+}
+
+// Derives the key from a passphrase using PBKDF2
+pub struct Passphrase<'a> {
+    passphrase: &'a str,
+    params: KeyParams,
+}
+
+impl<'a> Passphrase<'a> {
+    pub fn new(passphrase: &'a str) -> Passphrase<'a> {
+        Passphrase::with_params(passphrase, KeyParams::legacy())
+    }
+
+    pub fn with_params(passphrase: &'a str, params: KeyParams) -> Passphrase<'a> {
+        Passphrase { passphrase: passphrase, params: params }
+    }
+}
+
+impl<'a> KeySource for Passphrase<'a> {
+    fn name(&self) -> &'static str {
+        "passphrase"
+    }
+
+    fn key(&self) -> io::Result<[u8; 32]> {
+        let mut key = [0; 32];
+        let mut mac = Hmac::new(Sha256::new(), self.passphrase.as_bytes());
+
+        pbkdf2(&mut mac, &self.params.salt, self.params.iterations, &mut key);
+
+        Ok(key)
+    }
+}
+
+// Derives the key from the contents of a key file, optionally combined with a
+// passphrase, so that unattended backups need not store a passphrase in
+// plain text. The passphrase is authenticated with the file contents as key,
+// and the result is stretched with PBKDF2 as a passphrase would be.
+pub struct Keyfile<'a> {
+    passphrase: Option<&'a str>,
+    path: &'a Path,
+    params: KeyParams,
+}
+
+impl<'a> Keyfile<'a> {
+    pub fn new(passphrase: Option<&'a str>, path: &'a Path) -> Keyfile<'a> {
+        Keyfile::with_params(passphrase, path, KeyParams::legacy())
+    }
+
+    pub fn with_params(passphrase: Option<&'a str>,
+                       path: &'a Path,
+                       params: KeyParams)
+                       -> Keyfile<'a> {
+        Keyfile { passphrase: passphrase, path: path, params: params }
+    }
+}
+
+impl<'a> KeySource for Keyfile<'a> {
+    fn name(&self) -> &'static str {
+        "keyfile"
+    }
+
+    fn key(&self) -> io::Result<[u8; 32]> {
+        let mut contents = Vec::new();
+
+        try!(File::open(self.path).and_then(|mut file| file.read_to_end(&mut contents)));
+
+        if contents.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Key file is empty"));
+        }
+
+        let mut secret = [0; 32];
+        let mut combination = Hmac::new(Sha256::new(), &contents);
+
+        combination.input(self.passphrase.unwrap_or("").as_bytes());
+        combination.raw_result(&mut secret);
+
+        let mut key = [0; 32];
+        let mut mac = Hmac::new(Sha256::new(), &secret);
+
+        pbkdf2(&mut mac, &self.params.salt, self.params.iterations, &mut key);
+
+        Ok(key)
+    }
+}
+
+// Takes the key from the output of a shell command, such as a password
+// manager or a program talking to a hardware token. The command must print the
+// 32 key bytes in hexadecimal.
+pub struct KeyCommand {
+    command: String,
+}
+
+impl KeyCommand {
+    pub fn new(command: &str) -> KeyCommand {
+        KeyCommand { command: command.to_owned() }
+    }
+}
+
+impl KeySource for KeyCommand {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    fn key(&self) -> io::Result<[u8; 32]> {
+        let output = try!(Command::new("sh").arg("-c").arg(&self.command).output());
+
+        if !output.status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("Key command exited with {}", output.status)));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let bytes = try!(stdout.trim().from_hex().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           "Key command must print the key in hexadecimal")
+        }));
+
+        if bytes.len() != 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Key command printed {} bytes instead of 32",
+                                              bytes.len())));
+        }
+
+        let mut key = [0; 32];
+
+        for (target, byte) in key.iter_mut().zip(bytes.into_iter()) {
+            *target = byte;
+        }
+
+        Ok(key)
+    }
 }
 
 #[derive(Copy, Clone)]
 pub struct AesEncrypter {
     key: [u8; 32],
+    mac_key: [u8; 32],
+    // whether blocks without authentication code are accepted
+    legacy_formats: bool,
 }
 
 impl AesEncrypter {
     pub fn new(password: &str) -> AesEncrypter {
-        let mut scheme = AesEncrypter { key: [0; 32] };
+        // deriving a key from a passphrase cannot fail
+        AesEncrypter::from_key_source(&Passphrase::new(password)).unwrap()
+    }
 
-        let salt = [0; 16];
-        let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+    pub fn with_params(password: &str, salt: &[u8; 16], iterations: u32) -> AesEncrypter {
+        let params = KeyParams { salt: *salt, iterations: iterations };
 
-        pbkdf2(&mut mac, &salt, 100000, &mut scheme.key);
+        AesEncrypter::from_key_source(&Passphrase::with_params(password, params)).unwrap()
+    }
 
-        scheme
+    // Derives the key from a key file and an optional passphrase, using the
+    // legacy parameters
+    pub fn from_keyfile(password: Option<&str>, keyfile: &Path) -> io::Result<AesEncrypter> {
+        AesEncrypter::from_key_source(&Keyfile::new(password, keyfile))
+    }
+
+    pub fn from_key_source<K: KeySource>(source: &K) -> io::Result<AesEncrypter> {
+        source.key().map(|key| {
+            let mut mac_key = [0; 32];
+            let mut mac = Hmac::new(Sha256::new(), &key);
+
+            mac.input(MAC_KEY_CONTEXT);
+            mac.raw_result(&mut mac_key);
+
+            AesEncrypter { key: key, mac_key: mac_key, legacy_formats: false }
+        })
+    }
+
+    // Computes the authentication code of everything preceding it in a block
+    fn authenticate(&self, bytes: &[u8]) -> MacResult {
+        let mut mac = Hmac::new(Sha256::new(), &self.mac_key);
+
+        mac.input(bytes);
+        mac.result()
     }
 }
 
@@ -74,46 +383,417 @@ unsafe impl Send for AesEncrypter {}
 unsafe impl Sync for AesEncrypter {}
 
 impl CryptoScheme for AesEncrypter {
+    fn name(&self) -> &'static str {
+        AES_CIPHER
+    }
+
     fn hash_password(&self) -> String {
-        let mut hasher = Sha256::new();
+        hash_key(&self.key)
+    }
+
+    // Encrypts the block under a fresh random IV. The output consists of the
+    // format version, the IV, the cipher text and an HMAC over all of these,
+    // in that order.
+    fn encrypt_block_into(&self, block: &[u8], out: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let mut iv = [0; IV_SIZE];
+        let mut rng = try!(OsRng::new().map_err(|_| CryptoError));
+        let start = out.len();
+
+        rng.fill_bytes(&mut iv);
+
+        // padding adds at most a single block
+        out.reserve(1 + IV_SIZE + block.len() + AES_BLOCK_SIZE + MAC_SIZE);
+        out.push(BLOCK_FORMAT_VERSION);
+        out.extend(iv.iter().cloned());
+
+        try!(aes_encrypt_into(&self.key, &iv, block, out));
 
-        hasher.input(&self.key);
-        hasher.result_str()
+        let code = self.authenticate(&out[start..]);
+        out.extend(code.code().iter().cloned());
+
+        Ok(())
     }
 
-    fn encrypt_block(&self, block: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let iv: [u8; 16] = [0; 16];
+    // Blocks written by older versions consist of just the cipher text,
+    // encrypted under an all zero IV. As the cipher text is always a multiple
+    // of the AES block size in length, a versioned block never is. These and
+    // unauthenticated versioned blocks are only accepted in legacy mode.
+    fn decrypt_block(&self, block: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if self.legacy_formats && block.len() % AES_BLOCK_SIZE == 0 {
+            return aes_decrypt(&self.key, &[0; IV_SIZE], block);
+        }
+
+        if block.len() < 1 + IV_SIZE {
+            return Err(CryptoError);
+        }
+
+        if self.legacy_formats && block[0] == UNAUTHENTICATED_FORMAT_VERSION {
+            return aes_decrypt(&self.key, &block[1..1 + IV_SIZE], &block[1 + IV_SIZE..]);
+        }
+
+        if block.len() < 1 + IV_SIZE + MAC_SIZE || block[0] != BLOCK_FORMAT_VERSION {
+            return Err(CryptoError);
+        }
+
+        let (authenticated, code) = block.split_at(block.len() - MAC_SIZE);
+
+        // the comparison of MacResults takes constant time
+        if self.authenticate(authenticated) != MacResult::new(code) {
+            return Err(CryptoError);
+        }
+
+        aes_decrypt(&self.key, &block[1..1 + IV_SIZE], &authenticated[1 + IV_SIZE..])
+    }
+
+    fn with_legacy_formats(self) -> AesEncrypter {
+        AesEncrypter { legacy_formats: true, ..self }
+    }
+
+    fn streams(&self) -> bool {
+        true
+    }
+
+    // Encrypts and authenticates the source a buffer at a time, so that
+    // neither the clear text nor the cipher text is held in memory as a whole
+    fn encrypt_stream(&self, source: &mut Read, sink: &mut Write) -> Result<(), CryptoError> {
+        let mut iv = [0; IV_SIZE];
+        let mut rng = try!(OsRng::new().map_err(|_| CryptoError));
+
+        rng.fill_bytes(&mut iv);
+
         let mut encryptor = cbc_encryptor(KeySize::KeySize256, &self.key, &iv, PkcsPadding);
-        let mut final_result = Vec::<u8>::new();
-        let mut buffer = [0; 4096];
-        let mut read_buffer = RefReadBuffer::new(block);
-        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+        let mut mac = Hmac::new(Sha256::new(), &self.mac_key);
+        let mut input = vec![0; STREAM_BUFFER_SIZE];
+        let mut output = [0; STREAM_BUFFER_SIZE];
+        let mut write_buffer = RefWriteBuffer::new(&mut output);
+
+        try!(write_authenticated(sink, &mut mac, &[BLOCK_FORMAT_VERSION]));
+        try!(write_authenticated(sink, &mut mac, &iv));
+
+        loop {
+            let read = try!(read_full(source, &mut input).map_err(|_| CryptoError));
+            let eof = read < input.len();
+            let mut read_buffer = RefReadBuffer::new(&input[..read]);
+
+            do_while_match!({
+                let result = try!(encryptor.encrypt(&mut read_buffer, &mut write_buffer, eof));
+                try!(write_authenticated(sink,
+                                         &mut mac,
+                                         write_buffer.take_read_buffer().take_remaining()));
+                result
+            }, BufferResult::BufferOverflow);
+
+            if eof {
+                break;
+            }
+        }
+
+        sink.write_all(mac.result().code()).map_err(|_| CryptoError)
+    }
+
+    // Blocks in the current format are decrypted a buffer at a time. As their
+    // authentication code comes last, the clear text is written before it is
+    // checked. Blocks in older formats are decrypted as a whole. So are all
+    // blocks in legacy mode, as blocks from before versioning may start with
+    // the current version too, and only their length tells them apart.
+    fn decrypt_stream(&self, source: &mut Read, sink: &mut Write) -> Result<(), CryptoError> {
+        let mut header = [0; 1 + IV_SIZE];
+        let header_length = try!(read_full(source, &mut header).map_err(|_| CryptoError));
+
+        if self.legacy_formats || header_length < header.len() ||
+           header[0] != BLOCK_FORMAT_VERSION {
+            let mut block = header[..header_length].to_vec();
+
+            try!(source.read_to_end(&mut block).map_err(|_| CryptoError));
+
+            let clear_text = try!(self.decrypt_block(&block));
+
+            return sink.write_all(&clear_text).map_err(|_| CryptoError);
+        }
+
+        let iv = &header[1..];
+        let mut decryptor = cbc_decryptor(KeySize::KeySize256, &self.key, iv, PkcsPadding);
+        let mut mac = Hmac::new(Sha256::new(), &self.mac_key);
+        // the last bytes read are held back at the start of the buffer, as
+        // they may turn out to be the authentication code
+        let mut input = vec![0; MAC_SIZE + STREAM_BUFFER_SIZE];
+        let mut held = 0;
+        let mut output = [0; STREAM_BUFFER_SIZE];
+        let mut write_buffer = RefWriteBuffer::new(&mut output);
+
+        mac.input(&header);
+
+        loop {
+            let read = try!(read_full(source, &mut input[held..]).map_err(|_| CryptoError));
+            let available = held + read;
+            let eof = available < input.len();
+            let ready = available.saturating_sub(MAC_SIZE);
+
+            mac.input(&input[..ready]);
+
+            {
+                let mut read_buffer = RefReadBuffer::new(&input[..ready]);
+
+                do_while_match!({
+                    let result = try!(decryptor.decrypt(&mut read_buffer, &mut write_buffer, eof));
+                    try!(sink.write_all(write_buffer.take_read_buffer().take_remaining())
+                             .map_err(|_| CryptoError));
+                    result
+                }, BufferResult::BufferOverflow);
+            }
+
+            held = available - ready;
+
+            for index in 0..held {
+                input[index] = input[ready + index];
+            }
+
+            if eof {
+                break;
+            }
+        }
+
+        // the comparison of MacResults takes constant time
+        match held == MAC_SIZE && mac.result() == MacResult::new(&input[..MAC_SIZE]) {
+            true => Ok(()),
+            false => Err(CryptoError),
+        }
+    }
+}
+
+// Authenticated encryption with ChaCha20 and Poly1305, which is faster than
+// AES on machines without hardware support for the latter. Encrypted blocks
+// consist of the format version, the nonce, the authentication tag and the
+// cipher text. As the tag precedes the cipher text, blocks are not encrypted
+// as streams.
+#[derive(Copy, Clone)]
+pub struct ChaChaEncrypter {
+    key: [u8; 32],
+}
+
+impl ChaChaEncrypter {
+    pub fn new(password: &str) -> ChaChaEncrypter {
+        ChaChaEncrypter::from_key_source(&Passphrase::new(password)).unwrap()
+    }
+
+    pub fn from_key_source<K: KeySource>(source: &K) -> io::Result<ChaChaEncrypter> {
+        source.key().map(|key| ChaChaEncrypter { key: key })
+    }
+}
+
+impl CryptoScheme for ChaChaEncrypter {
+    fn name(&self) -> &'static str {
+        CHACHA_CIPHER
+    }
+
+    fn hash_password(&self) -> String {
+        hash_key(&self.key)
+    }
+
+    fn encrypt_block_into(&self, block: &[u8], out: &mut Vec<u8>) -> Result<(), CryptoError> {
+        let mut nonce = [0; CHACHA_NONCE_SIZE];
+        let mut rng = try!(OsRng::new().map_err(|_| CryptoError));
+
+        rng.fill_bytes(&mut nonce);
+
+        let mut cipher = ChaCha20Poly1305::new(&self.key, &nonce, &[]);
+        let mut tag = [0; POLY1305_TAG_SIZE];
+        let start = out.len() + 1 + CHACHA_NONCE_SIZE + POLY1305_TAG_SIZE;
+
+        // the cipher text is written in place, after which the tag is filled in
+        out.reserve(1 + CHACHA_NONCE_SIZE + POLY1305_TAG_SIZE + block.len());
+        out.push(CHACHA_FORMAT_VERSION);
+        out.extend(nonce.iter().cloned());
+        out.extend(repeat(0).take(POLY1305_TAG_SIZE + block.len()));
+
+        cipher.encrypt(block, &mut out[start..], &mut tag);
+
+        for (target, &byte) in out[start - POLY1305_TAG_SIZE..start].iter_mut().zip(tag.iter()) {
+            *target = byte;
+        }
+
+        Ok(())
+    }
+
+    fn decrypt_block(&self, block: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let header_size = 1 + CHACHA_NONCE_SIZE + POLY1305_TAG_SIZE;
+
+        if block.len() < header_size || block[0] != CHACHA_FORMAT_VERSION {
+            return Err(CryptoError);
+        }
+
+        let nonce = &block[1..1 + CHACHA_NONCE_SIZE];
+        let tag = &block[1 + CHACHA_NONCE_SIZE..header_size];
+        let cipher_text = &block[header_size..];
+        let mut cipher = ChaCha20Poly1305::new(&self.key, nonce, &[]);
+        let mut clear_text = vec![0; cipher_text.len()];
+
+        match cipher.decrypt(cipher_text, &mut clear_text, tag) {
+            true => Ok(clear_text),
+            false => Err(CryptoError),
+        }
+    }
+}
+
+// Either of the supported ciphers, for when the cipher of a backup is only
+// known at runtime
+#[derive(Copy, Clone)]
+pub enum Cipher {
+    Aes(AesEncrypter),
+    ChaCha(ChaChaEncrypter),
+}
 
-        do_while_match!({
-            let result = try!(encryptor.encrypt(&mut read_buffer, &mut write_buffer, true));
-            final_result.extend(write_buffer.take_read_buffer().take_remaining());
-            result
-        }, BufferResult::BufferOverflow);
+impl Cipher {
+    pub fn from_key_source<K: KeySource>(name: &str, source: &K) -> io::Result<Cipher> {
+        match name {
+            AES_CIPHER => AesEncrypter::from_key_source(source).map(Cipher::Aes),
+            CHACHA_CIPHER => ChaChaEncrypter::from_key_source(source).map(Cipher::ChaCha),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    format!("Unsupported cipher: {}", name))),
+        }
+    }
+}
 
-        Ok(final_result)
+impl CryptoScheme for Cipher {
+    fn name(&self) -> &'static str {
+        match *self {
+            Cipher::Aes(ref scheme) => scheme.name(),
+            Cipher::ChaCha(ref scheme) => scheme.name(),
+        }
+    }
+
+    fn hash_password(&self) -> String {
+        match *self {
+            Cipher::Aes(ref scheme) => scheme.hash_password(),
+            Cipher::ChaCha(ref scheme) => scheme.hash_password(),
+        }
+    }
+
+    fn encrypt_block_into(&self, block: &[u8], out: &mut Vec<u8>) -> Result<(), CryptoError> {
+        match *self {
+            Cipher::Aes(ref scheme) => scheme.encrypt_block_into(block, out),
+            Cipher::ChaCha(ref scheme) => scheme.encrypt_block_into(block, out),
+        }
     }
 
     fn decrypt_block(&self, block: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let iv: [u8; 16] = [0; 16];
-        let mut decryptor = cbc_decryptor(KeySize::KeySize256, &self.key, &iv, PkcsPadding);
-        let mut final_result = Vec::<u8>::new();
-        let mut buffer = [0; 4096];
+        match *self {
+            Cipher::Aes(ref scheme) => scheme.decrypt_block(block),
+            Cipher::ChaCha(ref scheme) => scheme.decrypt_block(block),
+        }
+    }
+
+    fn with_legacy_formats(self) -> Cipher {
+        match self {
+            Cipher::Aes(scheme) => Cipher::Aes(scheme.with_legacy_formats()),
+            Cipher::ChaCha(scheme) => Cipher::ChaCha(scheme.with_legacy_formats()),
+        }
+    }
+
+    fn streams(&self) -> bool {
+        match *self {
+            Cipher::Aes(ref scheme) => scheme.streams(),
+            Cipher::ChaCha(ref scheme) => scheme.streams(),
+        }
+    }
+
+    fn encrypt_stream(&self, source: &mut Read, sink: &mut Write) -> Result<(), CryptoError> {
+        match *self {
+            Cipher::Aes(ref scheme) => scheme.encrypt_stream(source, sink),
+            Cipher::ChaCha(ref scheme) => scheme.encrypt_stream(source, sink),
+        }
+    }
+
+    fn decrypt_stream(&self, source: &mut Read, sink: &mut Write) -> Result<(), CryptoError> {
+        match *self {
+            Cipher::Aes(ref scheme) => scheme.decrypt_stream(source, sink),
+            Cipher::ChaCha(ref scheme) => scheme.decrypt_stream(source, sink),
+        }
+    }
+}
+
+fn hash_key(key: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+
+    hasher.input(key);
+    hasher.result_str()
+}
+
+#[cfg(test)]
+fn aes_encrypt(key: &[u8], iv: &[u8], block: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut result = Vec::new();
+
+    try!(aes_encrypt_into(key, iv, block, &mut result));
+
+    Ok(result)
+}
+
+// Appends the cipher text to the buffer. As padding adds at most a single
+// block, the cipher text is written in place in one go.
+fn aes_encrypt_into(key: &[u8],
+                    iv: &[u8],
+                    block: &[u8],
+                    out: &mut Vec<u8>)
+                    -> Result<(), CryptoError> {
+    let mut encryptor = cbc_encryptor(KeySize::KeySize256, key, iv, PkcsPadding);
+    let start = out.len();
+
+    out.extend(repeat(0).take(block.len() + AES_BLOCK_SIZE));
+
+    let written = {
         let mut read_buffer = RefReadBuffer::new(block);
-        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+        let mut write_buffer = RefWriteBuffer::new(&mut out[start..]);
+
+        match try!(encryptor.encrypt(&mut read_buffer, &mut write_buffer, true)) {
+            BufferResult::BufferUnderflow => write_buffer.position(),
+            BufferResult::BufferOverflow => return Err(CryptoError),
+        }
+    };
+
+    out.truncate(start + written);
+
+    Ok(())
+}
+
+fn aes_decrypt(key: &[u8], iv: &[u8], block: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut decryptor = cbc_decryptor(KeySize::KeySize256, key, iv, PkcsPadding);
+    let mut final_result = Vec::<u8>::new();
+    let mut buffer = [0; 4096];
+    let mut read_buffer = RefReadBuffer::new(block);
+    let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+
+    do_while_match!({
+        let result = try!(decryptor.decrypt(&mut read_buffer, &mut write_buffer, true));
+        final_result.extend(write_buffer.take_read_buffer().take_remaining());
+        result
+    }, BufferResult::BufferOverflow);
+
+    Ok(final_result)
+}
 
-        do_while_match!({
-            let result = try!(decryptor.decrypt(&mut read_buffer, &mut write_buffer, true));
-            final_result.extend(write_buffer.take_read_buffer().take_remaining());
-            result
-        }, BufferResult::BufferOverflow);
+// Writes the bytes to the sink and adds them to the authentication code
+fn write_authenticated(sink: &mut Write,
+                       mac: &mut Hmac<Sha256>,
+                       bytes: &[u8])
+                       -> Result<(), CryptoError> {
+    mac.input(bytes);
+    sink.write_all(bytes).map_err(|_| CryptoError)
+}
 
-        Ok(final_result)
+// Reads from the source until the buffer is full or the source is exhausted.
+// Returns the number of bytes read, which is less than the length of the
+// buffer only at the end of the source.
+fn read_full(source: &mut Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match try!(source.read(&mut buffer[filled..])) {
+            0 => break,
+            read => filled += read,
+        }
     }
+
+    Ok(filled)
 }
 
 pub trait HashScheme {
@@ -122,55 +802,76 @@ pub trait HashScheme {
     fn hash_file(&self, path: &Path) -> io::Result<Vec<u8>>;
 }
 
-// pub struct Sha256Hasher;
+// Algorithms which files and blocks can be hashed with. Hashes identify
+// contents throughout a backup, so the algorithm is chosen when a backup is
+// initialized and never changes. Backups which do not record one use SHA256.
+// Both produce hashes of 32 bytes, but BLAKE2b is considerably faster.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake2b,
+}
 
-// impl HashScheme for Sha256Hasher {
-//     fn hash_file(&self, path: &Path) -> io::Result<Vec<u8>> {
-//         let mut chunks = try!(file_chunks(path, 1024));
-//         let mut hasher = Sha256::new();
+impl HashAlgorithm {
+    // Name under which the algorithm is recorded in the index
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake2b => "blake2b",
+        }
+    }
 
-//         while let Some(slice) = chunks.next() {
-//             let unwrapped_slice = try!(slice);
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake2b" => Some(HashAlgorithm::Blake2b),
+            _ => None,
+        }
+    }
 
-//             hasher.input(unwrapped_slice);
-//         }
+    fn digest(&self) -> Box<Digest> {
+        match *self {
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            HashAlgorithm::Blake2b => Box::new(Blake2b::new(HASH_SIZE)),
+        }
+    }
+}
 
-//         Ok(hasher.result_str())
-//     }
+impl HashScheme for HashAlgorithm {
+    fn hash_block(&self, block: &[u8]) -> Vec<u8> {
+        let mut hasher = self.digest();
+        let mut buffer = vec![0; HASH_SIZE];
 
-//     fn hash_block(&self, block: &[u8]) -> Vec<u8> {
-//         let mut hasher = Sha256::new();
+        hasher.input(block);
+        hasher.result(&mut buffer);
 
-//         hasher.input(block);
-//         hasher.result_str()
-//     }
-// }
+        buffer
+    }
 
-// Returns the SHA256 hash of a file
-pub fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
-    let mut chunks = try!(file_chunks(path, 1024));
-    let mut hasher = Sha256::new();
-    let mut buffer = vec![0; 32];
+    fn hash_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut chunks = try!(file_chunks(path, 1024));
+        let mut hasher = self.digest();
+        let mut buffer = vec![0; HASH_SIZE];
+
+        while let Some(slice) = chunks.next() {
+            let unwrapped_slice = try!(slice);
 
-    while let Some(slice) = chunks.next() {
-        let unwrapped_slice = try!(slice);
+            hasher.input(unwrapped_slice);
+        }
 
-        hasher.input(unwrapped_slice);
+        hasher.result(&mut buffer);
+        Ok(buffer)
     }
+}
 
-    hasher.result(&mut buffer);
-    Ok(buffer)
+// Returns the SHA256 hash of a file
+pub fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
+    HashAlgorithm::Sha256.hash_file(path)
 }
 
 // Returns the SHA256 hash of a slice of bytes
 pub fn hash_block(block: &[u8]) -> Vec<u8> {
-    let mut hasher = Sha256::new();
-    let mut buffer = vec![0; 32];
-
-    hasher.input(block);
-    hasher.result(&mut buffer);
-
-    buffer
+    HashAlgorithm::Sha256.hash_block(block)
 }
 
 #[cfg(test)]
@@ -179,11 +880,75 @@ mod test {
 
     use super::super::rand::{Rng, OsRng};
     use super::super::tempdir::TempDir;
-    use super::{CryptoScheme, AesEncrypter};
+    use super::{CryptoScheme, AesEncrypter, ChaChaEncrypter, Cipher, KeySource, KeyCommand,
+                Keyfile, KeyParams, Passphrase};
 
     use std::fs::File;
     use std::io::Write;
 
+    #[cfg_attr(unix, test)]
+    fn key_command() {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let source = KeyCommand::new(&format!("echo {}", hex));
+        let key = source.key().unwrap();
+
+        assert_eq!(hex, key.to_hex());
+        assert_eq!("command", source.name());
+
+        let encrypter = AesEncrypter::from_key_source(&source).unwrap();
+        let other_encrypter = AesEncrypter::from_key_source(&source).unwrap();
+        let encrypted = encrypter.encrypt_block(b"under lock and key").unwrap();
+
+        assert_eq!(b"under lock and key".to_vec(),
+                   other_encrypter.decrypt_block(&encrypted).unwrap());
+        assert!(encrypter.hash_password() != AesEncrypter::new(hex).hash_password());
+
+        assert!(KeyCommand::new("echo 0102").key().is_err());
+        assert!(KeyCommand::new("echo not hex").key().is_err());
+        assert!(KeyCommand::new("exit 1").key().is_err());
+        assert_eq!("passphrase", Passphrase::new("secret").name());
+    }
+
+    #[test]
+    fn keyfile() {
+        let temp_dir = TempDir::new("keyfile").unwrap();
+        let path = temp_dir.path().join("key");
+        let other_path = temp_dir.path().join("other");
+
+        File::create(&path).unwrap().write_all(b"random key material").unwrap();
+        File::create(&other_path).unwrap().write_all(b"other key material").unwrap();
+
+        let with_passphrase = AesEncrypter::from_keyfile(Some("secret"), &path).unwrap();
+        let without_passphrase = AesEncrypter::from_keyfile(None, &path).unwrap();
+        let encrypted = with_passphrase.encrypt_block(b"two factors").unwrap();
+
+        // the same combination always gives the same key
+        assert_eq!(b"two factors".to_vec(),
+                   AesEncrypter::from_keyfile(Some("secret"), &path)
+                       .unwrap()
+                       .decrypt_block(&encrypted)
+                       .unwrap());
+
+        // but every part of it matters
+        let hashes = vec![with_passphrase.hash_password(),
+                          without_passphrase.hash_password(),
+                          AesEncrypter::from_keyfile(Some("secret"), &other_path)
+                              .unwrap()
+                              .hash_password(),
+                          AesEncrypter::new("secret").hash_password()];
+
+        for (i, hash) in hashes.iter().enumerate() {
+            assert!(hashes[i + 1..].iter().all(|other| other != hash));
+        }
+
+        assert_eq!("keyfile", Keyfile::new(None, &path).name());
+        assert!(AesEncrypter::from_keyfile(None, &temp_dir.path().join("missing")).is_err());
+
+        File::create(&other_path).unwrap();
+
+        assert!(AesEncrypter::from_keyfile(None, &other_path).is_err());
+    }
+
     #[test]
     fn aes_encryption_decryption() {
         let mut data: [u8; 100000] = [0; 100000];
@@ -202,6 +967,23 @@ mod test {
         assert!(slice == &decrypted_data[..]);
     }
 
+    // Encrypted blocks are appended to whatever the buffer holds already
+    #[test]
+    fn encrypt_into_buffer() {
+        let message = b"reusing buffers";
+        let schemes = [Cipher::Aes(AesEncrypter::new("test")),
+                       Cipher::ChaCha(ChaChaEncrypter::new("test"))];
+
+        for scheme in schemes.iter() {
+            let mut buffer = b"prefix".to_vec();
+
+            scheme.encrypt_block_into(message, &mut buffer).unwrap();
+
+            assert_eq!(b"prefix", &buffer[..6]);
+            assert_eq!(message.to_vec(), scheme.decrypt_block(&buffer[6..]).unwrap());
+        }
+    }
+
     #[test]
     fn decryption_bad_key() {
         let message = b"hello, world!";
@@ -217,6 +999,172 @@ mod test {
         assert!(good_decrypt.is_ok());
     }
 
+    #[test]
+    fn random_iv() {
+        let message = b"identical blocks should not look identical";
+        let scheme = AesEncrypter::new("test");
+
+        let encrypted = scheme.encrypt_block(message).unwrap();
+        let encrypted_again = scheme.encrypt_block(message).unwrap();
+
+        assert!(encrypted != encrypted_again);
+        assert_eq!(super::BLOCK_FORMAT_VERSION, encrypted[0]);
+        assert_eq!(message.to_vec(), scheme.decrypt_block(&encrypted).unwrap());
+        assert_eq!(message.to_vec(), scheme.decrypt_block(&encrypted_again).unwrap());
+    }
+
+    // Blocks from older backups were encrypted under a zero IV without a
+    // version byte
+    #[test]
+    fn legacy_blocks() {
+        let message = b"written before IVs were randomized";
+        let scheme = AesEncrypter::new("test").with_legacy_formats();
+        let legacy = super::aes_encrypt(&scheme.key, &[0; 16], message).unwrap();
+
+        assert_eq!(message.to_vec(), scheme.decrypt_block(&legacy).unwrap());
+        assert!(scheme.decrypt_block(&[2; 33]).is_err());
+
+        // blocks with a random IV but without authentication code
+        let iv = [7; 16];
+        let mut unauthenticated = vec![super::UNAUTHENTICATED_FORMAT_VERSION];
+        unauthenticated.extend(iv.iter().cloned());
+        let cipher_text = super::aes_encrypt(&scheme.key, &iv, message).unwrap();
+        unauthenticated.extend(cipher_text.into_iter());
+
+        assert_eq!(message.to_vec(), scheme.decrypt_block(&unauthenticated).unwrap());
+
+        // unless explicitly enabled, neither is accepted
+        let strict = AesEncrypter::new("test");
+
+        assert!(strict.decrypt_block(&legacy).is_err());
+        assert!(strict.decrypt_block(&unauthenticated).is_err());
+    }
+
+    // Rewriting an authenticated block in an older format does not get it
+    // past the authentication check
+    #[test]
+    fn downgrade() {
+        let message = b"the authentication code cannot simply be dropped";
+        let scheme = AesEncrypter::new("test");
+        let encrypted = scheme.encrypt_block(message).unwrap();
+        let mut downgraded = encrypted[..encrypted.len() - super::MAC_SIZE].to_vec();
+
+        downgraded[0] = super::UNAUTHENTICATED_FORMAT_VERSION;
+
+        assert!(scheme.decrypt_block(&downgraded).is_err());
+        assert!(scheme.decrypt_stream(&mut &downgraded[..], &mut Vec::new()).is_err());
+        assert!(scheme.decrypt_block(&encrypted[1..encrypted.len() - super::MAC_SIZE]).is_err());
+        assert_eq!(message.to_vec(),
+                   scheme.with_legacy_formats().decrypt_block(&downgraded).unwrap());
+    }
+
+    #[test]
+    fn tampering() {
+        let message = b"a flipped bit should not go unnoticed";
+        let scheme = AesEncrypter::new("test");
+        let encrypted = scheme.encrypt_block(message).unwrap();
+
+        assert!(scheme.mac_key != scheme.key);
+
+        for &index in [0, 1, 20, encrypted.len() - 1].iter() {
+            let mut tampered = encrypted.clone();
+            tampered[index] ^= 1;
+
+            assert!(scheme.decrypt_block(&tampered).is_err());
+        }
+
+        assert!(scheme.decrypt_block(&encrypted[..encrypted.len() - 2]).is_err());
+        assert!(AesEncrypter::new("other").decrypt_block(&encrypted).is_err());
+    }
+
+    // Streamed blocks are interchangeable with whole ones, whatever their size
+    // relative to the stream buffers
+    #[test]
+    fn streams() {
+        let mut data = vec![0; 3 * super::STREAM_BUFFER_SIZE + 7];
+        let mut rng = OsRng::new().ok().unwrap();
+        let schemes = [Cipher::Aes(AesEncrypter::new("test")),
+                       Cipher::ChaCha(ChaChaEncrypter::new("test"))];
+
+        rng.fill_bytes(&mut data);
+
+        for scheme in schemes.iter() {
+            for &size in [0, 1, super::STREAM_BUFFER_SIZE, data.len()].iter() {
+                let message = &data[..size];
+                let mut streamed = Vec::new();
+                let mut clear_text = Vec::new();
+
+                scheme.encrypt_stream(&mut &message[..], &mut streamed).unwrap();
+
+                assert_eq!(message.to_vec(), scheme.decrypt_block(&streamed).unwrap());
+
+                scheme.decrypt_stream(&mut &scheme.encrypt_block(message).unwrap()[..],
+                                      &mut clear_text)
+                      .unwrap();
+
+                assert_eq!(message.to_vec(), clear_text);
+
+                // tampering is detected once the whole block has been read
+                let last = streamed.len() - 1;
+                streamed[last] ^= 1;
+
+                assert!(scheme.decrypt_stream(&mut &streamed[..], &mut Vec::new()).is_err());
+            }
+        }
+
+        assert!(schemes[0].streams());
+        assert!(!schemes[1].streams());
+
+        // blocks in older formats are decrypted as a whole, including those
+        // which start like a block in the current format
+        let scheme = AesEncrypter::new("test").with_legacy_formats();
+        let mut legacy = super::aes_encrypt(&scheme.key, &[0; 16], &data).unwrap();
+        let mut clear_text = Vec::new();
+
+        // changing the first byte only garbles the first block of clear text
+        legacy[0] = super::BLOCK_FORMAT_VERSION;
+
+        scheme.decrypt_stream(&mut &legacy[..], &mut clear_text).unwrap();
+
+        assert_eq!(&data[16..], &clear_text[16..]);
+    }
+
+    #[test]
+    fn chacha_encryption_decryption() {
+        let message = b"no aes-ni on this machine";
+        let scheme = ChaChaEncrypter::new("test");
+
+        let encrypted = scheme.encrypt_block(message).unwrap();
+
+        assert!(encrypted != scheme.encrypt_block(message).unwrap());
+        assert_eq!(message.to_vec(), scheme.decrypt_block(&encrypted).unwrap());
+        assert!(ChaChaEncrypter::new("hallo").decrypt_block(&encrypted).is_err());
+
+        // any modification of the cipher text is detected
+        let mut tampered = encrypted.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+
+        assert!(scheme.decrypt_block(&tampered).is_err());
+        assert!(scheme.decrypt_block(&encrypted[..10]).is_err());
+    }
+
+    #[test]
+    fn cipher_names() {
+        let passphrase = Passphrase::new("test");
+        let aes = Cipher::from_key_source(super::AES_CIPHER, &passphrase).unwrap();
+        let chacha = Cipher::from_key_source(super::CHACHA_CIPHER, &passphrase).unwrap();
+
+        assert_eq!(super::AES_CIPHER, aes.name());
+        assert_eq!(super::CHACHA_CIPHER, chacha.name());
+        assert_eq!(AesEncrypter::new("test").hash_password(), chacha.hash_password());
+        assert!(Cipher::from_key_source("rot13", &passphrase).is_err());
+
+        let encrypted = chacha.encrypt_block(b"message").unwrap();
+
+        assert_eq!(b"message".to_vec(), chacha.decrypt_block(&encrypted).unwrap());
+    }
+
     #[test]
     fn key_derivation() {
         let key = AesEncrypter::new("test").hash_password();
@@ -225,6 +1173,24 @@ mod test {
         assert!(key != key_two);
     }
 
+    #[test]
+    fn key_params() {
+        let params = KeyParams::generate(1000).unwrap();
+        let other_params = KeyParams::generate(1000).unwrap();
+
+        assert!(params.salt != other_params.salt);
+        assert_eq!(Some(params), KeyParams::decode(&params.encode()));
+        assert_eq!(None, KeyParams::decode("00ff\n1000\n"));
+        assert_eq!(None, KeyParams::decode(&params.encode()[..32]));
+
+        let salted = AesEncrypter::with_params("test", &params.salt, 1000);
+        let other_salted = AesEncrypter::with_params("test", &other_params.salt, 1000);
+
+        assert!(salted.hash_password() != other_salted.hash_password());
+        assert_eq!(AesEncrypter::new("test").hash_password(),
+                   AesEncrypter::with_params("test", &[0; 16], 100000).hash_password());
+    }
+
     #[test]
     fn hash_file() {
         let temp_dir = TempDir::new("hash-test").unwrap();
@@ -256,4 +1222,27 @@ mod test {
 
         assert_eq!(expected_hash, &hash[..]);
     }
+
+    #[test]
+    fn hash_algorithms() {
+        use super::{HashAlgorithm, HashScheme};
+
+        let temp_dir = TempDir::new("hash-algorithms").unwrap();
+        let file_path = temp_dir.path().join("file");
+        let contents = b"hashed by either algorithm";
+
+        File::create(&file_path).unwrap().write_all(contents).unwrap();
+
+        let sha256 = HashAlgorithm::Sha256.hash_block(contents);
+        let blake2b = HashAlgorithm::Blake2b.hash_block(contents);
+
+        assert_eq!(super::hash_block(contents), sha256);
+        assert_eq!(32, blake2b.len());
+        assert!(sha256 != blake2b);
+        assert_eq!(blake2b, HashAlgorithm::Blake2b.hash_file(&file_path).unwrap());
+
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake2b].iter() {
+            assert_eq!(Some(*algorithm), HashAlgorithm::from_name(algorithm.name()));
+        }
+    }
 }
@@ -5,22 +5,98 @@ extern crate libsqlite3_sys as libsqlite;
 use ::{epoch_milliseconds, Directory};
 use ::error::{BonzoResult, BonzoError};
 use ::{BlockId, FileId};
+use ::attributes::Attributes;
 use ::itertools::Itertools;
 
 use self::rusqlite::{SqliteResult, SqliteConnection, SqliteRow, SqliteOpenFlags,
-                     SQLITE_OPEN_FULL_MUTEX, SQLITE_OPEN_READ_WRITE, SQLITE_OPEN_CREATE};
+                     SQLITE_OPEN_FULL_MUTEX, SQLITE_OPEN_READ_WRITE, SQLITE_OPEN_READ_ONLY,
+                     SQLITE_OPEN_CREATE};
 use self::rusqlite::types::{FromSql, ToSql};
 use self::libc::c_int;
 
 use std::io::Read;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashSet;
 use std::iter::FromIterator;
 use std::error::Error;
 use std::convert::From;
+use std::thread::sleep;
+use std::time::Duration;
 use std::fmt;
 
+// Result codes SQLite returns when another connection holds a conflicting
+// lock on the database
+static SQLITE_BUSY: c_int = 5;
+static SQLITE_LOCKED: c_int = 6;
+
+// The busy timeout should normally make SQLite wait for locks by itself, but
+// it does not in every case, for instance when a read transaction needs to be
+// upgraded to a write transaction. Statements failing on a lock are therefore
+// tried this many times, waiting twice as long after each attempt.
+static BUSY_ATTEMPTS: u32 = 6;
+static INITIAL_BACKOFF_MILLISECONDS: u64 = 50;
+
+// Changes to the schema since the first release, in order. An index at
+// version n has had the first n applied, and is brought up to date when it is
+// opened. Indices created before versioning was introduced are at version 0.
+// Migrations must never be changed or reordered once released; new ones are
+// appended.
+static MIGRATIONS: &'static [&'static str] = &[
+    // Unix permission bits of every version of a file
+    "ALTER TABLE alias ADD COLUMN mode INTEGER;",
+    // Empty directories are recorded separately, as there are no files to
+    // recreate them from on restore. Every row marks the moment a directory
+    // became empty or stopped being so.
+    "CREATE TABLE empty_directory (
+         id           INTEGER PRIMARY KEY,
+         directory_id INTEGER NOT NULL,
+         empty        INTEGER NOT NULL,
+         timestamp    INTEGER NOT NULL,
+         FOREIGN KEY(directory_id) REFERENCES directory(id)
+     );",
+    // Target of aliases which are stored symlinks rather than files
+    "ALTER TABLE alias ADD COLUMN symlink TEXT;",
+    // Size of the contents of every file in bytes and its number of blocks,
+    // so that sizes can be reported without reading any blocks
+    "ALTER TABLE file ADD COLUMN size INTEGER;",
+    "ALTER TABLE file ADD COLUMN block_count INTEGER;",
+    // Extended attributes of every version of a file. They belong to aliases
+    // rather than files, as files are shared by all aliases with the same
+    // contents.
+    "CREATE TABLE xattr (
+         id       INTEGER PRIMARY KEY,
+         alias_id INTEGER NOT NULL,
+         name     TEXT NOT NULL,
+         value    BLOB NOT NULL,
+         FOREIGN KEY(alias_id) REFERENCES alias(id)
+     );",
+    "CREATE INDEX xattr_alias_index ON xattr (alias_id);",
+    // The start of every backup and, once it has completed, its end, so that
+    // the moments a backup can be restored to are known
+    "CREATE TABLE backup (
+         id       INTEGER PRIMARY KEY,
+         started  INTEGER NOT NULL,
+         finished INTEGER
+     );",
+];
+static SCHEMA_VERSION_KEY: &'static str = "schema_version";
+
+// Name SQLite opens a fresh database in memory for, rather than a file
+static IN_MEMORY_PATH: &'static str = ":memory:";
+
+// Conditions under which rows refer to rows which do not exist
+static DANGLING_FILEBLOCK: &'static str = "file_id NOT IN (SELECT id FROM file)
+                                            OR block_id NOT IN (SELECT id FROM block)";
+static DANGLING_ALIAS: &'static str = "directory_id NOT IN (SELECT id FROM directory)
+                                        OR (file_id IS NOT NULL
+                                            AND file_id NOT IN (SELECT id FROM file))";
+static DANGLING_XATTR: &'static str = "alias_id NOT IN (SELECT id FROM alias)";
+
+// Number of blocks of a file inserted by a single statement. Every block takes
+// one parameter, which must stay well below SQLite's limit of 999.
+static FILEBLOCK_INSERT_ROWS: usize = 400;
+
 pub struct DatabaseError {
     description: String,
     cause: Option<Box<Error>>,
@@ -114,16 +190,59 @@ impl FromSql for Directory {
     }
 }
 
+// A single row of the alias table
+pub struct AliasRecord {
+    pub directory: Directory,
+    pub file_id: Option<FileId>,
+    pub name: String,
+    pub attributes: Attributes,
+    pub timestamp: u64,
+    // Target of the symlink the alias refers to, if it is one
+    pub symlink: Option<String>,
+}
+
+// Rows which refer to rows that do not exist, and blocks which no file refers
+// to. A healthy index has none of these, but interrupted operations may leave
+// them behind.
+pub struct Orphans {
+    // Ids of file blocks whose file or block is missing
+    pub fileblocks: Vec<u64>,
+    // Ids of aliases whose file or directory is missing
+    pub aliases: Vec<u64>,
+    pub blocks: Vec<(BlockId, Vec<u8>)>,
+}
+
 // An iterator over files in a state determined by the given timestamp. A file
-// is represented by its path and a list of block id's.
+// is represented by its path, a list of block id's and its attributes. The
+// contents of subdirectories come before the files of their parent.
+// Directories being walked are kept on a stack rather than nested iterators,
+// so that deep trees do not take a deep call stack.
 // TODO: should be associated type?
 pub struct Aliases<'a> {
     database: &'a Database,
-    path: PathBuf, // FIXME: maybe this can be a &Path instead?
+    // Path of the innermost directory being walked
+    path: PathBuf,
     timestamp: u64,
-    file_list: Vec<(FileId, String)>,
+    // The directories being walked, from the outermost to the innermost
+    stack: Vec<AliasFrame>,
+}
+
+// Files and subdirectories of a directory which are yet to be walked
+struct AliasFrame {
+    file_list: Vec<(FileId, String, Attributes)>,
     directory_list: Vec<Directory>,
-    subdirectory: Option<Box<Aliases<'a>>>,
+}
+
+impl AliasFrame {
+    fn new(database: &Database,
+           directory: Directory,
+           timestamp: u64)
+           -> DatabaseResult<AliasFrame> {
+        Ok(AliasFrame {
+            file_list: try!(database.get_directory_content_at(directory, timestamp)),
+            directory_list: try!(database.get_subdirectories(directory)),
+        })
+    }
 }
 
 impl<'a> Aliases<'a> {
@@ -136,63 +255,73 @@ impl<'a> Aliases<'a> {
             database: database,
             path: path,
             timestamp: timestamp,
-            file_list: try!(database.get_directory_content_at(directory, timestamp)),
-            directory_list: try!(database.get_subdirectories(directory)),
-            subdirectory: None,
+            stack: vec![try!(AliasFrame::new(database, directory, timestamp))],
         })
     }
 }
 
 impl<'a> Iterator for Aliases<'a> {
-    type Item = DatabaseResult<(PathBuf, Vec<BlockId>)>;
+    type Item = DatabaseResult<(PathBuf, Vec<BlockId>, Attributes)>;
 
-    fn next(&mut self) -> Option<DatabaseResult<(PathBuf, Vec<BlockId>)>> {
-        // return file from child directory
+    fn next(&mut self) -> Option<DatabaseResult<(PathBuf, Vec<BlockId>, Attributes)>> {
         loop {
-            if let Some(ref mut dir) = self.subdirectory {
-                if let result@Some(_) = dir.next() {
-                    return result;
+            let subdirectory = {
+                let frame = match self.stack.last_mut() {
+                    Some(frame) => frame,
+                    None => return None,
+                };
+
+                // subdirectories are walked before the files of their parent
+                if let subdirectory@Some(..) = frame.directory_list.pop() {
+                    subdirectory
+                } else if let Some((id, name, attributes)) = frame.file_list.pop() {
+                    let path = self.path.join(&name);
+
+                    return Some(self.database
+                                    .get_file_block_list(id)
+                                    .map(|block_list| (path, block_list, attributes)));
+                } else {
+                    None
                 }
-            }
+            };
 
-            match self.directory_list.pop() {
-                None => break,
+            match subdirectory {
                 Some(id) => {
-                    let subdirectory = self.database
-                                           .get_directory_name(id)
-                                           .and_then(|directory_name| {
-                                               Aliases::new(self.database,
-                                                            self.path.join(&directory_name),
-                                                            id,
-                                                            self.timestamp)
-                                           });
-
-                    match subdirectory {
-                        Ok(subdir) => {
-                            self.subdirectory = Some(Box::new(subdir));
+                    let frame = self.database.get_directory_name(id).and_then(|name| {
+                        AliasFrame::new(self.database, id, self.timestamp).map(|frame| {
+                            (name, frame)
+                        })
+                    });
+
+                    match frame {
+                        Ok((name, frame)) => {
+                            self.path.push(&name);
+                            self.stack.push(frame);
                         }
-                        Err(e) => {
-                            self.directory_list.push(id);
+                        // the directory is skipped, so that the walk does
+                        // not return the same error over and over again
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                // the innermost directory has been walked entirely
+                None => {
+                    self.stack.pop();
 
-                            return Some(Err(e));
-                        }
+                    if !self.stack.is_empty() {
+                        self.path.pop();
                     }
                 }
             }
         }
-
-        // return file from current directory
-        self.file_list.pop().map(|(id, name)| {
-            self.database
-                .get_file_block_list(id)
-                .map(|block_list| (self.path.join(&name), block_list))
-        })
     }
 }
 
 pub struct Database {
     connection: SqliteConnection,
     path: PathBuf,
+    // Whether the connection was opened without write access, in which case
+    // clones are as well
+    read_only: bool,
 }
 
 unsafe impl Send for Database { }
@@ -202,6 +331,7 @@ impl Database {
         let db = Database {
             connection: try!(SqliteConnection::open_with_flags(&path, flags)),
             path: path,
+            read_only: !flags.contains(SQLITE_OPEN_READ_WRITE),
         };
 
         // set write lock timeout to 1 day
@@ -226,9 +356,16 @@ impl Database {
         Database::new(path, SQLITE_OPEN_FULL_MUTEX | SQLITE_OPEN_READ_WRITE)
     }
 
+    // Opens an existing index without write access, so that it can be read
+    // from read-only media and cannot be changed by accident. Its schema
+    // must be up to date already, as it cannot be migrated.
+    pub fn open_read_only(path: PathBuf) -> DatabaseResult<Database> {
+        Database::new(path, SQLITE_OPEN_FULL_MUTEX | SQLITE_OPEN_READ_ONLY)
+    }
+
     pub fn create(path: PathBuf) -> BonzoResult<Database> {
         match path.exists() {
-            true => Err(BonzoError::from_str("Database file already exists")),
+            true => Err(BonzoError::AlreadyInitialized),
             false => {
                 let open_options = SQLITE_OPEN_FULL_MUTEX | SQLITE_OPEN_READ_WRITE |
                                    SQLITE_OPEN_CREATE;
@@ -237,8 +374,47 @@ impl Database {
         }
     }
 
+    // Creates an empty index which lives in memory only, for tests and other
+    // short-lived uses. It is gone once dropped. As every connection to
+    // :memory: gets a database of its own, it can neither be cloned nor
+    // turned into bytes, so it cannot be handed to a backup.
+    pub fn in_memory() -> DatabaseResult<Database> {
+        let open_options = SQLITE_OPEN_FULL_MUTEX | SQLITE_OPEN_READ_WRITE | SQLITE_OPEN_CREATE;
+        let db = try!(Database::new(PathBuf::from(IN_MEMORY_PATH), open_options));
+
+        try!(db.setup());
+
+        Ok(db)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn try_clone(&self) -> DatabaseResult<Database> {
-        Database::from_file(self.path.clone())
+        if self.path == Path::new(IN_MEMORY_PATH) {
+            return Err(DatabaseError {
+                description: "An index in memory cannot be cloned".to_string(),
+                cause: None,
+            });
+        }
+
+        match self.read_only {
+            true => Database::open_read_only(self.path.clone()),
+            false => Database::from_file(self.path.clone()),
+        }
+    }
+
+    // Executes a statement, retrying when the database is locked
+    fn execute(&self, sql: &str, params: &[&ToSql]) -> SqliteResult<c_int> {
+        retry_when_busy(|| self.connection.execute(sql, params))
+    }
+
+    // Queries a single row, retrying when the database is locked
+    fn query_row_safe<T, F>(&self, sql: &str, params: &[&ToSql], f: F) -> SqliteResult<T>
+        where F: Fn(SqliteRow) -> T
+    {
+        retry_when_busy(|| self.connection.query_row_safe(sql, params, |row| f(row)))
     }
 
     fn query_and_collect<T, F, C>(&self, sql: &str, params: &[&ToSql], f: F) -> DatabaseResult<C>
@@ -280,8 +456,8 @@ impl Database {
     pub fn get_directory_content_at(&self,
                                     directory: Directory,
                                     timestamp: u64)
-                                    -> DatabaseResult<Vec<(FileId, String)>> {
-        self.query_and_collect("SELECT alias.file_id, alias.name
+                                    -> DatabaseResult<Vec<(FileId, String, Attributes)>> {
+        self.query_and_collect("SELECT alias.file_id, alias.name, alias.modified, alias.mode
                                   FROM alias
                                  INNER JOIN (SELECT MAX(id) AS max_id
                                                FROM alias
@@ -290,7 +466,7 @@ impl Database {
                                               GROUP BY name) a ON alias.id = a.max_id
                                  WHERE file_id IS NOT NULL;",
                                &[&directory, &(timestamp as i64)],
-                               |row| (row.get::<FileId>(0), row.get(1)))
+                               |row| (row.get::<FileId>(0), row.get(1), row_attributes(&row, 2)))
     }
 
     pub fn get_directory_filenames(&self, directory: Directory) -> DatabaseResult<HashSet<String>> {
@@ -299,20 +475,26 @@ impl Database {
                                                FROM alias
                                               WHERE directory_id = $1
                                               GROUP BY name) a ON alias.id = a.max_id
-                                 WHERE file_id IS NOT NULL;",
+                                 WHERE file_id IS NOT NULL OR symlink IS NOT NULL;",
                                &[&directory],
                                |row| row.get(0))
     }
 
-    fn get_directory_name(&self, directory: Directory) -> DatabaseResult<String> {
-        self.connection
-            .query_row_safe("SELECT name FROM directory WHERE id = $1;",
+    pub fn get_directory_parent(&self, directory: Directory) -> DatabaseResult<Directory> {
+        self.query_row_safe("SELECT parent_id FROM directory WHERE id = $1;",
+                            &[&directory],
+                            |row| row.get(0))
+            .map_err(From::from)
+    }
+
+    pub fn get_directory_name(&self, directory: Directory) -> DatabaseResult<String> {
+        self.query_row_safe("SELECT name FROM directory WHERE id = $1;",
                             &[&directory],
                             |row| row.get::<String>(0))
             .map_err(From::from)
     }
 
-    fn get_file_block_list(&self, file_id: FileId) -> DatabaseResult<Vec<BlockId>> {
+    pub fn get_file_block_list(&self, file_id: FileId) -> DatabaseResult<Vec<BlockId>> {
         self.query_and_collect("SELECT block_id FROM fileblock WHERE file_id = $1 ORDER BY \
                                 ordinal ASC;",
                                &[&file_id],
@@ -320,149 +502,626 @@ impl Database {
             .map_err(From::from)
     }
 
+    // Returns the file ids and timestamps of all aliases of the given file
+    // name, most recent first. Deletions are skipped.
+    pub fn get_file_history(&self,
+                            directory: Directory,
+                            filename: &str)
+                            -> DatabaseResult<Vec<(FileId, u64)>> {
+        self.query_and_collect("SELECT file_id, timestamp
+                                  FROM alias
+                                 WHERE directory_id = $1
+                                   AND name = $2
+                                   AND file_id IS NOT NULL
+                                 ORDER BY id DESC;",
+                               &[&directory, &filename],
+                               |row| (row.get::<FileId>(0), row.get::<i64>(1) as u64))
+    }
+
+    // Returns the file a name referred to at the given time, along with the
+    // attributes it had. Returns None when the name did not exist then.
+    pub fn get_file_at(&self,
+                       directory: Directory,
+                       filename: &str,
+                       timestamp: u64)
+                       -> DatabaseResult<Option<(FileId, Attributes)>> {
+        self.query_and_collect("SELECT file_id, modified, mode FROM alias
+                                 WHERE id = (SELECT MAX(id)
+                                               FROM alias
+                                              WHERE directory_id = $1
+                                                AND name = $2
+                                                AND timestamp <= $3)
+                                   AND file_id IS NOT NULL;",
+                               &[&directory, &filename, &(timestamp as i64)],
+                               |row| (row.get::<FileId>(0), row_attributes(&row, 1)))
+            .map(|mut files: Vec<_>| files.pop())
+    }
+
     pub fn persist_file(&self,
                         directory: Directory,
                         filename: &str,
                         hash: &[u8],
-                        last_modified: u64,
-                        block_id_list: &[BlockId])
+                        attributes: Attributes,
+                        block_id_list: &[BlockId],
+                        size: u64,
+                        xattrs: &[(String, Vec<u8>)])
                         -> DatabaseResult<()> {
-        let transaction = try!(self.connection.transaction());
+        // a savepoint rather than a transaction, as we may be in a batch
+        try!(self.execute("SAVEPOINT persist_file;", &[]));
 
-        try!(self.connection.execute("INSERT INTO file (hash) VALUES ($1);", &[&hash]));
+        let result = self.insert_file(hash, block_id_list, Some(size)).and_then(|file_id| {
+            self.persist_alias_with_xattrs(directory, Some(file_id), filename, attributes, xattrs)
+        });
+
+        if result.is_err() {
+            let _ = self.execute("ROLLBACK TO persist_file;", &[]);
+        }
+
+        try!(self.execute("RELEASE persist_file;", &[]));
+
+        result
+    }
+
+    // Starts a transaction in which subsequent changes are grouped until
+    // commit_batch is called. This saves a lot of overhead when persisting
+    // many small files. Other connections cannot write in the meantime.
+    pub fn begin_batch(&self) -> DatabaseResult<()> {
+        self.execute("BEGIN DEFERRED;", &[]).map(|_| ()).map_err(From::from)
+    }
+
+    pub fn commit_batch(&self) -> DatabaseResult<()> {
+        self.execute("COMMIT;", &[]).map(|_| ()).map_err(From::from)
+    }
+
+    // Discards all changes made since begin_batch was called
+    pub fn rollback_batch(&self) -> DatabaseResult<()> {
+        self.execute("ROLLBACK;", &[]).map(|_| ()).map_err(From::from)
+    }
+
+    // Runs the given closure within a savepoint, so that the changes it makes
+    // are undone when it fails. Works both inside and outside of a batch.
+    pub fn within_savepoint<T, E, F>(&self, name: &str, f: F) -> Result<T, E>
+        where F: FnOnce() -> Result<T, E>,
+              E: From<DatabaseError>
+    {
+        try!(self.execute(&format!("SAVEPOINT {};", name), &[]).map_err(DatabaseError::from));
+
+        let result = f();
+
+        if result.is_err() {
+            let _ = self.execute(&format!("ROLLBACK TO {};", name), &[]);
+        }
+
+        try!(self.execute(&format!("RELEASE {};", name), &[]).map_err(DatabaseError::from));
+
+        result
+    }
+
+    // Inserts a file and its ordered list of blocks. Does not create an
+    // alias, so callers should wrap this in a transaction together with
+    // persisting one. The size is that of the contents of the file, which is
+    // left unknown when it is not given.
+    pub fn insert_file(&self,
+                       hash: &[u8],
+                       block_id_list: &[BlockId],
+                       size: Option<u64>)
+                       -> DatabaseResult<FileId> {
+        let signed_size = size.map(|unsigned| unsigned as i64);
+        let block_count = block_id_list.len() as i64;
+
+        try!(self.execute("INSERT INTO file (hash, size, block_count) VALUES ($1, $2, $3);",
+                          &[&hash, &signed_size, &block_count]));
 
         let file_id = self.connection.last_insert_rowid();
 
-        let mut statement =
-            try!(self.connection.prepare("INSERT INTO fileblock (file_id, block_id, ordinal)
-                                          VALUES ($1, $2, $3);"));
+        try!(self.insert_fileblocks(file_id, block_id_list, FILEBLOCK_INSERT_ROWS));
+
+        Ok(FileId(file_id as u64))
+    }
+
+    // Inserts the blocks of a file in runs of the given length, so that large
+    // files take few statements. All full runs share a prepared statement.
+    fn insert_fileblocks(&self,
+                         file_id: i64,
+                         block_id_list: &[BlockId],
+                         rows_per_statement: usize)
+                         -> DatabaseResult<()> {
+        let remainder = block_id_list.len() % rows_per_statement;
+        let (runs, last_run) = block_id_list.split_at(block_id_list.len() - remainder);
+
+        if !runs.is_empty() {
+            let query = fileblock_insert_query(rows_per_statement);
+            let mut statement = try!(self.connection.prepare(&query));
+
+            for (index, run) in runs.chunks(rows_per_statement).enumerate() {
+                let first_ordinal = (index * rows_per_statement) as i64;
 
-        for (ordinal, block_id) in block_id_list.iter().enumerate() {
-            try!(statement.execute(&[&file_id, block_id, &(ordinal as i64)]));
+                try!(statement.execute(&fileblock_parameters(&file_id, &first_ordinal, run)));
+            }
         }
 
-        try!(self.persist_alias(directory,
-                                Some(FileId(file_id as u64)),
-                                filename,
-                                Some(last_modified)));
+        if !last_run.is_empty() {
+            let first_ordinal = runs.len() as i64;
+
+            try!(self.execute(&fileblock_insert_query(last_run.len()),
+                              &fileblock_parameters(&file_id, &first_ordinal, last_run)));
+        }
 
-        transaction.commit().map_err(From::from)
+        Ok(())
     }
 
     pub fn persist_alias(&self,
                          directory: Directory,
                          file_id: Option<FileId>,
                          filename: &str,
-                         last_modified: Option<u64>)
+                         attributes: Attributes)
                          -> DatabaseResult<()> {
-        let signed_modified = last_modified.map(|unsigned| unsigned as i64);
-        let timestamp = Some(epoch_milliseconds() as i64);
+        self.persist_alias_at(directory, file_id, filename, attributes, epoch_milliseconds())
+    }
+
+    // Persists an alias along with the names and values of its extended
+    // attributes
+    pub fn persist_alias_with_xattrs(&self,
+                                     directory: Directory,
+                                     file_id: Option<FileId>,
+                                     filename: &str,
+                                     attributes: Attributes,
+                                     xattrs: &[(String, Vec<u8>)])
+                                     -> DatabaseResult<()> {
+        if xattrs.is_empty() {
+            return self.persist_alias(directory, file_id, filename, attributes);
+        }
+
+        try!(self.execute("SAVEPOINT persist_alias;", &[]));
+
+        let result = self.persist_alias(directory, file_id, filename, attributes).and_then(|_| {
+            let alias_id = self.connection.last_insert_rowid();
 
-        self.connection
-            .execute("INSERT INTO alias (directory_id, file_id, name, modified, timestamp)
-                      VALUES ($1, $2, $3, $4, $5);",
-                     &[&directory, &file_id, &filename, &signed_modified, &timestamp])
+            xattrs.iter()
+                  .map(|&(ref name, ref value)| {
+                      let name: &str = name;
+                      let value: &[u8] = value;
+
+                      self.execute("INSERT INTO xattr (alias_id, name, value)
+                                    VALUES ($1, $2, $3);",
+                                   &[&alias_id, &name, &value])
+                  })
+                  .fold_results((), |_, _| ())
+                  .map_err(From::from)
+        });
+
+        if result.is_err() {
+            let _ = self.execute("ROLLBACK TO persist_alias;", &[]);
+        }
+
+        try!(self.execute("RELEASE persist_alias;", &[]));
+
+        result
+    }
+
+    // Persists an alias with an explicit timestamp instead of the current
+    // time. Aliases of a single name must be persisted in order of their
+    // timestamps, since the most recent alias is determined by id.
+    pub fn persist_alias_at(&self,
+                            directory: Directory,
+                            file_id: Option<FileId>,
+                            filename: &str,
+                            attributes: Attributes,
+                            timestamp: u64)
+                            -> DatabaseResult<()> {
+        let signed_modified = attributes.modified.map(|unsigned| unsigned as i64);
+        let signed_mode = attributes.mode.map(|unsigned| unsigned as i64);
+        let signed_timestamp = Some(timestamp as i64);
+
+        self.execute("INSERT INTO alias (directory_id, file_id, name, modified, mode, timestamp)
+                      VALUES ($1, $2, $3, $4, $5, $6);",
+                     &[&directory,
+                       &file_id,
+                       &filename,
+                       &signed_modified,
+                       &signed_mode,
+                       &signed_timestamp])
             .map(|_| ())
             .map_err(From::from)
     }
 
+    // Returns every alias in the index in the order in which they were
+    // persisted
+    pub fn get_all_aliases(&self) -> DatabaseResult<Vec<AliasRecord>> {
+        self.query_and_collect("SELECT directory_id, file_id, name, modified, mode, timestamp,
+                                       symlink
+                                  FROM alias
+                                 ORDER BY id ASC;",
+                               &[],
+                               |row| {
+                                   AliasRecord {
+                                       directory: row.get(0),
+                                       file_id: row.get(1),
+                                       name: row.get(2),
+                                       attributes: row_attributes(&row, 3),
+                                       timestamp: row.get::<Option<i64>>(5).unwrap_or(0) as u64,
+                                       symlink: row.get(6),
+                                   }
+                               })
+    }
+
+    pub fn latest_alias_timestamp(&self,
+                                  directory: Directory,
+                                  filename: &str)
+                                  -> DatabaseResult<Option<u64>> {
+        self.query_row_safe("SELECT MAX(timestamp) FROM alias
+                              WHERE directory_id = $1 AND name = $2;",
+                            &[&directory, &filename],
+                            |row| row.get::<Option<i64>>(0).map(|i| i as u64))
+            .map_err(From::from)
+    }
+
+    // Records the start of a backup. Returns the id with which its end is
+    // recorded.
+    pub fn start_backup(&self, timestamp: u64) -> DatabaseResult<u64> {
+        try!(self.execute("INSERT INTO backup (started) VALUES ($1);", &[&(timestamp as i64)]));
+
+        Ok(self.connection.last_insert_rowid() as u64)
+    }
+
+    pub fn finish_backup(&self, id: u64, timestamp: u64) -> DatabaseResult<()> {
+        self.execute("UPDATE backup SET finished = $1 WHERE id = $2;",
+                     &[&(timestamp as i64), &(id as i64)])
+            .map(|_| ())
+            .map_err(From::from)
+    }
+
+    // Returns the start of every backup recorded along with its end, oldest
+    // first. The end is unknown for backups which never completed.
+    pub fn get_backups(&self) -> DatabaseResult<Vec<(u64, Option<u64>)>> {
+        self.query_and_collect("SELECT started, finished FROM backup ORDER BY started ASC;",
+                               &[],
+                               |row| {
+                                   (row.get::<i64>(0) as u64,
+                                    row.get::<Option<i64>>(1).map(|i| i as u64))
+                               })
+    }
+
+    // Returns every distinct alias timestamp along with the number of aliases
+    // persisted at that time, oldest first
+    pub fn alias_timestamps(&self) -> DatabaseResult<Vec<(u64, u64)>> {
+        self.query_and_collect("SELECT timestamp, COUNT(id) FROM alias
+                                 GROUP BY timestamp
+                                 ORDER BY timestamp ASC;",
+                               &[],
+                               |row| (row.get::<i64>(0) as u64, row.get::<i64>(1) as u64))
+    }
+
+    pub fn get_file_hash(&self, file_id: FileId) -> DatabaseResult<Vec<u8>> {
+        self.query_row_safe("SELECT hash FROM file WHERE id = $1;", &[&file_id], |row| row.get(0))
+            .map_err(From::from)
+    }
+
+    // Returns the size of the contents of the file in bytes and its number of
+    // blocks, as recorded when it was backed up. Both are unknown for files
+    // backed up by older releases.
+    pub fn get_file_size(&self, file_id: FileId) -> DatabaseResult<Option<(u64, u64)>> {
+        self.query_row_safe("SELECT size, block_count FROM file WHERE id = $1;",
+                            &[&file_id],
+                            |row| {
+                                match (row.get::<Option<i64>>(0), row.get::<Option<i64>>(1)) {
+                                    (Some(size), Some(blocks)) => {
+                                        Some((size as u64, blocks as u64))
+                                    }
+                                    _ => None,
+                                }
+                            })
+            .map_err(From::from)
+    }
+
+    // Returns the total size of the contents of all files whose size is known,
+    // along with the number of files whose size is not
+    pub fn file_sizes(&self) -> DatabaseResult<(u64, u64)> {
+        self.query_row_safe("SELECT SUM(size), COUNT(id) - COUNT(size) FROM file;",
+                            &[],
+                            |row| {
+                                (row.get::<Option<i64>>(0).unwrap_or(0) as u64,
+                                 row.get::<i64>(1) as u64)
+                            })
+            .map_err(From::from)
+    }
+
+    // Symlinks are stored as aliases without a file, but with a target
+    pub fn persist_symlink(&self,
+                           directory: Directory,
+                           filename: &str,
+                           target: &str)
+                           -> DatabaseResult<()> {
+        self.persist_symlink_at(directory, filename, target, epoch_milliseconds())
+    }
+
+    pub fn persist_symlink_at(&self,
+                              directory: Directory,
+                              filename: &str,
+                              target: &str,
+                              timestamp: u64)
+                              -> DatabaseResult<()> {
+        self.execute("INSERT INTO alias (directory_id, name, symlink, timestamp)
+                      VALUES ($1, $2, $3, $4);",
+                     &[&directory, &filename, &target, &(timestamp as i64)])
+            .map(|_| ())
+            .map_err(From::from)
+    }
+
+    // Returns the target of the given name when its most recent alias is a
+    // symlink
+    pub fn get_symlink(&self,
+                       directory: Directory,
+                       filename: &str)
+                       -> DatabaseResult<Option<String>> {
+        self.query_and_collect("SELECT symlink FROM alias
+                                 WHERE id = (SELECT MAX(id)
+                                               FROM alias
+                                              WHERE directory_id = $1
+                                                AND name = $2)
+                                   AND symlink IS NOT NULL;",
+                               &[&directory, &filename],
+                               |row| row.get(0))
+            .map(|mut targets: Vec<String>| targets.pop())
+    }
+
+    // Returns the directory, name and target of every symlink in the backup
+    // at the given time
+    pub fn get_symlinks_at(&self,
+                           timestamp: u64)
+                           -> DatabaseResult<Vec<(Directory, String, String)>> {
+        self.query_and_collect("SELECT alias.directory_id, alias.name, alias.symlink
+                                  FROM alias
+                                 INNER JOIN (SELECT MAX(id) AS max_id
+                                               FROM alias
+                                              WHERE timestamp <= $1
+                                              GROUP BY directory_id, name) a
+                                    ON alias.id = a.max_id
+                                 WHERE alias.symlink IS NOT NULL;",
+                               &[&(timestamp as i64)],
+                               |row| (row.get(0), row.get(1), row.get(2)))
+    }
+
+    // Returns the directory and name of every file in the backup at the given
+    // time which has extended attributes, along with the name and value of
+    // each of them
+    pub fn get_xattrs_at(&self,
+                         timestamp: u64)
+                         -> DatabaseResult<Vec<(Directory, String, String, Vec<u8>)>> {
+        self.query_and_collect("SELECT alias.directory_id, alias.name, xattr.name, xattr.value
+                                  FROM alias
+                                 INNER JOIN (SELECT MAX(id) AS max_id
+                                               FROM alias
+                                              WHERE timestamp <= $1
+                                              GROUP BY directory_id, name) a
+                                    ON alias.id = a.max_id
+                                 INNER JOIN xattr
+                                    ON xattr.alias_id = alias.id
+                                 WHERE alias.file_id IS NOT NULL
+                                 ORDER BY xattr.id ASC;",
+                               &[&(timestamp as i64)],
+                               |row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+    }
+
     pub fn persist_null_alias(&self, directory: Directory, filename: &str) -> DatabaseResult<()> {
-        self.persist_alias(directory, None, filename, None).map_err(From::from)
+        self.persist_alias(directory, None, filename, Attributes::unknown()).map_err(From::from)
     }
 
     pub fn persist_block(&self, hash: &[u8]) -> DatabaseResult<BlockId> {
-        try!(self.connection.execute("INSERT INTO block (hash) VALUES ($1);", &[&hash]));
+        try!(self.execute("INSERT INTO block (hash) VALUES ($1);", &[&hash]));
 
         Ok(BlockId(self.connection.last_insert_rowid() as u64))
     }
 
     pub fn file_from_hash(&self, hash: &[u8]) -> DatabaseResult<Option<FileId>> {
-        self.connection
-            .query_row_safe("SELECT SUM(id) FROM file WHERE hash = $1;", &[&hash], |row| row.get(0))
-            .map_err(From::from)
+        self.query_and_collect("SELECT id FROM file WHERE hash = $1 LIMIT 1;",
+                               &[&hash],
+                               |row| row.get(0))
+            .map(|mut ids: Vec<FileId>| ids.pop())
     }
 
+    // Whether the latest version of a file was backed up with the given mode
+    // and a modification time no older than the given one, in which case it
+    // need not be backed up again
     pub fn alias_known(&self,
                        directory: Directory,
                        filename: &str,
-                       modified: u64)
+                       modified: u64,
+                       mode: Option<u32>)
                        -> DatabaseResult<bool> {
-        self.connection
-            .query_row_safe("SELECT COUNT(alias.id) FROM alias
+        let mode = mode.map(|mode| mode as i64);
+
+        self.query_row_safe("SELECT COUNT(alias.id) FROM alias
                               INNER JOIN (SELECT MAX(id) AS max_id
                                             FROM alias
                                            WHERE directory_id = $1 AND name = $2) a
                                          ON alias.id = a.max_id
                               WHERE modified >= $3
+                                AND mode IS $4
                                 AND file_id IS NOT NULL;",
-                            &[&directory, &filename, &(modified as i64)],
+                            &[&directory, &filename, &(modified as i64), &mode],
                             |row| row.get::<i64>(0) > 0)
             .map_err(From::from)
     }
 
     pub fn block_hash_from_id(&self, id: BlockId) -> DatabaseResult<Vec<u8>> {
-        self.connection
-            .query_row_safe("SELECT hash FROM block WHERE id = $1;", &[&id], |row| row.get(0))
+        self.query_row_safe("SELECT hash FROM block WHERE id = $1;", &[&id], |row| row.get(0))
             .map_err(From::from)
     }
 
     pub fn block_id_from_hash(&self, hash: &[u8]) -> DatabaseResult<Option<BlockId>> {
-        self.connection
-            .query_row_safe("SELECT SUM(id) FROM block WHERE hash = $1;",
-                            &[&hash],
-                            |row| row.get(0))
-            .map_err(From::from)
+        self.query_and_collect("SELECT id FROM block WHERE hash = $1 LIMIT 1;",
+                               &[&hash],
+                               |row| row.get(0))
+            .map(|mut ids: Vec<BlockId>| ids.pop())
+    }
+
+    // Looks up a directory without creating it when it does not exist
+    pub fn find_directory(&self,
+                          parent: Directory,
+                          name: &str)
+                          -> DatabaseResult<Option<Directory>> {
+        self.query_and_collect("SELECT id FROM directory
+                                 WHERE name = $1 AND parent_id = $2
+                                 LIMIT 1;",
+                               &[&name, &parent],
+                               |row| row.get(0))
+            .map(|mut ids: Vec<Directory>| ids.pop())
     }
 
     pub fn get_directory(&self, parent: Directory, name: &str) -> DatabaseResult<Directory> {
-        let possible_directory: Option<Directory> = try!({
-            let select_query = "SELECT SUM(id) FROM directory WHERE name = $1 AND parent_id = $2;";
-            self.connection.query_row_safe(select_query, &[&name, &parent], |row| row.get(0))
-        });
+        let possible_directory = try!(self.find_directory(parent, name));
 
         if let Some(directory) = possible_directory {
             return Ok(directory);
         }
 
-        try!(self.connection.execute("INSERT INTO directory (parent_id, name) VALUES ($1, $2);",
-                                     &[&parent, &name]));
+        try!(self.execute("INSERT INTO directory (parent_id, name) VALUES ($1, $2);",
+                          &[&parent, &name]));
 
         Ok(Directory::Child(self.connection.last_insert_rowid()))
     }
 
+    // Returns the path of a directory relative to the source directory
+    pub fn get_directory_path(&self, directory: Directory) -> DatabaseResult<PathBuf> {
+        let mut names = Vec::new();
+        let mut current = directory;
+
+        while current != Directory::Root {
+            names.push(try!(self.get_directory_name(current)));
+            current = try!(self.get_directory_parent(current));
+        }
+
+        Ok(names.iter().rev().collect())
+    }
+
+    // Records that a directory has become empty, or that it no longer is.
+    // Directories are only recorded while they are empty, since those are the
+    // ones restoring files will not recreate.
+    pub fn persist_empty_directory(&self, directory: Directory, empty: bool) -> DatabaseResult<()> {
+        self.execute("INSERT INTO empty_directory (directory_id, empty, timestamp)
+                      VALUES ($1, $2, $3);",
+                     &[&directory, &(empty as i64), &(epoch_milliseconds() as i64)])
+            .map(|_| ())
+            .map_err(From::from)
+    }
+
+    // Returns the subdirectories of the given directory which were empty at
+    // the end of the previous backup
+    pub fn get_empty_subdirectories(&self,
+                                    parent: Directory)
+                                    -> DatabaseResult<HashSet<Directory>> {
+        self.query_and_collect("SELECT empty_directory.directory_id FROM empty_directory
+                                 INNER JOIN (SELECT MAX(empty_directory.id) AS max_id
+                                               FROM empty_directory
+                                              INNER JOIN directory
+                                                 ON directory.id = empty_directory.directory_id
+                                              WHERE directory.parent_id = $1
+                                              GROUP BY empty_directory.directory_id) e
+                                    ON empty_directory.id = e.max_id
+                                 WHERE empty = 1;",
+                               &[&parent],
+                               |row| row.get(0))
+    }
+
+    // Returns the directories which were empty at the given time
+    pub fn get_empty_directories_at(&self, timestamp: u64) -> DatabaseResult<Vec<Directory>> {
+        self.query_and_collect("SELECT empty_directory.directory_id FROM empty_directory
+                                 INNER JOIN (SELECT MAX(id) AS max_id
+                                               FROM empty_directory
+                                              WHERE timestamp <= $1
+                                              GROUP BY directory_id) e
+                                    ON empty_directory.id = e.max_id
+                                 WHERE empty = 1;",
+                               &[&(timestamp as i64)],
+                               |row| row.get(0))
+    }
+
+    // Removes records of empty directories older than the given timestamp,
+    // except for the latest record of directories which are still empty
+    pub fn remove_old_empty_directories(&self, timestamp: u64) -> DatabaseResult<()> {
+        self.execute("DELETE FROM empty_directory
+                       WHERE timestamp < $1
+                         AND (empty = 0
+                              OR
+                              id NOT IN (SELECT MAX(id) FROM empty_directory
+                                          GROUP BY directory_id));",
+                     &[&(timestamp as i64)])
+            .map(|_| ())
+            .map_err(From::from)
+    }
+
     pub fn set_key(&self, key: &str, value: &str) -> DatabaseResult<i32> {
-        self.connection
-            .execute("INSERT INTO setting (key, value) VALUES ($1, $2);", &[&key, &value])
+        self.execute("INSERT OR REPLACE INTO setting (key, value) VALUES ($1, $2);",
+                     &[&key, &value])
+            .map_err(From::from)
+    }
+
+    // Runs SQLite's own consistency check on the index. Returns the problems
+    // found, which is an empty list for a healthy index.
+    pub fn integrity_check(&self) -> DatabaseResult<Vec<String>> {
+        let messages: Vec<String> =
+            try!(self.query_and_collect("PRAGMA integrity_check;", &[], |row| row.get(0)));
+
+        Ok(messages.into_iter().filter(|message| message != "ok").collect())
+    }
+
+    // Like integrity_check, but treats any problem found as an error. An index
+    // too damaged to be checked at all fails as well.
+    pub fn check_integrity(&self) -> DatabaseResult<()> {
+        let problems = try!(self.integrity_check());
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => {
+                Err(DatabaseError {
+                    description: format!("Index failed the integrity check: {}",
+                                         problems.join("; ")),
+                    cause: None,
+                })
+            }
+        }
+    }
+
+    // Rebuilds the index file without the space left behind by deleted rows
+    pub fn vacuum(&self) -> DatabaseResult<()> {
+        self.execute("VACUUM;", &[]).map(|_| ()).map_err(From::from)
+    }
+
+    pub fn remove_key(&self, key: &str) -> DatabaseResult<()> {
+        self.execute("DELETE FROM setting WHERE key = $1;", &[&key])
+            .map(|_| ())
             .map_err(From::from)
     }
 
     pub fn get_key(&self, key: &str) -> DatabaseResult<Option<String>> {
-        self.connection
-            .query_row_safe("SELECT value FROM setting WHERE key = $1;", &[&key], |row| row.get(0))
+        self.query_row_safe("SELECT value FROM setting WHERE key = $1;", &[&key], |row| row.get(0))
             .map_err(From::from)
     }
 
     pub fn remove_old_aliases(&self, timestamp: u64) -> DatabaseResult<u64> {
-        self.connection
-            .execute("DELETE FROM alias
+        self.execute("DELETE FROM alias
                        WHERE timestamp < $1
-                         AND (file_id IS NULL
+                         AND ((file_id IS NULL AND symlink IS NULL)
                               OR
                               id NOT IN (SELECT MAX(id) FROM alias GROUP BY name, directory_id));",
                      &[&(timestamp as i64)])
-            .map(|rows_deleted| rows_deleted as u64)
+            .and_then(|rows_deleted| {
+                // extended attributes go along with their aliases
+                self.execute(&format!("DELETE FROM xattr WHERE {};", DANGLING_XATTR), &[])
+                    .map(|_| rows_deleted as u64)
+            })
             .map_err(From::from)
     }
 
     pub fn remove_unused_files(&self) -> DatabaseResult<()> {
-        self.connection
-            .execute("DELETE FROM fileblock
+        self.execute("DELETE FROM fileblock
                        WHERE file_id not in (SELECT file_id FROM alias);",
                      &[])
             .and_then(|_| {
-                self.connection.execute("DELETE FROM file
-                                          WHERE id not in (SELECT file_id FROM alias);",
-                                        &[])
+                self.execute("DELETE FROM file
+                               WHERE id not in (SELECT file_id FROM alias);",
+                             &[])
             })
             .map(|_| ())
             .map_err(From::from)
@@ -471,18 +1130,149 @@ impl Database {
     pub fn get_unused_blocks(&self) -> DatabaseResult<Vec<(BlockId, Vec<u8>)>> {
         self.query_and_collect("SELECT id, hash
                                   FROM block
-                                 WHERE id not in (SELECT id FROM fileblock);",
+                                 WHERE id not in (SELECT block_id FROM fileblock);",
                                &[],
                                |row| (row.get(0), row.get(1)))
     }
 
+    pub fn find_orphans(&self) -> DatabaseResult<Orphans> {
+        let fileblock_query = format!("SELECT id FROM fileblock WHERE {};", DANGLING_FILEBLOCK);
+        let alias_query = format!("SELECT id FROM alias WHERE {};", DANGLING_ALIAS);
+        let fileblocks = try!(self.query_and_collect(&fileblock_query,
+                                                     &[],
+                                                     |row| row.get::<i64>(0) as u64));
+        let aliases = try!(self.query_and_collect(&alias_query,
+                                                  &[],
+                                                  |row| row.get::<i64>(0) as u64));
+
+        Ok(Orphans {
+            fileblocks: fileblocks,
+            aliases: aliases,
+            blocks: try!(self.get_unused_blocks()),
+        })
+    }
+
+    // Removes the dangling rows found by find_orphans. Files which miss any of
+    // their blocks cannot be restored, so they are removed as a whole, along
+    // with their aliases. Restores then fall back on the previous version of
+    // those files. Blocks which are no longer used are left for the caller to
+    // remove, as their contents are on disk.
+    pub fn remove_orphans(&self) -> DatabaseResult<()> {
+        ["DELETE FROM file
+           WHERE id IN (SELECT file_id
+                          FROM fileblock
+                         WHERE block_id NOT IN (SELECT id FROM block));"
+              .to_owned(),
+         format!("DELETE FROM alias WHERE {};", DANGLING_ALIAS),
+         format!("DELETE FROM xattr WHERE {};", DANGLING_XATTR),
+         format!("DELETE FROM fileblock WHERE {};", DANGLING_FILEBLOCK)]
+            .iter()
+            .map(|query| self.execute(query, &[]))
+            .fold_results((), |_, _| ())
+            .map_err(From::from)
+    }
+
+    pub fn get_all_blocks(&self) -> DatabaseResult<Vec<(BlockId, Vec<u8>)>> {
+        self.query_and_collect("SELECT id, hash FROM block;", &[], |row| (row.get(0), row.get(1)))
+    }
+
+    // Returns the total size of the files of all aliases, counting a file
+    // once for every alias referring to it. Files without a recorded size
+    // are left out.
+    pub fn referenced_file_bytes(&self) -> DatabaseResult<u64> {
+        self.query_row_safe("SELECT SUM(file.size)
+                               FROM alias
+                              INNER JOIN file ON file.id = alias.file_id;",
+                            &[],
+                            |row| row.get::<Option<i64>>(0).unwrap_or(0) as u64)
+            .map_err(From::from)
+    }
+
+    pub fn block_count(&self) -> DatabaseResult<u64> {
+        self.count_rows("SELECT COUNT(id) FROM block;")
+    }
+
+    pub fn file_count(&self) -> DatabaseResult<u64> {
+        self.count_rows("SELECT COUNT(id) FROM file;")
+    }
+
+    pub fn alias_count(&self) -> DatabaseResult<u64> {
+        self.count_rows("SELECT COUNT(id) FROM alias;")
+    }
+
+    fn count_rows(&self, sql: &str) -> DatabaseResult<u64> {
+        self.query_row_safe(sql, &[], |row| row.get::<i64>(0) as u64)
+            .map_err(From::from)
+    }
+
     pub fn remove_block(&self, id: BlockId) -> DatabaseResult<()> {
-        self.connection
-            .execute("DELETE FROM block WHERE id = $1;", &[&id])
+        self.execute("DELETE FROM block WHERE id = $1;", &[&id])
             .map(|_| ())
             .map_err(From::from)
     }
 
+    pub fn schema_version(&self) -> DatabaseResult<usize> {
+        match try!(self.get_key(SCHEMA_VERSION_KEY)) {
+            None => Ok(0),
+            Some(version) => {
+                version.parse().map_err(|_| {
+                    DatabaseError {
+                        description: format!("Invalid schema version: {}", version),
+                        cause: None,
+                    }
+                })
+            }
+        }
+    }
+
+    // Brings an index created by an older release up to date with the
+    // current schema by applying the migrations it is missing. Every
+    // migration is committed along with the new version, so an interrupted
+    // upgrade continues where it left off. Columns added are left empty for
+    // existing rows.
+    pub fn migrate(&self) -> DatabaseResult<()> {
+        let version = try!(self.schema_version());
+
+        if version > MIGRATIONS.len() {
+            return Err(DatabaseError {
+                description: format!("Index has schema version {}, but this release only \
+                                      supports up to {}",
+                                     version,
+                                     MIGRATIONS.len()),
+                cause: None,
+            });
+        }
+
+        if self.read_only && version < MIGRATIONS.len() {
+            return Err(DatabaseError {
+                description: "Index opened read-only needs to be migrated".to_owned(),
+                cause: None,
+            });
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            try!(self.begin_batch());
+
+            let result = self.execute(migration, &[])
+                             .map_err(From::from)
+                             .and_then(|_| {
+                                 self.set_key(SCHEMA_VERSION_KEY, &(index + 1).to_string())
+                             });
+
+            match result {
+                Ok(..) => try!(self.commit_batch()),
+                Err(e) => {
+                    try!(self.rollback_batch());
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Creates the tables of the original schema, and then migrates them to
+    // the current one
     pub fn setup(&self) -> DatabaseResult<()> {
         ["CREATE TABLE directory (
               id        INTEGER PRIMARY KEY,
@@ -528,24 +1318,89 @@ impl Database {
               value        TEXT
           );"]
             .iter()
-            .map(|&query| self.connection.execute(query, &[]))
+            .map(|&query| self.execute(query, &[]))
             .fold_results((), |_, _| ())
             .map_err(From::from)
+            .and_then(|_| self.migrate())
     }
 }
 
+// Reads the modification time and mode of an alias from two consecutive
+// columns, starting at the given one
+fn row_attributes(row: &SqliteRow, column: c_int) -> Attributes {
+    Attributes {
+        modified: row.get::<Option<i64>>(column).map(|i| i as u64),
+        mode: row.get::<Option<i64>>(column + 1).map(|i| i as u32),
+    }
+}
+
+// Runs the given operation until it succeeds, fails for a reason other than a
+// lock held by another connection, or has been tried BUSY_ATTEMPTS times.
+fn retry_when_busy<T, F>(mut operation: F) -> SqliteResult<T>
+    where F: FnMut() -> SqliteResult<T>
+{
+    let mut backoff = INITIAL_BACKOFF_MILLISECONDS;
+
+    for _ in 1..BUSY_ATTEMPTS {
+        match operation() {
+            Err(ref e) if is_busy(e) => {
+                sleep(Duration::from_millis(backoff));
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+
+    operation()
+}
+
+fn is_busy(error: &SqliteError) -> bool {
+    // extended result codes keep the primary code in their lowest byte
+    let code = error.code & 0xff;
+
+    code == SQLITE_BUSY || code == SQLITE_LOCKED
+}
+
+// Builds a statement inserting the given number of consecutive blocks of a
+// file. Its parameters are the file id, the ordinal of the first block and the
+// ids of the blocks, as given by fileblock_parameters.
+fn fileblock_insert_query(rows: usize) -> String {
+    let values: Vec<String> = (0..rows)
+                                  .map(|row| format!("($1, ${}, $2 + {})", row + 3, row))
+                                  .collect();
+
+    format!("INSERT INTO fileblock (file_id, block_id, ordinal) VALUES {};",
+            values.join(", "))
+}
+
+fn fileblock_parameters<'a>(file_id: &'a i64,
+                            first_ordinal: &'a i64,
+                            block_id_list: &'a [BlockId])
+                            -> Vec<&'a ToSql> {
+    let mut parameters: Vec<&ToSql> = vec![file_id, first_ordinal];
+
+    parameters.extend(block_id_list.iter().map(|block_id| block_id as &ToSql));
+
+    parameters
+}
+
 #[cfg(test)]
 mod test {
-    use Directory;
+    use std::fs::metadata;
+    use std::path::PathBuf;
+
+    use {Directory, BlockId};
+    use attributes::Attributes;
+    use test::Bencher;
+
+    use super::DatabaseError;
+    use super::rusqlite::{SqliteError, SqliteResult};
 
     use super::super::tempdir::TempDir;
 
     #[test]
     fn directory_queries() {
-        let temp = TempDir::new("query-collect").unwrap();
-        let path = temp.path().join("index.db3");
-        let db = super::Database::create(path).unwrap();
-        let _ = db.setup().unwrap();
+        let db = super::Database::in_memory().unwrap();
 
         let child1 = db.get_directory(Directory::Root, "child1").unwrap();
         let child2 = db.get_directory(Directory::Root, "child2").unwrap();
@@ -569,4 +1424,388 @@ mod test {
 
         assert_eq!(0usize, great_grand_children.len());
     }
+
+    #[test]
+    fn integrity_check() {
+        let db = super::Database::in_memory().unwrap();
+
+        db.get_directory(Directory::Root, "child").unwrap();
+
+        assert!(db.integrity_check().unwrap().is_empty());
+        assert!(db.check_integrity().is_ok());
+
+        // a new connection would not see the directory
+        assert!(db.try_clone().is_err());
+    }
+
+    #[test]
+    fn savepoints() {
+        let db = super::Database::in_memory().unwrap();
+
+        let failed: Result<(), DatabaseError> = db.within_savepoint("test", || {
+            try!(db.insert_file(b"undone", &[], None));
+
+            Err(DatabaseError { description: "failure".to_string(), cause: None })
+        });
+
+        assert!(failed.is_err());
+        assert_eq!(None, db.file_from_hash(b"undone").unwrap());
+
+        let kept: Result<_, DatabaseError> = db.within_savepoint("test", || {
+            db.insert_file(b"kept", &[], None)
+        });
+
+        assert_eq!(Some(kept.unwrap()), db.file_from_hash(b"kept").unwrap());
+    }
+
+    #[test]
+    fn ids_from_hashes() {
+        let db = super::Database::in_memory().unwrap();
+
+        let blocks: Vec<BlockId> = [b"one", b"two", b"six"]
+                                       .iter()
+                                       .map(|hash| db.persist_block(*hash).unwrap())
+                                       .collect();
+
+        for &name in ["first", "second", "third"].iter() {
+            db.persist_file(Directory::Root,
+                            name,
+                            name.as_bytes(),
+                            Attributes::unknown(),
+                            &blocks,
+                            9,
+                            &[])
+              .unwrap();
+        }
+
+        assert_eq!(Some(blocks[2]), db.block_id_from_hash(b"six").unwrap());
+        assert_eq!(None, db.block_id_from_hash(b"ten").unwrap());
+
+        let third = db.file_from_hash(b"third").unwrap().unwrap();
+
+        assert_eq!(blocks, db.get_file_block_list(third).unwrap());
+        assert!(db.file_from_hash(b"first").unwrap() != Some(third));
+        assert_eq!(None, db.file_from_hash(b"fourth").unwrap());
+    }
+
+    fn persist_blocks(db: &super::Database, count: usize) -> Vec<BlockId> {
+        (0..count)
+            .map(|i| db.persist_block(format!("block {}", i).as_bytes()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn long_block_lists() {
+        let db = super::Database::in_memory().unwrap();
+
+        // spans several statements, with blocks out of order and repeated
+        let mut blocks = persist_blocks(&db, 2 * super::FILEBLOCK_INSERT_ROWS + 7);
+        blocks.reverse();
+        blocks[super::FILEBLOCK_INSERT_ROWS] = blocks[0];
+
+        let long = db.insert_file(b"long", &blocks, None).unwrap();
+        let exact = db.insert_file(b"exact", &blocks[..super::FILEBLOCK_INSERT_ROWS], None)
+                      .unwrap();
+        let empty = db.insert_file(b"empty", &[], Some(0)).unwrap();
+
+        assert_eq!(blocks, db.get_file_block_list(long).unwrap());
+        assert_eq!(&blocks[..super::FILEBLOCK_INSERT_ROWS],
+                   &db.get_file_block_list(exact).unwrap()[..]);
+        assert!(db.get_file_block_list(empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn orphans() {
+        let db = super::Database::in_memory().unwrap();
+
+        let kept = db.persist_block(b"kept").unwrap();
+        let lost = db.persist_block(b"lost").unwrap();
+        let _ = db.persist_block(b"unused").unwrap();
+
+        db.persist_file(Directory::Root,
+                        "intact",
+                        b"intact",
+                        Attributes::unknown(),
+                        &[kept],
+                        5,
+                        &[])
+          .unwrap();
+        db.persist_file(Directory::Root,
+                        "broken",
+                        b"broken",
+                        Attributes::unknown(),
+                        &[kept, lost],
+                        9,
+                        &[])
+          .unwrap();
+
+        // lose a block and a file, as an interrupted operation might
+        db.remove_block(lost).unwrap();
+        db.execute("INSERT INTO alias (directory_id, file_id, name, timestamp)
+                    VALUES (0, 1000, \"ghost\", 0);",
+                   &[])
+          .unwrap();
+
+        let orphans = db.find_orphans().unwrap();
+
+        assert_eq!(1, orphans.fileblocks.len());
+        assert_eq!(1, orphans.aliases.len());
+        assert_eq!(1, orphans.blocks.len());
+
+        db.remove_orphans().unwrap();
+
+        let orphans = db.find_orphans().unwrap();
+        let now = ::epoch_milliseconds();
+
+        assert!(orphans.fileblocks.is_empty());
+        assert!(orphans.aliases.is_empty());
+        assert!(db.get_file_at(Directory::Root, "intact", now).unwrap().is_some());
+        assert!(db.get_file_at(Directory::Root, "broken", now).unwrap().is_none());
+
+        // unused blocks are left to the caller
+        assert_eq!(1, orphans.blocks.len());
+    }
+
+    #[test]
+    fn xattrs() {
+        let db = super::Database::in_memory().unwrap();
+        let block = db.persist_block(b"block").unwrap();
+        let tags = vec![("user.tags".to_owned(), b"red".to_vec()),
+                        ("user.origin".to_owned(), vec![0, 1, 2])];
+
+        db.persist_file(Directory::Root,
+                        "tagged",
+                        b"hash",
+                        Attributes::unknown(),
+                        &[block],
+                        5,
+                        &tags)
+          .unwrap();
+
+        // a later version without attributes shares the file, not its attributes
+        let file_id = db.file_from_hash(b"hash").unwrap();
+        db.persist_alias_with_xattrs(Directory::Root, file_id, "copy", Attributes::unknown(), &[])
+          .unwrap();
+
+        let xattrs = db.get_xattrs_at(::epoch_milliseconds()).unwrap();
+        let expected: Vec<_> = tags.into_iter()
+                                   .map(|(name, value)| {
+                                       (Directory::Root, "tagged".to_owned(), name, value)
+                                   })
+                                   .collect();
+
+        assert_eq!(expected, xattrs);
+        assert!(db.get_xattrs_at(0).unwrap().is_empty());
+
+        // attributes of removed versions are removed too
+        db.persist_null_alias(Directory::Root, "tagged").unwrap();
+        db.remove_old_aliases(::epoch_milliseconds() + 1).unwrap();
+
+        assert!(db.get_xattrs_at(::epoch_milliseconds()).unwrap().is_empty());
+        assert_eq!(0,
+                   db.query_row_safe("SELECT COUNT(*) FROM xattr;", &[], |row| row.get::<i64>(0))
+                     .unwrap());
+    }
+
+    // Every directory of a deep tree takes a frame on the heap, rather than
+    // one on the call stack
+    #[test]
+    fn deep_aliases() {
+        let db = super::Database::in_memory().unwrap();
+        let block = db.persist_block(b"block").unwrap();
+        let mut directory = Directory::Root;
+        let mut deep_path = PathBuf::new();
+
+        for _ in 0..100000 {
+            directory = db.get_directory(directory, "d").unwrap();
+            deep_path.push("d");
+        }
+
+        db.persist_file(directory, "deep", b"deep", Attributes::unknown(), &[block], 5, &[])
+          .unwrap();
+        db.persist_file(Directory::Root,
+                        "shallow",
+                        b"shallow",
+                        Attributes::unknown(),
+                        &[block],
+                        5,
+                        &[])
+          .unwrap();
+
+        let paths: Vec<PathBuf> = super::Aliases::new(&db,
+                                                      PathBuf::new(),
+                                                      Directory::Root,
+                                                      ::epoch_milliseconds())
+                                      .unwrap()
+                                      .map(|alias| alias.unwrap().0)
+                                      .collect();
+
+        assert_eq!(vec![deep_path.join("deep"), PathBuf::from("shallow")], paths);
+    }
+
+    #[test]
+    fn file_sizes() {
+        let db = super::Database::in_memory().unwrap();
+
+        let first = db.persist_block(b"first").unwrap();
+        let second = db.persist_block(b"second").unwrap();
+        let sized = db.insert_file(b"sized", &[first, second], Some(11)).unwrap();
+        let legacy = db.insert_file(b"legacy", &[first], None).unwrap();
+
+        assert_eq!(Some((11, 2)), db.get_file_size(sized).unwrap());
+        assert_eq!(None, db.get_file_size(legacy).unwrap());
+        assert_eq!((11, 1), db.file_sizes().unwrap());
+
+        // every alias counts, but those without a file or size do not
+        for &(file_id, name) in [(Some(sized), "first"),
+                                 (Some(sized), "second"),
+                                 (Some(legacy), "legacy"),
+                                 (None, "deleted")]
+                                    .iter() {
+            db.persist_alias(Directory::Root, file_id, name, Attributes::unknown()).unwrap();
+        }
+
+        assert_eq!(22, db.referenced_file_bytes().unwrap());
+    }
+
+    #[test]
+    fn migrations() {
+        let temp = TempDir::new("migrations").unwrap();
+        let db = super::Database::create(temp.path().join("new.db3")).unwrap();
+        let _ = db.setup().unwrap();
+
+        assert_eq!(super::MIGRATIONS.len(), db.schema_version().unwrap());
+
+        // migrating an index which is up to date changes nothing
+        db.migrate().unwrap();
+
+        // indices from before versioning have the original schema
+        let old = super::Database::create(temp.path().join("old.db3")).unwrap();
+
+        for query in ["CREATE TABLE alias (
+                           id           INTEGER PRIMARY KEY,
+                           directory_id INTEGER NOT NULL,
+                           file_id      INTEGER,
+                           name         TEXT NOT NULL,
+                           modified     INTEGER,
+                           timestamp    INTEGER
+                       );",
+                      "CREATE TABLE file (id INTEGER PRIMARY KEY, hash BLOB NOT NULL);",
+                      "CREATE TABLE setting (key TEXT PRIMARY KEY, value TEXT);"]
+                         .iter() {
+            old.execute(query, &[]).unwrap();
+        }
+
+        assert_eq!(0, old.schema_version().unwrap());
+
+        old.migrate().unwrap();
+
+        let columns: Vec<String> =
+            old.query_and_collect("PRAGMA table_info(alias);", &[], |row| row.get(1)).unwrap();
+
+        assert!(columns.iter().any(|column| column == "mode"));
+        assert!(columns.iter().any(|column| column == "symlink"));
+
+        let file_columns: Vec<String> =
+            old.query_and_collect("PRAGMA table_info(file);", &[], |row| row.get(1)).unwrap();
+
+        assert!(file_columns.iter().any(|column| column == "size"));
+        assert!(file_columns.iter().any(|column| column == "block_count"));
+        assert_eq!(super::MIGRATIONS.len(), old.schema_version().unwrap());
+
+        // indices from newer releases are refused
+        let newer = (super::MIGRATIONS.len() + 1).to_string();
+        old.set_key(super::SCHEMA_VERSION_KEY, &newer).unwrap();
+
+        assert!(old.migrate().is_err());
+    }
+
+    #[test]
+    fn read_only() {
+        let temp = TempDir::new("read-only").unwrap();
+        let path = temp.path().join("index.db3");
+        let db = super::Database::create(path.clone()).unwrap();
+        let _ = db.setup().unwrap();
+        db.set_key("backup_path", "/tmp/backup").unwrap();
+
+        let read_only = super::Database::open_read_only(path).unwrap();
+
+        assert_eq!(Some("/tmp/backup".to_owned()), read_only.get_key("backup_path").unwrap());
+        assert!(read_only.migrate().is_ok());
+        assert!(read_only.set_key("backup_path", "/elsewhere").is_err());
+        assert!(read_only.try_clone().unwrap().set_key("backup_path", "/elsewhere").is_err());
+
+        // an index which is behind cannot be migrated
+        db.set_key(super::SCHEMA_VERSION_KEY, "0").unwrap();
+
+        assert!(read_only.migrate().is_err());
+    }
+
+    #[test]
+    fn vacuum() {
+        let temp = TempDir::new("vacuum").unwrap();
+        let path = temp.path().join("index.db3");
+        let db = super::Database::create(path.clone()).unwrap();
+        let _ = db.setup().unwrap();
+
+        for i in 0..2000 {
+            db.get_directory(Directory::Root, &format!("directory-{}", i)).unwrap();
+        }
+
+        let size_before = metadata(&path).unwrap().len();
+
+        db.execute("DELETE FROM directory;", &[]).unwrap();
+        db.vacuum().unwrap();
+
+        assert!(metadata(&path).unwrap().len() < size_before);
+    }
+
+    #[test]
+    fn busy_retries() {
+        let mut attempts = 0;
+        let result = super::retry_when_busy(|| {
+            attempts += 1;
+
+            match attempts {
+                1 | 2 => Err(SqliteError { code: super::SQLITE_BUSY, message: "busy".to_owned() }),
+                _ => Ok(attempts),
+            }
+        });
+
+        assert_eq!(3, result.unwrap());
+
+        // other errors are not retried
+        attempts = 0;
+        let result: SqliteResult<()> = super::retry_when_busy(|| {
+            attempts += 1;
+            Err(SqliteError { code: 1, message: "syntax error".to_owned() })
+        });
+
+        assert!(result.is_err());
+        assert_eq!(1, attempts);
+    }
+
+    fn insert_fileblocks(b: &mut Bencher, rows_per_statement: usize) {
+        let db = super::Database::in_memory().unwrap();
+        let blocks = persist_blocks(&db, 4000);
+
+        // like persist_file, which inserts the blocks within a savepoint
+        db.begin_batch().unwrap();
+
+        b.iter(|| db.insert_fileblocks(1, &blocks, rows_per_statement).unwrap());
+
+        db.commit_batch().unwrap();
+    }
+
+    // Compare with batched_fileblocks to see the effect of inserting many
+    // blocks of a file per statement
+    #[bench]
+    fn single_fileblocks(b: &mut Bencher) {
+        insert_fileblocks(b, 1);
+    }
+
+    #[bench]
+    fn batched_fileblocks(b: &mut Bencher) {
+        insert_fileblocks(b, super::FILEBLOCK_INSERT_ROWS);
+    }
 }
@@ -11,27 +11,80 @@ pub enum BonzoError {
     Database(DatabaseError),
     Io(io::Error, Option<PathBuf>),
     Crypto(CryptoError),
+    // A block which could be decrypted, but whose contents could not be
+    // decompressed
+    Decompression(String),
+    // A backup was already initialized at the destination
+    AlreadyInitialized,
+    // The passphrase does not match the one the backup was made with
+    WrongPassword,
+    // A block listed in the index cannot be found at the destination
+    MissingBlock(String),
+    // A block whose contents do not match its hash
+    CorruptBlock,
+    // An argument which cannot be worked with, such as a block size or a path
+    InvalidInput(String),
     Other(String),
 }
 
+// Category of a BonzoError, so that callers can tell errors apart without
+// inspecting their messages
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum BonzoErrorKind {
+    Database,
+    Io,
+    Crypto,
+    Decompression,
+    AlreadyInitialized,
+    WrongPassword,
+    MissingBlock,
+    CorruptBlock,
+    InvalidInput,
+    Other,
+}
+
 impl BonzoError {
     pub fn from_str(slice: &str) -> BonzoError {
         BonzoError::Other(slice.to_string())
     }
+
+    pub fn kind(&self) -> BonzoErrorKind {
+        match *self {
+            BonzoError::Database(..) => BonzoErrorKind::Database,
+            BonzoError::Io(..) => BonzoErrorKind::Io,
+            BonzoError::Crypto(..) => BonzoErrorKind::Crypto,
+            BonzoError::Decompression(..) => BonzoErrorKind::Decompression,
+            BonzoError::AlreadyInitialized => BonzoErrorKind::AlreadyInitialized,
+            BonzoError::WrongPassword => BonzoErrorKind::WrongPassword,
+            BonzoError::MissingBlock(..) => BonzoErrorKind::MissingBlock,
+            BonzoError::CorruptBlock => BonzoErrorKind::CorruptBlock,
+            BonzoError::InvalidInput(..) => BonzoErrorKind::InvalidInput,
+            BonzoError::Other(..) => BonzoErrorKind::Other,
+        }
+    }
 }
 
-// TODO: implement!
 impl Error for BonzoError {
     fn description(&self) -> &str {
-        match self {
-            &BonzoError::Database(ref e) => e.description(),
-            _ => "",
+        match *self {
+            BonzoError::Database(ref e) => e.description(),
+            BonzoError::Io(ref e, _) => <io::Error as Error>::description(e),
+            BonzoError::Crypto(ref e) => e.description(),
+            BonzoError::Decompression(ref str) => str,
+            BonzoError::AlreadyInitialized => "Database file already exists",
+            BonzoError::WrongPassword => "Password is not the same as in database",
+            BonzoError::MissingBlock(ref str) => str,
+            BonzoError::CorruptBlock => "Block integrity check failed",
+            BonzoError::InvalidInput(ref str) => str,
+            BonzoError::Other(ref str) => str,
         }
     }
 
     fn cause(&self) -> Option<&Error> {
-        match self {
-            &BonzoError::Database(ref e) => Some(e),
+        match *self {
+            BonzoError::Database(ref e) => Some(e),
+            BonzoError::Io(ref e, _) => Some(e),
+            BonzoError::Crypto(ref e) => Some(e),
             _ => None,
         }
     }
@@ -66,7 +119,8 @@ impl fmt::Debug for BonzoError {
                                                       <io::Error as Error>::description(e),
                                                       e.to_string()),
             BonzoError::Crypto(ref e) => write!(f, "Crypto error: {}", e),
-            BonzoError::Other(ref str) => write!(f, "Error: {}", str),
+            BonzoError::Decompression(ref str) => write!(f, "Decompression error: {}", str),
+            _ => write!(f, "Error: {}", self.description()),
         }
     }
 }
@@ -87,3 +141,39 @@ macro_rules! try_io {
         }
     })
 }
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::io;
+
+    use crypto::CryptoError;
+    use super::{BonzoError, BonzoErrorKind};
+
+    #[test]
+    fn descriptions() {
+        let io_error = BonzoError::from(io::Error::new(io::ErrorKind::Other, "disk on fire"));
+
+        assert_eq!(BonzoErrorKind::Io, io_error.kind());
+        assert_eq!("disk on fire", io_error.description());
+        assert!(io_error.cause().is_some());
+
+        let crypto_error = BonzoError::from(CryptoError);
+
+        assert_eq!(BonzoErrorKind::Crypto, crypto_error.kind());
+        assert_eq!(CryptoError.description(), crypto_error.description());
+        assert!(crypto_error.cause().is_some());
+
+        let other_error = BonzoError::from_str("Block is empty");
+
+        assert_eq!(BonzoErrorKind::Other, other_error.kind());
+        assert_eq!("Block is empty", other_error.description());
+        assert!(other_error.cause().is_none());
+
+        let password_error = BonzoError::WrongPassword;
+
+        assert_eq!(BonzoErrorKind::WrongPassword, password_error.kind());
+        assert_eq!("Error: Password is not the same as in database",
+                   password_error.to_string());
+    }
+}
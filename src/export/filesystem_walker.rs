@@ -1,39 +1,195 @@
 use std::io;
 use std::path::{PathBuf, Path};
-use std::fs::read_dir;
-use std::borrow::ToOwned;
+use std::fs::{read_dir, read_link, canonicalize, metadata, File};
+use std::io::Read;
+use std::borrow::{Cow, ToOwned};
 use std::cmp::Ordering;
 use std::mem;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 
 use comm::spmc::bounded_fast as spmc;
+use comm::mpsc::bounded_fast as mpsc;
 use filetime::FileTime;
+use glob::Pattern;
 
 use ::itertools::Itertools;
-use database::Database;
-use Directory;
+use database::{Database, Aliases};
+use {Directory, epoch_milliseconds};
 use error::{BonzoResult, BonzoError};
+use crypto::{HashScheme, HashAlgorithm};
+use summary::SourceDiff;
+use options::SymlinkPolicy;
+use attributes::file_mode;
+use super::FileInstruction;
+use super::ignore::{IgnoreRule, IGNORE_FILENAME};
 
 pub struct FileInfo {
     pub path: PathBuf,
     pub modified: u64,
     pub filename: String,
     pub directory: Directory,
+    // Device and inode number of files with several hard links, when these
+    // are to be backed up only once
+    pub inode: Option<(u64, u64)>,
 }
 
 pub type FileInfoMessage = BonzoResult<FileInfo>;
 
+// Stands in for directories which a dry run finds but does not record in the
+// index. No directory has this id, so nothing is known about its contents.
+static UNRECORDED_DIRECTORY: i64 = -1;
+
+// Settings which determine which files are walked and in what order
+pub struct WalkOptions {
+    // Abort on unreadable directories instead of skipping them
+    pub strict: bool,
+    // Path of the last file completed by a previous run which timed out,
+    // along with the time that run started
+    pub cursor: Option<(PathBuf, u64)>,
+    // Send files which are not in the index yet before all others
+    pub new_files_first: bool,
+    // Absolute paths of directories which are skipped entirely
+    pub excluded: Vec<PathBuf>,
+    // Files and directories are skipped when either their name or their path
+    // relative to the source directory matches one of these
+    pub patterns: Vec<Pattern>,
+    // When not empty, only files whose name or relative path matches one of
+    // these are sent. Directories are walked regardless, and the patterns
+    // above take precedence.
+    pub include_patterns: Vec<Pattern>,
+    // Record directories without any files or subdirectories, so that they
+    // can be recreated on restore
+    pub empty_directories: bool,
+    // Whether symlinks are skipped, followed or stored as links
+    pub symlinks: SymlinkPolicy,
+    // Additional source directories, each walked into the top-level
+    // directory of the given name
+    pub roots: Vec<(String, PathBuf)>,
+    // Record the inodes of files with several hard links, so that the
+    // contents of every inode need only be read once
+    pub hardlinks: bool,
+    // Skip directories on another device than the source directory they are
+    // in, such as mount points of other filesystems
+    pub one_file_system: bool,
+    // Walk without recording anything in the index, such as new directories
+    // and deleted files
+    pub dry_run: bool,
+}
+
+impl WalkOptions {
+    pub fn new() -> WalkOptions {
+        WalkOptions {
+            strict: false,
+            cursor: None,
+            new_files_first: false,
+            excluded: Vec::new(),
+            patterns: Vec::new(),
+            include_patterns: Vec::new(),
+            empty_directories: true,
+            symlinks: SymlinkPolicy::Skip,
+            roots: Vec::new(),
+            hardlinks: false,
+            one_file_system: false,
+            dry_run: false,
+        }
+    }
+
+    // Whether the given directory is one of the excluded ones. These are
+    // compared to canonical paths.
+    fn is_excluded(&self, path: &Path) -> bool {
+        if self.excluded.is_empty() {
+            return false;
+        }
+
+        let absolute_path = canonicalize(path).unwrap_or(path.to_owned());
+
+        self.excluded.iter().any(|excluded| *excluded == absolute_path)
+    }
+
+    // Whether the given directory is to be skipped for being on another
+    // device than the given one, which is that of its source directory
+    fn is_other_device(&self, path: &Path, device: Option<u64>) -> bool {
+        self.one_file_system && device_of(path) != device
+    }
+
+    fn matches_pattern(&self, filename: &str, relative_path: &Path) -> bool {
+        self.patterns.iter().any(|pattern| {
+            pattern.matches(filename) || pattern.matches_path(relative_path)
+        })
+    }
+
+    fn is_included(&self, filename: &str, relative_path: &Path) -> bool {
+        self.include_patterns.is_empty() ||
+        self.include_patterns.iter().any(|pattern| {
+            pattern.matches(filename) || pattern.matches_path(relative_path)
+        })
+    }
+}
+
 struct FilePathExporter<'sender> {
     database: Database,
     channel: &'sender mut spmc::Producer<'static, FileInfoMessage>,
+    // Used to report directories which were skipped to the receiver
+    warning_channel: &'sender mpsc::Producer<'static, FileInstruction>,
+    options: WalkOptions,
+    // Canonical path of the source directory
+    root: PathBuf,
+    // Device of the source directory being walked
+    device: Cell<Option<u64>>,
+    // Whether the walk has passed the cursor
+    resumed: Cell<bool>,
+    // Files preceding the cursor, which are sent after all others
+    deferred: RefCell<Vec<FileInfo>>,
+    // Files which are in the index already, when new files go first. They
+    // are sent after the new files, but before those preceding the cursor.
+    known: RefCell<Vec<FileInfo>>,
+    // Inodes of the hard linked files sent so far
+    inodes: RefCell<HashSet<(u64, u64)>>,
+    // Further links to those inodes. They are sent last, so that the first
+    // link has most likely been backed up by the time they are exported.
+    links: RefCell<Vec<FileInfo>>,
+    // Further links preceding the cursor, which are sent after all others
+    deferred_links: RefCell<Vec<FileInfo>>,
+    // Directories recorded as empty which are nested below other directories,
+    // along with their ancestors from the grandparent up. Every ancestor is
+    // paired with the name of its child on the way down.
+    nested_empty: RefCell<Vec<(Directory, Vec<(Directory, String)>)>>,
 }
 
 impl<'sender> FilePathExporter<'sender> {
     // Recursively walks the given directory, processing all files within.
     // Deletes references to deleted files which were previously found from the
-    // database. Processes files in descending order of last mutation.
-    fn export_directory(&self, path: &Path, directory: Directory) -> BonzoResult<()> {
-        let content_iter = try!(newest_first_walker(path, false));
+    // database. Processes files in descending order of last mutation. The
+    // relative path is that of the directory relative to the source directory.
+    // The rules of ignore files in its ancestors are passed down. Returns
+    // whether the directory turned out to be empty, leaving aside anything
+    // which is excluded.
+    fn export_directory(&self,
+                        path: &Path,
+                        relative_path: &Path,
+                        directory: Directory,
+                        inherited_rules: &[IgnoreRule])
+                        -> BonzoResult<bool> {
+        let content_iter = match newest_first_walker(path, false, self.options.symlinks) {
+            Ok(iter) => iter,
+            Err(e) => return self.skip_directory(path, directory, e).map(|_| false),
+        };
+        let own_rules = try!(self.read_ignore_file(path, relative_path));
+        let rules = combine_rules(inherited_rules, own_rules);
         let mut deleted_filenames = try!(self.database.get_directory_filenames(directory));
+        let known_filenames = match self.options.new_files_first {
+            true => deleted_filenames.clone(),
+            false => HashSet::new(),
+        };
+        let mut empty_subdirectories = HashSet::new();
+        let mut subdirectory_names = HashSet::new();
+        let mut empty = true;
+
+        // the additional source directories live in the root of the index
+        if directory == Directory::Root {
+            subdirectory_names.extend(self.options.roots.iter().map(|&(ref name, _)| name.clone()));
+        }
 
         for item in content_iter {
             let (content_path, last_modified) = try!(item);
@@ -48,48 +204,362 @@ impl<'sender> FilePathExporter<'sender> {
                                         .ok_or(BonzoError::from_str("Could not convert \
                                                                      filename to string"))))
             };
+            let relative_content_path = relative_path.join(filename);
+            let is_link = self.options.symlinks == SymlinkPolicy::Store &&
+                          is_symlink(&content_path);
+            let is_directory = !is_link && content_path.is_dir();
+
+            if is_directory {
+                subdirectory_names.insert(filename.to_owned());
+            }
 
-            if content_path.is_dir() {
-                let child_directory = try!(self.database.get_directory(directory, filename));
+            // links to something within the source directory are not
+            // followed, as their target is backed up already
+            if self.options.symlinks == SymlinkPolicy::Follow && is_symlink(&content_path) &&
+               self.points_inside_root(&content_path) {
+                continue;
+            }
 
-                try!(self.export_directory(&content_path, child_directory));
+            // excluded files are left untouched in the index, so they are not
+            // considered deleted. The same goes for files which are not
+            // included.
+            if self.options.matches_pattern(filename, &relative_content_path) ||
+               rules.iter().any(|rule| rule.matches(&relative_content_path, is_directory)) ||
+               (!is_directory && !self.options.is_included(filename, &relative_content_path)) {
+                deleted_filenames.remove(filename);
                 continue;
             }
 
-            if directory != Directory::Root || filename != super::super::DATABASE_FILENAME {
+            if is_link {
                 deleted_filenames.remove(filename);
-                let owned_name = filename.to_string();
-
-                try!(
-                    self.channel.send_sync(Ok(FileInfo {
-                        path: content_path,
-                        modified: last_modified,
-                        filename: owned_name,
-                        directory: directory
-                    }))
-                    .map_err(|_| BonzoError::from_str("Failed sending file path"))
-                );
+                empty = false;
+
+                try!(self.store_symlink(directory, filename, &content_path));
+                continue;
             }
+
+            if is_directory {
+                // the contents of excluded directories are left untouched in
+                // the index, so they are not considered deleted. The same
+                // goes for mount points of other filesystems.
+                if self.options.is_excluded(&content_path) ||
+                   self.options.is_other_device(&content_path, self.device.get()) {
+                    continue;
+                }
+
+                let child_directory = try!(self.child_directory(directory, filename));
+                let child_empty = try!(self.export_directory(&content_path,
+                                                             &relative_content_path,
+                                                             child_directory,
+                                                             &rules));
+
+                if child_empty {
+                    empty_subdirectories.insert(child_directory);
+                }
+
+                empty = false;
+                continue;
+            }
+
+            // never back up our own working index
+            if content_path.as_path() != self.database.path() {
+                deleted_filenames.remove(filename);
+                empty = false;
+
+                let inode = match self.options.hardlinks {
+                    true => linked_inode(&content_path),
+                    false => None,
+                };
+                let info = FileInfo {
+                    path: content_path,
+                    modified: last_modified,
+                    filename: filename.to_string(),
+                    directory: directory,
+                    inode: inode,
+                };
+
+                // links are checked against the cursor too, as it may point
+                // at one
+                let deferred = self.is_deferred(&info);
+
+                if let Some(inode) = inode {
+                    if !self.inodes.borrow_mut().insert(inode) {
+                        match deferred {
+                            true => self.deferred_links.borrow_mut().push(info),
+                            false => self.links.borrow_mut().push(info),
+                        }
+
+                        continue;
+                    }
+                }
+
+                match deferred {
+                    true => self.deferred.borrow_mut().push(info),
+                    false if known_filenames.contains(filename) => {
+                        self.known.borrow_mut().push(info)
+                    }
+                    false => try!(self.send_file(info)),
+                }
+            }
+        }
+
+        if self.options.dry_run {
+            return Ok(empty);
+        }
+
+        try!(deleted_filenames.iter()
+                              .map(|filename| {
+                                  self.database
+                                      .persist_null_alias(directory, &filename)
+                                      .map_err(|e| BonzoError::Database(e))
+                              })
+                              .fold_results((), |_, _| ()));
+
+        if self.options.empty_directories {
+            try!(self.update_empty_subdirectories(directory, &empty_subdirectories));
+            try!(self.update_vanished_empty_directories(directory, &subdirectory_names));
+        }
+
+        Ok(empty)
+    }
+
+    // Walks the source directory into the root of the index, followed by the
+    // additional source directories, each into the top-level directory of its
+    // name
+    fn export_roots(&self, source_path: &Path, roots: &[(String, PathBuf)]) -> BonzoResult<()> {
+        if self.options.empty_directories && !self.options.dry_run {
+            *self.nested_empty.borrow_mut() = try!(self.nested_empty_directories());
         }
 
-        deleted_filenames.iter()
-                         .map(|filename| {
-                             self.database
-                                 .persist_null_alias(directory, &filename)
-                                 .map_err(|e| BonzoError::Database(e))
-                         })
-                         .fold_results((), |_, _| ())
+        self.device.set(device_of(source_path));
+        try!(self.export_directory(source_path, Path::new(""), Directory::Root, &[]));
+
+        for &(ref name, ref path) in roots.iter() {
+            let directory = try!(self.child_directory(Directory::Root, name));
+
+            self.device.set(device_of(path));
+            try!(self.export_directory(path, Path::new(name), directory, &[]));
+        }
+
+        Ok(())
+    }
+
+    // Looks up the directory of the given name in the index, adding it when
+    // it is not there yet. A dry run leaves the index alone.
+    fn child_directory(&self, parent: Directory, name: &str) -> BonzoResult<Directory> {
+        if !self.options.dry_run {
+            return self.database.get_directory(parent, name).map_err(From::from);
+        }
+
+        let directory = try!(self.database.find_directory(parent, name));
+
+        Ok(directory.unwrap_or(Directory::Child(UNRECORDED_DIRECTORY)))
+    }
+
+    // Records the target of a symlink, unless it is unchanged since the
+    // previous backup
+    fn store_symlink(&self, directory: Directory, filename: &str, path: &Path) -> BonzoResult<()> {
+        let target_path = try_io!(read_link(path), path);
+        let target = try!(target_path.to_str()
+                                     .ok_or(BonzoError::from_str("Could not convert symlink \
+                                                                  target to string")));
+
+        if self.options.dry_run ||
+           try!(self.database.get_symlink(directory, filename)) == Some(target.to_owned()) {
+            return Ok(());
+        }
+
+        self.database
+            .persist_symlink(directory, filename, target)
+            .map_err(From::from)
+    }
+
+    // Records the subdirectories which have become empty since the previous
+    // backup, and those which no longer are, either because they have gained
+    // contents or because they were removed
+    fn update_empty_subdirectories(&self,
+                                   directory: Directory,
+                                   empty_subdirectories: &HashSet<Directory>)
+                                   -> BonzoResult<()> {
+        let previously_empty = try!(self.database.get_empty_subdirectories(directory));
+
+        for subdirectory in empty_subdirectories.difference(&previously_empty) {
+            try!(self.database.persist_empty_directory(*subdirectory, true));
+        }
+
+        for subdirectory in previously_empty.difference(empty_subdirectories) {
+            try!(self.database.persist_empty_directory(*subdirectory, false));
+        }
+
+        Ok(())
+    }
+
+    // Returns the directories currently recorded as empty which are not in the
+    // root of the index, along with their ancestors as in nested_empty
+    fn nested_empty_directories(&self) -> BonzoResult<Vec<(Directory, Vec<(Directory, String)>)>> {
+        let mut nested = Vec::new();
+
+        for empty in try!(self.database.get_empty_directories_at(epoch_milliseconds())) {
+            let mut ancestors = Vec::new();
+            let mut current = try!(self.database.get_directory_parent(empty));
+
+            while current != Directory::Root {
+                let name = try!(self.database.get_directory_name(current));
+                let parent = try!(self.database.get_directory_parent(current));
+
+                ancestors.push((parent, name));
+                current = parent;
+            }
+
+            if !ancestors.is_empty() {
+                nested.push((empty, ancestors));
+            }
+        }
+
+        Ok(nested)
+    }
+
+    // Records that directories which were empty no longer are when a
+    // subdirectory of the given directory containing them was removed. The
+    // walk does not reach them, so their parents cannot tell.
+    fn update_vanished_empty_directories(&self,
+                                         directory: Directory,
+                                         subdirectory_names: &HashSet<String>)
+                                         -> BonzoResult<()> {
+        let mut nested = self.nested_empty.borrow_mut();
+        let mut remaining = Vec::new();
+
+        for (empty, ancestors) in mem::replace(&mut *nested, Vec::new()) {
+            let vanished = ancestors.iter().any(|&(ancestor, ref name)| {
+                ancestor == directory && !subdirectory_names.contains(name)
+            });
+
+            match vanished {
+                true => try!(self.database.persist_empty_directory(empty, false)),
+                false => remaining.push((empty, ancestors)),
+            }
+        }
+
+        *nested = remaining;
+
+        Ok(())
+    }
+
+    // Files up to the cursor have been processed by the previous run, so they
+    // are postponed until the rest of the tree has been walked. Files which
+    // were modified after the previous run started keep their priority.
+    fn is_deferred(&self, info: &FileInfo) -> bool {
+        match self.options.cursor {
+            Some((ref path, timestamp)) if !self.resumed.get() => {
+                if info.path == *path {
+                    self.resumed.set(true);
+                    return true;
+                }
+
+                info.modified < timestamp
+            }
+            _ => false,
+        }
+    }
+
+    fn points_inside_root(&self, path: &Path) -> bool {
+        canonicalize(path).map(|target| target.starts_with(&self.root)).unwrap_or(false)
+    }
+
+    // Reads the ignore file in the given directory, if there is one. Invalid
+    // patterns are reported to the receiver and skipped.
+    fn read_ignore_file(&self, path: &Path, relative_path: &Path) -> BonzoResult<Vec<IgnoreRule>> {
+        let (rules, invalid_patterns) = try!(read_ignore_rules(path, relative_path));
+
+        for pattern in invalid_patterns {
+            let warning = format!("Skipped invalid pattern {} in {}",
+                                  pattern,
+                                  path.join(IGNORE_FILENAME).display());
+
+            try!(self.warning_channel
+                     .send_sync(FileInstruction::Warning(warning))
+                     .map_err(|_| BonzoError::from_str("Failed sending warning")));
+        }
+
+        Ok(rules)
+    }
+
+    fn send_file(&self, info: FileInfo) -> BonzoResult<()> {
+        self.channel
+            .send_sync(Ok(info))
+            .map_err(|_| BonzoError::from_str("Failed sending file path"))
+    }
+
+    // Decides what to do when the contents of a directory could not be listed.
+    // Unless we are in strict mode, subdirectories we are not permitted to
+    // read are skipped and reported to the receiver. Their files are left
+    // untouched in the index, so they are not considered deleted.
+    fn skip_directory(&self,
+                      path: &Path,
+                      directory: Directory,
+                      error: BonzoError)
+                      -> BonzoResult<()> {
+        let permission_denied = match error {
+            BonzoError::Io(ref e, _) => e.kind() == io::ErrorKind::PermissionDenied,
+            _ => false,
+        };
+
+        if self.options.strict || !permission_denied || directory == Directory::Root {
+            return Err(error);
+        }
+
+        let warning = format!("Skipped unreadable directory {}", path.display());
+
+        self.warning_channel
+            .send_sync(FileInstruction::Warning(warning))
+            .map_err(|_| BonzoError::from_str("Failed sending warning"))
     }
 }
 
 // TODO: move this function and export_directory to own module
 pub fn send_files(source_path: &Path,
                   database: Database,
-                  mut channel: spmc::Producer<'static, FileInfoMessage>) {
+                  mut channel: spmc::Producer<'static, FileInfoMessage>,
+                  warning_channel: mpsc::Producer<'static, FileInstruction>,
+                  mut options: WalkOptions) {
+    // excluded paths are compared to canonical paths
+    options.excluded = options.excluded
+                              .into_iter()
+                              .map(|path| canonicalize(&path).unwrap_or(path))
+                              .collect();
+
+    let roots = mem::replace(&mut options.roots, Vec::new());
     let result = {
-        let exporter = FilePathExporter { database: database, channel: &mut channel };
+        let exporter = FilePathExporter {
+            database: database,
+            channel: &mut channel,
+            warning_channel: &warning_channel,
+            options: options,
+            root: canonicalize(source_path).unwrap_or(source_path.to_owned()),
+            device: Cell::new(None),
+            resumed: Cell::new(false),
+            deferred: RefCell::new(Vec::new()),
+            known: RefCell::new(Vec::new()),
+            inodes: RefCell::new(HashSet::new()),
+            links: RefCell::new(Vec::new()),
+            deferred_links: RefCell::new(Vec::new()),
+            nested_empty: RefCell::new(Vec::new()),
+        };
 
-        exporter.export_directory(source_path, Directory::Root)
+        exporter.export_roots(source_path, &roots).and_then(|_| {
+            let known = mem::replace(&mut *exporter.known.borrow_mut(), Vec::new());
+            let deferred = mem::replace(&mut *exporter.deferred.borrow_mut(), Vec::new());
+            let links = mem::replace(&mut *exporter.links.borrow_mut(), Vec::new());
+            let deferred_links = mem::replace(&mut *exporter.deferred_links.borrow_mut(),
+                                              Vec::new());
+
+            known.into_iter()
+                 .chain(deferred)
+                 .chain(links)
+                 .chain(deferred_links)
+                 .map(|info| exporter.send_file(info))
+                 .fold_results((), |_, _| ())
+        })
     };
 
     if let Err(e) = result {
@@ -97,16 +567,297 @@ pub fn send_files(source_path: &Path,
     }
 }
 
+// Estimates the number of files and bytes a backup will read, by walking the
+// source directories up front. Files whose modification time is recorded in
+// the index already are not counted, since they are not read again.
+// Unreadable directories and symlinks into the source directory are
+// disregarded, so the result is approximate.
+pub fn estimate_pending(source_path: &Path,
+                        database: &Database,
+                        options: &WalkOptions)
+                        -> BonzoResult<(u64, u64)> {
+    let canonical_options = WalkOptions {
+        excluded: options.excluded
+                         .iter()
+                         .map(|path| canonicalize(path).unwrap_or(path.clone()))
+                         .collect(),
+        patterns: options.patterns.clone(),
+        include_patterns: options.include_patterns.clone(),
+        symlinks: options.symlinks,
+        one_file_system: options.one_file_system,
+        ..WalkOptions::new()
+    };
+    let mut totals = (0, 0);
+
+    try!(estimate_directory(database,
+                            &canonical_options,
+                            source_path,
+                            Path::new(""),
+                            Some(Directory::Root),
+                            device_of(source_path),
+                            &[],
+                            &mut totals));
+
+    for &(ref name, ref path) in options.roots.iter() {
+        let directory = try!(database.find_directory(Directory::Root, name));
+
+        try!(estimate_directory(database,
+                                &canonical_options,
+                                path,
+                                Path::new(name),
+                                directory,
+                                device_of(path),
+                                &[],
+                                &mut totals));
+    }
+
+    Ok(totals)
+}
+
+// Adds the files and bytes pending in the given directory to the totals. The
+// directory is None when it is not in the index yet, in which case all of its
+// files are new. The device is that of the source directory it is in.
+fn estimate_directory(database: &Database,
+                      options: &WalkOptions,
+                      path: &Path,
+                      relative_path: &Path,
+                      directory: Option<Directory>,
+                      device: Option<u64>,
+                      inherited_rules: &[IgnoreRule],
+                      totals: &mut (u64, u64))
+                      -> BonzoResult<()> {
+    let content_iter = match newest_first_walker(path, false, options.symlinks) {
+        Ok(iter) => iter,
+        Err(..) => return Ok(()),
+    };
+    let (own_rules, _) = try!(read_ignore_rules(path, relative_path));
+    let rules = combine_rules(inherited_rules, own_rules);
+
+    for item in content_iter {
+        let (content_path, last_modified) = try!(item);
+        let filename = match content_path.file_name().and_then(|os_str| os_str.to_str()) {
+            Some(filename) => filename.to_owned(),
+            None => continue,
+        };
+        let relative_content_path = relative_path.join(&filename);
+        let is_directory = content_path.is_dir();
+
+        if options.matches_pattern(&filename, &relative_content_path) ||
+           (options.symlinks == SymlinkPolicy::Store && is_symlink(&content_path)) ||
+           content_path.as_path() == database.path() ||
+           rules.iter().any(|rule| rule.matches(&relative_content_path, is_directory)) {
+            continue;
+        }
+
+        if is_directory {
+            if options.is_excluded(&content_path) ||
+               options.is_other_device(&content_path, device) {
+                continue;
+            }
+
+            let child_directory = match directory {
+                Some(parent) => try!(database.find_directory(parent, &filename)),
+                None => None,
+            };
+
+            try!(estimate_directory(database,
+                                    options,
+                                    &content_path,
+                                    &relative_content_path,
+                                    child_directory,
+                                    device,
+                                    &rules,
+                                    totals));
+            continue;
+        }
+
+        if !options.is_included(&filename, &relative_content_path) {
+            continue;
+        }
+
+        let file_metadata = try_io!(metadata(&content_path), &content_path);
+        let known = match directory {
+            Some(parent) => {
+                try!(database.alias_known(parent,
+                                          &filename,
+                                          last_modified,
+                                          file_mode(&file_metadata)))
+            }
+            None => false,
+        };
+
+        if !known {
+            totals.0 += 1;
+            totals.1 += file_metadata.len();
+        }
+    }
+
+    Ok(())
+}
+
+// Compares the source directories with the state of the backup at the given
+// time. Files whose modification time is no newer than that of their alias
+// are taken to be unchanged. Others are hashed, so that files which were only
+// touched are not reported as modified. The working index is left out. Files
+// of roots which are not given are reported as deleted.
+pub fn diff_source(source_path: &Path,
+                   working_index: &Path,
+                   database: &Database,
+                   hash_algorithm: HashAlgorithm,
+                   timestamp: u64,
+                   options: &WalkOptions)
+                   -> BonzoResult<SourceDiff> {
+    let mut diff = SourceDiff::new();
+    let mut seen = HashSet::new();
+    let comparison = DiffComparison {
+        database: database,
+        working_index: working_index,
+        hash_algorithm: hash_algorithm,
+        timestamp: timestamp,
+        options: options,
+    };
+
+    try!(comparison.diff_directory(source_path,
+                                   Path::new(""),
+                                   Some(Directory::Root),
+                                   device_of(source_path),
+                                   &[],
+                                   &mut seen,
+                                   &mut diff));
+
+    for &(ref name, ref path) in options.roots.iter() {
+        let directory = try!(database.find_directory(Directory::Root, name));
+
+        try!(comparison.diff_directory(path,
+                                       Path::new(name),
+                                       directory,
+                                       device_of(path),
+                                       &[],
+                                       &mut seen,
+                                       &mut diff));
+    }
+
+    for alias in try!(Aliases::new(database, PathBuf::new(), Directory::Root, timestamp)) {
+        let (path, _, _) = try!(alias);
+
+        if !seen.contains(&path) {
+            diff.deleted.push(path);
+        }
+    }
+
+    diff.added.sort();
+    diff.modified.sort();
+    diff.deleted.sort();
+
+    Ok(diff)
+}
+
+struct DiffComparison<'a> {
+    database: &'a Database,
+    working_index: &'a Path,
+    hash_algorithm: HashAlgorithm,
+    timestamp: u64,
+    options: &'a WalkOptions,
+}
+
+impl<'a> DiffComparison<'a> {
+    // Adds the files in the given directory which differ from the backup to
+    // the diff, and all files compared to the set of those seen. The directory
+    // is None when it is not in the index, in which case all of its files are
+    // new. The device is that of the source directory it is in.
+    fn diff_directory(&self,
+                      path: &Path,
+                      relative_path: &Path,
+                      directory: Option<Directory>,
+                      device: Option<u64>,
+                      inherited_rules: &[IgnoreRule],
+                      seen: &mut HashSet<PathBuf>,
+                      diff: &mut SourceDiff)
+                      -> BonzoResult<()> {
+        let content_iter = match newest_first_walker(path, false, self.options.symlinks) {
+            Ok(iter) => iter,
+            Err(..) => return Ok(()),
+        };
+        let (own_rules, _) = try!(read_ignore_rules(path, relative_path));
+        let rules = combine_rules(inherited_rules, own_rules);
+
+        for item in content_iter {
+            let (content_path, last_modified) = try!(item);
+            let filename = match content_path.file_name().and_then(|os_str| os_str.to_str()) {
+                Some(filename) => filename.to_owned(),
+                None => continue,
+            };
+            let relative_content_path = relative_path.join(&filename);
+            let is_directory = content_path.is_dir();
+
+            if self.options.matches_pattern(&filename, &relative_content_path) ||
+               (self.options.symlinks == SymlinkPolicy::Store && is_symlink(&content_path)) ||
+               content_path.as_path() == self.working_index ||
+               rules.iter().any(|rule| rule.matches(&relative_content_path, is_directory)) {
+                continue;
+            }
+
+            if is_directory {
+                if self.options.is_excluded(&content_path) ||
+                   self.options.is_other_device(&content_path, device) {
+                    continue;
+                }
+
+                let child_directory = match directory {
+                    Some(parent) => try!(self.database.find_directory(parent, &filename)),
+                    None => None,
+                };
+
+                try!(self.diff_directory(&content_path,
+                                         &relative_content_path,
+                                         child_directory,
+                                         device,
+                                         &rules,
+                                         seen,
+                                         diff));
+                continue;
+            }
+
+            if !self.options.is_included(&filename, &relative_content_path) {
+                continue;
+            }
+
+            let backed_up = match directory {
+                Some(parent) => {
+                    try!(self.database.get_file_at(parent, &filename, self.timestamp))
+                }
+                None => None,
+            };
+
+            match backed_up {
+                None => diff.added.push(relative_content_path.clone()),
+                Some((_, ref attributes)) if attributes.modified >= Some(last_modified) => {}
+                Some((file_id, _)) => {
+                    let hash = try_io!(self.hash_algorithm.hash_file(&content_path),
+                                       &content_path);
+
+                    if try!(self.database.file_from_hash(&hash)) != Some(file_id) {
+                        diff.modified.push(relative_content_path.clone());
+                    }
+                }
+            }
+
+            seen.insert(relative_content_path);
+        }
+
+        Ok(())
+    }
+}
+
 // Walks the filesystem in an order that is defined by sort map, returning extra
 // information along with the paths. Is guaranteed to return directories before
 // their children
 pub struct FilesystemWalker<'a, T: 'static> {
-    root: PathBuf,
     cur: Vec<(PathBuf, T)>,
     file_map: &'a Fn(&Path) -> io::Result<T>,
     sort_map: &'a Fn(&(PathBuf, T), &(PathBuf, T)) -> Ordering,
     recursive: bool,
-    symlinks: bool,
+    symlinks: SymlinkPolicy,
 }
 
 impl<'a, T> Iterator for FilesystemWalker<'a, T> {
@@ -114,7 +865,8 @@ impl<'a, T> Iterator for FilesystemWalker<'a, T> {
 
     fn next(&mut self) -> Option<BonzoResult<(PathBuf, T)>> {
         self.cur.pop().map(|(path, extra)| {
-            if self.recursive && path.is_dir() {
+            if self.recursive && path.is_dir() &&
+               !(self.symlinks == SymlinkPolicy::Store && is_symlink(&path)) {
                 try!(self.read_dir_sorted(&path));
             }
 
@@ -123,24 +875,22 @@ impl<'a, T> Iterator for FilesystemWalker<'a, T> {
     }
 }
 
-// TODO: add tests for symlinks, non-symlinks case
 impl<'a, T> FilesystemWalker<'a, T> {
     pub fn new<F, S>(dir: &Path,
                      file_map: &'a F,
                      sort_map: &'a S,
                      recursive: bool,
-                     follow_symlinks: bool)
+                     symlinks: SymlinkPolicy)
                      -> BonzoResult<FilesystemWalker<'a, T>>
         where F: Fn(&Path) -> io::Result<T>,
               S: Fn(&(PathBuf, T), &(PathBuf, T)) -> Ordering
     {
         let mut walker = FilesystemWalker {
-            root: dir.to_owned(),
             cur: Vec::new(),
             file_map: file_map,
             sort_map: sort_map,
             recursive: recursive,
-            symlinks: follow_symlinks,
+            symlinks: symlinks,
         };
 
         try!(walker.read_dir_sorted(dir));
@@ -148,13 +898,27 @@ impl<'a, T> FilesystemWalker<'a, T> {
         Ok(walker)
     }
 
-    // filter out recursive symlinks or all symlinks, depending on
-    // settings
+    // filter out all symlinks, or only those which would lead us in circles,
+    // depending on settings. Stored symlinks are never traversed, so they are
+    // always accepted.
     fn is_accepted_path(&self, path: &Path) -> io::Result<bool> {
-        path.symlink_metadata().map(|meta| {
-            let is_symlink = meta.file_type().is_symlink();
+        let meta = try!(path.symlink_metadata());
+
+        if !meta.file_type().is_symlink() {
+            return Ok(true);
+        }
 
-            !is_symlink || self.symlinks && !path.starts_with(&self.root)
+        Ok(match self.symlinks {
+            SymlinkPolicy::Skip => false,
+            SymlinkPolicy::Store => true,
+            // dangling links are skipped, as are links to one of their own
+            // ancestors
+            SymlinkPolicy::Follow => {
+                match (canonicalize(path), path.parent().map(canonicalize)) {
+                    (Ok(target), Some(Ok(parent))) => !parent.starts_with(&target),
+                    _ => false,
+                }
+            }
         })
     }
 
@@ -189,8 +953,15 @@ fn newest_first(a: &(PathBuf, u64), b: &(PathBuf, u64)) -> Ordering {
     time_a.cmp(&time_b)
 }
 
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata().map(|meta| meta.file_type().is_symlink()).unwrap_or(false)
+}
+
+// Stored symlinks may be dangling, in which case the time of the link itself
+// is used
 fn modified_date(path: &Path) -> io::Result<u64> {
     path.metadata()
+        .or_else(|_| path.symlink_metadata())
         .map(|meta| FileTime::from_last_modification_time(&meta))
         .map(|filetime| {
             let millis = filetime.nanoseconds() as u64 / 1_000_000;
@@ -198,10 +969,82 @@ fn modified_date(path: &Path) -> io::Result<u64> {
         })
 }
 
+// Returns the device and inode number of the file at the given path when it
+// has more than one hard link
+#[cfg(unix)]
+fn linked_inode(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    match metadata(path) {
+        Ok(ref meta) if meta.nlink() > 1 => Some((meta.dev() as u64, meta.ino() as u64)),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn linked_inode(_: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// Returns the device the file at the given path is on. It is never known on
+// other platforms, so that every directory is taken to be on the same one.
+#[cfg(unix)]
+fn device_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata(path).ok().map(|meta| meta.dev() as u64)
+}
+
+#[cfg(not(unix))]
+fn device_of(_: &Path) -> Option<u64> {
+    None
+}
+
+// Parses the ignore file in the given directory, if there is one, into its
+// rules and the patterns which could not be parsed
+fn read_ignore_rules(path: &Path,
+                     relative_path: &Path)
+                     -> BonzoResult<(Vec<IgnoreRule>, Vec<String>)> {
+    let ignore_path = path.join(IGNORE_FILENAME);
+    let mut rules = Vec::new();
+    let mut invalid_patterns = Vec::new();
+
+    if !ignore_path.is_file() {
+        return Ok((rules, invalid_patterns));
+    }
+
+    let mut contents = String::new();
+    let depth = relative_path.components().count();
+
+    try_io!(File::open(&ignore_path).and_then(|mut file| file.read_to_string(&mut contents)),
+            &ignore_path);
+
+    for line in contents.lines() {
+        match IgnoreRule::parse(line, depth) {
+            Some(Ok(rule)) => rules.push(rule),
+            Some(Err(pattern)) => invalid_patterns.push(pattern),
+            None => {}
+        }
+    }
+
+    Ok((rules, invalid_patterns))
+}
+
+// Appends the rules of a directory's own ignore file to those of its parents
+fn combine_rules<'a>(inherited_rules: &'a [IgnoreRule],
+                     own_rules: Vec<IgnoreRule>)
+                     -> Cow<'a, [IgnoreRule]> {
+    match own_rules.is_empty() {
+        true => Cow::Borrowed(inherited_rules),
+        false => Cow::Owned(inherited_rules.iter().cloned().chain(own_rules).collect()),
+    }
+}
+
 pub fn newest_first_walker(dir: &Path,
-                           recursive: bool)
+                           recursive: bool,
+                           symlinks: SymlinkPolicy)
                            -> BonzoResult<FilesystemWalker<'static, u64>> {
-    FilesystemWalker::<u64>::new(dir, &FILE_MAP, &SORT_MAP, recursive, false)
+    FilesystemWalker::<u64>::new(dir, &FILE_MAP, &SORT_MAP, recursive, symlinks)
 }
 
 #[cfg(test)]
@@ -209,10 +1052,14 @@ mod test {
     use std::thread::sleep;
     use std::io::{self, Write};
     use std::path::Path;
-    use std::fs::{File, create_dir_all};
+    use std::fs::{File, create_dir_all, hard_link};
     use std::time::Duration;
 
     use super::super::super::tempdir::TempDir;
+    use comm::spmc::bounded_fast as spmc;
+    use comm::mpsc::bounded_fast as mpsc;
+    use filetime::{set_file_times, FileTime};
+    use options::SymlinkPolicy;
 
     fn write_to_disk(path: &Path, bytes: &[u8]) -> io::Result<()> {
         let mut file = try!(File::create(path));
@@ -262,7 +1109,8 @@ mod test {
             write_to_disk(&file_path, b"plswork").unwrap();
         }
 
-        let recursive_list = super::newest_first_walker(temp_dir.path(), true).unwrap();
+        let recursive_list = super::newest_first_walker(temp_dir.path(), true, SymlinkPolicy::Skip)
+                                 .unwrap();
 
         let all: Vec<String> = recursive_list.map(|x| {
                                                  let (path, _) = x.unwrap();
@@ -276,7 +1124,8 @@ mod test {
 
         assert_eq!(&["sub", "deadlast", "third", "second", "firstfile", "filezero"][..], &all[..]);
 
-        let flat_list = super::newest_first_walker(temp_dir.path(), false).unwrap();
+        let flat_list = super::newest_first_walker(temp_dir.path(), false, SymlinkPolicy::Skip)
+                            .unwrap();
 
         let directory: Vec<String> = flat_list.map(|x| {
                                                   let (path, _) = x.unwrap();
@@ -291,6 +1140,198 @@ mod test {
         assert_eq!(&["sub", "third", "second", "filezero"][..], &directory[..]);
     }
 
+    // Files up to the cursor are sent last, unless they were modified after the
+    // run which set the cursor started
+    #[test]
+    fn resume_order() {
+        let temp_dir = TempDir::new("resume-test").unwrap();
+        let dest_dir = TempDir::new("resume-dest").unwrap();
+        let crypto_scheme = ::crypto::AesEncrypter::new("password123");
+
+        ::init(&temp_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+
+        for name in ["a", "b", "c"].iter() {
+            write_to_disk(&temp_dir.path().join(name), name.as_bytes()).unwrap();
+            sleep(Duration::from_millis(50));
+        }
+
+        let cursor = (temp_dir.path().join("b"), ::epoch_milliseconds());
+
+        sleep(Duration::from_millis(50));
+        write_to_disk(&temp_dir.path().join("d"), b"d").unwrap();
+
+        let database_path = temp_dir.path().join(".backbonzo.db3");
+        let database = ::database::Database::from_file(database_path).unwrap();
+        let (path_transmitter, path_receiver) = unsafe { spmc::new(16) };
+        let (warning_transmitter, _warning_receiver) = unsafe { mpsc::new(16) };
+
+        super::send_files(temp_dir.path(),
+                          database,
+                          path_transmitter,
+                          warning_transmitter,
+                          super::WalkOptions {
+                              cursor: Some(cursor),
+                              ..super::WalkOptions::new()
+                          });
+
+        let mut order = Vec::new();
+
+        while let Ok(msg) = path_receiver.recv_sync() {
+            order.push(msg.ok().unwrap().filename);
+        }
+
+        assert_eq!(vec!["d", "a", "c", "b"], order);
+    }
+
+    // A cursor pointing at a further link to a file is found like any other
+    #[test]
+    fn resume_at_link() {
+        let temp_dir = TempDir::new("resume-link").unwrap();
+        let dest_dir = TempDir::new("resume-link-dest").unwrap();
+        let crypto_scheme = ::crypto::AesEncrypter::new("password123");
+
+        ::init(&temp_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+
+        for name in ["a", "b", "c"].iter() {
+            write_to_disk(&temp_dir.path().join(name), name.as_bytes()).unwrap();
+            sleep(Duration::from_millis(50));
+        }
+
+        hard_link(temp_dir.path().join("c"), temp_dir.path().join("l")).unwrap();
+
+        let database_path = temp_dir.path().join(".backbonzo.db3");
+
+        // whichever of the links is walked first, the other is a further link
+        for &cursor_name in ["c", "l"].iter() {
+            let cursor = (temp_dir.path().join(cursor_name), ::epoch_milliseconds());
+            let database = ::database::Database::from_file(database_path.clone()).unwrap();
+            let (path_transmitter, path_receiver) = unsafe { spmc::new(16) };
+            let (warning_transmitter, _warning_receiver) = unsafe { mpsc::new(16) };
+
+            super::send_files(temp_dir.path(),
+                              database,
+                              path_transmitter,
+                              warning_transmitter,
+                              super::WalkOptions {
+                                  cursor: Some(cursor),
+                                  hardlinks: true,
+                                  ..super::WalkOptions::new()
+                              });
+
+            let mut order = Vec::new();
+
+            while let Ok(msg) = path_receiver.recv_sync() {
+                order.push(msg.ok().unwrap().filename);
+            }
+
+            assert_eq!(4, order.len());
+            assert_eq!(&["b", "a"][..], &order[..2]);
+        }
+    }
+
+    // Further links to a hard linked file are sent after all other files,
+    // along with the inode of the first
+    #[test]
+    fn hardlinks_last() {
+        let temp_dir = TempDir::new("hardlink-walk").unwrap();
+        let dest_dir = TempDir::new("hardlink-walk-dest").unwrap();
+        let crypto_scheme = ::crypto::AesEncrypter::new("password123");
+
+        ::init(&temp_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+
+        write_to_disk(&temp_dir.path().join("a"), b"linked").unwrap();
+        hard_link(temp_dir.path().join("a"), temp_dir.path().join("z")).unwrap();
+        sleep(Duration::from_millis(50));
+        write_to_disk(&temp_dir.path().join("b"), b"single").unwrap();
+
+        let database_path = temp_dir.path().join(".backbonzo.db3");
+        let database = ::database::Database::from_file(database_path).unwrap();
+        let (path_transmitter, path_receiver) = unsafe { spmc::new(16) };
+        let (warning_transmitter, _warning_receiver) = unsafe { mpsc::new(16) };
+
+        super::send_files(temp_dir.path(),
+                          database,
+                          path_transmitter,
+                          warning_transmitter,
+                          super::WalkOptions { hardlinks: true, ..super::WalkOptions::new() });
+
+        let mut infos = Vec::new();
+
+        while let Ok(msg) = path_receiver.recv_sync() {
+            infos.push(msg.ok().unwrap());
+        }
+
+        assert_eq!(3, infos.len());
+        assert_eq!("b", infos[0].filename);
+        assert_eq!(None, infos[0].inode);
+        assert!(infos[1].inode.is_some());
+        assert_eq!(infos[1].inode, infos[2].inode);
+    }
+
+    // Directories are only left out for being on another device when staying
+    // on one filesystem
+    #[test]
+    fn other_devices() {
+        let temp_dir = TempDir::new("devices").unwrap();
+        let device = super::device_of(temp_dir.path());
+        let options = super::WalkOptions { one_file_system: true, ..super::WalkOptions::new() };
+
+        assert!(!options.is_other_device(temp_dir.path(), device));
+        assert!(!super::WalkOptions::new().is_other_device(temp_dir.path(), Some(!0)));
+
+        if cfg!(unix) {
+            assert!(options.is_other_device(temp_dir.path(), device.map(|id| id + 1)));
+        }
+    }
+
+    // Files which were never backed up are sent before those which were,
+    // regardless of their modification times
+    #[test]
+    fn new_files_first() {
+        let source_dir = TempDir::new("new-first-source").unwrap();
+        let dest_dir = TempDir::new("new-first-dest").unwrap();
+        let crypto_scheme = ::crypto::AesEncrypter::new("password123");
+        let time_limit = Duration::from_secs(30);
+
+        ::init(&source_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+        write_to_disk(&source_dir.path().join("known"), b"known").unwrap();
+        ::backup(source_dir.path(), &crypto_scheme, &::BackupOptions::new(1000, 0, time_limit))
+            .unwrap();
+
+        // the new file is older than the one backed up already
+        let fresh_path = source_dir.path().join("fresh");
+        let long_ago = FileTime::from_seconds_since_1970(1000000000, 0);
+
+        write_to_disk(&fresh_path, b"fresh").unwrap();
+        set_file_times(&fresh_path, long_ago, long_ago).unwrap();
+
+        for &(new_files_first, expected) in [(false, ["known", "fresh"]),
+                                             (true, ["fresh", "known"])]
+                                                .iter() {
+            let database_path = source_dir.path().join(".backbonzo.db3");
+            let database = ::database::Database::from_file(database_path).unwrap();
+            let (path_transmitter, path_receiver) = unsafe { spmc::new(16) };
+            let (warning_transmitter, _warning_receiver) = unsafe { mpsc::new(16) };
+
+            super::send_files(source_dir.path(),
+                              database,
+                              path_transmitter,
+                              warning_transmitter,
+                              super::WalkOptions {
+                                  new_files_first: new_files_first,
+                                  ..super::WalkOptions::new()
+                              });
+
+            let mut order = Vec::new();
+
+            while let Ok(msg) = path_receiver.recv_sync() {
+                order.push(msg.ok().unwrap().filename);
+            }
+
+            assert_eq!(&expected[..], &order[..]);
+        }
+    }
+
     #[cfg_attr(target_os = "linux", test)]
     fn check_loops() {
         use std::os::unix;
@@ -303,6 +1344,8 @@ mod test {
             Ok(..) => {}
         }
 
-        assert!(1 >= super::newest_first_walker(path, true).unwrap().count());
+        for policy in [SymlinkPolicy::Skip, SymlinkPolicy::Follow, SymlinkPolicy::Store].iter() {
+            assert!(1 >= super::newest_first_walker(path, true, *policy).unwrap().count());
+        }
     }
 }
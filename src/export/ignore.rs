@@ -0,0 +1,110 @@
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+
+// Name of the files listing patterns of paths to leave out of backups. The
+// patterns in such a file apply to the directory containing it and all of its
+// subdirectories.
+pub static IGNORE_FILENAME: &'static str = ".backbonzoignore";
+
+// A single pattern from an ignore file. Patterns starting with a slash are
+// anchored to the directory of the ignore file, others match at any depth
+// below it. A trailing slash restricts the pattern to directories.
+#[derive(Clone, Debug)]
+pub struct IgnoreRule {
+    pattern: Pattern,
+    // Number of components of the path of the ignore file's directory,
+    // relative to the source directory
+    depth: usize,
+    directory_only: bool,
+}
+
+impl IgnoreRule {
+    // Parses a line of an ignore file found at the given depth. Returns None
+    // for blank lines and comments, and the line itself when it is not a valid
+    // pattern.
+    pub fn parse(line: &str, depth: usize) -> Option<Result<IgnoreRule, String>> {
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() || trimmed.starts_with("#") {
+            return None;
+        }
+
+        let directory_only = trimmed.ends_with("/");
+        let without_slash = trimmed.trim_right_matches('/');
+        let glob = match without_slash.starts_with("/") {
+            true => without_slash[1..].to_owned(),
+            false => format!("**/{}", without_slash),
+        };
+
+        Some(Pattern::new(&glob)
+                 .map(|pattern| {
+                     IgnoreRule {
+                         pattern: pattern,
+                         depth: depth,
+                         directory_only: directory_only,
+                     }
+                 })
+                 .map_err(|_| trimmed.to_owned()))
+    }
+
+    // Checks whether the rule applies to the given path, which is relative to
+    // the source directory
+    pub fn matches(&self, relative_path: &Path, is_directory: bool) -> bool {
+        if self.directory_only && !is_directory {
+            return false;
+        }
+
+        let path_from_rule: PathBuf = relative_path.components()
+                                                   .skip(self.depth)
+                                                   .map(|component| component.as_os_str())
+                                                   .collect();
+
+        self.pattern.matches_path(&path_from_rule)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::IgnoreRule;
+
+    fn rule(line: &str, depth: usize) -> IgnoreRule {
+        IgnoreRule::parse(line, depth).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        assert!(IgnoreRule::parse("", 0).is_none());
+        assert!(IgnoreRule::parse("   ", 0).is_none());
+        assert!(IgnoreRule::parse("# comment", 0).is_none());
+        assert_eq!(Err("[".to_owned()), IgnoreRule::parse("[", 0).unwrap().map(|_| ()));
+    }
+
+    #[test]
+    fn anchoring() {
+        let unanchored = rule("*.tmp", 0);
+
+        assert!(unanchored.matches(Path::new("scratch.tmp"), false));
+        assert!(unanchored.matches(Path::new("a/b/scratch.tmp"), false));
+        assert!(!unanchored.matches(Path::new("a/b/scratch.txt"), false));
+
+        let anchored = rule("/build", 1);
+
+        assert!(anchored.matches(Path::new("project/build"), true));
+        assert!(!anchored.matches(Path::new("project/src/build"), true));
+
+        let nested = rule("build", 1);
+
+        assert!(nested.matches(Path::new("project/src/build"), true));
+    }
+
+    #[test]
+    fn directories_only() {
+        let directories = rule("cache/", 0);
+
+        assert!(directories.matches(Path::new("a/cache"), true));
+        assert!(!directories.matches(Path::new("a/cache"), false));
+    }
+}
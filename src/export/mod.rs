@@ -1,26 +1,29 @@
-extern crate num_cpus;
-
-use std::io::Read;
-use std::path::Path;
-use std::thread::spawn;
-use std::convert::From;
+use std::path::{Path, PathBuf};
+use std::thread::{spawn, JoinHandle};
+use std::fs::metadata;
 use std::borrow::ToOwned;
-
-use bzip2::Compress;
-use bzip2::reader::BzCompressor;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use Directory;
 use error::{BonzoResult, BonzoError};
 use database::Database;
-use crypto::{self, CryptoScheme};
-use file_chunks::file_chunks;
+use crypto::{CryptoScheme, HashScheme, HashAlgorithm, STREAMING_THRESHOLD,
+             MAX_ENCRYPTION_OVERHEAD};
+use compression::{compress_into, Compression, CompressionLevel, CompressReader};
+use attributes::{Attributes, file_mode, read_xattrs};
+use file_chunks::{file_chunks_with, Chunking};
 use comm::mpsc::bounded_fast as mpsc;
 use comm::spmc::bounded_fast as spmc;
 use BlockId;
 
 use self::filesystem_walker::{send_files, FileInfoMessage};
 
+pub use self::filesystem_walker::{WalkOptions, estimate_pending, diff_source};
+
 mod filesystem_walker;
+mod ignore;
 
 // The number of messages that should be buffered for the export thread. A large
 // buffer will take up lots of memory and make will make the exporter do more
@@ -31,8 +34,13 @@ static CHANNEL_BUFFER_SIZE: usize = 16;
 
 // Specification of messsages sent over the channel
 pub enum FileInstruction {
+    // Sent before the blocks of a file whose contents are not yet known
+    Started(PathBuf),
     NewBlock(FileBlock),
     Complete(FileComplete),
+    Warning(String),
+    // A file was left out because it exceeds the maximum file size
+    Skipped(PathBuf),
     Error(BonzoError),
 }
 
@@ -55,11 +63,20 @@ pub enum BlockReference {
 // the receiver's responsibility to persist the file to the index.
 #[derive(Debug)]
 pub struct FileComplete {
+    pub path: PathBuf,
     pub filename: String,
     pub hash: Vec<u8>,
-    pub last_modified: u64,
+    pub attributes: Attributes,
+    // Names and values of the extended attributes of the file
+    pub xattrs: Vec<(String, Vec<u8>)>,
     pub directory: Directory,
     pub block_reference_list: Vec<BlockReference>,
+    // Number of bytes read from the file
+    pub size: u64,
+    // Blocks of the file which were in the index already, and the number of
+    // bytes they were read from
+    pub deduplicated_blocks: u64,
+    pub deduplicated_bytes: u64,
 }
 
 // Manager which walks the file system and prepares files for backup. This
@@ -72,9 +89,29 @@ pub struct ExportBlockSender<'sender, C>
 {
     database: Database,
     crypto_scheme: Box<C>,
+    compression: Compression,
+    compression_level: CompressionLevel,
+    hash_algorithm: HashAlgorithm,
     block_size: usize,
+    chunking: Chunking,
+    // Files larger than this number of bytes are not backed up
+    max_file_size: Option<u64>,
+    // Files last modified before or after these times, in milliseconds since
+    // the epoch, are not backed up
+    modified_after: Option<u64>,
+    modified_before: Option<u64>,
+    // Record the extended attributes of every file
+    xattrs: bool,
+    // Leave the index alone, even for files whose contents are known
+    dry_run: bool,
     path_receiver: spmc::Consumer<'static, FileInfoMessage>,
     sender: &'sender mut mpsc::Producer<'static, FileInstruction>,
+    // Holds the compressed form of the block being processed. Every worker
+    // thread has its own, which keeps its capacity from block to block.
+    compression_buffer: RefCell<Vec<u8>>,
+    // Hashes of the contents of hard linked files, by device and inode
+    // number, shared by all worker threads
+    inode_hashes: Arc<Mutex<HashMap<(u64, u64), Vec<u8>>>>,
 }
 
 impl<'sender, C: CryptoScheme> ExportBlockSender<'sender, C> {
@@ -82,7 +119,11 @@ impl<'sender, C: CryptoScheme> ExportBlockSender<'sender, C> {
         while let Ok(msg) = self.path_receiver.recv_sync() {
             let info = try!(msg);
 
-            try!(self.export_file(info.directory, &info.path, info.filename, info.modified));
+            try!(self.export_file(info.directory,
+                                  &info.path,
+                                  info.filename,
+                                  info.modified,
+                                  info.inode));
         }
 
         Ok(())
@@ -93,44 +134,107 @@ impl<'sender, C: CryptoScheme> ExportBlockSender<'sender, C> {
     // is created. For unknown files, its (compressed and encrypted) blocks are
     // sent over the channel. When all blocks are transmitted, a FileComplete
     // message is sent, so the receiver can persist the file to the
-    // database.
+    // database. Further hard links to a file which has been hashed before are
+    // not hashed again, and not read at all once the first link has been
+    // persisted.
     fn export_file(&self,
                    directory: Directory,
                    path: &Path,
                    filename: String,
-                   last_modified: u64)
+                   last_modified: u64,
+                   inode: Option<(u64, u64)>)
                    -> BonzoResult<()> {
-        if try!(self.database.alias_known(directory, &filename, last_modified)) {
+        // oversized files are ignored altogether, so no null alias is recorded
+        if let Some(limit) = self.max_file_size {
+            if try_io!(metadata(path), path).len() > limit {
+                return self.sender
+                           .send_sync(FileInstruction::Skipped(path.to_owned()))
+                           .map_err(|_| BonzoError::from_str("Failed sending skipped file"));
+            }
+        }
+
+        // files outside the window of modification times are ignored in the
+        // same way, but not reported
+        if self.modified_after.map(|after| last_modified < after).unwrap_or(false) ||
+           self.modified_before.map(|before| last_modified > before).unwrap_or(false) {
+            return Ok(());
+        }
+
+        let attributes = Attributes {
+            modified: Some(last_modified),
+            mode: file_mode(&try_io!(metadata(path), path)),
+        };
+
+        if try!(self.database.alias_known(directory, &filename, last_modified, attributes.mode)) {
             return Ok(());
         }
 
-        let hash = try_io!(crypto::hash_file(path), path);
+        // extended attributes are only read for new versions, so changing
+        // nothing but them does not yield a new version
+        let xattrs = match self.xattrs {
+            true => try_io!(read_xattrs(path), path),
+            false => Vec::new(),
+        };
+        let known_hash = inode.and_then(|inode| {
+            self.inode_hashes.lock().unwrap().get(&inode).cloned()
+        });
+        let hash = match known_hash {
+            Some(hash) => hash,
+            None => try_io!(self.hash_algorithm.hash_file(path), path),
+        };
+
+        if let Some(inode) = inode {
+            self.inode_hashes.lock().unwrap().insert(inode, hash.clone());
+        }
 
         if let Some(file_id) = try!(self.database.file_from_hash(&hash)) {
-            let result = self.database.persist_alias(directory,
-                                                     Some(file_id),
-                                                     &filename,
-                                                     Some(last_modified));
+            if self.dry_run {
+                return Ok(());
+            }
+
+            let result = self.database.persist_alias_with_xattrs(directory,
+                                                                 Some(file_id),
+                                                                 &filename,
+                                                                 attributes,
+                                                                 &xattrs);
             return Ok(try!(result));
         }
 
-        let mut chunks = try_io!(file_chunks(path, self.block_size), path);
+        try!(self.sender
+                 .send_sync(FileInstruction::Started(path.to_owned()))
+                 .map_err(|_| BonzoError::from_str("Failed sending file start")));
+
+        let mut chunks = try_io!(file_chunks_with(path, self.block_size, self.chunking), path);
         let mut block_reference_list = Vec::new();
+        let mut size = 0;
+        let mut deduplicated_blocks = 0;
+        let mut deduplicated_bytes = 0;
 
         // TODO: we can make this into a map, just have to implement it on chunks
         while let Some(slice) = chunks.next() {
             let unwrapped_slice = try_io!(slice, path);
             let block_reference = try!(self.export_block(unwrapped_slice));
 
+            if let BlockReference::ById(..) = block_reference {
+                deduplicated_blocks += 1;
+                deduplicated_bytes += unwrapped_slice.len() as u64;
+            }
+
+            size += unwrapped_slice.len() as u64;
             block_reference_list.push(block_reference);
         }
 
         try!(self.sender.send_sync(FileInstruction::Complete(FileComplete {
+            path: path.to_owned(),
             filename: filename,
             hash: hash,
-            last_modified: last_modified,
+            attributes: attributes,
+            xattrs: xattrs,
             directory: directory,
-            block_reference_list: block_reference_list
+            block_reference_list: block_reference_list,
+            size: size,
+            deduplicated_blocks: deduplicated_blocks,
+            deduplicated_bytes: deduplicated_bytes,
         })).map_err(|_| BonzoError::from_str("Failed sending file")));
 
         Ok(())
@@ -140,13 +244,18 @@ impl<'sender, C: CryptoScheme> ExportBlockSender<'sender, C> {
     // Otherwise, it compresses and encrypts a block and sends the result on
     // the channel to be processed.
     pub fn export_block(&self, block: &[u8]) -> BonzoResult<BlockReference> {
-        let hash = crypto::hash_block(block);
+        let hash = self.hash_algorithm.hash_block(block);
 
         if let Some(id) = try!(self.database.block_id_from_hash(&hash)) {
             return Ok(BlockReference::ById(id))
         }
 
-        let processed_bytes = try!(process_block(block, &*self.crypto_scheme));
+        let mut buffer = self.compression_buffer.borrow_mut();
+        let processed_bytes = try!(process_block_with(block,
+                                                      self.compression,
+                                                      self.compression_level,
+                                                      &*self.crypto_scheme,
+                                                      &mut buffer));
 
         try!(self.sender.send_sync(FileInstruction::NewBlock(FileBlock {
             bytes: processed_bytes,
@@ -159,50 +268,117 @@ impl<'sender, C: CryptoScheme> ExportBlockSender<'sender, C> {
 }
 
 pub fn process_block<C: CryptoScheme>(clear_text: &[u8],
+                                      compression: Compression,
                                       crypto_scheme: &C)
                                       -> BonzoResult<Vec<u8>> {
-    let mut compressor = BzCompressor::new(clear_text, Compress::Best);
-    let mut buffer = Vec::new();
-    try!(compressor.read_to_end(&mut buffer));
+    process_block_with(clear_text,
+                       compression,
+                       CompressionLevel::Best,
+                       crypto_scheme,
+                       &mut Vec::new())
+}
+
+// Compresses at the given level into the given buffer, which may be reused
+// for the next block. Only the encrypted block, which is handed to the
+// receiver, is allocated anew, at its final size. Large blocks are compressed
+// straight into the cipher when it can encrypt a buffer at a time, so that
+// no compressed copy of them is kept.
+pub fn process_block_with<C: CryptoScheme>(clear_text: &[u8],
+                                           compression: Compression,
+                                           level: CompressionLevel,
+                                           crypto_scheme: &C,
+                                           buffer: &mut Vec<u8>)
+                                           -> BonzoResult<Vec<u8>> {
+    let mut result = Vec::new();
+
+    if !crypto_scheme.streams() || clear_text.len() <= STREAMING_THRESHOLD {
+        try!(compress_into(clear_text, compression, level, buffer));
+        try!(crypto_scheme.encrypt_block_into(buffer, &mut result));
+
+        return Ok(result);
+    }
+
+    result.reserve(clear_text.len() + MAX_ENCRYPTION_OVERHEAD);
+
+    let mut compressed = CompressReader::new(clear_text, compression, level);
+
+    match crypto_scheme.encrypt_stream(&mut compressed, &mut result) {
+        Err(..) if compressed.expanded() => {
+            let mut stored = CompressReader::new(clear_text, Compression::Stored, level);
+
+            result.clear();
+            try!(crypto_scheme.encrypt_stream(&mut stored, &mut result));
+        }
+        encrypted => try!(encrypted),
+    }
 
-    crypto_scheme.encrypt_block(&buffer).map_err(From::from)
+    Ok(result)
 }
 
 // Starts a new thread in which the given source path is recursively walked
-// and backed up. Returns a receiver to which new processed blocks and files
-// will be sent.
+// and backed up, and the given number of threads which compress and encrypt
+// the files found. Returns a receiver to which new processed blocks and files
+// will be sent, along with the handles of all threads. A thread which panics
+// sends nothing, so the handles are the only way to find out.
 pub fn start_export_thread<C>(database: &Database,
                               crypto_scheme: &C,
+                              compression: Compression,
+                              compression_level: CompressionLevel,
+                              hash_algorithm: HashAlgorithm,
                               block_size: usize,
-                              source_path: &Path)
-                              -> BonzoResult<mpsc::Consumer<'static, FileInstruction>>
+                              chunking: Chunking,
+                              max_file_size: Option<u64>,
+                              modified_after: Option<u64>,
+                              modified_before: Option<u64>,
+                              xattrs: bool,
+                              dry_run: bool,
+                              worker_count: usize,
+                              source_path: &Path,
+                              walk_options: WalkOptions)
+                              -> BonzoResult<(mpsc::Consumer<'static, FileInstruction>,
+                                              Vec<JoinHandle<()>>)>
     where C: CryptoScheme + 'static
 {
     let (block_transmitter, block_receiver) = unsafe { mpsc::new(CHANNEL_BUFFER_SIZE) };
     let (path_transmitter, path_receiver) = unsafe { spmc::new(CHANNEL_BUFFER_SIZE) };
     let sender_database = try!(database.try_clone());
     let path = source_path.to_owned();
+    let warning_transmitter = block_transmitter.clone();
+    let inode_hashes = Arc::new(Mutex::new(HashMap::new()));
+    let mut workers = Vec::with_capacity(worker_count + 1);
 
     // spawn thread that sends file paths
-    spawn(move || {
-        send_files(&path, sender_database, path_transmitter);
-    });
+    workers.push(spawn(move || {
+        send_files(&path, sender_database, path_transmitter, warning_transmitter, walk_options);
+    }));
 
     // spawn encoder threads
-    for _ in 0..self::num_cpus::get() {
+    for _ in 0..worker_count {
         let mut transmitter = block_transmitter.clone();
         let new_database = try!(database.try_clone());
         let receiver = path_receiver.clone();
         let scheme = Box::new(*crypto_scheme);
+        let shared_hashes = inode_hashes.clone();
 
-        spawn(move || {
+        workers.push(spawn(move || {
             let result = {
                 let exporter = ExportBlockSender {
                     database: new_database,
                     crypto_scheme: scheme,
+                    compression: compression,
+                    compression_level: compression_level,
+                    hash_algorithm: hash_algorithm,
                     block_size: block_size,
+                    chunking: chunking,
+                    max_file_size: max_file_size,
+                    modified_after: modified_after,
+                    modified_before: modified_before,
+                    xattrs: xattrs,
+                    dry_run: dry_run,
                     path_receiver: receiver,
                     sender: &mut transmitter,
+                    compression_buffer: RefCell::new(Vec::new()),
+                    inode_hashes: shared_hashes,
                 };
 
                 exporter.listen_for_paths()
@@ -211,23 +387,37 @@ pub fn start_export_thread<C>(database: &Database,
             if let Err(e) = result {
                 let _ = transmitter.send_sync(FileInstruction::Error(e));
             }
-        });
+        }));
     }
 
-    Ok(block_receiver)
+    Ok((block_receiver, workers))
 }
 
 #[cfg(test)]
 mod test {
+    use std::iter::repeat;
     use std::thread::sleep;
     use std::time::Duration;
 
     use tempdir::TempDir;
+    use test::Bencher;
     use write_to_disk;
+    use compression::{Compression, CompressionLevel};
+    use crypto::{AesEncrypter, HashAlgorithm};
+    use file_chunks::Chunking;
+    use super::{process_block, process_block_with};
+
+    // Many small blocks, as produced by a tree of small files
+    fn small_blocks() -> Vec<Vec<u8>> {
+        (0..64)
+            .map(|i| repeat(format!("line {}\n", i)).take(400).collect::<String>().into_bytes())
+            .collect()
+    }
 
     #[test]
     fn channel_buffer() {
         let temp_dir = TempDir::new("buffer-test").unwrap();
+        let dest_dir = TempDir::new("buffer-dest").unwrap();
 
         let file_count = 3 * super::CHANNEL_BUFFER_SIZE;
 
@@ -242,22 +432,33 @@ mod test {
         let database_path = temp_dir.path().join(".backbonzo.db3");
         let crypto_scheme = ::crypto::AesEncrypter::new(password);
 
-        ::init(&temp_dir.path(), &temp_dir.path(), &crypto_scheme).unwrap();
+        ::init(&temp_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
 
         let database = ::database::Database::from_file(database_path).unwrap();
-        let receiver = super::start_export_thread(&database,
-                                                  &crypto_scheme,
-                                                  10000000,
-                                                  temp_dir.path())
-                           .unwrap();
+        let (receiver, workers) = super::start_export_thread(&database,
+                                                             &crypto_scheme,
+                                                             Compression::Bzip2,
+                                                             CompressionLevel::Best,
+                                                             HashAlgorithm::Sha256,
+                                                             10000000,
+                                                             Chunking::Fixed,
+                                                             None,
+                                                             None,
+                                                             None,
+                                                             false,
+                                                             false,
+                                                             2,
+                                                             temp_dir.path(),
+                                                             super::WalkOptions::new())
+                                      .unwrap();
 
         // give the export thread plenty of time to process all files
         sleep(Duration::from_millis(200));
 
-        // we should receive two messages for each file: one for its block and
-        // one for the file completion.
-        // One for each finished thread
-        let expected_message_count = 2 * file_count;
+        // we should receive three messages for each file: one for its start,
+        // one for its block and one for the file completion, regardless of the
+        // number of workers.
+        let expected_message_count = 3 * file_count;
 
         let mut count = 0;
 
@@ -270,5 +471,41 @@ mod test {
         }
 
         assert_eq!(expected_message_count, count);
+
+        for worker in workers {
+            assert!(worker.join().is_ok());
+        }
+    }
+
+    // Compare with reused_buffers to see the effect of keeping the
+    // compression buffer between blocks
+    #[bench]
+    fn fresh_buffers(b: &mut Bencher) {
+        let crypto_scheme = AesEncrypter::new("password123");
+        let blocks = small_blocks();
+
+        b.iter(|| {
+            for block in blocks.iter() {
+                process_block(block, Compression::Gzip, &crypto_scheme).unwrap();
+            }
+        });
+    }
+
+    #[bench]
+    fn reused_buffers(b: &mut Bencher) {
+        let crypto_scheme = AesEncrypter::new("password123");
+        let blocks = small_blocks();
+        let mut buffer = Vec::new();
+
+        b.iter(|| {
+            for block in blocks.iter() {
+                process_block_with(block,
+                                   Compression::Gzip,
+                                   CompressionLevel::Best,
+                                   &crypto_scheme,
+                                   &mut buffer)
+                    .unwrap();
+            }
+        });
     }
 }
@@ -2,29 +2,228 @@ use std::io::{self, Read};
 use std::fs::File;
 use std::path::Path;
 
+// The largest chunk size we are willing to allocate a buffer for. Every
+// encoder thread keeps a buffer of this size around, along with the compressed
+// and encrypted versions of its contents.
+pub static MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+// Number of bytes the rolling hash of the content-defined chunker is computed
+// over. Chunk boundaries only depend on this many preceding bytes, which is
+// what allows them to realign after an insertion or deletion.
+static HASH_WINDOW: usize = 48;
+
+// Seed for the table of the rolling hash. Changing it moves every chunk
+// boundary, which would make new blocks of existing backups deduplicate
+// poorly against the old ones.
+static HASH_TABLE_SEED: u64 = 0x62_61_63_6b_62_6f_6e_7a;
+
+// Determines where files are split into blocks. With Fixed, every block but
+// the last has the block size given for the backup run. With ContentDefined,
+// blocks end wherever the rolling hash of the last few bytes matches a
+// pattern, so that an insertion early in a file only changes the blocks
+// around it rather than every block after it. The sizes are in bytes; the
+// average is only approximated, but no block ever exceeds the maximum.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Chunking {
+    Fixed,
+    ContentDefined {
+        min: usize,
+        average: usize,
+        max: usize,
+    },
+}
+
+impl Chunking {
+    // Representation under which the chunking is recorded in the index
+    pub fn encode(&self) -> String {
+        match *self {
+            Chunking::Fixed => "fixed".to_owned(),
+            Chunking::ContentDefined { min, average, max } => {
+                format!("content-defined {} {} {}", min, average, max)
+            }
+        }
+    }
+
+    // Inverse of encode. Errs on unknown representations and on sizes which
+    // check rejects, as a damaged index could hold either.
+    pub fn decode(encoded: &str) -> io::Result<Chunking> {
+        let words: Vec<&str> = encoded.split(' ').collect();
+        let unsupported = || {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("Unsupported chunking: {}", encoded))
+        };
+
+        if words == ["fixed"] {
+            return Ok(Chunking::Fixed);
+        }
+
+        if words.len() != 4 || words[0] != "content-defined" {
+            return Err(unsupported());
+        }
+
+        let chunking = match (words[1].parse(), words[2].parse(), words[3].parse()) {
+            (Ok(min), Ok(average), Ok(max)) => {
+                Chunking::ContentDefined { min: min, average: average, max: max }
+            }
+            _ => return Err(unsupported()),
+        };
+
+        try!(chunking.check());
+
+        Ok(chunking)
+    }
+
+    // Errs when the sizes of content-defined chunks are out of order or
+    // outside of the range of acceptable block sizes
+    pub fn check(&self) -> io::Result<()> {
+        if let Chunking::ContentDefined { min, average, max } = *self {
+            try!(check_chunk_size(max));
+
+            if min < HASH_WINDOW || min > average || average > max {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("Block sizes must satisfy {} <= minimum <= \
+                                                   average <= maximum, got {}, {} and {}",
+                                                  HASH_WINDOW,
+                                                  min,
+                                                  average,
+                                                  max)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 // Semi-iterator which reads a file one block at a time. Is not a proper
 // Iterator because we only keep one block in memory at a time.
 pub struct Chunks<R> {
     file: R,
     buffer: Vec<u8>,
+    chunking: Chunking,
+    // Content-defined chunking reads ahead of the block it returns. These are
+    // the number of bytes in the buffer and the number of those handed out
+    // by the last call to next.
+    filled: usize,
+    consumed: usize,
+    table: Vec<u64>,
 }
 
 impl<R: Read> Chunks<R> {
-    pub fn new(reader: R, chunk_size: usize) -> Chunks<R> {
-        Chunks { file: reader, buffer: vec![0; chunk_size] }
+    pub fn new(reader: R, chunk_size: usize) -> io::Result<Chunks<R>> {
+        Chunks::with_chunking(reader, chunk_size, Chunking::Fixed)
+    }
+
+    // The chunk size is ignored for content-defined chunking
+    pub fn with_chunking(reader: R,
+                         chunk_size: usize,
+                         chunking: Chunking)
+                         -> io::Result<Chunks<R>> {
+        let (buffer_size, table) = match chunking {
+            Chunking::Fixed => {
+                try!(check_chunk_size(chunk_size));
+                (chunk_size, Vec::new())
+            }
+            Chunking::ContentDefined { max, .. } => {
+                try!(chunking.check());
+                (max, hash_table())
+            }
+        };
+
+        Ok(Chunks {
+            file: reader,
+            buffer: vec![0; buffer_size],
+            chunking: chunking,
+            filled: 0,
+            consumed: 0,
+            table: table,
+        })
     }
 
     pub fn next(&mut self) -> Option<io::Result<&[u8]>> {
-        match self.file.read(&mut self.buffer[..]) {
-            Ok(0) => None,
-            Ok(bytes) => Some(Ok(&self.buffer[0..bytes])),
-            Err(e) => Some(Err(e)),
+        let (min, average) = match self.chunking {
+            Chunking::Fixed => {
+                return match self.file.read(&mut self.buffer[..]) {
+                    Ok(0) => None,
+                    Ok(bytes) => Some(Ok(&self.buffer[0..bytes])),
+                    Err(e) => Some(Err(e)),
+                };
+            }
+            Chunking::ContentDefined { min, average, .. } => (min, average),
+        };
+
+        // move the bytes read ahead to the front of the buffer
+        for i in self.consumed..self.filled {
+            self.buffer[i - self.consumed] = self.buffer[i];
+        }
+
+        self.filled -= self.consumed;
+        self.consumed = 0;
+
+        while self.filled < self.buffer.len() {
+            match self.file.read(&mut self.buffer[self.filled..]) {
+                Ok(0) => break,
+                Ok(bytes) => self.filled += bytes,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        if self.filled == 0 {
+            return None;
+        }
+
+        self.consumed = find_boundary(&self.buffer[..self.filled], min, average, &self.table);
+
+        Some(Ok(&self.buffer[..self.consumed]))
+    }
+}
+
+// Returns the length of the first content-defined chunk of the given bytes,
+// which is the whole slice when no boundary is found. Boundaries are placed
+// where the low bits of a buzhash over the last HASH_WINDOW bytes are all
+// zero. The number of bits is chosen so that, past the minimum size, a
+// boundary is expected once every `average` bytes.
+fn find_boundary(bytes: &[u8], min: usize, average: usize, table: &[u64]) -> usize {
+    if bytes.len() <= min {
+        return bytes.len();
+    }
+
+    let mask = (average.next_power_of_two() - 1) as u64;
+    // the hash of a position only depends on the window before it, so there
+    // is no need to hash anything further back
+    let start = min - HASH_WINDOW;
+    let mut hash = 0u64;
+
+    for i in start..bytes.len() {
+        hash = hash.rotate_left(1) ^ table[bytes[i] as usize];
+
+        if i >= start + HASH_WINDOW {
+            hash ^= table[bytes[i - HASH_WINDOW] as usize].rotate_left(HASH_WINDOW as u32);
+        }
+
+        if i + 1 >= min && hash & mask == 0 {
+            return i + 1;
         }
     }
+
+    bytes.len()
+}
+
+// Generates the pseudo-random values the rolling hash maps bytes to, using a
+// xorshift generator so that the table is the same on every machine
+fn hash_table() -> Vec<u64> {
+    let mut state = HASH_TABLE_SEED;
+
+    (0..256).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }).collect()
 }
 
 pub trait Chunk: Read + Sized {
-    fn chunks(self, chunk_size: usize) -> Chunks<Self> {
+    fn chunks(self, chunk_size: usize) -> io::Result<Chunks<Self>> {
         Chunks::new(self, chunk_size)
     }
 }
@@ -32,7 +231,27 @@ pub trait Chunk: Read + Sized {
 impl<T: Read> Chunk for T {}
 
 pub fn file_chunks(path: &Path, chunk_size: usize) -> io::Result<Chunks<File>> {
-    File::open(&path).map(|file| file.chunks(chunk_size))
+    file_chunks_with(path, chunk_size, Chunking::Fixed)
+}
+
+pub fn file_chunks_with(path: &Path,
+                        chunk_size: usize,
+                        chunking: Chunking)
+                        -> io::Result<Chunks<File>> {
+    File::open(&path).and_then(|file| Chunks::with_chunking(file, chunk_size, chunking))
+}
+
+// Errs for chunk sizes we cannot sensibly work with. A chunk size of zero would
+// make every file look empty, while a huge one may exhaust memory.
+pub fn check_chunk_size(chunk_size: usize) -> io::Result<()> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("Block size of {} bytes is not between 1 and {} bytes",
+                                          chunk_size,
+                                          MAX_CHUNK_SIZE)));
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -40,7 +259,10 @@ mod test {
     use std::io::Write;
     use std::fs::File;
 
+    use rand::{Rng, OsRng};
+
     use super::super::tempdir::TempDir;
+    use super::{Chunks, Chunking};
 
     #[test]
     fn file_chunks() {
@@ -58,5 +280,66 @@ mod test {
         assert!(chunks.next().is_none());
     }
 
-    // TODO: add test for different read object
+    #[test]
+    fn excessive_chunk_size() {
+        let temp_dir = TempDir::new("chunks").unwrap();
+        let file_path = temp_dir.path().join("test");
+
+        File::create(&file_path).unwrap();
+
+        assert!(super::file_chunks(&file_path, super::MAX_CHUNK_SIZE).is_ok());
+        assert!(super::file_chunks(&file_path, super::MAX_CHUNK_SIZE + 1).is_err());
+        assert!(super::file_chunks(&file_path, 0).is_err());
+    }
+
+    fn content_chunks(bytes: &[u8], chunking: Chunking) -> Vec<Vec<u8>> {
+        let mut chunks = Chunks::with_chunking(bytes, 0, chunking).unwrap();
+        let mut result = Vec::new();
+
+        while let Some(chunk) = chunks.next() {
+            result.push(chunk.unwrap().to_vec());
+        }
+
+        result
+    }
+
+    #[test]
+    fn content_defined_chunks() {
+        let chunking = Chunking::ContentDefined { min: 256, average: 4096, max: 16384 };
+        let mut original = vec![0; 200000];
+        OsRng::new().unwrap().fill_bytes(&mut original);
+
+        let chunks = content_chunks(&original, chunking);
+        let joined: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+
+        assert_eq!(original, joined);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 16384));
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() >= 256));
+
+        // an insertion near the start should only affect the first few chunks
+        let mut edited = original[..100].to_vec();
+        edited.extend(b"inserted".iter().cloned());
+        edited.extend(original[100..].iter().cloned());
+
+        let edited_chunks = content_chunks(&edited, chunking);
+        let shared = chunks.iter().filter(|chunk| edited_chunks.contains(chunk)).count();
+
+        assert!(shared + 4 >= chunks.len());
+    }
+
+    #[test]
+    fn chunking_settings() {
+        let content_defined = Chunking::ContentDefined { min: 1024, average: 8192, max: 65536 };
+
+        for &chunking in [Chunking::Fixed, content_defined].iter() {
+            assert_eq!(chunking, Chunking::decode(&chunking.encode()).unwrap());
+            assert!(chunking.check().is_ok());
+        }
+
+        assert!(Chunking::decode("content-defined 1 2").is_err());
+        assert!(Chunking::decode("content-defined 8192 1024 65536").is_err());
+        assert!(Chunking::decode("content-defined 1024 8192 0").is_err());
+        assert!(Chunking::ContentDefined { min: 8192, average: 1024, max: 65536 }.check().is_err());
+        assert!(Chunking::ContentDefined { min: 1, average: 1024, max: 65536 }.check().is_err());
+    }
 }
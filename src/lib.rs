@@ -1,39 +1,81 @@
 #![feature(libc, into_cow)]
+#![cfg_attr(test, feature(test))]
 
 extern crate rustc_serialize;
 extern crate time;
 extern crate bzip2;
+extern crate flate2;
 extern crate glob;
 extern crate comm;
 extern crate rand;
 extern crate tempdir;
 extern crate filetime;
 extern crate itertools;
+extern crate num_cpus;
+extern crate xattr;
+
+#[cfg(feature = "sftp")]
+extern crate ssh2;
 
 #[cfg(test)]
 extern crate regex;
+#[cfg(test)]
+extern crate test;
 
-use std::io::{self, Read, Write, BufReader};
-use std::fs::{remove_file, copy, File, create_dir_all, metadata};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufWriter};
+use std::fs::{remove_file, hard_link, canonicalize, File, OpenOptions, create_dir_all,
+              metadata, set_permissions};
 use std::path::{PathBuf, Path};
 use std::env::current_dir;
 use std::convert::{From, AsRef};
+use std::collections::{HashMap, HashSet};
 use std::borrow::IntoCow;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::mem;
+use std::time::Duration;
 
 use tempdir::TempDir;
-use bzip2::reader::BzDecompressor;
 use glob::Pattern;
 use time::get_time;
-use rustc_serialize::hex::ToHex;
 use filetime::set_file_times;
-use itertools::Itertools;
+use flate2::Compression as GzipLevel;
+use flate2::write::GzEncoder;
 
-use export::{process_block, FileInstruction, FileBlock, FileComplete, BlockReference};
+use export::{process_block, FileInstruction, FileBlock, FileComplete, BlockReference,
+             WalkOptions};
 use database::Database;
-use summary::{RestorationSummary, BackupSummary, InitSummary, CleanupSummary};
-
-pub use error::{BonzoError, BonzoResult};
-pub use crypto::{CryptoScheme, AesEncrypter, hash_block};
+use backend::{Storage, PREFIX_MODULO_BUCKETING, DEFAULT_SHARD_DEPTH, is_remote, check_shard_depth,
+              probe_index_finalization, finalize_index, snapshot_index, index_snapshots};
+use summary::{RestorationSummary, BackupSummary, InitSummary, CleanupSummary, ManifestSummary,
+              SyncSummary, IndexCheckSummary, VerifySummary, PasswordSummary, RepoStats,
+              FsckSummary, SourceDiff};
+use file_chunks::check_chunk_size;
+use compression::decompress;
+use crypto::STREAMING_THRESHOLD;
+use options::{database_path, ArchiveFormat};
+use progress::{report, report_file};
+use restore::{FileRestorer, RestoreManifest, Xattrs, start_restore_threads, forward_events,
+              restore_symlink};
+use attributes::Attributes;
+use throttle::{Throttle, write_throttled};
+use block_cache::BlockCache;
+use archive::TarWriter;
+
+pub use error::{BonzoError, BonzoErrorKind, BonzoResult};
+pub use crypto::{CryptoScheme, AesEncrypter, ChaChaEncrypter, Cipher, KeySource, KeyCommand,
+                 Keyfile, KeyParams, Passphrase, HashScheme, HashAlgorithm, hash_block,
+                 DEFAULT_ITERATIONS, AES_CIPHER, CHACHA_CIPHER};
+pub use file_chunks::{MAX_CHUNK_SIZE, Chunking};
+pub use compression::{Compression, CompressionLevel};
+pub use options::{InitOptions, BackupOptions, RestoreOptions, Overwrite, UsagePolicy,
+                  SymlinkPolicy, ArchiveFormat};
+pub use progress::{ProgressEvent, Progress, FileResults};
+pub use summary::BackupSummary;
+pub use backend::{Backend, LocalBackend};
+#[cfg(feature = "sftp")]
+pub use sftp::{SftpBackend, SftpLocation};
 
 #[macro_use]
 mod error;
@@ -42,20 +84,43 @@ mod crypto;
 mod export;
 mod summary;
 mod file_chunks;
+mod backend;
+mod options;
+mod compression;
+mod progress;
+mod restore;
+mod attributes;
+mod throttle;
+mod block_cache;
+mod archive;
+#[cfg(feature = "sftp")]
+mod sftp;
 
 // TODO: Move this constant to main.rs
 pub static DATABASE_FILENAME: &'static str = ".backbonzo.db3";
-
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+// Key derivation parameters are kept in the clear next to the encrypted index,
+// as they are needed to decrypt it
+pub static KEY_PARAMS_FILENAME: &'static str = "key-params";
+pub static CIPHER_FILENAME: &'static str = "cipher";
+// The index is only vacuumed after a cleanup which removed at least this many
+// aliases and blocks combined, since vacuuming rewrites the entire file
+static VACUUM_THRESHOLD: u64 = 1000;
+// Backups made before they were recorded in the index are recovered from the
+// times at which their aliases were persisted. Aliases persisted by a single
+// backup are rarely further apart than this many milliseconds, so larger
+// gaps are taken to separate backups.
+static RESTORE_POINT_GAP: u64 = 10 * 60 * 1000;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum Directory {
     Root,
     Child(i64),
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub struct FileId(u64);
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub struct BlockId(u64);
 
 pub struct BackupManager<C>
@@ -64,7 +129,14 @@ pub struct BackupManager<C>
     database: Database,
     source_path: PathBuf,
     backup_path: PathBuf,
+    backend: Storage,
+    manifest: bool,
+    compression: Compression,
+    chunking: Chunking,
+    hash_algorithm: HashAlgorithm,
     crypto_scheme: Box<C>,
+    // Limits the rate at which blocks and the index are written
+    throttle: Option<Throttle>,
 }
 
 impl<C: CryptoScheme> BackupManager<C> {
@@ -72,6 +144,8 @@ impl<C: CryptoScheme> BackupManager<C> {
                source_path: PathBuf,
                crypto_scheme: &C)
                -> BonzoResult<BackupManager<C>> {
+        try!(database.migrate());
+
         let backup_path = try!(
             database.get_key("backup_path")
                 .map_err(|error| BonzoError::Database(error))
@@ -83,11 +157,71 @@ impl<C: CryptoScheme> BackupManager<C> {
                 })
         );
 
+        let destinations = match try!(database.get_key("destinations")) {
+            Some(encoded) => encoded.lines().map(|line| decode_path(&line)).collect(),
+            None => vec![backup_path.clone()],
+        };
+
+        if let Some(bucketing) = try!(database.get_key("bucketing")) {
+            if bucketing != PREFIX_MODULO_BUCKETING {
+                return Err(BonzoError::Other(format!("Unsupported bucketing scheme: {}",
+                                                     bucketing)));
+            }
+        }
+
+        let shard_depth = match try!(database.get_key("shard_depth")) {
+            Some(encoded) => {
+                try!(encoded.parse()
+                            .map_err(|_| BonzoError::Other(format!("Invalid shard depth: {}",
+                                                                   encoded))))
+            }
+            None => DEFAULT_SHARD_DEPTH,
+        };
+
+        let manifest = try!(database.get_key("shard_manifest")).is_some();
+
+        // backups initialized before the algorithm was configurable use bzip2
+        let compression = match try!(database.get_key("compression")) {
+            Some(name) => try!(Compression::from_name(&name).ok_or(BonzoError::Other(format!(
+                "Unsupported compression algorithm: {}",
+                name
+            )))),
+            None => Compression::Bzip2,
+        };
+
+        // likewise, older backups always split files into blocks of fixed size
+        let chunking = match try!(database.get_key("chunking")) {
+            Some(encoded) => try!(Chunking::decode(&encoded)),
+            None => Chunking::Fixed,
+        };
+
+        // and hash with SHA256
+        let hash_algorithm = match try!(database.get_key("hash")) {
+            Some(name) => try!(HashAlgorithm::from_name(&name).ok_or(BonzoError::Other(format!(
+                "Unsupported hash algorithm: {}",
+                name
+            )))),
+            None => HashAlgorithm::Sha256,
+        };
+
+        // backups initialized before blocks were authenticated may still
+        // contain blocks in the older formats
+        let crypto_scheme = match try!(database.get_key("block_authentication")) {
+            Some(..) => *crypto_scheme,
+            None => crypto_scheme.with_legacy_formats(),
+        };
+
         let manager = BackupManager {
             database: database,
             source_path: source_path,
             backup_path: backup_path,
-            crypto_scheme: Box::new(*crypto_scheme),
+            backend: try!(Storage::open(destinations, shard_depth)),
+            manifest: manifest,
+            compression: compression,
+            chunking: chunking,
+            hash_algorithm: hash_algorithm,
+            crypto_scheme: Box::new(crypto_scheme),
+            throttle: None,
         };
 
         try!(manager.check_password());
@@ -96,349 +230,2493 @@ impl<C: CryptoScheme> BackupManager<C> {
     }
 
     // Update the state of the backup. Starts a walker thread and listens
-    // to its messages. Exits after the time has surpassed the deadline, even
-    // when the update hasn't been fully completed
-    pub fn update(&mut self, block_bytes: usize, deadline: time::Tm) -> BonzoResult<BackupSummary> {
-        let channel_receiver = try!(export::start_export_thread(
+    // to its messages. Exits once the time limit has passed, even
+    // when the update hasn't been fully completed. Progress is reported for
+    // every file whose contents are backed up.
+    pub fn update(&mut self,
+                  options: &BackupOptions,
+                  mut progress: Progress)
+                  -> BonzoResult<BackupSummary> {
+        try!(check_roots(&self.source_path, &options.roots));
+        try!(check_destinations(&self.source_path, &options.roots, self.backend.destinations()));
+
+        let start = epoch_milliseconds();
+        let deadline = start.saturating_add(duration_milliseconds(options.time_limit));
+        let cursor = match options.resume {
+            true => try!(self.resume_cursor()),
+            false => None,
+        };
+        let walk_options = WalkOptions { cursor: cursor, ..walk_options(options) };
+        let backup_id = match options.dry_run {
+            true => None,
+            false => Some(try!(self.database.start_backup(start))),
+        };
+
+        if options.estimate {
+            let (files, bytes) =
+                try!(export::estimate_pending(&self.source_path, &self.database, &walk_options));
+
+            report(&mut progress, ProgressEvent::Estimate(files, bytes));
+        }
+
+        let (channel_receiver, workers) = try!(export::start_export_thread(
             &self.database,
             &*self.crypto_scheme,
-            block_bytes,
-            &self.source_path
+            self.compression,
+            options.compression_level,
+            self.hash_algorithm,
+            options.block_bytes,
+            self.chunking,
+            options.max_file_size,
+            options.newer_than.map(|age| start.saturating_sub(age)),
+            options.older_than.map(|age| start.saturating_sub(age)),
+            options.xattrs,
+            options.dry_run,
+            options.worker_count,
+            &self.source_path,
+            walk_options
         ));
 
         let mut summary = BackupSummary::new();
+        // blocks are not persisted during a dry run, so we keep track of the
+        // ones we have seen to avoid counting them twice
+        let mut dry_run_hashes = match options.dry_run {
+            true => Some(HashSet::new()),
+            false => None,
+        };
+        let mut last_completed = None;
+        let batching = options.batch_size > 1;
+        let mut batched = 0;
+        let mut failure = None;
+
+        loop {
+            let msg = match channel_receiver.recv_async() {
+                Ok(msg) => msg,
+                Err(..) => {
+                    // the exporting threads may need to write to the index,
+                    // so never keep it locked while waiting for them
+                    try!(self.commit_batch(&mut batched));
+
+                    match channel_receiver.recv_sync() {
+                        Ok(msg) => msg,
+                        Err(..) => break,
+                    }
+                }
+            };
 
-        while let Ok(msg) = channel_receiver.recv_sync() {
-            if time::now_utc() > deadline {
+            let interrupted = options.interrupt.map_or(false, |flag| flag.load(Ordering::SeqCst));
+
+            if interrupted || epoch_milliseconds() >= deadline {
                 summary.timeout = true;
                 break;
             }
 
-            match msg {
-                FileInstruction::Error(e) => return Err(e),
-                FileInstruction::NewBlock(ref block) =>
-                    try!(self.handle_new_block(block, &mut summary)),
-                FileInstruction::Complete(ref file) =>
-                    try!(self.handle_new_file (file,  &mut summary)),
+            if batching && batched == 0 {
+                try!(self.database.begin_batch());
+            }
+
+            let result = match msg {
+                FileInstruction::Error(e) => Err(e),
+                FileInstruction::Started(path) =>
+                    Ok(report(&mut progress, ProgressEvent::FileStarted(path))),
+                FileInstruction::NewBlock(ref block) => {
+                    self.handle_new_block(block, dry_run_hashes.as_mut(), &mut summary)
+                        .map(|written| if written {
+                            report(&mut progress,
+                                   ProgressEvent::BlockWritten(block.bytes.len() as u64))
+                        })
+                }
+                FileInstruction::Complete(file) => {
+                    let result = self.handle_new_file(&file, options.dry_run, &mut summary);
+                    report(&mut progress, ProgressEvent::FileCompleted(file.path.clone()));
+                    last_completed = Some(file.path);
+                    result
+                }
+                FileInstruction::Warning(warning) => Ok(summary.add_warning(warning)),
+                FileInstruction::Skipped(..) => Ok(summary.add_skipped_file()),
+            };
+
+            if batching {
+                batched += 1;
+            }
+
+            if let Err(e) = result {
+                failure = Some(e);
+                break;
+            }
+
+            if batched >= options.batch_size {
+                try!(self.commit_batch(&mut batched));
+            }
+        }
+
+        // when the channel closed by itself, every thread has quit. One that
+        // panicked may have done so before sending all of its files, so the
+        // backup would be incomplete
+        if failure.is_none() && !summary.timeout {
+            for worker in workers {
+                if worker.join().is_err() && failure.is_none() {
+                    failure = Some(BonzoError::from_str("An export thread panicked"));
+                }
+            }
+        }
+
+        // changes made before a failure are kept, like they would be without
+        // batching
+        try!(self.commit_batch(&mut batched));
+
+        if let Some(id) = backup_id {
+            try!(self.database.finish_backup(id, epoch_milliseconds()));
+        }
+
+        if let Some(e) = failure {
+            return Err(e);
+        }
+
+        if options.resume && !options.dry_run {
+            try!(self.save_resume_cursor(summary.timeout, last_completed, start));
+        }
+
+        summary.dry_run = options.dry_run;
+
+        Ok(summary)
+    }
+
+    // Commits the current batch of changes to the index, if there is one
+    fn commit_batch(&self, batched: &mut usize) -> BonzoResult<()> {
+        if *batched > 0 {
+            try!(self.database.commit_batch());
+        }
+
+        *batched = 0;
+
+        Ok(())
+    }
+
+    fn resume_cursor(&self) -> BonzoResult<Option<(PathBuf, u64)>> {
+        let path = try!(self.database.get_key("resume_path"));
+        let timestamp = try!(self.database.get_key("resume_timestamp"))
+                            .and_then(|stamp| stamp.parse::<u64>().ok());
+
+        Ok(path.and_then(|path| timestamp.map(|stamp| (PathBuf::from(path), stamp))))
+    }
+
+    // Remembers the last file completed by a run which timed out, so that the
+    // next run can continue from there. Forgets it once a run completes.
+    fn save_resume_cursor(&self,
+                          timeout: bool,
+                          last_completed: Option<PathBuf>,
+                          start: u64)
+                          -> BonzoResult<()> {
+        if !timeout {
+            try!(self.database.remove_key("resume_path"));
+            return Ok(try!(self.database.remove_key("resume_timestamp")));
+        }
+
+        if let Some(path) = last_completed.as_ref().and_then(|path| path.to_str()) {
+            try!(self.database.set_key("resume_path", path));
+            try!(self.database.set_key("resume_timestamp", &start.to_string()));
+        }
+
+        Ok(())
+    }
+
+    // Restores the files matching the filter in the state at the given time.
+    // The files are restored in parallel by several threads, while this one
+    // walks the index. Files which should be hard linked to an identical file
+    // are linked after all others have been restored. When a callback for the
+    // results is given, a file which cannot be restored is reported to it and
+    // the restore carries on with the next.
+    pub fn restore(&self,
+                   timestamp: u64,
+                   filter: String,
+                   options: &RestoreOptions,
+                   mut progress: Progress,
+                   mut results: FileResults)
+                   -> BonzoResult<RestorationSummary> {
+        if options.worker_count == 0 {
+            return Err(BonzoError::from_str("At least one worker thread is needed"));
+        }
+
+        let pattern = try!(glob_pattern(&filter));
+        let manifest = match options.manifest {
+            Some(ref path) => Some(Arc::new(try!(RestoreManifest::create(path,
+                                                                         self.hash_algorithm)))),
+            None => None,
+        };
+        let shared_summary = Arc::new(Mutex::new(RestorationSummary::new()));
+        let cache = Arc::new(Mutex::new(BlockCache::new(options.cache_bytes)));
+        let (event_transmitter, events) = channel();
+        let (mut transmitter, workers) = try!(start_restore_threads(&self.database,
+                                                                    &self.backend,
+                                                                    &*self.crypto_scheme,
+                                                                    self.hash_algorithm,
+                                                                    options.ignore_integrity,
+                                                                    options.worker_count,
+                                                                    manifest.clone(),
+                                                                    cache,
+                                                                    shared_summary.clone(),
+                                                                    event_transmitter));
+        let mut first_paths: HashMap<Vec<BlockId>, PathBuf> = HashMap::new();
+        let mut links: Vec<(PathBuf, PathBuf, Vec<BlockId>, Attributes, Xattrs)> = Vec::new();
+        let mut xattrs: HashMap<PathBuf, Xattrs> = HashMap::new();
+        let mut failure = None;
+
+        // extended attributes are set by whoever restores the file, so that
+        // failures are reported along with it
+        if options.xattrs {
+            for (directory, filename, name, value) in try!(self.database.get_xattrs_at(timestamp)) {
+                let path = self.source_path
+                               .join(try!(self.database.get_directory_path(directory)))
+                               .join(&filename);
+
+                xattrs.entry(path).or_insert_with(Vec::new).push((name, value));
+            }
+        }
+
+        for alias in try!(database::Aliases::new(&self.database,
+                                                 self.source_path.clone(),
+                                                 Directory::Root,
+                                                 timestamp)) {
+            let result = alias.map_err(From::from).and_then(|(path, block_list, attributes)| {
+                if !pattern.matches_path(&path) {
+                    return Ok(());
+                }
+
+                let file_xattrs = xattrs.remove(&path).unwrap_or_else(Vec::new);
+                let keep = try!(self.keep_existing_file(&path, &block_list, options.overwrite));
+
+                // files with identical contents have identical block lists
+                if options.hardlinks && !block_list.is_empty() {
+                    if let Some(original) = first_paths.get(&block_list) {
+                        if !keep {
+                            links.push((original.clone(),
+                                        path,
+                                        block_list.clone(),
+                                        attributes,
+                                        file_xattrs));
+                        }
+
+                        return Ok(());
+                    }
+
+                    // a kept file can only serve as link source when its
+                    // contents are those of the backup
+                    let source = match (keep, options.overwrite) {
+                        (true, Overwrite::Never) => {
+                            try!(self.matches_block_list(&path, &block_list))
+                        }
+                        _ => true,
+                    };
+
+                    if source {
+                        first_paths.insert(block_list.clone(), path.clone());
+                    }
+                }
+
+                match keep {
+                    true => Ok(shared_summary.lock().unwrap().add_skipped_file()),
+                    false => {
+                        transmitter.send_sync((path, block_list, attributes, file_xattrs))
+                                   .map_err(|_| BonzoError::from_str("Failed sending file"))
+                    }
+                }
+            });
+
+            let forwarded = result.and_then(|_| {
+                forward_events(&events, &mut progress, &mut results, false)
+            });
+
+            if let Err(e) = forwarded {
+                failure = Some(e);
+                break;
+            }
+        }
+
+        // the threads quit once all files sent have been restored
+        drop(transmitter);
+
+        let remaining = forward_events(&events, &mut progress, &mut results, true);
+
+        for worker in workers {
+            if worker.join().is_err() && failure.is_none() {
+                failure = Some(BonzoError::from_str("A restore thread panicked"));
+            }
+        }
+
+        if let Some(e) = failure.or(remaining.err()) {
+            return Err(e);
+        }
+
+        let mut summary = mem::replace(&mut *shared_summary.lock().unwrap(),
+                                       RestorationSummary::new());
+
+        // files recreate their own directories, but empty ones have to be
+        // recreated separately
+        for directory in try!(self.database.get_empty_directories_at(timestamp)) {
+            let path = self.source_path.join(try!(self.database.get_directory_path(directory)));
+
+            if pattern.matches_path(&path) {
+                try_io!(create_dir_all(&path), &path);
+                summary.add_directory();
+            }
+        }
+
+        // symlinks are recreated as links, rather than from blocks
+        for (directory, name, target) in try!(self.database.get_symlinks_at(timestamp)) {
+            let path = self.source_path
+                           .join(try!(self.database.get_directory_path(directory)))
+                           .join(&name);
+
+            if !pattern.matches_path(&path) {
+                continue;
+            }
+
+            match try!(restore_symlink(&path, &target, options.overwrite)) {
+                true => {
+                    summary.add_symlink();
+                    try!(report_file(&mut results, Ok(path)));
+                }
+                false => summary.add_skipped_file(),
+            }
+        }
+
+        // linked files share the attributes of the original
+        for (original, path, block_list, attributes, xattrs) in links {
+            if try!(self.keep_existing_file(&path, &block_list, options.overwrite)) {
+                summary.add_skipped_file();
+                continue;
+            }
+
+            let restored = match try!(link_restored_file(&original, &path)) {
+                true => {
+                    summary.add_hardlink();
+                    path.metadata()
+                        .map(|metadata| metadata.len())
+                        .map_err(|e| BonzoError::Io(e, Some(path.clone())))
+                }
+                false => {
+                    self.restore_file(&path,
+                                      &block_list,
+                                      attributes,
+                                      &xattrs,
+                                      options.ignore_integrity,
+                                      &mut summary,
+                                      &mut progress)
+                }
+            };
+            let result = restored.and_then(|bytes| {
+                match manifest {
+                    Some(ref manifest) => manifest.record(&path, bytes, block_list.len()),
+                    None => Ok(()),
+                }
+            });
+
+            try!(report_file(&mut results, result.map(|_| path)));
+        }
+
+        Ok(summary)
+    }
+
+    // Determines whether a file present at the restore location should be left
+    // alone. A file is considered identical to the one in the backup when its
+    // hash belongs to a backed up file with the same blocks.
+    fn keep_existing_file(&self,
+                          path: &Path,
+                          block_list: &[BlockId],
+                          overwrite: Overwrite)
+                          -> BonzoResult<bool> {
+        if overwrite == Overwrite::Always || !path.exists() {
+            return Ok(false);
+        }
+
+        if overwrite == Overwrite::Never {
+            return Ok(true);
+        }
+
+        self.matches_block_list(path, block_list)
+    }
+
+    // Checks whether the existing file at the given path has exactly the
+    // contents described by the block list
+    fn matches_block_list(&self, path: &Path, block_list: &[BlockId]) -> BonzoResult<bool> {
+        let hash = try_io!(self.hash_algorithm.hash_file(path), path);
+
+        let identical = match try!(self.database.file_from_hash(&hash)) {
+            Some(file_id) => &try!(self.database.get_file_block_list(file_id))[..] == block_list,
+            None => false,
+        };
+
+        Ok(identical)
+    }
+
+    // Returns the paths, relative to the source directory, of the files in the
+    // backup at the given time which match the filter
+    pub fn list(&self, timestamp: u64, filter: String) -> BonzoResult<Vec<PathBuf>> {
+        let pattern = try!(glob_pattern(&filter));
+        let aliases = try!(database::Aliases::new(&self.database,
+                                                  PathBuf::new(),
+                                                  Directory::Root,
+                                                  timestamp));
+        let mut paths = Vec::new();
+
+        for alias in aliases {
+            let (path, _, _) = try!(alias);
+
+            if pattern.matches_path(&path) {
+                paths.push(path);
+            }
+        }
+
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    // Like list, but also returns the size of every file in bytes and its
+    // number of blocks, when these were recorded in the index
+    pub fn list_with_sizes(&self,
+                           timestamp: u64,
+                           filter: String)
+                           -> BonzoResult<Vec<(PathBuf, Option<(u64, u64)>)>> {
+        let mut entries = Vec::new();
+
+        for path in try!(self.list(timestamp, filter)) {
+            let (directory, filename) = try!(self.resolve_path(&path));
+            let size = match try!(self.database.get_file_at(directory, &filename, timestamp)) {
+                Some((file_id, _)) => try!(self.database.get_file_size(file_id)),
+                None => None,
+            };
+
+            entries.push((path, size));
+        }
+
+        Ok(entries)
+    }
+
+    // Compares the given source directory with the backup at the given time.
+    // The working index in the source directory is left out, as is everything
+    // a backup with the given options would leave out.
+    pub fn diff(&self,
+                source_path: &Path,
+                working_index: &Path,
+                timestamp: u64,
+                options: &BackupOptions)
+                -> BonzoResult<SourceDiff> {
+        export::diff_source(source_path,
+                            working_index,
+                            &self.database,
+                            self.hash_algorithm,
+                            timestamp,
+                            &walk_options(options))
+    }
+
+    // Lists the moments at which backups completed, along with the number of
+    // files added, changed or removed by each. Restoring to any of these
+    // moments gives the state right after that backup.
+    pub fn restore_points(&self) -> BonzoResult<Vec<(u64, u64)>> {
+        let backups = try!(self.database.get_backups());
+        let timestamps = try!(self.database.alias_timestamps());
+
+        Ok(list_restore_points(&backups, &timestamps, RESTORE_POINT_GAP))
+    }
+
+    // Writes the files matching the filter, in the state at the given time,
+    // to the given sink as a tar archive, along with empty directories and
+    // symlinks. Paths in the archive are relative to the backup root. The
+    // contents of each file pass through a temporary file, since their size
+    // has to be known before they are archived; the archive itself is
+    // streamed. Files are written one at a time, and never as hard links.
+    pub fn restore_to_archive(&self,
+                              timestamp: u64,
+                              filter: String,
+                              options: &RestoreOptions,
+                              out: &mut Write,
+                              mut progress: Progress)
+                              -> BonzoResult<RestorationSummary> {
+        let pattern = try!(glob_pattern(&filter));
+        let temp_directory = try!(TempDir::new("bonzo-archive"));
+        let spool_path = temp_directory.path().join("contents");
+        let cache = Mutex::new(BlockCache::new(options.cache_bytes));
+        let restorer = self.file_restorer().with_cache(&cache);
+        let mut writer = TarWriter::new(out);
+        let mut summary = RestorationSummary::new();
+
+        for alias in try!(database::Aliases::new(&self.database,
+                                                 PathBuf::new(),
+                                                 Directory::Root,
+                                                 timestamp)) {
+            let (path, block_list, attributes) = try!(alias);
+
+            if !pattern.matches_path(&path) {
+                continue;
+            }
+
+            report(&mut progress, ProgressEvent::FileStarted(path.clone()));
+
+            let mut spool = try_io!(OpenOptions::new()
+                                        .read(true)
+                                        .write(true)
+                                        .create(true)
+                                        .truncate(true)
+                                        .open(&spool_path),
+                                    &spool_path);
+            let size = try!(restorer.write_blocks(&block_list,
+                                                  &mut spool,
+                                                  options.ignore_integrity,
+                                                  &mut summary,
+                                                  &mut progress));
+
+            try_io!(spool.seek(SeekFrom::Start(0)), &spool_path);
+            try_io!(writer.append_file(&path, size, attributes, &mut spool), &path);
+
+            summary.add_file();
+            report(&mut progress, ProgressEvent::FileCompleted(path));
+        }
+
+        for directory in try!(self.database.get_empty_directories_at(timestamp)) {
+            let path = try!(self.database.get_directory_path(directory));
+
+            if pattern.matches_path(&path) {
+                try_io!(writer.append_directory(&path), &path);
+                summary.add_directory();
+            }
+        }
+
+        for (directory, name, target) in try!(self.database.get_symlinks_at(timestamp)) {
+            let path = try!(self.database.get_directory_path(directory)).join(&name);
+
+            if pattern.matches_path(&path) {
+                try_io!(writer.append_symlink(&path, &target), &path);
+                summary.add_symlink();
+            }
+        }
+
+        try!(writer.finish());
+
+        Ok(summary)
+    }
+
+    // Writes the contents of the single file matching the filter, in the
+    // state at the given time, to the given sink. Returns the number of bytes
+    // written. It is an error for the filter to match no files or several.
+    pub fn cat(&self, timestamp: u64, filter: String, out: &mut Write) -> BonzoResult<u64> {
+        let pattern = try!(glob_pattern(&filter));
+        let aliases = try!(database::Aliases::new(&self.database,
+                                                  PathBuf::new(),
+                                                  Directory::Root,
+                                                  timestamp));
+        let mut matches = Vec::new();
+
+        for alias in aliases {
+            let (path, block_list, _) = try!(alias);
+
+            if pattern.matches_path(&path) {
+                matches.push(block_list);
+            }
+        }
+
+        match matches.len() {
+            0 => Err(BonzoError::Other(format!("No file matches {}", filter))),
+            1 => self.restore_to_writer(&matches[0], out),
+            count => {
+                Err(BonzoError::Other(format!("{} files match {}, but only one can be written",
+                                              count,
+                                              filter)))
+            }
+        }
+    }
+
+    // Restores the most recent distinct versions of a single file next to
+    // each other in the given directory, suffixing their names with a version
+    // number. The oldest restored version gets suffix .v1.
+    pub fn restore_versions(&self,
+                            dest: &Path,
+                            relative_path: &Path,
+                            count: usize)
+                            -> BonzoResult<RestorationSummary> {
+        let (directory, filename) = try!(self.resolve_path(relative_path));
+        let history = try!(self.database.get_file_history(directory, &filename));
+        let mut versions: Vec<FileId> = Vec::new();
+
+        for (file_id, _) in history {
+            if versions.len() >= count {
+                break;
+            }
+
+            if !versions.contains(&file_id) {
+                versions.push(file_id);
             }
         }
 
+        if versions.is_empty() {
+            return Err(BonzoError::Other(format!("Could not find any versions of {}",
+                                                 relative_path.display())));
+        }
+
+        let mut summary = RestorationSummary::new();
+
+        for (index, file_id) in versions.iter().rev().enumerate() {
+            let block_list = try!(self.database.get_file_block_list(*file_id));
+            let path = dest.join(format!("{}.v{}", filename, index + 1));
+
+            try!(self.restore_file(&path,
+                                   &block_list,
+                                   Attributes::unknown(),
+                                   &[],
+                                   false,
+                                   &mut summary,
+                                   &mut None));
+        }
+
         Ok(summary)
     }
 
-    pub fn restore(&self, timestamp: u64, filter: String) -> BonzoResult<RestorationSummary> {
-        let pattern =
-            try!(Pattern::new(&filter).map_err(|_| BonzoError::from_str("Invalid glob pattern")));
+    // Restores a single file, in the state it was in at the given time, to
+    // the given output path. The path of the file is relative to the backup
+    // root.
+    pub fn restore_single(&self,
+                          source_relative: &Path,
+                          timestamp: u64,
+                          out_path: &Path)
+                          -> BonzoResult<RestorationSummary> {
+        let (directory, filename) = try!(self.resolve_path(source_relative));
+        let file = try!(self.database.get_file_at(directory, &filename, timestamp));
+        let (file_id, attributes) = try!(file.ok_or_else(|| {
+            BonzoError::Other(format!("Could not find {} at the given time",
+                                      source_relative.display()))
+        }));
+        let block_list = try!(self.database.get_file_block_list(file_id));
         let mut summary = RestorationSummary::new();
 
-        try!(database::Aliases::new(
-            &self.database,
-            self.source_path.clone(),
-            Directory::Root,
-            timestamp
-        ))
-            .filter(|alias| {
-                match alias {
-                    &Err(..) => true,
-                    &Ok((ref path, _)) => pattern.matches_path(path),
+        try!(self.restore_file(out_path,
+                               &block_list,
+                               attributes,
+                               &[],
+                               false,
+                               &mut summary,
+                               &mut None));
+
+        Ok(summary)
+    }
+
+    // Computes the storage taken up at the backup destinations by the files in
+    // every directory, including its subdirectories, in the state at the given
+    // timestamp. Sizes are those of the processed blocks, as reported by the
+    // backend, so that remote backups work too. The policy determines how
+    // blocks shared by several files are accounted for.
+    pub fn disk_usage(&self,
+                      timestamp: u64,
+                      policy: UsagePolicy)
+                      -> BonzoResult<Vec<(PathBuf, u64)>> {
+        let mut block_sizes: HashMap<BlockId, u64> = HashMap::new();
+        let mut usage: HashMap<PathBuf, u64> = HashMap::new();
+        let aliases = try!(database::Aliases::new(&self.database,
+                                                  PathBuf::from("."),
+                                                  Directory::Root,
+                                                  timestamp));
+
+        for alias in aliases {
+            let (path, block_list, _) = try!(alias);
+            let mut bytes = 0;
+
+            for block_id in block_list {
+                let counted = block_sizes.contains_key(&block_id);
+
+                if !counted {
+                    let hash = try!(self.database.block_hash_from_id(block_id));
+                    let (_, size) = try!(self.backend.open_block(&hash));
+
+                    block_sizes.insert(block_id, size);
                 }
-            })
-            .map(|alias| {
-                alias.map_err(From::from).and_then(|(ref path, ref block_list)| {
-                    self.restore_file(path, &block_list, &mut summary)
-                })
-            })
-            .fold_results((), |_, _| ())
-            .and_then(move |_| Ok(summary))
+
+                if !counted || policy == UsagePolicy::Total {
+                    bytes += block_sizes[&block_id];
+                }
+            }
+
+            let mut current = path.as_path();
+
+            while let Some(parent) = current.parent() {
+                if parent.as_os_str().is_empty() {
+                    break;
+                }
+
+                *usage.entry(parent.to_owned()).or_insert(0) += bytes;
+                current = parent;
+            }
+        }
+
+        let mut list: Vec<(PathBuf, u64)> = usage.into_iter().collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(list)
+    }
+
+    // Finds the directory and file name in the index for a path relative to
+    // the backup root
+    fn resolve_path(&self, relative_path: &Path) -> BonzoResult<(Directory, String)> {
+        let filename = try!(relative_path.file_name()
+                                         .and_then(|os_str| os_str.to_str())
+                                         .ok_or(BonzoError::from_str("Invalid file path")));
+        let mut directory = Directory::Root;
+
+        if let Some(parent) = relative_path.parent() {
+            for component in parent.iter() {
+                let name = try!(component.to_str()
+                                         .ok_or(BonzoError::from_str("Invalid file path")));
+
+                let child = try!(self.database.find_directory(directory, name));
+
+                directory = try!(child.ok_or_else(|| {
+                    BonzoError::Other(format!("Could not find directory {}", name))
+                }));
+            }
+        }
+
+        Ok((directory, filename.to_owned()))
     }
 
-    // Restores a single file by decrypting and inflating a sequence of blocks
-    // and writing them to the given path in order
+    // Restores a single file from its blocks. See FileRestorer::restore_file.
     pub fn restore_file(&self,
                         path: &Path,
                         block_list: &[BlockId],
-                        summary: &mut RestorationSummary)
-                        -> BonzoResult<()> {
-        try!(create_parent_dir(path));
+                        attributes: Attributes,
+                        xattrs: &[(String, Vec<u8>)],
+                        ignore_integrity: bool,
+                        summary: &mut RestorationSummary,
+                        progress: &mut Progress)
+                        -> BonzoResult<u64> {
+        self.file_restorer()
+            .restore_file(path, block_list, attributes, xattrs, ignore_integrity, summary, progress)
+    }
+
+    fn file_restorer(&self) -> FileRestorer<C> {
+        FileRestorer::new(&self.database, &self.backend, &*self.crypto_scheme, self.hash_algorithm)
+    }
+
+    // Writes the contents made up by the given blocks to any sink, without
+    // touching the filesystem. Returns the number of bytes written.
+    pub fn restore_to_writer(&self, block_list: &[BlockId], out: &mut Write) -> BonzoResult<u64> {
+        self.file_restorer()
+            .write_blocks(block_list, out, false, &mut RestorationSummary::new(), &mut None)
+    }
+
+    // Stores a new block. During a dry run, the block is only counted; the
+    // given set then holds the hashes of the blocks counted so far.
+    // Returns whether the block was written to the destination. It is not
+    // when the block turns out to be known already, or on a dry run.
+    fn handle_new_block(&self,
+                        block: &FileBlock,
+                        dry_run_hashes: Option<&mut HashSet<Vec<u8>>>,
+                        summary: &mut BackupSummary)
+                        -> BonzoResult<bool> {
+        // make sure block has not already been persisted, for instance as
+        // part of another file processed at the same time
+        if let Some(..) = try!(self.database.block_id_from_hash(&block.hash)) {
+            summary.add_deduplicated_blocks(1, block.source_byte_count);
+            return Ok(false);
+        }
+
+        let byte_slice = &block.bytes;
+
+        let written = match dry_run_hashes {
+            Some(hashes) => {
+                if !hashes.insert(block.hash.clone()) {
+                    summary.add_deduplicated_blocks(1, block.source_byte_count);
+                    return Ok(false);
+                }
+
+                false
+            }
+            None => {
+                try!(self.store_block(&block.hash, byte_slice));
+                true
+            }
+        };
+
+        summary.add_block(byte_slice, block.source_byte_count);
+
+        Ok(written)
+    }
+
+    // Writes a processed block to its destination and persists it to the
+    // index. The backend writes the block under a partial name and then
+    // renames it into place, so that a stored block is always complete. It is
+    // only persisted after that. An interruption can thus leave a partial
+    // block, which is removed by the next backup, or a complete block missing
+    // from the index, which is overwritten when its contents are met again
+    // and removed by prune-orphans otherwise.
+    fn store_block(&self, hash: &[u8], bytes: &[u8]) -> BonzoResult<BlockId> {
+        try!(self.backend.write_block(hash, bytes, self.throttle.as_ref()));
+
+        if self.manifest {
+            try!(try!(self.backend.local()).record_block(hash));
+        }
+
+        self.database.persist_block(hash).map_err(From::from)
+    }
 
-        let mut file = try_io!(File::create(path), path);
+    // Copies the files and aliases of another backup into this one, along
+    // with the blocks which are not yet present here. Blocks are re-encrypted
+    // when the two backups use different keys. For every name, only aliases
+    // which are newer than the most recent alias of that name in this backup
+    // are copied, so that the history of every name stays in chronological
+    // order.
+    pub fn sync_from<D: CryptoScheme>(&self,
+                                      other: &BackupManager<D>)
+                                      -> BonzoResult<SyncSummary> {
+        // files and blocks are identified by their hash
+        if self.hash_algorithm != other.hash_algorithm {
+            return Err(BonzoError::from_str("Cannot sync backups using different hash algorithms"));
+        }
 
-        for block_id in block_list.iter() {
-            let hash = try!(self.database.block_hash_from_id(*block_id));
-            let block_path = block_output_path(&self.backup_path, &hash);
-            let bytes = try!(load_processed_block(&block_path, &*self.crypto_scheme));
+        // blocks can only be copied as they are when both use the same cipher,
+        // and may be in a legacy format unless the other backup authenticates
+        let same_key = self.crypto_scheme.name() == other.crypto_scheme.name() &&
+                       self.crypto_scheme.hash_password() == other.crypto_scheme.hash_password() &&
+                       (try!(other.authenticates_blocks()) || !try!(self.authenticates_blocks()));
+        let mut directories: HashMap<Directory, Directory> = HashMap::new();
+        let mut files: HashMap<FileId, FileId> = HashMap::new();
+        let mut latest_timestamps: HashMap<(Directory, String), Option<u64>> = HashMap::new();
+        let mut summary = SyncSummary::new();
+
+        directories.insert(Directory::Root, Directory::Root);
+
+        for alias in try!(other.database.get_all_aliases()) {
+            let directory = try!(self.map_directory(other, alias.directory, &mut directories));
+            let key = (directory, alias.name.clone());
+
+            if !latest_timestamps.contains_key(&key) {
+                let latest = try!(self.database.latest_alias_timestamp(directory, &alias.name));
+                latest_timestamps.insert(key.clone(), latest);
+            }
 
-            if hash_block(&bytes) != hash {
-                return Err(BonzoError::from_str("Block integrity check failed"));
+            if let Some(Some(latest)) = latest_timestamps.get(&key).cloned() {
+                if alias.timestamp <= latest {
+                    continue;
+                }
             }
 
-            summary.add_block(&bytes);
+            // a file is only kept in the index together with its first alias
+            try!(self.database.within_savepoint("sync_alias", || -> BonzoResult<()> {
+                let file_id = match alias.file_id {
+                    None => None,
+                    Some(other_id) => {
+                        if !files.contains_key(&other_id) {
+                            let id = try!(self.sync_file(other, other_id, same_key, &mut summary));
+                            files.insert(other_id, id);
+                        }
+
+                        files.get(&other_id).cloned()
+                    }
+                };
+
+                match alias.symlink {
+                    Some(ref target) => {
+                        try!(self.database.persist_symlink_at(directory,
+                                                              &alias.name,
+                                                              target,
+                                                              alias.timestamp))
+                    }
+                    None => {
+                        try!(self.database.persist_alias_at(directory,
+                                                            file_id,
+                                                            &alias.name,
+                                                            alias.attributes,
+                                                            alias.timestamp))
+                    }
+                }
 
-            try_io!(file.write_all(&bytes), path);
+                Ok(summary.aliases += 1)
+            }));
         }
 
-        try_io!(file.sync_all(), path);
+        Ok(summary)
+    }
 
-        summary.add_file();
+    // Returns the directory in this index corresponding to a directory in the
+    // index of another backup, creating it when necessary
+    fn map_directory<D: CryptoScheme>(&self,
+                                      other: &BackupManager<D>,
+                                      directory: Directory,
+                                      directories: &mut HashMap<Directory, Directory>)
+                                      -> BonzoResult<Directory> {
+        if let Some(mapped) = directories.get(&directory) {
+            return Ok(*mapped);
+        }
 
-        Ok(())
+        let parent = try!(other.database.get_directory_parent(directory));
+        let name = try!(other.database.get_directory_name(directory));
+        let mapped_parent = try!(self.map_directory(other, parent, directories));
+        let mapped = try!(self.database.get_directory(mapped_parent, &name));
+
+        directories.insert(directory, mapped);
+
+        Ok(mapped)
     }
 
-    fn handle_new_block(&self, block: &FileBlock, summary: &mut BackupSummary) -> BonzoResult<()> {
-        // make sure block has not already been persisted
-        if let Some(..) = try!(self.database.block_id_from_hash(&block.hash)) {
-            return Ok(());
+    // Makes sure a file of another backup is present in this one, copying its
+    // blocks where necessary. Returns the id of the file in this index.
+    fn sync_file<D: CryptoScheme>(&self,
+                                  other: &BackupManager<D>,
+                                  file_id: FileId,
+                                  same_key: bool,
+                                  summary: &mut SyncSummary)
+                                  -> BonzoResult<FileId> {
+        let hash = try!(other.database.get_file_hash(file_id));
+
+        if let Some(id) = try!(self.database.file_from_hash(&hash)) {
+            return Ok(id);
         }
 
-        let path = block_output_path(&self.backup_path, &block.hash);
-        let byte_slice = &block.bytes;
+        let mut block_id_list = Vec::new();
+
+        for other_block_id in try!(other.database.get_file_block_list(file_id)) {
+            let block_hash = try!(other.database.block_hash_from_id(other_block_id));
+
+            if let Some(id) = try!(self.database.block_id_from_hash(&block_hash)) {
+                summary.blocks_skipped += 1;
+                block_id_list.push(id);
+                continue;
+            }
+
+            let bytes = match same_key {
+                true => try!(other.backend.read_block(&block_hash)),
+                false => {
+                    let (mut stored, length) = try!(other.backend.open_block(&block_hash));
+                    let clear_text = try!(decode_block_from(&mut *stored,
+                                                            length,
+                                                            &*other.crypto_scheme));
+
+                    if other.hash_algorithm.hash_block(&clear_text) != block_hash {
+                        return Err(BonzoError::CorruptBlock);
+                    }
+
+                    try!(process_block(&clear_text, self.compression, &*self.crypto_scheme))
+                }
+            };
+
+            block_id_list.push(try!(self.store_block(&block_hash, &bytes)));
+            summary.blocks_copied += 1;
+            summary.bytes += bytes.len() as u64;
+        }
+
+        summary.files += 1;
+
+        let size = try!(other.database.get_file_size(file_id)).map(|(bytes, _)| bytes);
+
+        self.database.insert_file(&hash, &block_id_list, size).map_err(From::from)
+    }
+
+    fn handle_new_file(&self,
+                       file: &FileComplete,
+                       dry_run: bool,
+                       summary: &mut BackupSummary)
+                       -> BonzoResult<()> {
+        summary.add_deduplicated_blocks(file.deduplicated_blocks, file.deduplicated_bytes);
+
+        if dry_run {
+            return Ok(summary.add_file());
+        }
+
+        // if file hash was already known, only add a new alias
+        if let file_id@Some(..) = try!(self.database.file_from_hash(&file.hash)) {
+            try!(self.database.persist_alias_with_xattrs(
+                file.directory,
+                file_id,
+                &file.filename,
+                file.attributes,
+                &file.xattrs
+            ));
+
+            return Ok(summary.add_file());
+        }
+
+        let block_id_list: Vec<_> = try!(
+            file.block_reference_list
+            .iter()
+            .map(|reference| match *reference {
+                BlockReference::ById(id)         => Ok(id),
+                BlockReference::ByHash(ref hash) => {
+                    let id_option = try!(self.database.block_id_from_hash(hash));
+                    id_option.ok_or_else(|| {
+                        BonzoError::MissingBlock(format!("Could not find block with hash {:?}",
+                                                         hash))
+                    })
+                }
+            })
+            .collect()
+        );
+
+        try!(self.database.persist_file(
+            file.directory,
+            &file.filename,
+            &file.hash,
+            file.attributes,
+            &block_id_list,
+            file.size,
+            &file.xattrs
+        ));
+
+        summary.add_file();
+
+        Ok(())
+    }
+
+    // Whether every block of the backup carries an authentication code. This
+    // holds for all backups initialized since blocks were authenticated.
+    fn authenticates_blocks(&self) -> BonzoResult<bool> {
+        Ok(try!(self.database.get_key("block_authentication")).is_some())
+    }
+
+    // Returns an error when the given password does not match the one saved
+    // in the index
+    fn check_password(&self) -> BonzoResult<()> {
+        let cipher = try!(self.database.get_key("cipher")).unwrap_or(AES_CIPHER.to_owned());
+
+        if cipher != self.crypto_scheme.name() {
+            return Err(BonzoError::Other(format!("Backup is encrypted with {}, not {}",
+                                                 cipher,
+                                                 self.crypto_scheme.name())));
+        }
+
+        let hash_opt = try!(self.database.get_key("password"));
+        let hash = try!(hash_opt.ok_or(BonzoError::from_str("Saved hash is NULL")));
+
+        match self.crypto_scheme.hash_password() == hash {
+            true => Ok(()),
+            false => Err(BonzoError::WrongPassword),
+        }
+    }
+
+    // Remove old aliases and unused blocks from database and disk. All changes
+    // to the index are made in a single transaction, which is committed before
+    // any block is removed from disk. When we are interrupted in between, the
+    // destination is left with blocks the index does not know about, which
+    // prune-orphans can remove, rather than an index referring to blocks which
+    // no longer exist.
+    fn cleanup(&self, max_age_milliseconds: u64) -> BonzoResult<CleanupSummary> {
+        let timestamp = cleanup_timestamp(max_age_milliseconds);
+
+        try!(self.database.begin_batch());
+
+        let result = self.remove_unused_entries(timestamp);
+
+        match result {
+            Ok(..) => try!(self.database.commit_batch()),
+            Err(..) => try!(self.database.rollback_batch()),
+        }
+
+        let (aliases, unused_block_list) = try!(result);
+        let (blocks, bytes) = try!(self.remove_block_files(unused_block_list));
+
+        // deleted rows leave free pages behind, so the index does not shrink
+        // until it is vacuumed. This cannot be done within a transaction.
+        if aliases + blocks >= VACUUM_THRESHOLD {
+            try!(self.database.vacuum());
+        }
+
+        Ok(CleanupSummary { aliases: aliases, blocks: blocks, bytes: bytes, dry_run: false })
+    }
+
+    // Works out what cleanup would remove, without removing anything. The
+    // same changes are made to the index, but in a transaction which is
+    // rolled back afterwards.
+    pub fn preview_cleanup(&self, max_age_milliseconds: u64) -> BonzoResult<CleanupSummary> {
+        let timestamp = cleanup_timestamp(max_age_milliseconds);
+
+        try!(self.database.begin_batch());
+
+        let result = self.remove_unused_entries(timestamp);
+
+        try!(self.database.rollback_batch());
+
+        let (aliases, unused_block_list) = try!(result);
+        let mut bytes = 0;
+
+        let backend = try!(self.backend.local());
+
+        for &(_, ref hash) in unused_block_list.iter() {
+            let path = backend.block_path(hash);
+
+            if path.exists() {
+                bytes += try_io!(metadata(&path), &path).len();
+            }
+        }
+
+        Ok(CleanupSummary {
+            aliases: aliases,
+            blocks: unused_block_list.len() as u64,
+            bytes: bytes,
+            dry_run: true,
+        })
+    }
+
+    // Removes aliases older than the given timestamp from the index, along
+    // with the files and blocks which are no longer referenced as a result.
+    // Returns the number of aliases removed and the blocks, which are still
+    // on disk.
+    fn remove_unused_entries(&self, timestamp: u64) -> BonzoResult<(u64, Vec<(BlockId, Vec<u8>)>)> {
+        let aliases = try!(self.database.remove_old_aliases(timestamp));
+        try!(self.database.remove_old_empty_directories(timestamp));
+        try!(self.database.remove_unused_files());
+        let unused_block_list = try!(self.database.get_unused_blocks());
+
+        for &(id, _) in unused_block_list.iter() {
+            try!(self.database.remove_block(id));
+        }
+
+        Ok((aliases, unused_block_list))
+    }
+
+    // Removes the given blocks from disk. Returns the number of blocks and the
+    // total number of bytes within.
+    fn remove_block_files(&self,
+                          block_list: Vec<(BlockId, Vec<u8>)>)
+                          -> BonzoResult<(u64, u64)> {
+        let block_count = block_list.len();
+        let mut removed_hashes = Vec::new();
+        let mut bytes = 0;
+
+        for (_, hash) in block_list {
+            // Do not err when the file was already removed. We may need to
+            // revisit this decision later as it is indicative of potential
+            // issues.
+            if let Some(size) = try!(self.backend.remove_block(&hash)) {
+                bytes += size;
+                removed_hashes.push(hash);
+            }
+        }
+
+        if self.manifest {
+            try!(try!(self.backend.local()).forget_blocks(&removed_hashes));
+        }
+
+        Ok((block_count as u64, bytes))
+    }
+
+    // Removes blocks from the backup destinations which are not known to the
+    // index. These are left behind when backbonzo is interrupted between
+    // writing a block and persisting it. Stored blocks are enumerated from the
+    // shard manifests when they are enabled.
+    pub fn prune_orphans(&self) -> BonzoResult<CleanupSummary> {
+        let backend = try!(self.backend.local());
+        let stored_blocks = match self.manifest {
+            true => try!(backend.manifest_blocks()),
+            false => try!(backend.scan_blocks()),
+        };
+        let mut orphans = Vec::new();
+        let mut bytes = 0;
+
+        for hash in stored_blocks {
+            if try!(self.database.block_id_from_hash(&hash)).is_some() {
+                continue;
+            }
+
+            bytes += try!(self.backend.remove_block(&hash)).unwrap_or(0);
+            orphans.push(hash);
+        }
+
+        if self.manifest {
+            try!(backend.forget_blocks(&orphans));
+        }
+
+        Ok(CleanupSummary {
+            aliases: 0,
+            blocks: orphans.len() as u64,
+            bytes: bytes,
+            dry_run: false,
+        })
+    }
+
+    // Computes the size of the backup. The size of every block is taken from
+    // disk, so all destinations need to be available. The sizes of files are
+    // taken from the index.
+    pub fn stats(&self) -> BonzoResult<RepoStats> {
+        let mut stored_bytes = 0;
+
+        for (_, hash) in try!(self.database.get_all_blocks()) {
+            let path = try!(try!(self.backend.local()).existing_block_path(&hash));
+
+            stored_bytes += try_io!(metadata(&path), &path).len();
+        }
+
+        let referenced_bytes = try!(self.database.referenced_file_bytes());
+        let (file_bytes, unsized_files) = try!(self.database.file_sizes());
+
+        Ok(RepoStats {
+            blocks: try!(self.database.block_count()),
+            files: try!(self.database.file_count()),
+            aliases: try!(self.database.alias_count()),
+            stored_bytes: stored_bytes,
+            referenced_bytes: referenced_bytes,
+            file_bytes: file_bytes,
+            unsized_files: unsized_files,
+        })
+    }
+
+    // Checks every block referenced by the index by loading it from the
+    // backup destination and comparing its contents to its hash. This goes
+    // through the backend, so that remote backups can be verified as well.
+    pub fn verify(&self) -> BonzoResult<VerifySummary> {
+        let mut summary = VerifySummary::new();
+
+        for (_, hash) in try!(self.database.get_all_blocks()) {
+            let decoded = self.backend.open_block(&hash).and_then(|(mut stored, length)| {
+                decode_block_from(&mut *stored, length, &*self.crypto_scheme)
+            });
+
+            match decoded {
+                Ok(ref bytes) if self.hash_algorithm.hash_block(bytes) == hash => {
+                    summary.ok += 1
+                }
+                Err(ref e) if e.kind() == BonzoErrorKind::MissingBlock => summary.missing += 1,
+                _ => summary.corrupt += 1,
+            }
+        }
+
+        Ok(summary)
+    }
+
+    // Checks that rows of the index only refer to rows which exist. With fix,
+    // the dangling rows are removed, as are the blocks which are no longer
+    // used as a result, both from the index and from disk.
+    pub fn fsck(&self, fix: bool) -> BonzoResult<FsckSummary> {
+        let orphans = try!(self.database.find_orphans());
+        let mut summary = FsckSummary {
+            fileblocks: orphans.fileblocks.len() as u64,
+            aliases: orphans.aliases.len() as u64,
+            blocks: orphans.blocks.len() as u64,
+            fixed: false,
+        };
+
+        if !fix || summary.is_consistent() {
+            return Ok(summary);
+        }
+
+        try!(self.database.begin_batch());
+
+        let result = self.remove_orphans();
+
+        match result {
+            Ok(..) => try!(self.database.commit_batch()),
+            Err(..) => try!(self.database.rollback_batch()),
+        }
+
+        try!(self.remove_block_files(try!(result)));
+        summary.fixed = true;
+
+        Ok(summary)
+    }
+
+    // Removes dangling rows from the index, followed by the blocks which are
+    // no longer used. Returns those blocks, which are still on disk.
+    fn remove_orphans(&self) -> BonzoResult<Vec<(BlockId, Vec<u8>)>> {
+        try!(self.database.remove_orphans());
+
+        let unused_block_list = try!(self.database.get_unused_blocks());
+
+        for &(id, _) in unused_block_list.iter() {
+            try!(self.database.remove_block(id));
+        }
+
+        Ok(unused_block_list)
+    }
+
+    // Checks the shard manifests against the contents of the destination
+    // directories. When rebuild is set, the manifests are rewritten from a
+    // scan of the destinations and kept up to date from then on.
+    pub fn manifests(&mut self, rebuild: bool) -> BonzoResult<ManifestSummary> {
+        if rebuild {
+            let blocks = try!(try!(self.backend.local()).rebuild_manifests());
+
+            try!(self.database.set_key("shard_manifest", "enabled"));
+            self.manifest = true;
+
+            return Ok(ManifestSummary { blocks: blocks, missing: 0, stale: 0, rebuilt: true });
+        }
+
+        if !self.manifest {
+            return Err(BonzoError::from_str("Shard manifests are not enabled for this backup"));
+        }
+
+        let backend = try!(self.backend.local());
+        let (missing, stale) = try!(backend.check_manifests());
+        let blocks = try!(backend.manifest_blocks()).len() as u64;
+
+        Ok(ManifestSummary { blocks: blocks, missing: missing, stale: stale, rebuilt: false })
+    }
+
+    // Replaces the passphrase of the backup with the one of the given scheme,
+    // which must use the same cipher. The password hash in the index is
+    // updated and the index is exported encrypted with the new key. Blocks
+    // are NOT re-encrypted: those stored so far can only be decrypted with
+    // the old key, while new blocks are encrypted with the new one. Rotating
+    // the key of the blocks as well requires a full backup to a fresh
+    // destination.
+    pub fn reencrypt_index<D: CryptoScheme>(self, new_scheme: &D) -> BonzoResult<()> {
+        if new_scheme.name() != self.crypto_scheme.name() {
+            return Err(BonzoError::Other(format!("Backup is encrypted with {}, not {}",
+                                                 self.crypto_scheme.name(),
+                                                 new_scheme.name())));
+        }
+
+        try!(self.database.set_key("password", &new_scheme.hash_password()));
+
+        self.write_index(new_scheme, 0)
+    }
+
+    // Deals with an index-new left behind by an export which was interrupted.
+    // Since it is written only after all blocks, a copy which can be decrypted
+    // is at least as recent as the index and takes its place. Otherwise, it
+    // is incomplete and removed. Modification times are of no help here, as
+    // they are reset for everything we write. Returns a description of the
+    // action taken, if any.
+    fn recover_new_index(&self) -> BonzoResult<Option<String>> {
+        let new_index = self.backup_path.join("index-new");
+
+        // remote storage reads the new index by itself when the old one is
+        // gone, and leaves it in place otherwise
+        if !self.backend.is_local() || !new_index.exists() {
+            return Ok(None);
+        }
+
+        let bytes = try_io!(read_file(&new_index), &new_index);
+        let valid = self.crypto_scheme
+                        .decrypt_block(&bytes)
+                        .map_err(From::from)
+                        .and_then(|clear_text| decompress(&clear_text))
+                        .is_ok();
+
+        if !valid {
+            try_io!(remove_file(&new_index), &new_index);
+
+            return Ok(Some(format!("Removed incomplete index {} left behind by an interrupted \
+                                    backup",
+                                   new_index.display())));
+        }
+
+        let index = self.backup_path.join("index");
+        let finalization = probe_index_finalization(&self.backup_path, &index);
+
+        try!(finalize_index(&new_index, &index, &bytes, finalization));
+
+        Ok(Some(format!("Replaced the index with {} left behind by an interrupted backup",
+                        new_index.display())))
+    }
+
+    // Removes the blocks which an interrupted backup was still writing.
+    // Returns a description of the action taken, if any.
+    fn recover_partial_blocks(&self) -> BonzoResult<Option<String>> {
+        let count = match self.backend.local() {
+            Ok(backend) => try!(backend.remove_partial_blocks()),
+            Err(..) => 0,
+        };
+
+        Ok(match count {
+            0 => None,
+            _ => Some(format!("Removed {} partially written blocks left behind by an \
+                               interrupted backup",
+                              count)),
+        })
+    }
+
+    // Closes the database connection and saves it to the backup destination in
+    // encrypted form. When the number of snapshots to keep is positive, a
+    // timestamped copy of the index is kept as well.
+    fn export_index(self, keep_snapshots: usize) -> BonzoResult<()> {
+        let crypto_scheme = *self.crypto_scheme;
+
+        self.write_index(&crypto_scheme, keep_snapshots)
+    }
+
+    fn write_index<D: CryptoScheme>(self,
+                                    crypto_scheme: &D,
+                                    keep_snapshots: usize)
+                                    -> BonzoResult<()> {
+        // recorded for diagnostics only
+        if let Ok(backend) = self.backend.local() {
+            try!(self.database.set_key("index_finalization", backend.index_finalization().name()));
+        }
+
+        let bytes = try!(self.database.to_bytes());
+        let procesed_bytes = try!(process_block(&bytes, self.compression, crypto_scheme));
+
+        // the index must never refer to blocks which could still be lost
+        self.backend.flush();
+        try!(self.backend.write_index(&procesed_bytes, self.throttle.as_ref()));
+
+        if keep_snapshots > 0 {
+            try!(self.backend.local());
+            try!(snapshot_index(&self.backup_path,
+                                &procesed_bytes,
+                                epoch_milliseconds(),
+                                keep_snapshots));
+        }
+
+        Ok(())
+    }
+}
+
+// TODO: move this to main.rs
+pub fn init<C: CryptoScheme, P: AsRef<Path>>(source_path: &P,
+                                             backup_path: &P,
+                                             crypto_scheme: &C)
+                                             -> BonzoResult<InitSummary> {
+    init_with_options(source_path, backup_path, crypto_scheme, &InitOptions::new())
+}
+
+pub fn init_with_options<C: CryptoScheme, P: AsRef<Path>>(source_path: &P,
+                                                          backup_path: &P,
+                                                          crypto_scheme: &C,
+                                                          options: &InitOptions)
+                                                          -> BonzoResult<InitSummary> {
+    try!(options.chunking.check());
+    try!(check_shard_depth(options.shard_depth));
+
+    let mut destinations = vec![backup_path.as_ref().to_owned()];
+    destinations.extend(options.extra_destinations.iter().cloned());
+
+    try!(check_destinations(source_path.as_ref(), &[], &destinations));
+
+    let storage = try!(Storage::open(destinations, options.shard_depth));
+    let index_path = database_path(source_path.as_ref(), options.index_path.as_ref());
+    let database = try!(Database::create(index_path));
+    let hash = crypto_scheme.hash_password();
+
+    try!(database.setup());
+    try!(database.set_key("password", &hash));
+    try!(database.set_key("key_source", options.key_source));
+    try!(database.set_key("compression", options.compression.name()));
+    try!(database.set_key("chunking", &options.chunking.encode()));
+    try!(database.set_key("hash", options.hash_algorithm.name()));
+    try!(database.set_key("shard_depth", &options.shard_depth.to_string()));
+    try!(database.set_key("cipher", crypto_scheme.name()));
+    try!(database.set_key("block_authentication", "enabled"));
+    try!(storage.write_metadata(CIPHER_FILENAME, crypto_scheme.name().as_bytes()));
+
+    if let Some(ref params) = options.key_params {
+        let encoded_params = params.encode();
+
+        try!(database.set_key("key_params", &encoded_params));
+        try!(storage.write_metadata(KEY_PARAMS_FILENAME, encoded_params.as_bytes()));
+    }
+
+    let encoded_backup_path = try!(encode_path(backup_path));
+
+    try!(database.set_key("backup_path", &encoded_backup_path));
+
+    if !options.extra_destinations.is_empty() {
+        let mut encoded_destinations = vec![encoded_backup_path];
+
+        for destination in options.extra_destinations.iter() {
+            encoded_destinations.push(try!(encode_path(destination)));
+        }
+
+        try!(database.set_key("destinations", &encoded_destinations.join("\n")));
+        try!(database.set_key("bucketing", PREFIX_MODULO_BUCKETING));
+    }
+
+    Ok(InitSummary)
+}
+
+fn create_parent_dir(path: &Path) -> BonzoResult<()> {
+    let parent = try!(path.parent().ok_or(BonzoError::from_str("Couldn't get parent directory")));
+
+    Ok(try_io!(create_dir_all(parent), path))
+}
+
+// Makes sure files can be restored into the given directory before any work
+// is done, creating it when it does not exist yet
+fn check_output_directory(path: &Path) -> BonzoResult<()> {
+    if path.exists() && !path.is_dir() {
+        return Err(BonzoError::InvalidInput(format!("Restore destination {} is not a directory",
+                                                    path.display())));
+    }
+
+    try_io!(create_dir_all(path), path);
+
+    match TempDir::new_in(path, "bonzo-probe") {
+        Ok(..) => Ok(()),
+        Err(..) => Err(BonzoError::Other(format!("Restore destination {} is not writable",
+                                                 path.display()))),
+    }
+}
+
+// Creates a hard link to a file restored earlier. Returns false when this is
+// not possible, for example because both are on different filesystems, in
+// which case the file should be restored normally.
+fn link_restored_file(original: &Path, path: &Path) -> BonzoResult<bool> {
+    try!(create_parent_dir(path));
+
+    Ok(hard_link(original, path).is_ok())
+}
+
+// Takes a path, turns it into an absolute path if necessary. Remote
+// destinations are kept as they are.
+fn encode_path<P: AsRef<Path>>(path: &P) -> io::Result<String> {
+    if path.as_ref().is_relative() && !is_remote(path.as_ref()) {
+        let mut cwd = try!(current_dir());
+        cwd.push(path);
+
+        return Ok(cwd.to_string_lossy().into_owned())
+    }
+
+    Ok(path.as_ref().to_string_lossy().into_owned())
+}
+
+fn decode_path<P: AsRef<Path>>(path: &P) -> PathBuf {
+    PathBuf::from(path.as_ref())
+}
+
+pub fn backup<'p, C: CryptoScheme, SP: IntoCow<'p, Path>>(source_path: SP,
+                                                          crypto_scheme: &C,
+                                                          options: &BackupOptions)
+                                                          -> BonzoResult<BackupSummary> {
+    backup_with_progress(source_path, crypto_scheme, options, None)
+}
+
+// Like backup, but reports the progress of the backup to the given callback
+pub fn backup_with_progress<'p, C, SP>(source_path: SP,
+                                       crypto_scheme: &C,
+                                       options: &BackupOptions,
+                                       progress: Progress)
+                                       -> BonzoResult<BackupSummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    try!(check_chunk_size(options.block_bytes)
+             .map_err(|e| BonzoError::InvalidInput(e.to_string())));
+
+    if options.worker_count == 0 {
+        return Err(BonzoError::from_str("At least one worker thread is needed"));
+    }
+
+    let mut manager = try!(open_source_manager(source_path,
+                                               options.index_path.as_ref(),
+                                               crypto_scheme));
+    manager.throttle = options.max_bytes_per_second.map(Throttle::new);
+    manager.backend.set_sync(options.sync_blocks);
+
+    let mut recovery = Vec::new();
+
+    if !options.dry_run {
+        recovery.extend(try!(manager.recover_new_index()));
+        recovery.extend(try!(manager.recover_partial_blocks()));
+    }
+
+    let mut summary = match manager.update(options, progress) {
+        Ok(summary) => summary,
+        Err(e) => {
+            // the blocks and files stored before the failure are in the
+            // working index, so the exported one should know about them too.
+            // The failure itself is what gets reported.
+            if !options.dry_run {
+                let _ = manager.export_index(options.keep_indices);
+            }
+
+            return Err(e);
+        }
+    };
+
+    for message in recovery {
+        summary.add_warning(message);
+    }
+
+    if options.dry_run {
+        return Ok(summary);
+    }
+
+    // recorded for diagnostics only, as blocks are read the same way at any
+    // level
+    try!(manager.database.set_key("compression_level", options.compression_level.name()));
+
+    if !summary.timeout {
+        let cleanup_summary = try!(manager.cleanup(options.max_age_milliseconds));
+        summary.add_cleanup_summary(cleanup_summary);
+    }
+
+    try!(manager.export_index(options.keep_indices));
+
+    Ok(summary)
+}
+
+// Checks or rebuilds the shard manifests of the backup of the given source
+// directory
+pub fn manifests<'p, C: CryptoScheme, SP: IntoCow<'p, Path>>(source_path: SP,
+                                                             index_path: Option<&PathBuf>,
+                                                             crypto_scheme: &C,
+                                                             rebuild: bool)
+                                                             -> BonzoResult<ManifestSummary> {
+    let mut manager = try!(open_source_manager(source_path, index_path, crypto_scheme));
+
+    manager.manifests(rebuild)
+}
+
+// Removes old versions of files from the backup of the given source
+// directory, along with the blocks no longer needed, without backing up
+// anything new. With a dry run, only reports what would be removed.
+pub fn prune<'p, C, SP>(source_path: SP,
+                        crypto_scheme: &C,
+                        options: &BackupOptions)
+                        -> BonzoResult<CleanupSummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let mut manager = try!(open_source_manager(source_path,
+                                               options.index_path.as_ref(),
+                                               crypto_scheme));
+    manager.throttle = options.max_bytes_per_second.map(Throttle::new);
+
+    if options.dry_run {
+        return manager.preview_cleanup(options.max_age_milliseconds);
+    }
+
+    let summary = try!(manager.cleanup(options.max_age_milliseconds));
+
+    try!(manager.export_index(options.keep_indices));
+
+    Ok(summary)
+}
+
+// Removes blocks from the backup of the given source directory which are not
+// referenced by its index
+pub fn prune_orphans<'p, C: CryptoScheme, SP: IntoCow<'p, Path>>(source_path: SP,
+                                                                 index_path: Option<&PathBuf>,
+                                                                 crypto_scheme: &C)
+                                                                 -> BonzoResult<CleanupSummary> {
+    let manager = try!(open_source_manager(source_path, index_path, crypto_scheme));
+
+    manager.prune_orphans()
+}
+
+// Checks the references between the rows of the working index of the given
+// source directory. With fix, inconsistencies are repaired and the index is
+// written to the backup destination.
+pub fn fsck<'p, C: CryptoScheme, SP: IntoCow<'p, Path>>(source_path: SP,
+                                                        index_path: Option<&PathBuf>,
+                                                        crypto_scheme: &C,
+                                                        fix: bool)
+                                                        -> BonzoResult<FsckSummary> {
+    let manager = try!(open_source_manager(source_path, index_path, crypto_scheme));
+    let summary = try!(manager.fsck(fix));
+
+    if summary.fixed {
+        try!(manager.export_index(0));
+    }
+
+    Ok(summary)
+}
+
+// Reports the size of the backup of the given source directory
+pub fn stats<'p, C: CryptoScheme, SP: IntoCow<'p, Path>>(source_path: SP,
+                                                         index_path: Option<&PathBuf>,
+                                                         crypto_scheme: &C)
+                                                         -> BonzoResult<RepoStats> {
+    let manager = try!(open_source_manager(source_path, index_path, crypto_scheme));
+
+    manager.stats()
+}
+
+// Lists the files in the backup at the given location at the given time
+pub fn list<'p, 's, C, SP, S>(backup_path: SP,
+                              crypto_scheme: &C,
+                              timestamp: u64,
+                              filter: S)
+                              -> BonzoResult<Vec<PathBuf>>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>,
+          S: IntoCow<'s, str>
+{
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let backup_cow = backup_path.into_cow();
+    let database = try!(open_index(&backup_cow, temp_directory.path(), crypto_scheme));
+    let manager = try!(BackupManager::new(database, backup_cow.into_owned(), crypto_scheme));
+
+    manager.list(timestamp, filter.into_cow().into_owned())
+}
+
+// Lists the files which were added, modified or deleted in the source
+// directory since the given time, according to the backup at the given
+// location. Files which a backup with the given options would skip are left
+// out.
+pub fn diff<'p, C, SP>(source_path: SP,
+                       backup_path: SP,
+                       crypto_scheme: &C,
+                       timestamp: u64,
+                       options: &BackupOptions)
+                       -> BonzoResult<SourceDiff>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let source_cow = source_path.into_cow();
+    let backup_cow = backup_path.into_cow();
+    let working_index = database_path(&source_cow, options.index_path.as_ref());
+    let database = try!(open_index(&backup_cow, temp_directory.path(), crypto_scheme));
+    let manager = try!(BackupManager::new(database, backup_cow.into_owned(), crypto_scheme));
+
+    manager.diff(&source_cow, &working_index, timestamp, options)
+}
+
+// Lists the moments the backup at the given location can be restored to, as
+// milliseconds since the epoch along with the number of files changed then
+pub fn restore_points<'p, C, SP>(backup_path: SP,
+                                 crypto_scheme: &C)
+                                 -> BonzoResult<Vec<(u64, u64)>>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let backup_cow = backup_path.into_cow();
+    let database = try!(open_index(&backup_cow, temp_directory.path(), crypto_scheme));
+    let manager = try!(BackupManager::new(database, backup_cow.into_owned(), crypto_scheme));
+
+    manager.restore_points()
+}
+
+// Like list, but along with the size and number of blocks of every file
+pub fn list_with_sizes<'p, 's, C, SP, S>(backup_path: SP,
+                                         crypto_scheme: &C,
+                                         timestamp: u64,
+                                         filter: S)
+                                         -> BonzoResult<Vec<(PathBuf, Option<(u64, u64)>)>>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>,
+          S: IntoCow<'s, str>
+{
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let backup_cow = backup_path.into_cow();
+    let database = try!(open_index(&backup_cow, temp_directory.path(), crypto_scheme));
+    let manager = try!(BackupManager::new(database, backup_cow.into_owned(), crypto_scheme));
+
+    manager.list_with_sizes(timestamp, filter.into_cow().into_owned())
+}
+
+// Writes the contents of a single file in the backup at the given location
+// to the given sink, without creating any files besides the decrypted index
+pub fn cat<'p, 's, C, SP, S>(backup_path: SP,
+                             crypto_scheme: &C,
+                             timestamp: u64,
+                             filter: S,
+                             out: &mut Write)
+                             -> BonzoResult<u64>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>,
+          S: IntoCow<'s, str>
+{
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let backup_cow = backup_path.into_cow();
+    let database = try!(open_index(&backup_cow, temp_directory.path(), crypto_scheme));
+    let manager = try!(BackupManager::new(database, backup_cow.into_owned(), crypto_scheme));
+
+    manager.cat(timestamp, filter.into_cow().into_owned(), out)
+}
+
+// Checks all blocks of the backup at the given location for corruption
+pub fn verify<'p, C, SP>(backup_path: SP, crypto_scheme: &C) -> BonzoResult<VerifySummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let backup_cow = backup_path.into_cow();
+    let database = try!(open_index(&backup_cow, temp_directory.path(), crypto_scheme));
+    let manager = try!(BackupManager::new(database, backup_cow.into_owned(), crypto_scheme));
+
+    manager.verify()
+}
+
+// Initializes a backup and immediately performs its first run. The backup is
+// not started when initialization fails. The working index location of the
+// initialization options takes precedence over that of the backup options.
+pub fn init_and_backup<C, P>(source_path: &P,
+                             backup_path: &P,
+                             crypto_scheme: &C,
+                             init_options: &InitOptions,
+                             backup_options: &BackupOptions)
+                             -> BonzoResult<BackupSummary>
+    where C: CryptoScheme,
+          P: AsRef<Path>
+{
+    try!(init_with_options(source_path, backup_path, crypto_scheme, init_options));
+
+    let mut options = backup_options.clone();
+    options.index_path = init_options.index_path.clone();
+
+    backup(source_path.as_ref(), crypto_scheme, &options)
+}
+
+// Returns the parameters the passphrase of the backup of the given source
+// directory is derived with
+pub fn source_key_params<'p, SP>(source_path: SP,
+                                 index_path: Option<&PathBuf>)
+                                 -> BonzoResult<KeyParams>
+    where SP: IntoCow<'p, Path>
+{
+    let database = try!(Database::from_file(database_path(&source_path.into_cow(), index_path)));
+
+    match try!(database.get_key("key_params")) {
+        Some(encoded) => decode_key_params(&encoded),
+        None => Ok(KeyParams::legacy()),
+    }
+}
+
+// Returns the parameters the passphrase of the backup at the given location is
+// derived with
+pub fn backup_key_params<'p, SP: IntoCow<'p, Path>>(backup_path: SP) -> BonzoResult<KeyParams> {
+    let storage = try!(Storage::open(vec![backup_path.into_cow().into_owned()],
+                                     DEFAULT_SHARD_DEPTH));
+
+    match try!(storage.read_metadata(KEY_PARAMS_FILENAME)) {
+        Some(bytes) => decode_key_params(&String::from_utf8_lossy(&bytes)),
+        None => Ok(KeyParams::legacy()),
+    }
+}
+
+// Returns the name of the cipher the backup of the given source directory is
+// encrypted with
+pub fn source_cipher<'p, SP>(source_path: SP, index_path: Option<&PathBuf>) -> BonzoResult<String>
+    where SP: IntoCow<'p, Path>
+{
+    let database = try!(Database::from_file(database_path(&source_path.into_cow(), index_path)));
+
+    Ok(try!(database.get_key("cipher")).unwrap_or(AES_CIPHER.to_owned()))
+}
+
+// Returns the name of the cipher the backup at the given location is
+// encrypted with
+pub fn backup_cipher<'p, SP: IntoCow<'p, Path>>(backup_path: SP) -> BonzoResult<String> {
+    let storage = try!(Storage::open(vec![backup_path.into_cow().into_owned()],
+                                     DEFAULT_SHARD_DEPTH));
+
+    match try!(storage.read_metadata(CIPHER_FILENAME)) {
+        Some(bytes) => Ok(String::from_utf8_lossy(&bytes).trim().to_owned()),
+        None => Ok(AES_CIPHER.to_owned()),
+    }
+}
+
+// Describes the walk over the source directories made by a backup with the
+// given options, starting from the beginning
+fn walk_options(options: &BackupOptions) -> WalkOptions {
+    WalkOptions {
+        strict: options.strict,
+        cursor: None,
+        new_files_first: options.new_files_first,
+        excluded: options.excluded_directories.clone(),
+        patterns: options.exclude_patterns.clone(),
+        include_patterns: options.include_patterns.clone(),
+        empty_directories: options.empty_directories,
+        symlinks: options.symlinks,
+        roots: options.roots.clone(),
+        hardlinks: options.dereference_hardlinks,
+        one_file_system: options.one_file_system,
+        dry_run: options.dry_run,
+    }
+}
+
+// Makes sure every additional source directory exists and has a name which
+// is unique and cannot be confused with anything in the main source directory
+fn check_roots(source_path: &Path, roots: &[(String, PathBuf)]) -> BonzoResult<()> {
+    let mut names = HashSet::new();
+
+    for &(ref name, ref path) in roots.iter() {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(BonzoError::Other(format!("Invalid source name: {}", name)));
+        }
+
+        if !names.insert(name) || source_path.join(name).exists() {
+            return Err(BonzoError::Other(format!("Source name {} is already taken", name)));
+        }
+
+        if !path.is_dir() {
+            return Err(BonzoError::InvalidInput(format!("Source {} is not a directory",
+                                                        path.display())));
+        }
+    }
+
+    Ok(())
+}
+
+// Makes sure no backup destination lies within the source directory or any
+// of the additional ones. Such a backup would try to back up its own blocks.
+fn check_destinations(source_path: &Path,
+                      roots: &[(String, PathBuf)],
+                      destinations: &[PathBuf])
+                      -> BonzoResult<()> {
+    let mut sources = vec![source_path];
+    sources.extend(roots.iter().map(|&(_, ref path)| path.as_path()));
+
+    for destination in destinations.iter().filter(|destination| !is_remote(destination)) {
+        let canonical_destination = canonical_path(destination);
+
+        for source in sources.iter() {
+            if canonical_destination.starts_with(canonical_path(source)) {
+                return Err(BonzoError::Other(format!("Backup destination {} is inside source \
+                                                      directory {}",
+                                                     destination.display(),
+                                                     source.display())));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Resolves symlinks and relative components of the path. Paths which do not
+// exist are only made absolute.
+fn canonical_path(path: &Path) -> PathBuf {
+    canonicalize(path)
+        .or_else(|_| current_dir().map(|cwd| cwd.join(path)))
+        .unwrap_or(path.to_owned())
+}
+
+fn glob_pattern(filter: &str) -> BonzoResult<Pattern> {
+    Pattern::new(filter).map_err(|_| BonzoError::from_str("Invalid glob pattern"))
+}
+
+fn decode_key_params(encoded: &str) -> BonzoResult<KeyParams> {
+    KeyParams::decode(encoded).ok_or(BonzoError::from_str("Invalid key derivation parameters"))
+}
+
+// Opens the working index of the given source directory
+fn open_source_manager<'p, C, SP>(source_path: SP,
+                                  index_path: Option<&PathBuf>,
+                                  crypto_scheme: &C)
+                                  -> BonzoResult<BackupManager<C>>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let source_cow = source_path.into_cow();
+    let database = try!(Database::from_file(database_path(&source_cow, index_path)));
+
+    BackupManager::new(database, source_cow.into_owned(), crypto_scheme)
+}
+
+// Changes the passphrase of the backup of the given source directory. See
+// BackupManager::reencrypt_index for why this does not make the old
+// passphrase obsolete.
+pub fn change_password<'p, C, D, SP>(source_path: SP,
+                                     index_path: Option<&PathBuf>,
+                                     crypto_scheme: &C,
+                                     new_scheme: &D)
+                                     -> BonzoResult<PasswordSummary>
+    where C: CryptoScheme,
+          D: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let manager = try!(open_source_manager(source_path, index_path, crypto_scheme));
+
+    try!(manager.reencrypt_index(new_scheme));
+
+    Ok(PasswordSummary)
+}
+
+// Copies the history of the backup at the given location into the backup of
+// the given source directory. Blocks which are already present in the latter
+// are not copied again.
+pub fn sync_repos<'p, C, D, SP>(backup_path: SP,
+                                source_path: SP,
+                                index_path: Option<&PathBuf>,
+                                backup_crypto_scheme: &C,
+                                crypto_scheme: &D)
+                                -> BonzoResult<SyncSummary>
+    where C: CryptoScheme,
+          D: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let backup_cow = backup_path.into_cow();
+    let other_database = try!(open_index(&backup_cow, temp_directory.path(), backup_crypto_scheme));
+    let other = try!(BackupManager::new(other_database,
+                                        backup_cow.into_owned(),
+                                        backup_crypto_scheme));
+    let manager = try!(open_source_manager(source_path, index_path, crypto_scheme));
+    let summary = try!(manager.sync_from(&other));
+
+    try!(manager.export_index(0));
+
+    Ok(summary)
+}
+
+pub fn restore<'p, 's, C: CryptoScheme, SP: IntoCow<'p, Path>, S: IntoCow<'s, str>>
+    (source_path: SP,
+     backup_path: SP,
+     crypto_scheme: &C,
+     timestamp: u64,
+     filter: S)
+     -> BonzoResult<RestorationSummary> {
+    restore_with_options(source_path,
+                         backup_path,
+                         crypto_scheme,
+                         timestamp,
+                         filter,
+                         &RestoreOptions::new())
+}
+
+pub fn restore_with_options<'p, 's, C, SP, S>(source_path: SP,
+                                              backup_path: SP,
+                                              crypto_scheme: &C,
+                                              timestamp: u64,
+                                              filter: S,
+                                              options: &RestoreOptions)
+                                              -> BonzoResult<RestorationSummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>,
+          S: IntoCow<'s, str>
+{
+    restore_with_progress(source_path, backup_path, crypto_scheme, timestamp, filter, options, None)
+}
+
+// Like restore_with_options, but reports the progress of the restore to the
+// given callback
+pub fn restore_with_progress<'p, 's, C, SP, S>(source_path: SP,
+                                               backup_path: SP,
+                                               crypto_scheme: &C,
+                                               timestamp: u64,
+                                               filter: S,
+                                               options: &RestoreOptions,
+                                               progress: Progress)
+                                               -> BonzoResult<RestorationSummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>,
+          S: IntoCow<'s, str>
+{
+    restore_with_results(source_path,
+                         backup_path,
+                         crypto_scheme,
+                         timestamp,
+                         filter,
+                         options,
+                         progress,
+                         None)
+}
+
+// Like restore_with_progress, but also hands every restored file to the
+// given callback as soon as it is done. Files which cannot be restored are
+// handed over along with the error, instead of aborting the restore. Errors
+// which affect the restore as a whole are still returned.
+pub fn restore_with_results<'p, 's, C, SP, S>(source_path: SP,
+                                              backup_path: SP,
+                                              crypto_scheme: &C,
+                                              timestamp: u64,
+                                              filter: S,
+                                              options: &RestoreOptions,
+                                              progress: Progress,
+                                              results: FileResults)
+                                              -> BonzoResult<RestorationSummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>,
+          S: IntoCow<'s, str>
+{
+    let source_cow = source_path.into_cow();
+
+    try!(check_output_directory(&source_cow));
 
-        try!(create_parent_dir(&path));
-        try!(write_to_disk(&path, byte_slice));
-        try!(self.database.persist_block(&block.hash));
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let database = try!(open_index_at(&backup_path.into_cow(),
+                                      temp_directory.path(),
+                                      crypto_scheme,
+                                      timestamp));
+    let manager = try!(BackupManager::new(database, source_cow.into_owned(), crypto_scheme));
 
-        summary.add_block(byte_slice, block.source_byte_count);
+    manager.restore(timestamp, filter.into_cow().into_owned(), options, progress, results)
+}
 
-        Ok(())
+// Restores the files matching the filter, in the state at the given time, into
+// an archive at the given path instead of onto the filesystem. See
+// BackupManager::restore_to_archive.
+pub fn restore_to_archive<'p, 's, C, SP, S>(backup_path: SP,
+                                            crypto_scheme: &C,
+                                            timestamp: u64,
+                                            filter: S,
+                                            options: &RestoreOptions,
+                                            format: ArchiveFormat,
+                                            out_path: &Path)
+                                            -> BonzoResult<RestorationSummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>,
+          S: IntoCow<'s, str>
+{
+    if out_path.is_dir() {
+        return Err(BonzoError::Other(format!("Archive destination {} is a directory",
+                                             out_path.display())));
     }
 
-    fn handle_new_file(&self, file: &FileComplete, summary: &mut BackupSummary) -> BonzoResult<()> {
-        // if file hash was already known, only add a new alias
-        if let file_id@Some(..) = try!(self.database.file_from_hash(&file.hash)) {
-            try!(self.database.persist_alias(
-                file.directory,
-                file_id,
-                &file.filename,
-                Some(file.last_modified)
-            ));
-
-            return Ok(summary.add_file());
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let backup_cow = backup_path.into_cow();
+    let database =
+        try!(open_index_at(&backup_cow, temp_directory.path(), crypto_scheme, timestamp));
+    let manager = try!(BackupManager::new(database, backup_cow.into_owned(), crypto_scheme));
+    let filter = filter.into_cow().into_owned();
+    let mut out = BufWriter::new(try_io!(File::create(out_path), out_path));
+
+    let summary = match format {
+        ArchiveFormat::Tar => {
+            try!(manager.restore_to_archive(timestamp, filter, options, &mut out, None))
         }
+        ArchiveFormat::TarGzip => {
+            let mut encoder = GzEncoder::new(&mut out, GzipLevel::Default);
+            let summary = try!(manager.restore_to_archive(timestamp,
+                                                          filter,
+                                                          options,
+                                                          &mut encoder,
+                                                          None));
 
-        let block_id_list: Vec<_> = try!(
-            file.block_reference_list
-            .iter()
-            .map(|reference| match *reference {
-                BlockReference::ById(id)         => Ok(id),
-                BlockReference::ByHash(ref hash) => {
-                    let id_option = try!(self.database.block_id_from_hash(hash));
-                    id_option.ok_or_else(|| {
-                        BonzoError::Other(format!("Could not find block with hash {:?}", hash))
-                    })
-                }
-            })
-            .collect()
-        );
+            try_io!(encoder.finish(), out_path);
 
-        try!(self.database.persist_file(
-            file.directory,
-            &file.filename,
-            &file.hash,
-            file.last_modified,
-            &block_id_list
-        ));
+            summary
+        }
+    };
 
-        summary.add_file();
+    try_io!(out.flush(), out_path);
 
-        Ok(())
-    }
+    Ok(summary)
+}
 
-    // Returns an error when the given password does not match the one saved
-    // in the index
-    fn check_password(&self) -> BonzoResult<()> {
-        let hash_opt = try!(self.database.get_key("password"));
-        let hash = try!(hash_opt.ok_or(BonzoError::from_str("Saved hash is NULL")));
+// Runs SQLite's integrity check on the working index of the given source
+// directory
+pub fn check_index<'p, SP: IntoCow<'p, Path>>(source_path: SP,
+                                              index_path: Option<&PathBuf>)
+                                              -> BonzoResult<IndexCheckSummary> {
+    let path = database_path(&source_path.into_cow(), index_path);
+    let database = try!(Database::from_file(path));
+    let problems = try!(database.integrity_check());
 
-        match self.crypto_scheme.hash_password() == hash {
-            true => Ok(()),
-            false => Err(BonzoError::from_str("Password is not the same as in database")),
-        }
-    }
+    Ok(IndexCheckSummary { problems: problems })
+}
 
-    // Remove old aliases and unused blocks from database and disk
-    fn cleanup(&self, max_age_milliseconds: u64) -> BonzoResult<CleanupSummary> {
-        let now = epoch_milliseconds();
+// Reports the storage used by every directory in the backup as it was at the
+// given timestamp
+pub fn du<'p, C, SP>(backup_path: SP,
+                     crypto_scheme: &C,
+                     timestamp: u64,
+                     policy: UsagePolicy)
+                     -> BonzoResult<Vec<(PathBuf, u64)>>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let backup_cow = backup_path.into_cow();
+    let database = try!(open_index(&backup_cow, temp_directory.path(), crypto_scheme));
+    let manager = try!(BackupManager::new(database, backup_cow.into_owned(), crypto_scheme));
 
-        let timestamp = match now < max_age_milliseconds {
-            true => 0,
-            false => now - max_age_milliseconds,
-        };
+    manager.disk_usage(timestamp, policy)
+}
 
-        let aliases = try!(self.database.remove_old_aliases(timestamp));
-        try!(self.database.remove_unused_files());
-        let (blocks, bytes) = try!(self.clean_unused_blocks());
+// Restores the last `count` distinct versions of the file at the given
+// relative path into the destination directory
+pub fn restore_versions<'p, C, SP>(backup_path: SP,
+                                   dest: SP,
+                                   crypto_scheme: &C,
+                                   relative_path: &Path,
+                                   count: usize)
+                                   -> BonzoResult<RestorationSummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    let dest_cow = dest.into_cow();
 
-        Ok(CleanupSummary { aliases: aliases, blocks: blocks, bytes: bytes })
-    }
+    try!(check_output_directory(&dest_cow));
 
-    // Returns the number of unused blocks and the total number of bytes within.
-    fn clean_unused_blocks(&self) -> BonzoResult<(u64, u64)> {
-        let unused_block_list = try!(self.database.get_unused_blocks());
-        let block_count = unused_block_list.len();
-        let mut bytes = 0;
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let database = try!(open_index(&backup_path.into_cow(), temp_directory.path(), crypto_scheme));
+    let manager = try!(BackupManager::new(database, dest_cow.to_path_buf(), crypto_scheme));
 
-        for (id, hash) in unused_block_list {
-            let path = block_output_path(&self.backup_path, &hash);
+    manager.restore_versions(&dest_cow, relative_path, count)
+}
 
-            // Do not err when the file was already removed. We may need to
-            // revisit this decision later as it is indicative of potential
-            // issues.
-            if !path.exists() {
-                continue;
-            }
+// Restores a single file from the backup at the given location to the given
+// output path
+pub fn restore_single<'p, C, SP>(backup_path: SP,
+                                 crypto_scheme: &C,
+                                 source_relative: &Path,
+                                 timestamp: u64,
+                                 out_path: &Path)
+                                 -> BonzoResult<RestorationSummary>
+    where C: CryptoScheme,
+          SP: IntoCow<'p, Path>
+{
+    if out_path.is_dir() {
+        return Err(BonzoError::Other(format!("Restore destination {} is a directory",
+                                             out_path.display())));
+    }
 
-            bytes += try_io!(metadata(&path), &path).len();
-            try_io!(remove_file(&path), &path);
-            try!(self.database.remove_block(id));
-        }
+    let temp_directory = try!(TempDir::new("bonzo"));
+    let database = try!(open_index(&backup_path.into_cow(), temp_directory.path(), crypto_scheme));
+    let output_directory = out_path.parent().unwrap_or(Path::new(".")).to_owned();
+    let manager = try!(BackupManager::new(database, output_directory, crypto_scheme));
 
-        Ok((block_count as u64, bytes))
-    }
+    manager.restore_single(source_relative, timestamp, out_path)
+}
 
-    // Closes the database connection and saves it to the backup destination in
-    // encrypted form
-    fn export_index(self) -> BonzoResult<()> {
-        let bytes = try!(self.database.to_bytes());
-        let procesed_bytes = try!(process_block(&bytes, &*self.crypto_scheme));
-        let new_index = self.backup_path.join("index-new");
-        let index = self.backup_path.join("index");
+pub fn epoch_milliseconds() -> u64 {
+    let stamp = get_time();
 
-        try_io!(write_to_disk(&new_index, &procesed_bytes), &new_index);
-        try_io!(copy(&new_index, &index), &new_index);
+    stamp.nsec as u64 / 1000 / 1000 + stamp.sec as u64 * 1000
+}
 
-        Ok(try_io!(remove_file(&new_index), new_index))
-    }
+fn duration_milliseconds(duration: Duration) -> u64 {
+    duration.as_secs()
+            .saturating_mul(1000)
+            .saturating_add(duration.subsec_nanos() as u64 / 1000 / 1000)
 }
 
-// TODO: move this to main.rs
-pub fn init<C: CryptoScheme, P: AsRef<Path>>(source_path: &P,
-                                             backup_path: &P,
-                                             crypto_scheme: &C)
-                                             -> BonzoResult<InitSummary> {
-    let database_path = source_path.as_ref().join(DATABASE_FILENAME);
-    let database = try!(Database::create(database_path));
-    let hash = crypto_scheme.hash_password();
+// Lists the restore points of the given backups, as recorded in the index
+// with their start and end, from alias timestamps, both given oldest first
+// along with their number of aliases. A backup which never completed lasted
+// until the next one started, or until its last alias. Aliases from before
+// the first backup recorded are grouped by the given gap.
+fn list_restore_points(backups: &[(u64, Option<u64>)],
+                       timestamps: &[(u64, u64)],
+                       gap: u64)
+                       -> Vec<(u64, u64)> {
+    let first_start = backups.first().map_or(std::u64::MAX, |&(started, _)| started);
+    let unrecorded: Vec<(u64, u64)> = timestamps.iter()
+                                                .cloned()
+                                                .filter(|&(timestamp, _)| timestamp < first_start)
+                                                .collect();
+    let mut points = group_restore_points(&unrecorded, gap);
+
+    for (index, &(started, finished)) in backups.iter().enumerate() {
+        let next_start = backups.get(index + 1).map(|&(next, _)| next);
+        let (count, last) = timestamps.iter()
+                                      .filter(|&&(timestamp, _)| {
+                                          timestamp >= started &&
+                                          match (finished, next_start) {
+                                              (Some(end), _) => timestamp <= end,
+                                              (None, Some(next)) => timestamp < next,
+                                              (None, None) => true,
+                                          }
+                                      })
+                                      .fold((0, started), |(count, _), &(timestamp, aliases)| {
+                                          (count + aliases, timestamp)
+                                      });
+
+        points.push((finished.unwrap_or(last), count));
+    }
 
-    try!(database.setup());
-    try!(database.set_key("password", &hash));
+    points
+}
 
-    let encoded_backup_path = try!(encode_path(backup_path));
+// Merges alias timestamps, given oldest first along with their number of
+// aliases, into groups in which consecutive timestamps are at most the given
+// gap apart. Every group is represented by its last timestamp and the total
+// number of aliases within.
+fn group_restore_points(timestamps: &[(u64, u64)], gap: u64) -> Vec<(u64, u64)> {
+    let mut points: Vec<(u64, u64)> = Vec::new();
 
-    try!(database.set_key("backup_path", &encoded_backup_path));
+    for &(timestamp, count) in timestamps {
+        let same_backup = points.last().map(|&(last, _)| timestamp - last <= gap).unwrap_or(false);
 
-    Ok(InitSummary)
-}
+        match same_backup {
+            true => {
+                let point = points.last_mut().unwrap();
 
-fn create_parent_dir(path: &Path) -> BonzoResult<()> {
-    let parent = try!(path.parent().ok_or(BonzoError::from_str("Couldn't get parent directory")));
+                point.0 = timestamp;
+                point.1 += count;
+            }
+            false => points.push((timestamp, count)),
+        }
+    }
 
-    Ok(try_io!(create_dir_all(parent), path))
+    points
 }
 
-// Takes a path, turns it into an absolute path if necessary
-fn encode_path<P: AsRef<Path>>(path: &P) -> io::Result<String> {
-    if path.as_ref().is_relative() {
-        let mut cwd = try!(current_dir());
-        cwd.push(path);
+// Returns the moment before which old versions of files are removed by a
+// cleanup
+fn cleanup_timestamp(max_age_milliseconds: u64) -> u64 {
+    let now = epoch_milliseconds();
 
-        return Ok(cwd.to_string_lossy().into_owned())
+    match now < max_age_milliseconds {
+        true => 0,
+        false => now - max_age_milliseconds,
     }
+}
 
-    Ok(path.as_ref().to_string_lossy().into_owned())
+// Opens the most recent index stored at the backup location, decrypted into
+// the given directory. See open_index_at.
+fn open_index<C: CryptoScheme>(backup_path: &Path,
+                               temp_dir: &Path,
+                               crypto_scheme: &C)
+                               -> BonzoResult<Database> {
+    open_index_at(backup_path, temp_dir, crypto_scheme, std::u64::MAX)
 }
 
-fn decode_path<P: AsRef<Path>>(path: &P) -> PathBuf {
-    PathBuf::from(path.as_ref())
+// Opens the index best suited for reading the state of the backup at the
+// given time, decrypted into the given directory. Like any block, the index
+// is rejected when it has been tampered with, and like any index, when it
+// fails the integrity check. It is brought up to date and opened read-only,
+// as nothing reading a backup ever changes its index.
+fn open_index_at<C: CryptoScheme>(backup_path: &Path,
+                                  temp_dir: &Path,
+                                  crypto_scheme: &C,
+                                  timestamp: u64)
+                                  -> BonzoResult<Database> {
+    let decrypted_index_path = try!(decrypt_index_at(backup_path,
+                                                     temp_dir,
+                                                     crypto_scheme,
+                                                     timestamp));
+
+    Ok(try!(Database::open_read_only(decrypted_index_path)))
 }
 
-pub fn backup<'p, C: CryptoScheme, SP: IntoCow<'p, Path>>(source_path: SP,
-                                                          block_bytes: usize,
-                                                          crypto_scheme: &C,
-                                                          max_age_milliseconds: u64,
-                                                          deadline: time::Tm)
-                                                          -> BonzoResult<BackupSummary> {
-    let source_cow = source_path.into_cow();
-    let database_path = source_cow.join(DATABASE_FILENAME);
-    let database = try!(Database::from_file(database_path));
-    let mut manager = try!(BackupManager::new(database, source_cow.into_owned(), crypto_scheme));
-    let mut summary = try!(manager.update(block_bytes, deadline));
+// Decrypts the index best suited for restoring the state at the given time.
+// The snapshots taken at or after that time are tried first, oldest first, as
+// they are the least affected by the removal of old versions. Next is the live
+// index, followed by the older snapshots, newest first. The first which can be
+// decrypted and passes the integrity check is used.
+fn decrypt_index_at<C: CryptoScheme>(backup_path: &Path,
+                                     temp_dir: &Path,
+                                     crypto_scheme: &C,
+                                     timestamp: u64)
+                                     -> BonzoResult<PathBuf> {
+    let decrypted_index_path = temp_dir.join(DATABASE_FILENAME);
 
-    if !summary.timeout {
-        let cleanup_summary = try!(manager.cleanup(max_age_milliseconds));
-        summary.add_cleanup_summary(cleanup_summary);
-    }
+    // remote storage keeps no snapshots
+    if is_remote(backup_path) {
+        let storage = try!(Storage::open(vec![backup_path.to_owned()], DEFAULT_SHARD_DEPTH));
 
-    try!(manager.export_index());
+        try!(decode_index(&try!(storage.read_index()), crypto_scheme, &decrypted_index_path));
+        try!(prepare_decrypted_index(&decrypted_index_path));
 
-    Ok(summary)
-}
+        return Ok(decrypted_index_path);
+    }
 
-pub fn restore<'p, 's, C: CryptoScheme, SP: IntoCow<'p, Path>, S: IntoCow<'s, str>>
-    (source_path: SP,
-     backup_path: SP,
-     crypto_scheme: &C,
-     timestamp: u64,
-     filter: S)
-     -> BonzoResult<RestorationSummary> {
-    let temp_directory = try!(TempDir::new("bonzo"));
-    let decrypted_index_path =
-        try!(decrypt_index(&backup_path.into_cow(), temp_directory.path(), crypto_scheme));
-    let database = try!(Database::from_file(decrypted_index_path));
-    let manager =
-        try!(BackupManager::new(database, source_path.into_cow().into_owned(), crypto_scheme));
+    let snapshots = try!(index_snapshots(backup_path));
+    let split = snapshots.iter()
+                         .position(|&(taken, _)| taken >= timestamp)
+                         .unwrap_or(snapshots.len());
+    let candidates: Vec<PathBuf> = snapshots[split..]
+                                       .iter()
+                                       .map(|&(_, ref path)| path.clone())
+                                       .chain(Some(backup_path.join("index")))
+                                       .chain(snapshots[..split]
+                                                  .iter()
+                                                  .rev()
+                                                  .map(|&(_, ref path)| path.clone()))
+                                       .collect();
+    let mut last_error = None;
+
+    for path in candidates {
+        let decoded = read_file(&path).map_err(From::from).and_then(|contents| {
+            decode_index(&contents, crypto_scheme, &decrypted_index_path)
+        });
+
+        match decoded {
+            Ok(()) => {
+                match prepare_decrypted_index(&decrypted_index_path) {
+                    Ok(()) => return Ok(decrypted_index_path),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
 
-    manager.restore(timestamp, filter.into_cow().into_owned())
+    Err(last_error.unwrap_or(BonzoError::from_str("Could not find an index")))
 }
 
-pub fn epoch_milliseconds() -> u64 {
-    let stamp = get_time();
+// Decrypts an index to the given path. The index of a backup which predates
+// the authentication of blocks may be in an older format. Such an index is
+// only accepted when it does not belong to a backup which authenticates its
+// blocks, as it could otherwise be an authenticated index stripped of its
+// authentication code.
+fn decode_index<C: CryptoScheme>(contents: &[u8],
+                                 crypto_scheme: &C,
+                                 path: &Path)
+                                 -> BonzoResult<()> {
+    let (bytes, authenticated) = match decode_block(contents, crypto_scheme) {
+        Ok(bytes) => (bytes, true),
+        Err(..) => (try!(decode_block(contents, &crypto_scheme.with_legacy_formats())), false),
+    };
+
+    try_io!(write_to_disk(path, &bytes), path);
+
+    if !authenticated {
+        let database = try!(Database::from_file(path.to_owned()));
+
+        if try!(database.get_key("block_authentication")).is_some() {
+            return Err(BonzoError::from_str("Index is not authenticated"));
+        }
+    }
 
-    stamp.nsec as u64 / 1000 / 1000 + stamp.sec as u64 * 1000
+    Ok(())
 }
 
-fn decrypt_index<C: CryptoScheme>(backup_path: &Path,
-                                  temp_dir: &Path,
-                                  crypto_scheme: &C)
-                                  -> BonzoResult<PathBuf> {
-    let decrypted_index_path = temp_dir.join(DATABASE_FILENAME);
-    let bytes = try!(load_processed_block(&backup_path.join("index"), crypto_scheme));
+// Runs SQLite's integrity check on a decrypted index, so that a damaged index
+// is rejected before anything is restored from it. It is then brought up to
+// date and marked read-only, as restores only ever read from it.
+fn prepare_decrypted_index(path: &Path) -> BonzoResult<()> {
+    {
+        let database = try!(Database::from_file(path.to_owned()));
+
+        try!(database.check_integrity());
+        try!(database.migrate());
+    }
 
-    try_io!(write_to_disk(&decrypted_index_path, &bytes), &decrypted_index_path);
+    let mut permissions = try_io!(metadata(path), path).permissions();
+    permissions.set_readonly(true);
 
-    Ok(decrypted_index_path)
+    Ok(try_io!(set_permissions(path, permissions), path))
 }
 
-fn load_processed_block<C: CryptoScheme>(path: &Path, crypto_scheme: &C) -> BonzoResult<Vec<u8>> {
-    let contents: Vec<u8> = try!(
-        File::open(path).and_then(|mut file| {
-            let mut buffer = Vec::new();
-            try!(file.read_to_end(&mut buffer));
-            Ok(buffer)
-        })
-    );
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
 
-    let decrypted_bytes = try!(crypto_scheme.decrypt_block(&contents));
-    let mut decompressor = BzDecompressor::new(BufReader::new(&decrypted_bytes[..]));
+    try!(File::open(path).and_then(|mut file| file.read_to_end(&mut buffer)));
 
-    let mut buffer = Vec::new();
-    try!(decompressor.read_to_end(&mut buffer));
     Ok(buffer)
 }
 
-fn block_output_path(base_path: &Path, hash: &[u8]) -> PathBuf {
-    let hex = hash.to_hex();
-    let mut path = base_path.join(&hex[0..2]);
+// Decrypts and inflates a block or index as read from the backend, reversing
+// process_block
+fn decode_block<C: CryptoScheme>(contents: &[u8], crypto_scheme: &C) -> BonzoResult<Vec<u8>> {
+    decode_block_from(&mut &contents[..], contents.len() as u64, crypto_scheme)
+}
 
-    path.push(hex);
+// Like decode_block, but reads a block of the given length from the source.
+// When the cipher supports it, large blocks are decrypted as they are read,
+// into a buffer of their own size which the clear text never exceeds, so that
+// the encrypted block is never held in memory.
+fn decode_block_from<C: CryptoScheme>(source: &mut Read,
+                                      length: u64,
+                                      crypto_scheme: &C)
+                                      -> BonzoResult<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(length as usize);
+    let streamed = crypto_scheme.streams() && length > STREAMING_THRESHOLD as u64;
+    let decrypted_bytes = match streamed {
+        true => {
+            try!(crypto_scheme.decrypt_stream(source, &mut buffer));
+            buffer
+        }
+        false => {
+            try!(source.read_to_end(&mut buffer));
+            try!(crypto_scheme.decrypt_block(&buffer))
+        }
+    };
 
-    path
+    decompress(&decrypted_bytes)
 }
 
 fn write_to_disk(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    write_to_disk_throttled(path, bytes, None, true)
+}
+
+// Unless sync is set, the bytes may still be in the page cache when this
+// returns
+fn write_to_disk_throttled(path: &Path,
+                           bytes: &[u8],
+                           throttle: Option<&Throttle>,
+                           sync: bool)
+                           -> io::Result<()> {
     let mut file = try!(File::create(path));
 
-    try!(file.write_all(bytes));
-    try!(file.sync_all());
+    try!(write_throttled(throttle, &mut file, bytes));
+
+    if sync {
+        try!(file.sync_all());
+    }
 
     set_file_times(path, filetime::FileTime::zero(), filetime::FileTime::zero())
 }
@@ -447,15 +2725,18 @@ fn write_to_disk(path: &Path, bytes: &[u8]) -> io::Result<()> {
 mod test {
     use std::io::{Read, Write, BufReader};
     use std::fs::{create_dir_all, File, copy};
+    use std::time::Duration;
 
     use super::tempdir::TempDir;
     use super::rand::{Rng, OsRng};
     use super::bzip2::reader::{BzDecompressor, BzCompressor};
     use super::bzip2::Compress;
-    use super::crypto::hash_file;
-    use super::{write_to_disk, block_output_path, init, backup, restore, epoch_milliseconds,
-                BonzoError};
-    use super::time;
+    use super::crypto::{CryptoScheme, hash_file};
+    use super::compression::{compress, Compression, CompressionLevel};
+    use super::export::process_block;
+    use super::backend::block_output_path;
+    use super::{write_to_disk, read_file, decode_block, init, backup, restore, epoch_milliseconds,
+                restore_with_options, BonzoError, BonzoErrorKind, BackupOptions, RestoreOptions};
 
     // It can happen that a block is (partially) written, but not persisted to database
     // Therefore, backbonzo will retry to write this block. this should not err
@@ -470,7 +2751,7 @@ mod test {
         write_to_disk(&in_path, bytes).ok().expect("write input");
 
         let hash = hash_file(&in_path).ok().expect("compute hash");
-        let out_path = block_output_path(dest_dir.path(), &hash);
+        let out_path = block_output_path(dest_dir.path(), &hash, 1);
 
         create_dir_all(&out_path.parent().unwrap()).ok().expect("created dir");
 
@@ -479,11 +2760,13 @@ mod test {
             Err(e) => panic!("{:?}", e.to_string()),
         }
 
-        let deadline = time::now() + time::Duration::seconds(30);
+        let time_limit = Duration::from_secs(30);
         let crypto_scheme = super::crypto::AesEncrypter::new("passwerd");
 
         init(&source_dir.path(), &dest_dir.path(), &crypto_scheme).ok().expect("init ok");
-        backup(source_dir.path(), 1_000_000, &crypto_scheme, 0, deadline)
+        backup(source_dir.path(),
+               &crypto_scheme,
+               &BackupOptions::new(1_000_000, 0, time_limit))
             .ok()
             .expect("backup successful");
     }
@@ -502,18 +2785,20 @@ mod test {
         write_to_disk(&file_one_path, file_one_content).ok().expect("write input file one ");
         write_to_disk(&file_two_path, file_two_content).ok().expect("write input file two");
 
-        let deadline = time::now() + time::Duration::seconds(30);
+        let time_limit = Duration::from_secs(30);
         let crypto_scheme = super::crypto::AesEncrypter::new("passwerd");
 
         init(&source_dir.path(), &dest_dir.path(), &crypto_scheme).ok().expect("init ok");
-        backup(source_dir.path(), 1_000_000, &crypto_scheme, 0, deadline)
+        backup(source_dir.path(),
+               &crypto_scheme,
+               &BackupOptions::new(1_000_000, 0, time_limit))
             .ok()
             .expect("backup successful");
 
         let file_one_hash = hash_file(&file_one_path).ok().expect("compute hash");
         let file_two_hash = hash_file(&file_two_path).ok().expect("compute hash");
-        let file_one_out_path = block_output_path(dest_dir.path(), &file_one_hash);
-        let file_two_out_path = block_output_path(dest_dir.path(), &file_two_hash);
+        let file_one_out_path = block_output_path(dest_dir.path(), &file_one_hash, 1);
+        let file_two_out_path = block_output_path(dest_dir.path(), &file_two_hash, 1);
 
         copy(file_one_out_path, file_two_out_path).ok().expect("copy files");
 
@@ -525,11 +2810,173 @@ mod test {
                              "**".to_string());
 
         let is_expected = match result {
-            Err(BonzoError::Other(ref str)) => &str[..] == "Block integrity check failed",
+            Err(BonzoError::CorruptBlock) => true,
             _ => false,
         };
 
         assert!(is_expected);
+
+        let mut options = RestoreOptions::new();
+        options.ignore_integrity = true;
+
+        let lenient_dir = TempDir::new("integ-lenient").unwrap();
+        let summary = restore_with_options(lenient_dir.path(),
+                                           dest_dir.path(),
+                                           &crypto_scheme,
+                                           epoch_milliseconds(),
+                                           "**".to_string(),
+                                           &options)
+                          .unwrap();
+
+        assert_eq!(2, summary.summary.files);
+        assert_eq!(1, summary.corrupted_blocks);
+    }
+
+    // A backup may contain blocks compressed with different algorithms, as
+    // well as untagged blocks written by older versions
+    #[test]
+    fn mixed_compression() {
+        let file_one_content = b"this block will be rewritten in the legacy format";
+        let file_two_content = b"and this one will be stored without compression";
+
+        let source_dir = TempDir::new("mixed-source").unwrap();
+        let dest_dir = TempDir::new("mixed-dest").unwrap();
+        let file_one_path = source_dir.path().join("file-one");
+        let file_two_path = source_dir.path().join("file-two");
+
+        write_to_disk(&file_one_path, file_one_content).unwrap();
+        write_to_disk(&file_two_path, file_two_content).unwrap();
+
+        let time_limit = Duration::from_secs(30);
+        let crypto_scheme = super::crypto::AesEncrypter::new("passwerd");
+
+        init(&source_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+        backup(source_dir.path(),
+               &crypto_scheme,
+               &BackupOptions::new(1_000_000, 0, time_limit))
+            .unwrap();
+
+        let mut compressor = BzCompressor::new(&file_one_content[..], Compress::Best);
+        let mut legacy_bytes = Vec::new();
+        compressor.read_to_end(&mut legacy_bytes).unwrap();
+
+        let stored_bytes = compress(file_two_content, Compression::Stored).unwrap();
+
+        let rewrites = [(&file_one_path, legacy_bytes), (&file_two_path, stored_bytes)];
+
+        for &(path, ref bytes) in rewrites.iter() {
+            let hash = hash_file(path).unwrap();
+            let encrypted = crypto_scheme.encrypt_block(bytes).unwrap();
+
+            write_to_disk(&block_output_path(dest_dir.path(), &hash, 1), &encrypted).unwrap();
+        }
+
+        let restore_dir = TempDir::new("mixed-restore").unwrap();
+
+        restore(restore_dir.path(),
+                dest_dir.path(),
+                &crypto_scheme,
+                epoch_milliseconds(),
+                "**".to_string())
+            .unwrap();
+
+        let expected = [("file-one", &file_one_content[..]), ("file-two", &file_two_content[..])];
+
+        for &(name, content) in expected.iter() {
+            let mut buffer = Vec::new();
+            File::open(restore_dir.path().join(name)).unwrap().read_to_end(&mut buffer).unwrap();
+
+            assert_eq!(content, &buffer[..]);
+        }
+    }
+
+    #[test]
+    fn compression_level() {
+        let contents = b"log line, log line, log line, log line, log line, log line, log line";
+        let source_dir = TempDir::new("level-source").unwrap();
+        let dest_dir = TempDir::new("level-dest").unwrap();
+
+        write_to_disk(&source_dir.path().join("log"), contents).unwrap();
+
+        let time_limit = Duration::from_secs(30);
+        let crypto_scheme = super::crypto::AesEncrypter::new("passwerd");
+        let mut options = BackupOptions::new(1_000_000, 0, time_limit);
+        options.compression_level = CompressionLevel::Fast;
+
+        init(&source_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+        backup(source_dir.path(), &crypto_scheme, &options).unwrap();
+
+        let database_path = source_dir.path().join(super::DATABASE_FILENAME);
+        let database = super::Database::from_file(database_path).unwrap();
+
+        assert_eq!(Some("fast".to_owned()), database.get_key("compression_level").unwrap());
+
+        let restore_dir = TempDir::new("level-restore").unwrap();
+
+        restore(restore_dir.path(),
+                dest_dir.path(),
+                &crypto_scheme,
+                epoch_milliseconds(),
+                "**".to_string())
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        File::open(restore_dir.path().join("log")).unwrap().read_to_end(&mut buffer).unwrap();
+
+        assert_eq!(&contents[..], &buffer[..]);
+    }
+
+    // Blocks which decrypt fine but fail to decompress are an error, or left
+    // out of their file when integrity is ignored
+    #[test]
+    fn failed_decompression() {
+        let source_dir = TempDir::new("inflate-source").unwrap();
+        let dest_dir = TempDir::new("inflate-dest").unwrap();
+        let file_one_path = source_dir.path().join("file-one");
+        let file_two_path = source_dir.path().join("file-two");
+
+        write_to_disk(&file_one_path, b"this block will not decompress").unwrap();
+        write_to_disk(&file_two_path, b"but this one is fine").unwrap();
+
+        let time_limit = Duration::from_secs(30);
+        let crypto_scheme = super::crypto::AesEncrypter::new("passwerd");
+
+        init(&source_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+        backup(source_dir.path(),
+               &crypto_scheme,
+               &BackupOptions::new(1_000_000, 0, time_limit))
+            .unwrap();
+
+        let hash = hash_file(&file_one_path).unwrap();
+        let garbage = [Compression::Gzip.tag(), 1, 2, 3, 4, 5];
+        let encrypted = crypto_scheme.encrypt_block(&garbage).unwrap();
+
+        write_to_disk(&block_output_path(dest_dir.path(), &hash, 1), &encrypted).unwrap();
+
+        let restore_dir = TempDir::new("inflate-restore").unwrap();
+        let result = restore(restore_dir.path(),
+                             dest_dir.path(),
+                             &crypto_scheme,
+                             epoch_milliseconds(),
+                             "**".to_string());
+
+        assert_eq!(BonzoErrorKind::Decompression, result.unwrap_err().kind());
+
+        let mut options = RestoreOptions::new();
+        options.ignore_integrity = true;
+
+        let lenient_dir = TempDir::new("inflate-lenient").unwrap();
+        let summary = restore_with_options(lenient_dir.path(),
+                                           dest_dir.path(),
+                                           &crypto_scheme,
+                                           epoch_milliseconds(),
+                                           "**".to_string(),
+                                           &options)
+                          .unwrap();
+
+        assert_eq!(2, summary.summary.files);
+        assert_eq!(1, summary.failed_decompressions);
+        assert_eq!(0, lenient_dir.path().join("file-one").metadata().unwrap().len());
     }
 
     #[test]
@@ -539,17 +2986,112 @@ mod test {
         let file_path = dir.path().join("hash.txt");
         let crypto_scheme = super::crypto::AesEncrypter::new("test1234");
 
-        let processed_bytes = super::export::process_block(bytes, &crypto_scheme).unwrap();
+        let processed_bytes =
+            super::export::process_block(bytes, Compression::Gzip, &crypto_scheme).unwrap();
 
         let mut file = File::create(&file_path).unwrap();
         assert!(file.write_all(&processed_bytes).is_ok());
         assert!(file.sync_all().is_ok());
 
-        let retrieved_bytes = super::load_processed_block(&file_path, &crypto_scheme).unwrap();
+        let retrieved_bytes = decode_block(&read_file(&file_path).unwrap(), &crypto_scheme)
+                                  .unwrap();
 
         assert_eq!(&bytes[..], &retrieved_bytes[..]);
     }
 
+    #[test]
+    fn restore_to_writer() {
+        let source_dir = TempDir::new("writer-source").unwrap();
+        let dest_dir = TempDir::new("writer-dest").unwrap();
+        let contents = b"streamed straight from the backup";
+
+        write_to_disk(&source_dir.path().join("file"), contents).unwrap();
+
+        let time_limit = Duration::from_secs(30);
+        let crypto_scheme = super::crypto::AesEncrypter::new("passwerd");
+
+        init(&source_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+        backup(source_dir.path(), &crypto_scheme, &BackupOptions::new(10, 0, time_limit)).unwrap();
+
+        let database_path = source_dir.path().join(super::DATABASE_FILENAME);
+        let database = super::Database::from_file(database_path).unwrap();
+        let manager = super::BackupManager::new(database,
+                                                source_dir.path().to_owned(),
+                                                &crypto_scheme)
+                          .unwrap();
+        let (file_id, _) = manager.database
+                                  .get_file_at(super::Directory::Root, "file", epoch_milliseconds())
+                                  .unwrap()
+                                  .unwrap();
+        let block_list = manager.database.get_file_block_list(file_id).unwrap();
+        let mut buffer = Vec::new();
+
+        assert!(block_list.len() > 1);
+        assert_eq!(contents.len() as u64,
+                   manager.restore_to_writer(&block_list, &mut buffer).unwrap());
+        assert_eq!(&contents[..], &buffer[..]);
+    }
+
+    // Blocks above the streaming threshold are compressed, encrypted and
+    // decrypted as streams. Those which do not compress are stored instead.
+    #[test]
+    fn large_blocks() {
+        let zeroes = vec![0; super::STREAMING_THRESHOLD + 1];
+        let mut bytes = zeroes.clone();
+        let crypto_scheme = super::crypto::AesEncrypter::new("passwerd");
+
+        OsRng::new().ok().unwrap().fill_bytes(&mut bytes);
+
+        let processed = process_block(&bytes, Compression::Gzip, &crypto_scheme).unwrap();
+
+        assert!(processed.len() > super::STREAMING_THRESHOLD);
+        assert_eq!(bytes, decode_block(&processed, &crypto_scheme).unwrap());
+
+        let processed = process_block(&zeroes, Compression::Gzip, &crypto_scheme).unwrap();
+
+        assert!(processed.len() < super::STREAMING_THRESHOLD);
+        assert_eq!(zeroes, decode_block(&processed, &crypto_scheme).unwrap());
+    }
+
+    #[test]
+    fn corrupted_index() {
+        let source_dir = TempDir::new("corrupt-index-source").unwrap();
+        let dest_dir = TempDir::new("corrupt-index-dest").unwrap();
+        let restore_dir = TempDir::new("corrupt-index-restore").unwrap();
+
+        write_to_disk(&source_dir.path().join("file"), b"never restored").unwrap();
+
+        let time_limit = Duration::from_secs(30);
+        let crypto_scheme = super::crypto::AesEncrypter::new("passwerd");
+
+        init(&source_dir.path(), &dest_dir.path(), &crypto_scheme).unwrap();
+        backup(source_dir.path(),
+               &crypto_scheme,
+               &BackupOptions::new(1000, 0, time_limit))
+            .unwrap();
+
+        // damage every page but the first, so that the index still opens and
+        // decrypts fine
+        let database_path = source_dir.path().join(super::DATABASE_FILENAME);
+        let mut bytes = super::read_file(&database_path).unwrap();
+        let page_size = ((bytes[16] as usize) << 8) | bytes[17] as usize;
+
+        for byte in bytes[page_size..].iter_mut() {
+            *byte = 0xaa;
+        }
+
+        let processed = process_block(&bytes, Compression::Bzip2, &crypto_scheme).unwrap();
+        write_to_disk(&dest_dir.path().join("index"), &processed).unwrap();
+
+        assert!(restore(restore_dir.path(),
+                        dest_dir.path(),
+                        &crypto_scheme,
+                        epoch_milliseconds(),
+                        "**")
+                    .is_err());
+        assert!(!restore_dir.path().join("file").exists());
+    }
+
     #[test]
     fn write_file() {
         let temp_dir = TempDir::new("write-test").unwrap();
@@ -586,4 +3128,22 @@ mod test {
             assert_eq!(slice, &decompressed_bytes[..]);
         }
     }
+
+    #[test]
+    fn restore_points() {
+        let timestamps = [(1000, 3), (1500, 1), (2500, 2), (9000, 1), (9100, 4)];
+
+        assert_eq!(vec![(2500, 6), (9100, 5)],
+                   super::group_restore_points(&timestamps, 1000));
+        assert_eq!(vec![(9100, 11)], super::group_restore_points(&timestamps, 10000));
+        assert!(super::group_restore_points(&[], 1000).is_empty());
+
+        // recorded backups are listed by their end, and earlier aliases are
+        // still grouped
+        let backups = [(2000, Some(2600)), (8000, None), (9050, None)];
+
+        assert_eq!(vec![(1500, 4), (2600, 2), (9000, 1), (9100, 4)],
+                   super::list_restore_points(&backups, &timestamps, 1000));
+        assert_eq!(vec![(9100, 11)], super::list_restore_points(&[], &timestamps, 10000));
+    }
 }
@@ -4,35 +4,139 @@
 extern crate rustc_serialize;
 extern crate backbonzo;
 extern crate docopt;
-extern crate time;
 extern crate termios;
 extern crate libc;
+extern crate glob;
+extern crate time;
 
 use docopt::Docopt;
-use std::path::PathBuf;
+use glob::Pattern;
+use std::path::{Path, PathBuf};
 use std::error::Error;
-use time::Duration;
+use std::time::Duration;
 use std::fmt::Display;
-use std::io::{Write, stderr, stdout, stdin};
-use backbonzo::{init, backup, restore, epoch_milliseconds, BonzoResult, AesEncrypter};
+use std::io::{BufRead, BufReader, Write, stderr, stdout, stdin};
+use std::process::exit;
+use std::fs::File;
+use std::env;
+use std::ffi::OsString;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use backbonzo::{init_with_options, init_and_backup, backup_with_progress, restore_versions,
+                manifests, prune, prune_orphans, sync_repos, change_password, stats, du,
+                check_index, fsck, verify, list, list_with_sizes, restore_points,
+                restore_with_progress, restore_to_archive, restore_single, cat, diff,
+                epoch_milliseconds, source_key_params, backup_key_params, source_cipher,
+                backup_cipher, BonzoResult,
+                BackupSummary, Cipher, InitOptions, BackupOptions, RestoreOptions, Overwrite,
+                UsagePolicy, KeySource, KeyCommand, Keyfile, KeyParams, Passphrase, HashAlgorithm,
+                Compression, CompressionLevel, Chunking, SymlinkPolicy, ArchiveFormat, Progress,
+                ProgressEvent, MAX_CHUNK_SIZE};
+
+// Environment variable the passphrase may be passed in, for unattended use
+static PASSPHRASE_VARIABLE: &'static str = "BACKBONZO_PASSPHRASE";
+
+// Formats of the dates accepted for --timestamp, tried in order
+static DATE_FORMATS: [&'static str; 4] = ["%Y-%m-%dT%H:%M:%S",
+                                          "%Y-%m-%d %H:%M:%S",
+                                          "%Y-%m-%dT%H:%M",
+                                          "%Y-%m-%d"];
+
+// Exit code of a backup which ran out of time, as opposed to the 1 of failures
+static TIMEOUT_EXIT_CODE: i32 = 2;
+
+// Set when the user interrupts a backup, which then stops like it timed out
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
 
 static USAGE: &'static str = "
 backbonzo
 
 Usage:
-  backbonzo init    -d <dest> [options]
-  backbonzo backup            [options]
+  backbonzo init    -d <dest> [-e <dest>...] [--and-backup] [options]
+  backbonzo backup            [--exclude-dir=<dir>...] [--exclude=<glob>...] [--include=<glob>...] [--root=<root>...] [--dry-run] [options]
   backbonzo restore -d <dest> [options]
+  backbonzo versions <path> -d <dest> --dest=<dir> [options]
+  backbonzo extract <path> -d <dest> -o <file> [options]
+  backbonzo manifest          [--rebuild] [options]
+  backbonzo prune             [--dry-run] [options]
+  backbonzo prune-orphans     [options]
+  backbonzo stats             [options]
+  backbonzo sync    -d <dest> [options]
+  backbonzo changepw          [options]
+  backbonzo du      -d <dest> [--total] [options]
+  backbonzo check-index [options]
+  backbonzo verify  -d <dest> [options]
+  backbonzo fsck              [--fix] [options]
+  backbonzo list    -d <dest> [--long] [options]
+  backbonzo backups -d <dest> [options]
+  backbonzo diff    -d <dest> -s <source> [--exclude-dir=<dir>...] [--exclude=<glob>...] [--include=<glob>...] [--root=<root>...] [options]
+  backbonzo cat     -d <dest> -f <exp> [options]
   backbonzo --help
 
+Exit status is 0 on success and 1 on errors. A backup which ran out of time before it
+was complete exits with 2.
+
 Options:
   -s --source=<source>       Source directory [default: ./].
-  -d --destination=<dest>    Backup directory.
+  -d --destination=<dest>    Backup directory, or sftp://[user@]host[:port]/path.
+  -e --extra=<dest>          Additional directory to spread blocks over.
   -b --blocksize=<bs>        Size of blocks in kilobytes [default: 1000].
-  -t --timestamp=<mseconds>  State to restore to in milliseconds since epoch [default: 0].
+  -t --timestamp=<when>      State to restore to: milliseconds since epoch, a date or "3 days ago" [default: 0].
   -T --timeout=<seconds>     Maximum execution time in seconds [default: 0].
   -f --filter=<exp>          Glob expression for paths to restore [default: **].
-  -a --age=<days>            Number of days to retain old data [default: 183].
+  -a --age=<age>             Time to retain old data: days, or a number followed by s, m, h, d or w [default: 183].
+  -n --count=<n>             Number of versions to restore [default: 5].
+  --dest=<dir>               Directory to restore the versions of a file to.
+  -o --output=<file>         Path to extract a single file to.
+  --rebuild                  Rebuild shard manifests and keep them up to date.
+  -i --index-db=<path>       Location of the working index [default: ].
+  --strict                   Abort the backup when a directory cannot be read.
+  --total                    Charge shared blocks to every file using them.
+  --long                     List the size and number of blocks of every file.
+  --resume                   Continue where a timed out backup left off.
+  --new-files-first          Back up files which are not in the backup yet before all others.
+  --fix                      Remove dangling rows from the working index.
+  --hardlinks                Restore identical files as hard links.
+  --ignore-integrity         Restore corrupted blocks and skip blocks which cannot be decompressed.
+  --overwrite=<mode>         Replace existing files: always, if-different, never [default: always].
+  --restore-manifest=<file>  List restored files with their hash, size and block count [default: ].
+  --block-cache=<mb>         Megabytes of shared blocks kept in memory while restoring [default: 64].
+  --format=<fmt>             Restore to files, or to a tar archive at the output path [default: files].
+  --gzip                     Compress the tar archive with gzip.
+  -k --key-command=<cmd>     Command printing the key in hexadecimal [default: ].
+  --keyfile=<path>           File whose contents are combined with the passphrase into the key [default: ].
+  --no-passphrase            Derive the key from the key file alone.
+  --passphrase-fd=<n>        Read the passphrase from this file descriptor instead of the terminal [default: ].
+  --batch-size=<n>           Number of index changes per transaction [default: 100].
+  --and-backup               Perform the first backup right after initialization.
+  -c --compression=<alg>     Compression for a new backup: bzip2, gzip or none [default: bzip2].
+  --compression-level=<lvl>  Compression level of new blocks: fast, default or best [default: best].
+  --chunking=<sizes>         Content-defined block sizes for a new backup as min,avg,max kilobytes.
+  --cipher=<name>            Cipher for a new backup [default: aes-256-cbc].
+  --hash=<alg>               Hash algorithm for a new backup: sha256 or blake2b [default: sha256].
+  --shard-depth=<n>          Directory levels blocks of a new backup are spread over [default: 1].
+  --iterations=<n>           Number of PBKDF2 iterations for a new backup [default: 100000].
+  --exclude-dir=<dir>        Absolute path of a directory to leave out of the backup.
+  --root=<root>              Additional source directory as name=path, backed up under name.
+  --exclude=<glob>           Pattern of file or directory names to leave out of the backup.
+  --include=<glob>           Pattern of file names to back up, leaving out all others. Excludes win.
+  --max-size=<kb>            Leave out files larger than this many kilobytes [default: 0].
+  --newer-than=<days>        Only back up files modified less than this many days ago [default: 0].
+  --older-than=<days>        Only back up files modified more than this many days ago [default: 0].
+  --max-rate=<kb>            Kilobytes written to the destination per second, 0 for no limit [default: 0].
+  --no-fsync                 Sync new blocks to disk all at once before writing the index.
+  --skip-empty-dirs          Do not record empty directories, so restores will not recreate them.
+  --symlinks=<policy>        Handle symlinks: skip, follow or store as links [default: skip].
+  --follow-symlinks          Same as --symlinks=follow.
+  --dereference-hardlinks    Read files with several hard links once and back up the other links as copies.
+  --xattrs                   Back up or restore the extended attributes of files.
+  --one-file-system          Leave out directories on which other filesystems are mounted.
+  --dry-run                  Report what would be backed up or pruned without writing anything.
+  -q --quiet                 Print no summary on success.
+  -v --verbose               Print every file as it is backed up or restored.
+  --progress                 Count the files to back up first, then show how many are done.
+  --threads=<n>              Number of threads processing files, 0 for one per CPU [default: 0].
+  --keep-indices=<n>         Number of timestamped copies of the index to keep [default: 0].
 ";
 
 #[derive(RustcDecodable, Debug)]
@@ -40,16 +144,109 @@ struct Args {
     pub cmd_init: bool,
     pub cmd_backup: bool,
     pub cmd_restore: bool,
+    pub cmd_versions: bool,
+    pub cmd_extract: bool,
+    pub cmd_manifest: bool,
+    pub cmd_prune: bool,
+    pub cmd_prune_orphans: bool,
+    pub cmd_stats: bool,
+    pub cmd_sync: bool,
+    pub cmd_changepw: bool,
+    pub cmd_du: bool,
+    pub cmd_check_index: bool,
+    pub cmd_fsck: bool,
+    pub cmd_verify: bool,
+    pub cmd_list: bool,
+    pub cmd_backups: bool,
+    pub cmd_diff: bool,
+    pub cmd_cat: bool,
+    pub arg_path: String,
     pub flag_destination: String,
+    pub flag_extra: Vec<String>,
     pub flag_source: String,
     pub flag_blocksize: u32,
-    pub flag_timestamp: u64,
+    pub flag_timestamp: String,
     pub flag_timeout: u64,
     pub flag_filter: String,
-    pub flag_age: u32
+    pub flag_age: String,
+    pub flag_count: usize,
+    pub flag_dest: String,
+    pub flag_output: String,
+    pub flag_rebuild: bool,
+    pub flag_index_db: String,
+    pub flag_strict: bool,
+    pub flag_total: bool,
+    pub flag_long: bool,
+    pub flag_resume: bool,
+    pub flag_new_files_first: bool,
+    pub flag_fix: bool,
+    pub flag_hardlinks: bool,
+    pub flag_ignore_integrity: bool,
+    pub flag_overwrite: String,
+    pub flag_restore_manifest: String,
+    pub flag_block_cache: usize,
+    pub flag_format: String,
+    pub flag_gzip: bool,
+    pub flag_key_command: String,
+    pub flag_keyfile: String,
+    pub flag_no_passphrase: bool,
+    pub flag_passphrase_fd: String,
+    pub flag_batch_size: usize,
+    pub flag_and_backup: bool,
+    pub flag_exclude_dir: Vec<String>,
+    pub flag_root: Vec<String>,
+    pub flag_exclude: Vec<String>,
+    pub flag_include: Vec<String>,
+    pub flag_max_size: u64,
+    pub flag_newer_than: u64,
+    pub flag_older_than: u64,
+    pub flag_max_rate: u64,
+    pub flag_dry_run: bool,
+    pub flag_no_fsync: bool,
+    pub flag_dereference_hardlinks: bool,
+    pub flag_xattrs: bool,
+    pub flag_one_file_system: bool,
+    pub flag_skip_empty_dirs: bool,
+    pub flag_symlinks: String,
+    pub flag_follow_symlinks: bool,
+    pub flag_threads: usize,
+    pub flag_keep_indices: usize,
+    pub flag_iterations: u32,
+    pub flag_compression: String,
+    pub flag_compression_level: String,
+    pub flag_chunking: String,
+    pub flag_cipher: String,
+    pub flag_hash: String,
+    pub flag_shard_depth: usize,
+    pub flag_quiet: bool,
+    pub flag_verbose: bool,
+    pub flag_progress: bool,
 }
 
-fn fetch_password() -> String {
+// How much is printed. Summaries go to standard output, everything else to
+// standard error.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+// Strips the line ending from a passphrase read from standard input, which
+// may be either \n or \r\n. Returns None when the passphrase is empty.
+fn trim_passphrase(line: &str) -> Option<&str> {
+    let trimmed = line.trim_right_matches(|c| c == '\n' || c == '\r');
+
+    match trimmed.is_empty() {
+        true => None,
+        false => Some(trimmed),
+    }
+}
+
+// Reads a passphrase from standard input without echoing it. Exits when no
+// passphrase was entered. The prompt is written to standard error, so that it
+// does not end up in the output of cat.
+fn fetch_password(prompt: &str) -> String {
     let optional_term = termios::Termios::from_fd(0).ok();
 
     if let Some(mut term) = optional_term {
@@ -58,60 +255,924 @@ fn fetch_password() -> String {
 
         termios::tcsetattr(0, termios::TCSANOW, &term).unwrap();
 
-        print!("Passphrase: ");
-        stdout().flush().unwrap();
+        let _ = write!(&mut stderr(), "{}", prompt);
     }
 
-    let mut password = String::new();
-    stdin().read_line(&mut password).unwrap();
+    let mut line = String::new();
+    let read_result = stdin().read_line(&mut line);
 
     if let Some(term) = optional_term {
         termios::tcsetattr(0, termios::TCSANOW, &term).unwrap();
     }
 
-    password.pop().unwrap();
+    let password = read_result.ok().and_then(|_| trim_passphrase(&line).map(|p| p.to_owned()));
 
-    password
+    match password {
+        Some(password) => password,
+        None => {
+            let _ = writeln!(&mut stderr(), "No passphrase given");
+            exit(1);
+        }
+    }
+}
+
+// Obtains the passphrase of the backup. The value of the environment variable
+// is used when it was set, followed by the first line of the given file
+// descriptor, so that it can be passed without a terminal.
+fn read_passphrase(args: &Args, from_environment: Option<OsString>) -> String {
+    if let Some(value) = from_environment {
+        return match value.to_str().and_then(trim_passphrase) {
+            Some(passphrase) => passphrase.to_owned(),
+            None => {
+                let _ = writeln!(&mut stderr(), "Invalid passphrase in {}", PASSPHRASE_VARIABLE);
+                exit(1);
+            }
+        };
+    }
+
+    if args.flag_passphrase_fd.is_empty() {
+        return fetch_password("Passphrase: ");
+    }
+
+    let fd = match args.flag_passphrase_fd.parse() {
+        Ok(fd) if fd >= 0 => fd,
+        _ => {
+            let _ = writeln!(&mut stderr(), "Invalid file descriptor: {}", args.flag_passphrase_fd);
+            exit(1);
+        }
+    };
+    let mut reader = BufReader::new(unsafe { File::from_raw_fd(fd) });
+    let mut line = String::new();
+
+    match reader.read_line(&mut line).ok().and_then(|_| trim_passphrase(&line)) {
+        Some(passphrase) => passphrase.to_owned(),
+        None => {
+            let _ = writeln!(&mut stderr(), "No passphrase read from file descriptor {}", fd);
+            exit(1);
+        }
+    }
 }
 
 fn main() {
     let args: Args = Docopt::new(USAGE)
                             .and_then(|d| d.decode())
                             .unwrap_or_else(|e| e.exit());
-    let password = fetch_password();
-    let crypto_scheme = AesEncrypter::new(&password);
+
+    // the passphrase is taken out of the environment right away, so that it
+    // is not passed on to any process we start, such as a key command
+    let environment_passphrase = env::var_os(PASSPHRASE_VARIABLE);
+    env::remove_var(PASSPHRASE_VARIABLE);
+
+    // reject absurd block sizes before asking for a passphrase
+    if args.flag_blocksize == 0 || args.flag_blocksize as usize > MAX_CHUNK_SIZE / 1000 {
+        let _ = writeln!(&mut stderr(),
+                         "Block size must be between 1 and {} kilobytes",
+                         MAX_CHUNK_SIZE / 1000);
+        exit(1);
+    }
+
+    if args.flag_iterations == 0 {
+        let _ = writeln!(&mut stderr(), "The number of iterations must be positive");
+        exit(1);
+    }
+
+    let timestamp = match parse_timestamp(&args.flag_timestamp, epoch_milliseconds()) {
+        Some(timestamp) => timestamp,
+        None => {
+            let _ = writeln!(&mut stderr(), "Could not parse timestamp: {}", args.flag_timestamp);
+            exit(1);
+        }
+    };
+
+    let verbosity = match (args.flag_quiet, args.flag_verbose) {
+        (false, false) => Verbosity::Normal,
+        (true, false) => Verbosity::Quiet,
+        (false, true) => Verbosity::Verbose,
+        (true, true) => {
+            let _ = writeln!(&mut stderr(), "Cannot be both quiet and verbose");
+            exit(1);
+        }
+    };
+
+    // in verbose mode, files are listed as soon as they have been handled.
+    // With --progress, the number of files handled is kept up to date on a
+    // single line, along with the estimated total.
+    let show_progress = args.flag_progress;
+    let mut expected_files = None;
+    let mut completed_files = 0;
+    let mut print_file = |event: ProgressEvent| {
+        match event {
+            ProgressEvent::Estimate(files, _) => expected_files = Some(files),
+            ProgressEvent::FileCompleted(path) => {
+                completed_files += 1;
+
+                match (verbosity, expected_files) {
+                    (Verbosity::Verbose, _) => {
+                        let _ = writeln!(&mut stderr(), "{}", path.display());
+                    }
+                    (_, Some(total)) => {
+                        let _ = write!(&mut stderr(), "\r{} of about {} files", completed_files, total);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    };
+    let progress: Progress = match verbosity {
+        Verbosity::Verbose => Some(&mut print_file),
+        _ if show_progress => Some(&mut print_file),
+        _ => None,
+    };
+
+    if args.flag_no_passphrase && args.flag_keyfile.is_empty() {
+        let _ = writeln!(&mut stderr(), "A key file is required without a passphrase");
+        exit(1);
+    }
+
+    // a key command yields the whole key, so a key file would go unused
+    if !args.flag_keyfile.is_empty() && !args.flag_key_command.is_empty() {
+        let _ = writeln!(&mut stderr(), "A key file cannot be combined with a key command");
+        exit(1);
+    }
+
+    let index_path = match &args.flag_index_db[..] {
+        "" => None,
+        path => Some(PathBuf::from(path)),
+    };
+
+    // the working index is not encrypted, so no passphrase is needed
+    if args.cmd_check_index {
+        let result = check_index(PathBuf::from(args.flag_source), index_path.as_ref());
+        return handle_result(result, verbosity);
+    }
+
+    let cipher = match cipher_name(&args, index_path.as_ref()) {
+        Ok(cipher) => cipher,
+        Err(e) => {
+            let _ = writeln!(&mut stderr(), "Could not determine cipher: {:?}", e);
+            exit(1);
+        }
+    };
+
+    let (scheme_result, key_source, key_params) = match &args.flag_key_command[..] {
+        "" if args.flag_keyfile.is_empty() => {
+            let params = fetch_key_params(&args, index_path.as_ref());
+            let password = read_passphrase(&args, environment_passphrase);
+            let scheme = Cipher::from_key_source(&cipher, &Passphrase::with_params(&password, params));
+
+            (scheme, "passphrase", Some(params))
+        },
+        "" => {
+            let params = fetch_key_params(&args, index_path.as_ref());
+            let password = match args.flag_no_passphrase {
+                true => None,
+                false => Some(read_passphrase(&args, environment_passphrase)),
+            };
+            let path = PathBuf::from(&args.flag_keyfile);
+            let source = Keyfile::with_params(password.as_ref().map(|p| &p[..]), &path, params);
+
+            (Cipher::from_key_source(&cipher, &source), source.name(), Some(params))
+        },
+        command => {
+            let source = KeyCommand::new(command);
+
+            (Cipher::from_key_source(&cipher, &source), source.name(), None)
+        }
+    };
+
+    let crypto_scheme = match scheme_result {
+        Ok(scheme) => scheme,
+        Err(e) => {
+            let _ = writeln!(&mut stderr(), "Could not obtain key: {}", e);
+            exit(1);
+        }
+    };
 
     if args.cmd_init {
-        let result = init(&args.flag_source, &args.flag_destination, &crypto_scheme);
-        handle_result(result);
+        let mut options = InitOptions::new();
+        options.extra_destinations = args.flag_extra.iter().map(PathBuf::from).collect();
+        options.index_path = index_path;
+        options.key_source = key_source;
+        options.key_params = key_params;
+        options.compression = match Compression::from_name(&args.flag_compression) {
+            Some(compression) => compression,
+            None => {
+                let _ = writeln!(&mut stderr(), "Unknown compression algorithm: {}", args.flag_compression);
+                exit(1);
+            }
+        };
+        options.hash_algorithm = match HashAlgorithm::from_name(&args.flag_hash) {
+            Some(algorithm) => algorithm,
+            None => {
+                let _ = writeln!(&mut stderr(), "Unknown hash algorithm: {}", args.flag_hash);
+                exit(1);
+            }
+        };
+        options.shard_depth = args.flag_shard_depth;
+        options.chunking = match parse_chunking(&args.flag_chunking) {
+            Some(chunking) => chunking,
+            None => {
+                let _ = writeln!(&mut stderr(), "Invalid block sizes: {}", args.flag_chunking);
+                exit(1);
+            }
+        };
+
+        if args.flag_and_backup {
+            let mut backup_options = backup_options(&args, None);
+            backup_options.interrupt = Some(catch_interrupt());
+            let result = init_and_backup(&PathBuf::from(&args.flag_source), &PathBuf::from(&args.flag_destination), &crypto_scheme, &options, &backup_options);
+            return handle_backup_result(result, verbosity);
+        }
+
+        let result = init_with_options(&PathBuf::from(args.flag_source), &PathBuf::from(args.flag_destination), &crypto_scheme, &options);
+        handle_result(result, verbosity);
     }
     else if args.cmd_backup {
-        let deadline = time::now() + match args.flag_timeout {
-            0    => Duration::weeks(52),
-            secs => Duration::seconds(secs as i64)
-        };
-        let max_alias_age_milliseconds = args.flag_age as u64 * 24 * 60 * 60 * 1000;
-        let block_bytes = 1000 * (args.flag_blocksize as usize);
+        let mut options = backup_options(&args, index_path);
+        options.interrupt = Some(catch_interrupt());
+
+        let result = backup_with_progress(PathBuf::from(args.flag_source), &crypto_scheme, &options, progress);
+
+        // end the progress line
+        if show_progress {
+            let _ = writeln!(&mut stderr(), "");
+        }
 
-        let result = backup(PathBuf::from(args.flag_source), block_bytes, &crypto_scheme, max_alias_age_milliseconds, deadline);
-        handle_result(result);
+        handle_backup_result(result, verbosity);
     }
     else if args.cmd_restore {
-        let timestamp = match args.flag_timestamp {
-            0 => epoch_milliseconds(),
-            v => v
+        let mut options = RestoreOptions::new();
+        options.hardlinks = args.flag_hardlinks;
+        options.ignore_integrity = args.flag_ignore_integrity;
+        options.cache_bytes = match args.flag_block_cache.checked_mul(1024 * 1024) {
+            Some(bytes) => bytes,
+            None => {
+                let _ = writeln!(&mut stderr(),
+                                 "Block cache is too large: {} megabytes",
+                                 args.flag_block_cache);
+                exit(1);
+            }
         };
+        options.xattrs = args.flag_xattrs;
+
+        if args.flag_threads > 0 {
+            options.worker_count = args.flag_threads;
+        }
+
+        if !args.flag_restore_manifest.is_empty() {
+            options.manifest = Some(PathBuf::from(&args.flag_restore_manifest));
+        }
+
+        options.overwrite = match &args.flag_overwrite[..] {
+            "always" => Overwrite::Always,
+            "if-different" => Overwrite::IfDifferent,
+            "never" => Overwrite::Never,
+            mode => {
+                let _ = writeln!(&mut stderr(), "Unknown overwrite mode: {}", mode);
+                exit(1);
+            }
+        };
+
+        let archive_format = match (&args.flag_format[..], args.flag_gzip) {
+            ("files", false) => None,
+            ("tar", false) => Some(ArchiveFormat::Tar),
+            ("tar", true) => Some(ArchiveFormat::TarGzip),
+            ("files", true) => {
+                let _ = writeln!(&mut stderr(), "Only tar archives can be compressed");
+                exit(1);
+            }
+            (format, _) => {
+                let _ = writeln!(&mut stderr(), "Unknown restore format: {}", format);
+                exit(1);
+            }
+        };
+
+        let result = match archive_format {
+            None => restore_with_progress(PathBuf::from(args.flag_source), PathBuf::from(args.flag_destination), &crypto_scheme, timestamp, args.flag_filter, &options, progress),
+            Some(..) if args.flag_output.is_empty() => {
+                let _ = writeln!(&mut stderr(), "An output path is required for an archive");
+                exit(1);
+            }
+            Some(format) => restore_to_archive(PathBuf::from(args.flag_destination), &crypto_scheme, timestamp, args.flag_filter, &options, format, Path::new(&args.flag_output)),
+        };
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_versions {
+        let result = restore_versions(PathBuf::from(args.flag_destination), PathBuf::from(args.flag_dest), &crypto_scheme, Path::new(&args.arg_path), args.flag_count);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_extract {
+        let result = restore_single(PathBuf::from(args.flag_destination), &crypto_scheme, Path::new(&args.arg_path), timestamp, Path::new(&args.flag_output));
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_manifest {
+        let result = manifests(PathBuf::from(args.flag_source), index_path.as_ref(), &crypto_scheme, args.flag_rebuild);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_prune {
+        let options = backup_options(&args, index_path);
+
+        let result = prune(PathBuf::from(args.flag_source), &crypto_scheme, &options);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_prune_orphans {
+        let result = prune_orphans(PathBuf::from(args.flag_source), index_path.as_ref(), &crypto_scheme);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_stats {
+        let result = stats(PathBuf::from(args.flag_source), index_path.as_ref(), &crypto_scheme);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_sync {
+        // the passphrase given first is the one of the receiving backup
+        let other_settings = backup_cipher(PathBuf::from(&args.flag_destination)).and_then(|cipher| {
+            backup_key_params(PathBuf::from(&args.flag_destination)).map(|params| (cipher, params))
+        });
+        let (other_cipher, other_params) = match other_settings {
+            Ok(settings) => settings,
+            Err(e) => return handle_result::<String>(Err(e), verbosity)
+        };
+        let other_password = fetch_password("Passphrase of backup to copy from: ");
+        let other_crypto_scheme = match Cipher::from_key_source(&other_cipher, &Passphrase::with_params(&other_password, other_params)) {
+            Ok(scheme) => scheme,
+            Err(e) => return handle_result::<String>(Err(From::from(e)), verbosity)
+        };
+
+        let result = sync_repos(PathBuf::from(args.flag_destination), PathBuf::from(args.flag_source), index_path.as_ref(), &other_crypto_scheme, &crypto_scheme);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_changepw {
+        // the new passphrase is derived with the same parameters, so that the
+        // key parameters at the destination stay valid
+        let params = match key_params {
+            Some(params) => params,
+            None => {
+                let _ = writeln!(&mut stderr(), "Only passphrases can be changed");
+                exit(1);
+            }
+        };
+        let new_password = fetch_password("New passphrase: ");
+
+        if fetch_password("Repeat new passphrase: ") != new_password {
+            let _ = writeln!(&mut stderr(), "Passphrases do not match");
+            exit(1);
+        }
+
+        let new_crypto_scheme = match Cipher::from_key_source(&cipher, &Passphrase::with_params(&new_password, params)) {
+            Ok(scheme) => scheme,
+            Err(e) => return handle_result::<String>(Err(From::from(e)), verbosity)
+        };
+
+        let result = change_password(PathBuf::from(args.flag_source), index_path.as_ref(), &crypto_scheme, &new_crypto_scheme);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_du {
+        let policy = match args.flag_total {
+            true  => UsagePolicy::Total,
+            false => UsagePolicy::FirstOccurrence
+        };
+
+        match du(PathBuf::from(args.flag_destination), &crypto_scheme, timestamp, policy) {
+            Ok(usage) => for (path, bytes) in usage {
+                println!("{}\t{}", bytes, path.display());
+            },
+            Err(e) => handle_result::<String>(Err(e), verbosity)
+        }
+    }
+    else if args.cmd_fsck {
+        let result = fsck(PathBuf::from(args.flag_source), index_path.as_ref(), &crypto_scheme, args.flag_fix);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_verify {
+        let result = verify(PathBuf::from(args.flag_destination), &crypto_scheme);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_list {
+        if args.flag_long {
+            // sizes of files backed up by older releases are unknown
+            match list_with_sizes(PathBuf::from(args.flag_destination), &crypto_scheme, timestamp, args.flag_filter) {
+                Ok(entries) => for (path, size) in entries {
+                    match size {
+                        Some((bytes, blocks)) => println!("{}\t{}\t{}", bytes, blocks, path.display()),
+                        None => println!("?\t?\t{}", path.display()),
+                    }
+                },
+                Err(e) => handle_result::<String>(Err(e), verbosity)
+            }
+        }
+        else {
+            match list(PathBuf::from(args.flag_destination), &crypto_scheme, timestamp, args.flag_filter) {
+                Ok(paths) => for path in paths {
+                    println!("{}", path.display());
+                },
+                Err(e) => handle_result::<String>(Err(e), verbosity)
+            }
+        }
+    }
+    else if args.cmd_backups {
+        match restore_points(PathBuf::from(args.flag_destination), &crypto_scheme) {
+            Ok(points) => for (timestamp, files) in points {
+                let date = time::at_utc(time::Timespec::new((timestamp / 1000) as i64, 0));
+
+                println!("{}\t{}\t{} files", timestamp, date.rfc3339(), files);
+            },
+            Err(e) => handle_result::<String>(Err(e), verbosity)
+        }
+    }
+    else if args.cmd_diff {
+        let options = backup_options(&args, index_path);
+        let result = diff(PathBuf::from(&args.flag_source), PathBuf::from(&args.flag_destination), &crypto_scheme, timestamp, &options);
+        handle_result(result, verbosity);
+    }
+    else if args.cmd_cat {
+        let stdout = stdout();
+        let mut handle = stdout.lock();
+
+        if let Err(e) = cat(PathBuf::from(args.flag_destination), &crypto_scheme, timestamp, args.flag_filter, &mut handle) {
+            let _ = writeln!(&mut stderr(), "{:?}", e);
+            exit(1);
+        }
+    }
+}
+
+// Determines the cipher to use. As with the key derivation parameters, it is
+// read from the working index or the backup destination.
+fn cipher_name(args: &Args, index_path: Option<&PathBuf>) -> BonzoResult<String> {
+    if args.cmd_init {
+        return Ok(args.flag_cipher.clone());
+    }
+
+    if args.cmd_backup || args.cmd_manifest || args.cmd_prune || args.cmd_prune_orphans ||
+       args.cmd_sync || args.cmd_changepw || args.cmd_stats || args.cmd_fsck {
+        return source_cipher(PathBuf::from(&args.flag_source), index_path);
+    }
+
+    backup_cipher(PathBuf::from(&args.flag_destination))
+}
+
+// Determines the parameters to derive the key from the passphrase with. New
+// backups get a fresh salt, commands operating on the working index read them
+// from there and all others from the backup destination.
+fn key_params(args: &Args, index_path: Option<&PathBuf>) -> BonzoResult<KeyParams> {
+    if args.cmd_init {
+        return KeyParams::generate(args.flag_iterations).map_err(From::from);
+    }
+
+    if args.cmd_backup || args.cmd_manifest || args.cmd_prune || args.cmd_prune_orphans ||
+       args.cmd_sync || args.cmd_changepw || args.cmd_stats || args.cmd_fsck {
+        return source_key_params(PathBuf::from(&args.flag_source), index_path);
+    }
+
+    backup_key_params(PathBuf::from(&args.flag_destination))
+}
 
-        let result = restore(PathBuf::from(args.flag_source), PathBuf::from(args.flag_destination), &crypto_scheme, timestamp, args.flag_filter);
-        handle_result(result);
+// Like key_params, but exits when the parameters cannot be obtained
+fn fetch_key_params(args: &Args, index_path: Option<&PathBuf>) -> KeyParams {
+    match key_params(args, index_path) {
+        Ok(params) => params,
+        Err(e) => {
+            let _ = writeln!(&mut stderr(), "Could not obtain key parameters: {:?}", e);
+            exit(1);
+        }
     }
 }
 
-// Writes the result of the program to stdio in case of success, or stderr when
-// it failed
-fn handle_result<T: Display>(result: BonzoResult<T>) {
+fn backup_options(args: &Args, index_path: Option<PathBuf>) -> BackupOptions {
+    let time_limit = match args.flag_timeout {
+        0    => Duration::from_secs(52 * 7 * 24 * 60 * 60),
+        secs => Duration::from_secs(secs)
+    };
+    let max_alias_age_milliseconds = match parse_age(&args.flag_age) {
+        Some(milliseconds) => milliseconds,
+        None => {
+            let _ = writeln!(&mut stderr(), "Could not parse age: {}", args.flag_age);
+            exit(1);
+        }
+    };
+    let block_bytes = 1000 * (args.flag_blocksize as usize);
+
+    let mut options = BackupOptions::new(block_bytes, max_alias_age_milliseconds, time_limit);
+    options.index_path = index_path;
+    options.strict = args.flag_strict;
+    options.resume = args.flag_resume;
+    options.new_files_first = args.flag_new_files_first;
+    options.batch_size = args.flag_batch_size;
+    options.dry_run = args.flag_dry_run;
+    options.sync_blocks = !args.flag_no_fsync;
+    options.dereference_hardlinks = args.flag_dereference_hardlinks;
+    options.xattrs = args.flag_xattrs;
+    options.one_file_system = args.flag_one_file_system;
+    options.empty_directories = !args.flag_skip_empty_dirs;
+    options.symlinks = match &args.flag_symlinks[..] {
+        _ if args.flag_follow_symlinks => SymlinkPolicy::Follow,
+        "skip" => SymlinkPolicy::Skip,
+        "follow" => SymlinkPolicy::Follow,
+        "store" => SymlinkPolicy::Store,
+        policy => {
+            let _ = writeln!(&mut stderr(), "Unknown symlink policy: {}", policy);
+            exit(1);
+        }
+    };
+    options.keep_indices = args.flag_keep_indices;
+    options.estimate = args.flag_progress;
+    options.compression_level = match CompressionLevel::from_name(&args.flag_compression_level) {
+        Some(level) => level,
+        None => {
+            let _ = writeln!(&mut stderr(), "Unknown compression level: {}", args.flag_compression_level);
+            exit(1);
+        }
+    };
+
+    if args.flag_threads > 0 {
+        options.worker_count = args.flag_threads;
+    }
+
+    options.max_file_size = scale_limit(args.flag_max_size, 1000, "--max-size");
+    options.newer_than = scale_limit(args.flag_newer_than, 24 * 60 * 60 * 1000, "--newer-than");
+    options.older_than = scale_limit(args.flag_older_than, 24 * 60 * 60 * 1000, "--older-than");
+    options.max_bytes_per_second = scale_limit(args.flag_max_rate, 1000, "--max-rate");
+    options.excluded_directories = args.flag_exclude_dir.iter().map(PathBuf::from).collect();
+
+    for root in args.flag_root.iter() {
+        let mut parts = root.splitn(2, '=');
+
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(path)) => options.roots.push((name.to_owned(), PathBuf::from(path))),
+            _ => {
+                let _ = writeln!(&mut stderr(), "Expected a source as name=path: {}", root);
+                exit(1);
+            }
+        }
+    }
+
+    for glob in args.flag_exclude.iter() {
+        match Pattern::new(glob) {
+            Ok(pattern) => options.exclude_patterns.push(pattern),
+            Err(..) => {
+                let _ = writeln!(&mut stderr(), "Invalid exclude pattern: {}", glob);
+                exit(1);
+            }
+        }
+    }
+
+    for glob in args.flag_include.iter() {
+        match Pattern::new(glob) {
+            Ok(pattern) => options.include_patterns.push(pattern),
+            Err(..) => {
+                let _ = writeln!(&mut stderr(), "Invalid include pattern: {}", glob);
+                exit(1);
+            }
+        }
+    }
+
+    options
+}
+
+// Converts a limit given on the command line into the unit used internally.
+// Zero means there is no limit. Limits which do not fit in 64 bits are
+// rejected, rather than wrapping around to something small.
+fn scale_limit(value: u64, factor: u64, flag: &str) -> Option<u64> {
+    if value == 0 {
+        return None;
+    }
+
+    match value.checked_mul(factor) {
+        Some(scaled) => Some(scaled),
+        None => {
+            let _ = writeln!(&mut stderr(), "Value of {} is too large: {}", flag, value);
+            exit(1);
+        }
+    }
+}
+
+// Parses the time old data is retained into milliseconds. A bare number is a
+// number of days, as it always has been. Otherwise, the number is followed by
+// a unit: s, m, h, d or w for seconds, minutes, hours, days or weeks.
+fn parse_age(input: &str) -> Option<u64> {
+    let input = input.trim();
+
+    if let Ok(days) = input.parse::<u64>() {
+        return Some(days.saturating_mul(24 * 60 * 60 * 1000));
+    }
+
+    let (split, unit) = match input.char_indices().last() {
+        Some(last) => last,
+        None => return None,
+    };
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    input[..split].parse::<u64>().ok().map(|count| count.saturating_mul(seconds * 1000))
+}
+
+// Parses the point in time to restore to into milliseconds since the epoch.
+// Besides raw milliseconds, of which zero means now, this takes dates with an
+// optional time and UTC offset, like 2015-06-29T20:16:27+02:00, and amounts of
+// time ago, like "2 days ago". Dates without an offset are in UTC.
+fn parse_timestamp(input: &str, now: u64) -> Option<u64> {
+    let input = input.trim();
+
+    if let Ok(milliseconds) = input.parse::<u64>() {
+        return Some(match milliseconds {
+            0 => now,
+            v => v,
+        });
+    }
+
+    match input.ends_with(" ago") {
+        true => parse_time_ago(&input[..input.len() - 4], now),
+        false => parse_date(input),
+    }
+}
+
+fn parse_time_ago(amount: &str, now: u64) -> Option<u64> {
+    let words: Vec<&str> = amount.split_whitespace().collect();
+
+    if words.len() != 2 {
+        return None;
+    }
+
+    let count: u64 = match words[0].parse() {
+        Ok(count) => count,
+        Err(..) => return None,
+    };
+    let seconds = match words[1].trim_right_matches('s') {
+        "second" | "sec" => 1,
+        "minute" | "min" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    Some(now.saturating_sub(count.saturating_mul(seconds * 1000)))
+}
+
+fn parse_date(input: &str) -> Option<u64> {
+    let (date, offset_seconds) = split_utc_offset(input);
+
+    for format in DATE_FORMATS.iter() {
+        if let Ok(tm) = time::strptime(date, format) {
+            // strptime ignores whatever follows the format, so the date must
+            // read the same once formatted again
+            match tm.strftime(format) {
+                Ok(formatted) if formatted.to_string() == date => {}
+                _ => continue,
+            }
+
+            let seconds = tm.to_timespec().sec - offset_seconds;
+
+            return match seconds >= 0 {
+                true => Some(seconds as u64 * 1000),
+                false => None,
+            };
+        }
+    }
+
+    None
+}
+
+// Separates a trailing Z or offset like +02:00 from a date, as strptime does
+// not understand offsets with a colon. Returns the offset in seconds. The
+// sign and colon are checked as bytes, after which every slice lies on a
+// character boundary.
+fn split_utc_offset(input: &str) -> (&str, i64) {
+    if input.ends_with("Z") {
+        return (&input[..input.len() - 1], 0);
+    }
+
+    let bytes = input.as_bytes();
+    let length = bytes.len();
+
+    if length < 6 || !input.contains('T') || bytes[length - 3] != b':' {
+        return (input, 0);
+    }
+
+    let sign = match bytes[length - 6] {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return (input, 0),
+    };
+
+    match (input[length - 5..length - 3].parse::<i64>(), input[length - 2..].parse::<i64>()) {
+        (Ok(hours), Ok(minutes)) => (&input[..length - 6], sign * (hours * 60 + minutes) * 60),
+        _ => (input, 0),
+    }
+}
+
+// Parses the minimum, average and maximum size of content-defined blocks,
+// given in kilobytes. Without sizes, blocks have a fixed size. Sizes beyond
+// the maximum block size are rejected.
+fn parse_chunking(sizes: &str) -> Option<Chunking> {
+    if sizes.is_empty() {
+        return Some(Chunking::Fixed);
+    }
+
+    let parsed: Option<Vec<usize>> = sizes.split(',').map(parse_block_size).collect();
+
+    match parsed {
+        Some(ref bytes) if bytes.len() == 3 => Some(Chunking::ContentDefined {
+            min: bytes[0],
+            average: bytes[1],
+            max: bytes[2],
+        }),
+        _ => None,
+    }
+}
+
+// Parses a block size in kilobytes into bytes, provided that it does not
+// exceed the maximum block size
+fn parse_block_size(kilobytes: &str) -> Option<usize> {
+    match kilobytes.trim().parse::<usize>().ok().and_then(|size| size.checked_mul(1000)) {
+        Some(bytes) if bytes <= MAX_CHUNK_SIZE => Some(bytes),
+        _ => None,
+    }
+}
+
+// Writes the result of the program to stdio in case of success, unless quiet,
+// or stderr when it failed. Failures exit with a non-zero code.
+fn handle_result<T: Display>(result: BonzoResult<T>, verbosity: Verbosity) {
     match result {
+        Ok(_) if verbosity == Verbosity::Quiet => {}
         Ok(summary) => println!("{}", summary),
-        Err(ref e)  => { let _ = writeln!(&mut stderr(), "{:?}", e); }
+        Err(ref e)  => {
+            let _ = writeln!(&mut stderr(), "{:?}", e);
+            exit(1);
+        }
+    }
+}
+
+extern "C" fn handle_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+
+    // a second interrupt kills the process right away
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+// Makes the first interrupt stop the backup after the file or block being
+// written, so that everything completed so far makes it into the exported
+// index. Returns the flag which the backup checks.
+fn catch_interrupt() -> &'static AtomicBool {
+    unsafe {
+        libc::signal(libc::SIGINT, handle_interrupt as libc::sighandler_t);
+    }
+
+    &INTERRUPTED
+}
+
+// Like handle_result, but exits with a code of its own when the backup timed
+// out, so that scripts can tell an incomplete backup from a complete one
+fn handle_backup_result(result: BonzoResult<BackupSummary>, verbosity: Verbosity) {
+    let timeout = result.as_ref().map(|summary| summary.timeout).unwrap_or(false);
+
+    handle_result(result, verbosity);
+
+    if timeout {
+        exit(TIMEOUT_EXIT_CODE);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_age, parse_timestamp, parse_time_ago, parse_date, split_utc_offset,
+                trim_passphrase, scale_limit, parse_chunking};
+    use backbonzo::{Chunking, MAX_CHUNK_SIZE};
+
+    static SECOND: u64 = 1000;
+    static DAY: u64 = 24 * 60 * 60 * 1000;
+    static NOW: u64 = 1_500_000_000_000;
+
+    #[test]
+    fn ages() {
+        assert_eq!(Some(30 * SECOND), parse_age("30s"));
+        assert_eq!(Some(15 * 60 * SECOND), parse_age("15m"));
+        assert_eq!(Some(12 * 60 * 60 * SECOND), parse_age("12h"));
+        assert_eq!(Some(3 * 24 * 60 * 60 * SECOND), parse_age("3d"));
+        assert_eq!(Some(2 * 7 * 24 * 60 * 60 * SECOND), parse_age("2w"));
+        assert_eq!(Some(0), parse_age("0d"));
+    }
+
+    // a bare number is a number of days
+    #[test]
+    fn bare_days() {
+        assert_eq!(Some(0), parse_age("0"));
+        assert_eq!(Some(5 * 24 * 60 * 60 * SECOND), parse_age(" 5 "));
+        assert_eq!(Some(!0), parse_age("18446744073709551615"));
+    }
+
+    #[test]
+    fn invalid_ages() {
+        for input in ["", "d", "3y", "-3d", "3.5h", "h3", "3 d", "3é", "é", "3dd"].iter() {
+            assert_eq!(None, parse_age(input));
+        }
+    }
+
+    #[test]
+    fn chunking() {
+        assert_eq!(Some(Chunking::Fixed), parse_chunking(""));
+        assert_eq!(Some(Chunking::ContentDefined { min: 2000, average: 8000, max: 32000 }),
+                   parse_chunking("2, 8,32"));
+
+        let too_large = format!("2,8,{}", MAX_CHUNK_SIZE / 1000 + 1);
+
+        for sizes in ["2,8", "2,8,32,64", "2,x,32", "2,8,18446744073709551615"].iter() {
+            assert_eq!(None, parse_chunking(sizes));
+        }
+
+        assert_eq!(None, parse_chunking(&too_large));
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(None, scale_limit(0, DAY, "--newer-than"));
+        assert_eq!(Some(3 * DAY), scale_limit(3, DAY, "--newer-than"));
+        assert_eq!(Some(!0), scale_limit(!0, 1, "--newer-than"));
+    }
+
+    // raw milliseconds since the epoch, of which zero means now
+    #[test]
+    fn raw_timestamps() {
+        assert_eq!(Some(1435608987000), parse_timestamp("1435608987000", NOW));
+        assert_eq!(Some(NOW), parse_timestamp("0", NOW));
+        assert_eq!(Some(12), parse_timestamp(" 12 ", NOW));
+    }
+
+    #[test]
+    fn times_ago() {
+        assert_eq!(Some(NOW - 2 * DAY), parse_timestamp("2 days ago", NOW));
+        assert_eq!(Some(NOW - DAY), parse_timestamp("1 day ago", NOW));
+        assert_eq!(Some(NOW - 3 * 7 * DAY), parse_timestamp("3 weeks ago", NOW));
+        assert_eq!(Some(NOW - 60 * 60 * SECOND), parse_timestamp("1 hour ago", NOW));
+        assert_eq!(Some(NOW - 5 * 60 * SECOND), parse_time_ago("5 mins", NOW));
+        assert_eq!(Some(NOW - 10 * SECOND), parse_time_ago("10 secs", NOW));
+        assert_eq!(Some(0), parse_time_ago("100000 weeks", NOW));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(Some(1435536000000), parse_date("2015-06-29"));
+        assert_eq!(Some(1435608960000), parse_date("2015-06-29T20:16"));
+        assert_eq!(Some(1435608987000), parse_date("2015-06-29T20:16:27"));
+        assert_eq!(Some(1435608987000), parse_date("2015-06-29 20:16:27"));
+        assert_eq!(Some(1435608987000), parse_timestamp("2015-06-29T20:16:27Z", NOW));
+    }
+
+    #[test]
+    fn utc_offsets() {
+        assert_eq!(("2015-06-29T20:16:27", 2 * 60 * 60),
+                   split_utc_offset("2015-06-29T20:16:27+02:00"));
+        assert_eq!(("2015-06-29T20:16:27", -(5 * 60 + 30) * 60),
+                   split_utc_offset("2015-06-29T20:16:27-05:30"));
+        assert_eq!(("2015-06-29T20:16:27", 0), split_utc_offset("2015-06-29T20:16:27Z"));
+        assert_eq!(("2015-06-29", 0), split_utc_offset("2015-06-29"));
+        assert_eq!(("2015-06-29T20:16", 0), split_utc_offset("2015-06-29T20:16"));
+
+        assert_eq!(Some(1435608987000 - 2 * 60 * 60 * SECOND),
+                   parse_date("2015-06-29T20:16:27+02:00"));
+        assert_eq!(Some(1435608987000 + 2 * 60 * 60 * SECOND),
+                   parse_date("2015-06-29T20:16:27-02:00"));
+    }
+
+    #[test]
+    fn malformed_timestamps() {
+        let inputs = ["",
+                      "yesterday",
+                      "ago",
+                      "2 ago",
+                      "two days ago",
+                      "2 fortnights ago",
+                      "2 days 3 hours ago",
+                      "-5",
+                      "2015-13-45",
+                      "29-06-2015",
+                      "2015-06-29 garbage",
+                      "1969-12-31",
+                      "2015-06-29T20:16:27+0x:00",
+                      "2015-06-29Té1:00",
+                      "é days ago"];
+
+        for input in inputs.iter() {
+            assert_eq!(None, parse_timestamp(input, NOW));
+        }
+    }
+
+    #[test]
+    fn passphrase_trimming() {
+        assert_eq!(Some("secret"), trim_passphrase("secret\n"));
+        assert_eq!(Some("secret"), trim_passphrase("secret\r\n"));
+        assert_eq!(Some("secret"), trim_passphrase("secret"));
+        assert_eq!(Some(" spaced "), trim_passphrase(" spaced \n"));
+        assert_eq!(None, trim_passphrase("\n"));
+        assert_eq!(None, trim_passphrase("\r\n"));
+        assert_eq!(None, trim_passphrase(""));
     }
 }
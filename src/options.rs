@@ -0,0 +1,278 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::sync::atomic::AtomicBool;
+
+use num_cpus;
+use glob::Pattern;
+
+use DATABASE_FILENAME;
+use crypto::{KeyParams, HashAlgorithm};
+use compression::{Compression, CompressionLevel};
+use file_chunks::Chunking;
+use backend::DEFAULT_SHARD_DEPTH;
+
+// Settings for the initialization of a new backup
+pub struct InitOptions {
+    // Directories besides the main backup destination over which blocks are
+    // spread. The index is always kept at the main destination.
+    pub extra_destinations: Vec<PathBuf>,
+    // Location of the working index. Defaults to a hidden file in the source
+    // directory.
+    pub index_path: Option<PathBuf>,
+    // Name of the kind of key source used, which is recorded in the index
+    pub key_source: &'static str,
+    // Parameters the passphrase was derived with. These are recorded so that
+    // the same key can be derived later. When absent, the legacy parameters
+    // are assumed.
+    pub key_params: Option<KeyParams>,
+    // Algorithm new blocks are compressed with
+    pub compression: Compression,
+    // How files are split into blocks. With content-defined chunking, the
+    // block size given for each backup run is ignored.
+    pub chunking: Chunking,
+    // Algorithm which identifies the contents of files and blocks
+    pub hash_algorithm: HashAlgorithm,
+    // Number of nested directory levels blocks are spread over. A single
+    // level has only 256 directories, each of which may hold a great many
+    // blocks in large backups.
+    pub shard_depth: usize,
+}
+
+impl InitOptions {
+    pub fn new() -> InitOptions {
+        InitOptions {
+            extra_destinations: Vec::new(),
+            index_path: None,
+            key_source: "passphrase",
+            key_params: None,
+            compression: Compression::Bzip2,
+            chunking: Chunking::Fixed,
+            hash_algorithm: HashAlgorithm::Sha256,
+            shard_depth: DEFAULT_SHARD_DEPTH,
+        }
+    }
+}
+
+// Settings for a single backup run. The block size and maximum age are in
+// bytes and milliseconds respectively. The run stops once it has taken longer
+// than the time limit, leaving the remainder for the next run.
+#[derive(Clone)]
+pub struct BackupOptions {
+    pub block_bytes: usize,
+    pub max_age_milliseconds: u64,
+    pub time_limit: Duration,
+    // Abort when a directory cannot be read instead of skipping it
+    pub strict: bool,
+    // Continue where the previous run left off when it timed out
+    pub resume: bool,
+    // Back up files which are not in the backup yet before files which may
+    // have changed since they were backed up, so that repeated runs which
+    // time out cover every file eventually. The files in the backup already
+    // are kept in memory until the whole source directory has been walked.
+    pub new_files_first: bool,
+    // Maximum number of blocks and files persisted to the index in a single
+    // transaction. A value of 1 commits every change separately.
+    pub batch_size: usize,
+    // Absolute paths of directories to leave out of this run. Previously
+    // backed up files within them are not considered deleted.
+    pub excluded_directories: Vec<PathBuf>,
+    // Glob patterns of files and directories to leave out, matched against
+    // both their names and their paths relative to the source directory
+    pub exclude_patterns: Vec<Pattern>,
+    // Glob patterns of files to back up, matched in the same way. When there
+    // are any, other files are left out, while directories are still searched
+    // for matches. A file matching both an include and an exclude pattern is
+    // left out.
+    pub include_patterns: Vec<Pattern>,
+    // Files larger than this number of bytes are left out of the backup
+    pub max_file_size: Option<u64>,
+    // Only files modified less than this many milliseconds before the backup
+    // started are backed up. Others are left out, without being considered
+    // deleted.
+    pub newer_than: Option<u64>,
+    // Likewise, only files modified more than this many milliseconds before
+    // the backup started are backed up
+    pub older_than: Option<u64>,
+    // Walk the source directories once before backing up, to report an
+    // estimate of the number of files and bytes to be read as progress
+    pub estimate: bool,
+    // Level at which new blocks are compressed. Lower levels are much faster,
+    // which may be worth the larger blocks for highly compressible data.
+    pub compression_level: CompressionLevel,
+    // Walk and process the source directory as usual, but write nothing to
+    // the backup destination. The summary reports what would have been
+    // stored.
+    pub dry_run: bool,
+    // Number of threads compressing and encrypting blocks. Defaults to the
+    // number of logical processors.
+    pub worker_count: usize,
+    // Number of timestamped copies of the index to keep at the destination,
+    // besides the live index. Restores fall back on these when the live index
+    // is damaged. Blocks are removed once the live index no longer references
+    // them, so older snapshots may not be complete. Zero disables snapshots.
+    pub keep_indices: usize,
+    // Record directories without any contents, so that restores recreate
+    // them
+    pub empty_directories: bool,
+    // What to do with symlinks in the source directory
+    pub symlinks: SymlinkPolicy,
+    // Additional source directories, along with a name. Each is backed up
+    // into a top-level directory of that name, so it can be restored on its
+    // own with a filter. Names may not clash with anything in the main source
+    // directory. When a source is left out of a run, its files remain in the
+    // backup as they were.
+    pub roots: Vec<(String, PathBuf)>,
+    // Maximum number of bytes per second written to the backup destination,
+    // for both blocks and the index. Unlimited when absent.
+    pub max_bytes_per_second: Option<u64>,
+    // Location of the working index. Must match the one given at
+    // initialization.
+    pub index_path: Option<PathBuf>,
+    // Once this flag is set, for instance by a signal handler, the run stops
+    // as if its time limit had passed. Blocks and files completed so far are
+    // kept and the index is exported as usual.
+    pub interrupt: Option<&'static AtomicBool>,
+    // Sync every block to disk before it is moved into place. Without this,
+    // all blocks are synced at once just before the index is exported, which
+    // is much faster for many small blocks. A crash during the run may then
+    // lose blocks which the working index already lists, so that the next
+    // run will not write them again. Fsck or verify finds such blocks.
+    pub sync_blocks: bool,
+    // Read files with several hard links only once. Further links to the
+    // same inode are recorded as copies of the first, using its hash.
+    pub dereference_hardlinks: bool,
+    // Record the extended attributes of every file along with its other
+    // attributes. Only attributes with UTF-8 names are kept.
+    pub xattrs: bool,
+    // Stay on the filesystem of each source directory, leaving out
+    // directories on which other filesystems are mounted along with their
+    // contents
+    pub one_file_system: bool,
+}
+
+impl BackupOptions {
+    pub fn new(block_bytes: usize,
+               max_age_milliseconds: u64,
+               time_limit: Duration)
+               -> BackupOptions {
+        BackupOptions {
+            block_bytes: block_bytes,
+            max_age_milliseconds: max_age_milliseconds,
+            time_limit: time_limit,
+            strict: false,
+            resume: false,
+            new_files_first: false,
+            batch_size: 100,
+            excluded_directories: Vec::new(),
+            exclude_patterns: Vec::new(),
+            include_patterns: Vec::new(),
+            max_file_size: None,
+            newer_than: None,
+            older_than: None,
+            estimate: false,
+            compression_level: CompressionLevel::Best,
+            dry_run: false,
+            worker_count: num_cpus::get(),
+            keep_indices: 0,
+            empty_directories: true,
+            symlinks: SymlinkPolicy::Skip,
+            roots: Vec::new(),
+            max_bytes_per_second: None,
+            index_path: None,
+            interrupt: None,
+            sync_blocks: true,
+            dereference_hardlinks: false,
+            xattrs: false,
+            one_file_system: false,
+        }
+    }
+}
+
+// Settings for restoring a backup. The index is always checked for integrity
+// before anything is restored.
+pub struct RestoreOptions {
+    // Restore files with identical contents as hard links to the first one
+    pub hardlinks: bool,
+    // Restore blocks which fail the integrity check and leave out blocks which
+    // cannot be decompressed, instead of aborting
+    pub ignore_integrity: bool,
+    // What to do with files which already exist at the restore location
+    pub overwrite: Overwrite,
+    // Number of threads restoring files. Defaults to the number of logical
+    // processors.
+    pub worker_count: usize,
+    // Location of a file listing every file written by the restore, along
+    // with its hash, size and number of blocks. Files which were kept and
+    // symlinks are left out.
+    pub manifest: Option<PathBuf>,
+    // Maximum number of bytes of recently restored blocks kept in memory, so
+    // that blocks shared by several files are only read once. Zero disables
+    // the cache.
+    pub cache_bytes: usize,
+    // Set the extended attributes recorded for every restored file. Files
+    // which were kept get them as well.
+    pub xattrs: bool,
+}
+
+impl RestoreOptions {
+    pub fn new() -> RestoreOptions {
+        RestoreOptions {
+            hardlinks: false,
+            ignore_integrity: false,
+            overwrite: Overwrite::Always,
+            worker_count: num_cpus::get(),
+            manifest: None,
+            cache_bytes: 64 * 1024 * 1024,
+            xattrs: false,
+        }
+    }
+}
+
+// Kinds of archive a backup can be restored into, rather than onto the
+// filesystem
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGzip,
+}
+
+// Determines whether existing files are replaced when restoring. With
+// IfDifferent, files whose contents match the backup are left untouched,
+// which saves a lot of writing when resuming an interrupted restore.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Overwrite {
+    Always,
+    IfDifferent,
+    Never,
+}
+
+// Determines how symlinks are backed up. With Follow, the contents they point
+// to are backed up as if they were regular files and directories, except for
+// links to something within the source directory, which is backed up anyway,
+// and links to one of their own ancestors. With Store, only the target of the
+// link is recorded, and the link itself is recreated on restore.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SymlinkPolicy {
+    Skip,
+    Follow,
+    Store,
+}
+
+// Determines how blocks which are shared by several files are accounted for
+// when reporting disk usage. With Total, every file is charged for all of its
+// blocks, as if nothing were deduplicated. With FirstOccurrence, a block is
+// only charged to the first file found referencing it, so that the usage of
+// the whole backup adds up to the storage actually taken.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum UsagePolicy {
+    Total,
+    FirstOccurrence,
+}
+
+// Returns the location of the working index for the given source directory
+pub fn database_path(source_path: &Path, index_path: Option<&PathBuf>) -> PathBuf {
+    match index_path {
+        Some(path) => path.clone(),
+        None => source_path.join(DATABASE_FILENAME),
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+
+use error::BonzoResult;
+
+// Events reported while a backup or restore is running, so that callers can
+// show progress before the whole operation has finished. Paths are those of
+// the files in the source directory or at the restore location. Block sizes
+// are in bytes as written to disk.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum ProgressEvent {
+    // Approximate number of files and bytes a backup is going to read, sent
+    // before any file is started when requested
+    Estimate(u64, u64),
+    FileStarted(PathBuf),
+    BlockWritten(u64),
+    FileCompleted(PathBuf),
+}
+
+// Optional callback which receives the progress events
+pub type Progress<'a> = Option<&'a mut FnMut(ProgressEvent)>;
+
+pub fn report(progress: &mut Progress, event: ProgressEvent) {
+    if let Some(ref mut callback) = *progress {
+        callback(event);
+    }
+}
+
+// Optional callback which receives the path of every file restored, or the
+// error which kept a file from being restored
+pub type FileResults<'a> = Option<&'a mut FnMut(BonzoResult<PathBuf>)>;
+
+// Hands the outcome for a single file to the callback. Without a callback,
+// failures are returned, so that they abort the operation.
+pub fn report_file(results: &mut FileResults, result: BonzoResult<PathBuf>) -> BonzoResult<()> {
+    match *results {
+        Some(ref mut callback) => Ok(callback(result)),
+        None => result.map(|_| ()),
+    }
+}
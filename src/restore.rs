@@ -0,0 +1,349 @@
+use std::io::{self, Write};
+use std::fs::{File, remove_file, read_link};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, Receiver};
+use std::thread::{spawn, JoinHandle};
+
+use comm::spmc::bounded_fast as spmc;
+use rustc_serialize::hex::ToHex;
+
+use error::{BonzoResult, BonzoError};
+use database::Database;
+use backend::{Backend, Storage};
+use crypto::{CryptoScheme, HashScheme, HashAlgorithm};
+use summary::RestorationSummary;
+use progress::{report, report_file, Progress, ProgressEvent, FileResults};
+use attributes::{Attributes, set_xattr};
+use options::Overwrite;
+use block_cache::BlockCache;
+use {BlockId, create_parent_dir, decode_block_from};
+
+// The number of files buffered for the restore threads. Like the buffer of
+// the export threads, it only needs to be large enough to keep every thread
+// busy.
+static CHANNEL_BUFFER_SIZE: usize = 16;
+
+// The extended attributes of a file, as pairs of name and value
+pub type Xattrs = Vec<(String, Vec<u8>)>;
+
+// A file to restore, along with its blocks in order and its attributes
+pub type RestoreJob = (PathBuf, Vec<BlockId>, Attributes, Xattrs);
+
+// Writes files from their blocks. It only borrows what it needs, so that it
+// can be used both by the backup manager and by restore threads which have
+// their own connection to the index. Restore threads share a cache of blocks.
+pub struct FileRestorer<'a, C>
+    where C: CryptoScheme
+{
+    database: &'a Database,
+    backend: &'a Storage,
+    crypto_scheme: &'a C,
+    hash_algorithm: HashAlgorithm,
+    cache: Option<&'a Mutex<BlockCache>>,
+}
+
+impl<'a, C: CryptoScheme> FileRestorer<'a, C> {
+    pub fn new(database: &'a Database,
+               backend: &'a Storage,
+               crypto_scheme: &'a C,
+               hash_algorithm: HashAlgorithm)
+               -> FileRestorer<'a, C> {
+        FileRestorer {
+            database: database,
+            backend: backend,
+            crypto_scheme: crypto_scheme,
+            hash_algorithm: hash_algorithm,
+            cache: None,
+        }
+    }
+
+    pub fn with_cache(mut self, cache: &'a Mutex<BlockCache>) -> FileRestorer<'a, C> {
+        self.cache = Some(cache);
+        self
+    }
+
+    // Restores a single file by writing its blocks to the given path. The
+    // attributes are set once all contents have been written, the extended
+    // ones first, as the mode may leave the file read-only. Returns the size
+    // of the file.
+    pub fn restore_file(&self,
+                        path: &Path,
+                        block_list: &[BlockId],
+                        attributes: Attributes,
+                        xattrs: &[(String, Vec<u8>)],
+                        ignore_integrity: bool,
+                        summary: &mut RestorationSummary,
+                        progress: &mut Progress)
+                        -> BonzoResult<u64> {
+        report(progress, ProgressEvent::FileStarted(path.to_owned()));
+
+        try!(create_parent_dir(path));
+
+        let mut file = try_io!(File::create(path), path);
+
+        let written = try!(self.write_blocks(block_list,
+                                             &mut file,
+                                             ignore_integrity,
+                                             summary,
+                                             progress)
+                               .map_err(|error| {
+                                   match error {
+                                       BonzoError::Io(e, None) => {
+                                           BonzoError::Io(e, Some(path.to_owned()))
+                                       }
+                                       e => e,
+                                   }
+                               }));
+
+        try_io!(file.sync_all(), path);
+        drop(file);
+
+        for &(ref name, ref value) in xattrs {
+            try_io!(set_xattr(path, name, value), path);
+        }
+
+        try_io!(attributes.apply(path), path);
+
+        summary.add_file();
+        report(progress, ProgressEvent::FileCompleted(path.to_owned()));
+
+        Ok(written)
+    }
+
+    // Decrypts and inflates a sequence of blocks and writes them to the given
+    // sink in order. Blocks whose contents do not match their hash are an
+    // error, unless integrity is ignored, in which case they are written
+    // regardless and counted in the summary. Likewise, blocks which cannot be
+    // decompressed are then left out and counted. Blocks are taken from the
+    // cache when possible, and only intact blocks are added to it. Returns the
+    // number of bytes written.
+    pub fn write_blocks(&self,
+                        block_list: &[BlockId],
+                        out: &mut Write,
+                        ignore_integrity: bool,
+                        summary: &mut RestorationSummary,
+                        progress: &mut Progress)
+                        -> BonzoResult<u64> {
+        let mut written = 0;
+
+        for block_id in block_list.iter() {
+            let bytes = match self.cache.and_then(|cache| cache.lock().unwrap().get(*block_id)) {
+                Some(bytes) => bytes,
+                None => {
+                    match try!(self.load_block(*block_id, ignore_integrity, summary)) {
+                        Some(bytes) => bytes,
+                        None => continue,
+                    }
+                }
+            };
+
+            summary.add_block(&bytes);
+
+            try!(out.write_all(&bytes));
+            written += bytes.len() as u64;
+
+            report(progress, ProgressEvent::BlockWritten(bytes.len() as u64));
+        }
+
+        Ok(written)
+    }
+
+    // Reads, decrypts and inflates a single block, checking its integrity.
+    // Returns None when it could not be decompressed and integrity is
+    // ignored.
+    fn load_block(&self,
+                  block_id: BlockId,
+                  ignore_integrity: bool,
+                  summary: &mut RestorationSummary)
+                  -> BonzoResult<Option<Arc<Vec<u8>>>> {
+        let hash = try!(self.database.block_hash_from_id(block_id));
+        let (mut stored, length) = try!(self.backend.open_block(&hash));
+        let bytes = match decode_block_from(&mut *stored, length, self.crypto_scheme) {
+            Err(BonzoError::Decompression(..)) if ignore_integrity => {
+                summary.add_failed_decompression();
+                return Ok(None);
+            }
+            result => Arc::new(try!(result)),
+        };
+
+        if self.hash_algorithm.hash_block(&bytes) != hash {
+            if !ignore_integrity {
+                return Err(BonzoError::CorruptBlock);
+            }
+
+            summary.add_corrupted_block();
+        } else if let Some(cache) = self.cache {
+            cache.lock().unwrap().insert(block_id, bytes.clone());
+        }
+
+        Ok(Some(bytes))
+    }
+}
+
+// A record of the files written by a restore, for comparing the result with
+// what was expected. Every line holds the path of a file, the hash of its
+// contents as read back from disk, its size in bytes and its number of
+// blocks, separated by tabs. Lines are added as files are completed, so
+// their order varies between restores.
+pub struct RestoreManifest {
+    path: PathBuf,
+    file: Mutex<File>,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl RestoreManifest {
+    // Creates the manifest, replacing any existing file at the given path
+    pub fn create(path: &Path, hash_algorithm: HashAlgorithm) -> BonzoResult<RestoreManifest> {
+        Ok(RestoreManifest {
+            path: path.to_owned(),
+            file: Mutex::new(try_io!(File::create(path), path)),
+            hash_algorithm: hash_algorithm,
+        })
+    }
+
+    pub fn record(&self, path: &Path, bytes: u64, blocks: usize) -> BonzoResult<()> {
+        let hash = try_io!(self.hash_algorithm.hash_file(path), path);
+        let line = format!("{}\t{}\t{}\t{}\n", path.display(), hash.to_hex(), bytes, blocks);
+        let mut file = self.file.lock().unwrap();
+
+        Ok(try_io!(file.write_all(line.as_bytes()), &self.path))
+    }
+}
+
+// Recreates a symlink with the given target. Returns false when an existing
+// file was kept instead. With IfDifferent, only a link to the same target is
+// kept.
+pub fn restore_symlink(path: &Path, target: &str, overwrite: Overwrite) -> BonzoResult<bool> {
+    if let Ok(meta) = path.symlink_metadata() {
+        let identical = meta.file_type().is_symlink() &&
+                        read_link(path).map(|link| link == Path::new(target)).unwrap_or(false);
+
+        match overwrite {
+            Overwrite::Never => return Ok(false),
+            Overwrite::IfDifferent if identical => return Ok(false),
+            _ => try_io!(remove_file(path), path),
+        }
+    }
+
+    try!(create_parent_dir(path));
+    try_io!(create_symlink(target, path), path);
+
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> io::Result<()> {
+    ::std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_: &str, _: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Symlinks can only be restored on Unix"))
+}
+
+// Starts the given number of threads, which restore the files sent to the
+// returned producer. Every file is restored by a single thread, so its blocks
+// are written in order. The threads add their work to the shared summary and
+// send their progress and errors over the given channel. They quit once the
+// producer is dropped and all files sent have been handled.
+pub fn start_restore_threads<C>(database: &Database,
+                                backend: &Storage,
+                                crypto_scheme: &C,
+                                hash_algorithm: HashAlgorithm,
+                                ignore_integrity: bool,
+                                worker_count: usize,
+                                manifest: Option<Arc<RestoreManifest>>,
+                                cache: Arc<Mutex<BlockCache>>,
+                                summary: Arc<Mutex<RestorationSummary>>,
+                                events: Sender<BonzoResult<ProgressEvent>>)
+                                -> BonzoResult<(spmc::Producer<'static, RestoreJob>,
+                                                Vec<JoinHandle<()>>)>
+    where C: CryptoScheme + 'static
+{
+    let (job_transmitter, job_receiver) = unsafe { spmc::new(CHANNEL_BUFFER_SIZE) };
+    let mut workers = Vec::with_capacity(worker_count);
+
+    for _ in 0..worker_count {
+        let new_database = try!(database.try_clone());
+        let new_backend = backend.clone();
+        let scheme = *crypto_scheme;
+        let receiver = job_receiver.clone();
+        let shared_summary = summary.clone();
+        let transmitter = events.clone();
+        let manifest = manifest.clone();
+        let cache = cache.clone();
+
+        workers.push(spawn(move || {
+            let restorer = FileRestorer::new(&new_database, &new_backend, &scheme, hash_algorithm)
+                               .with_cache(&cache);
+
+            while let Ok((path, block_list, attributes, xattrs)) = receiver.recv_sync() {
+                let mut file_summary = RestorationSummary::new();
+                let result = {
+                    let mut forward = |event: ProgressEvent| {
+                        let _ = transmitter.send(Ok(event));
+                    };
+                    let mut progress: Progress = Some(&mut forward);
+
+                    restorer.restore_file(&path,
+                                          &block_list,
+                                          attributes,
+                                          &xattrs,
+                                          ignore_integrity,
+                                          &mut file_summary,
+                                          &mut progress)
+                            .and_then(|bytes| {
+                                match manifest {
+                                    Some(ref manifest) => {
+                                        manifest.record(&path, bytes, block_list.len())
+                                    }
+                                    None => Ok(()),
+                                }
+                            })
+                };
+
+                // we keep taking files after a failure, so that the producer
+                // is never left waiting for us
+                match result {
+                    Ok(()) => shared_summary.lock().unwrap().add_summary(&file_summary),
+                    Err(e) => {
+                        let _ = transmitter.send(Err(e));
+                    }
+                }
+            }
+        }));
+    }
+
+    Ok((job_transmitter, workers))
+}
+
+// Reports the progress events received from the restore threads, along with
+// the files completed and the errors. When waiting, this returns once all
+// threads have quit. Otherwise, it returns as soon as there are no more
+// events. Without a callback for the results, returns the first error
+// received.
+pub fn forward_events(events: &Receiver<BonzoResult<ProgressEvent>>,
+                      progress: &mut Progress,
+                      results: &mut FileResults,
+                      wait: bool)
+                      -> BonzoResult<()> {
+    loop {
+        let message = match wait {
+            true => events.recv().ok(),
+            false => events.try_recv().ok(),
+        };
+
+        match message {
+            Some(Ok(event)) => {
+                if let ProgressEvent::FileCompleted(ref path) = event {
+                    try!(report_file(results, Ok(path.clone())));
+                }
+
+                report(progress, event);
+            }
+            Some(Err(e)) => try!(report_file(results, Err(e))),
+            None => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::io::Read;
+use std::env;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+use ssh2::{self, Session, Sftp};
+use rustc_serialize::hex::ToHex;
+
+use error::{BonzoResult, BonzoError};
+use backend::{Backend, PARTIAL_DIRECTORY, SFTP_SCHEME, block_output_path};
+use throttle::{Throttle, write_throttled};
+
+static DEFAULT_PORT: u16 = 22;
+// Mode of the shard directories created on the server
+static DIRECTORY_MODE: i32 = 0o755;
+
+// Where a backup lives on a remote server, as given by a destination URL of
+// the form sftp://[user@]host[:port]/path. The user defaults to the local
+// one. The path is taken as is, so it is relative to the home directory of
+// the user unless it starts with a second slash.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SftpLocation {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub path: PathBuf,
+}
+
+impl SftpLocation {
+    pub fn parse(url: &str) -> BonzoResult<SftpLocation> {
+        let invalid = || BonzoError::Other(format!("Invalid SFTP destination: {}", url));
+
+        if !url.starts_with(SFTP_SCHEME) {
+            return Err(invalid());
+        }
+
+        let remainder = &url[SFTP_SCHEME.len()..];
+        let (authority, path) = match remainder.find('/') {
+            Some(index) => (&remainder[..index], &remainder[index + 1..]),
+            None => return Err(invalid()),
+        };
+        let (user, address) = match authority.rfind('@') {
+            Some(index) => (authority[..index].to_owned(), &authority[index + 1..]),
+            None => (try!(env::var("USER").map_err(|_| invalid())), authority),
+        };
+        let (host, port) = match address.rfind(':') {
+            Some(index) => {
+                (&address[..index], try!(address[index + 1..].parse().map_err(|_| invalid())))
+            }
+            None => (address, DEFAULT_PORT),
+        };
+
+        if user.is_empty() || host.is_empty() || path.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(SftpLocation {
+            user: user,
+            host: host.to_owned(),
+            port: port,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+// Stores blocks and the index on a remote server over SSH. Blocks are spread
+// over the same shard directories as they would be locally, so a backup can
+// be copied between the server and a local disk as is. Authentication is left
+// to the SSH agent. Every operation opens its own SFTP channel on the shared
+// session, as a channel cannot outlive the borrow of its session.
+pub struct SftpBackend {
+    location: SftpLocation,
+    shard_depth: usize,
+    session: Session,
+    // The session is only usable while its connection stays open
+    _stream: TcpStream,
+}
+
+impl SftpBackend {
+    pub fn connect(url: &str, shard_depth: usize) -> BonzoResult<SftpBackend> {
+        let location = try!(SftpLocation::parse(url));
+        let stream = try!(TcpStream::connect((&location.host[..], location.port)));
+        let mut session = try!(Session::new()
+                                   .ok_or(BonzoError::from_str("Could not start SSH session")));
+
+        try!(session.handshake(&stream).map_err(ssh_error));
+        try!(session.userauth_agent(&location.user).map_err(ssh_error));
+
+        if !session.authenticated() {
+            return Err(BonzoError::Other(format!("Could not authenticate as {} on {}",
+                                                 location.user,
+                                                 location.host)));
+        }
+
+        Ok(SftpBackend {
+            location: location,
+            shard_depth: shard_depth,
+            session: session,
+            _stream: stream,
+        })
+    }
+
+    fn sftp(&self) -> BonzoResult<Sftp> {
+        self.session.sftp().map_err(ssh_error)
+    }
+
+    fn block_path(&self, hash: &[u8]) -> PathBuf {
+        block_output_path(&self.location.path, hash, self.shard_depth)
+    }
+
+    // Writes the bytes under a temporary name and renames them into place,
+    // replacing whatever was there before. Servers speaking version 3 of the
+    // protocol, like OpenSSH, refuse to rename onto an existing file, so that
+    // is removed first. In between, only the temporary file is complete.
+    fn write_file(&self,
+                  temporary_path: &Path,
+                  path: &Path,
+                  bytes: &[u8],
+                  throttle: Option<&Throttle>)
+                  -> BonzoResult<()> {
+        let sftp = try!(self.sftp());
+
+        try!(create_remote_parent_dir(&sftp, temporary_path));
+        try!(create_remote_parent_dir(&sftp, path));
+
+        {
+            let mut file = try!(sftp.create(temporary_path).map_err(ssh_error));
+
+            try_io!(write_throttled(throttle, &mut file, bytes), temporary_path);
+        }
+
+        if sftp.stat(path).is_ok() {
+            try!(sftp.unlink(path).map_err(ssh_error));
+        }
+
+        sftp.rename(temporary_path, path, Some(ssh2::RenameFlags::empty())).map_err(ssh_error)
+    }
+
+    fn file_exists(&self, path: &Path) -> BonzoResult<bool> {
+        Ok(try!(self.sftp()).stat(path).is_ok())
+    }
+
+    fn read_file(&self, path: &Path) -> BonzoResult<Vec<u8>> {
+        let sftp = try!(self.sftp());
+        let mut file = try!(sftp.open(path).map_err(|_| {
+            BonzoError::Other(format!("Could not find {} on {}",
+                                      path.display(),
+                                      self.location.host))
+        }));
+        let mut buffer = Vec::new();
+
+        try_io!(file.read_to_end(&mut buffer), path);
+
+        Ok(buffer)
+    }
+}
+
+impl Backend for SftpBackend {
+    fn write_block(&self, hash: &[u8], bytes: &[u8], throttle: Option<&Throttle>)
+                   -> BonzoResult<()> {
+        let partial_path = self.location.path.join(PARTIAL_DIRECTORY).join(hash.to_hex());
+
+        self.write_file(&partial_path, &self.block_path(hash), bytes, throttle)
+    }
+
+    fn read_block(&self, hash: &[u8]) -> BonzoResult<Vec<u8>> {
+        let path = self.block_path(hash);
+
+        match try!(self.file_exists(&path)) {
+            true => self.read_file(&path),
+            false => {
+                Err(BonzoError::MissingBlock(format!("Could not find block {} on {}",
+                                                     hash.to_hex(),
+                                                     self.location.host)))
+            }
+        }
+    }
+
+    fn remove_block(&self, hash: &[u8]) -> BonzoResult<Option<u64>> {
+        let sftp = try!(self.sftp());
+        let path = self.block_path(hash);
+        let size = match sftp.stat(&path) {
+            Ok(stat) => stat.size.unwrap_or(0),
+            Err(..) => return Ok(None),
+        };
+
+        try!(sftp.unlink(&path).map_err(ssh_error));
+
+        Ok(Some(size))
+    }
+
+    fn write_index(&self, bytes: &[u8], throttle: Option<&Throttle>) -> BonzoResult<()> {
+        let new_index = self.location.path.join("index-new");
+
+        self.write_file(&new_index, &self.location.path.join("index"), bytes, throttle)
+    }
+
+    // The new index is read when the previous one was removed to make room
+    // for it, but the rename did not happen
+    fn read_index(&self) -> BonzoResult<Vec<u8>> {
+        let index = self.location.path.join("index");
+
+        match try!(self.file_exists(&index)) {
+            true => self.read_file(&index),
+            false => self.read_file(&self.location.path.join("index-new")),
+        }
+    }
+
+    fn write_metadata(&self, name: &str, bytes: &[u8]) -> BonzoResult<()> {
+        let partial_path = self.location.path.join(PARTIAL_DIRECTORY).join(name);
+
+        self.write_file(&partial_path, &self.location.path.join(name), bytes, None)
+    }
+
+    fn read_metadata(&self, name: &str) -> BonzoResult<Option<Vec<u8>>> {
+        let path = self.location.path.join(name);
+
+        match try!(self.file_exists(&path)) {
+            true => self.read_file(&path).map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+// Creates the parent directory of the given remote path and all of its
+// ancestors which do not exist yet
+fn create_remote_parent_dir(sftp: &Sftp, path: &Path) -> BonzoResult<()> {
+    let mut missing = Vec::new();
+    let mut directory = path.parent();
+
+    while let Some(parent) = directory {
+        if parent.as_os_str().is_empty() || sftp.stat(parent).is_ok() {
+            break;
+        }
+
+        missing.push(parent);
+        directory = parent.parent();
+    }
+
+    for parent in missing.into_iter().rev() {
+        try!(sftp.mkdir(parent, DIRECTORY_MODE).map_err(ssh_error));
+    }
+
+    Ok(())
+}
+
+fn ssh_error(error: ssh2::Error) -> BonzoError {
+    BonzoError::Other(format!("SSH error: {}", error))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::SftpLocation;
+
+    #[test]
+    fn locations() {
+        assert_eq!(SftpLocation {
+                       user: "marcus".to_owned(),
+                       host: "nas.local".to_owned(),
+                       port: 2222,
+                       path: PathBuf::from("backups/photos"),
+                   },
+                   SftpLocation::parse("sftp://marcus@nas.local:2222/backups/photos").unwrap());
+
+        let absolute = SftpLocation::parse("sftp://marcus@nas.local//srv/backup").unwrap();
+
+        assert_eq!(22, absolute.port);
+        assert_eq!(PathBuf::from("/srv/backup"), absolute.path);
+
+        assert!(SftpLocation::parse("/local/path").is_err());
+        assert!(SftpLocation::parse("sftp://marcus@nas.local").is_err());
+        assert!(SftpLocation::parse("sftp://marcus@nas.local/").is_err());
+        assert!(SftpLocation::parse("sftp://marcus@nas.local:ssh/backup").is_err());
+        assert!(SftpLocation::parse("sftp://@nas.local/backup").is_err());
+    }
+}
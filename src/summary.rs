@@ -4,6 +4,7 @@ use self::number_prefix::{decimal_prefix, Standalone, Prefixed};
 
 use std::fmt;
 use std::time::Duration;
+use std::path::PathBuf;
 use super::time;
 
 fn format_bytes(bytes: u64) -> String {
@@ -22,17 +23,43 @@ impl fmt::Display for InitSummary {
     }
 }
 
+#[derive(Debug)]
+pub struct PasswordSummary;
+
+impl fmt::Display for PasswordSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Changed the passphrase of the index. Blocks stored earlier can still only be \
+             decrypted with the old passphrase."
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct CleanupSummary {
     pub bytes: u64,
     pub aliases: u64,
     pub blocks: u64,
+    // Nothing was actually removed; the numbers are what a cleanup would
+    // remove
+    pub dry_run: bool,
 }
 
 impl fmt::Display for CleanupSummary {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let byte_desc = format_bytes(self.bytes);
 
+        if self.dry_run {
+            return write!(
+                f,
+                "Would clean up {} old versions of files, removing {} blocks containing {}.",
+                self.aliases,
+                self.blocks,
+                byte_desc
+            );
+        }
+
         write!(
             f,
             "Cleaned up {} old versions of files, removing {} blocks containing {}.",
@@ -43,6 +70,254 @@ impl fmt::Display for CleanupSummary {
     }
 }
 
+// Outcome of checking or rebuilding the shard manifests. Missing refers to
+// blocks on disk which are not in any manifest, stale to manifest entries for
+// which there is no block on disk.
+#[derive(Debug)]
+pub struct ManifestSummary {
+    pub blocks: u64,
+    pub missing: u64,
+    pub stale: u64,
+    pub rebuilt: bool,
+}
+
+impl fmt::Display for ManifestSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.rebuilt {
+            return write!(f, "Rebuilt shard manifests listing {} blocks.", self.blocks);
+        }
+
+        write!(
+            f,
+            "Shard manifests list {} blocks. {} blocks are missing from the manifests, {} \
+             entries are stale.",
+            self.blocks,
+            self.missing,
+            self.stale
+        )
+    }
+}
+
+// Problems reported by SQLite's integrity check of an index
+#[derive(Debug)]
+pub struct IndexCheckSummary {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for IndexCheckSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.problems.is_empty() {
+            return write!(f, "Index passed the integrity check.");
+        }
+
+        try!(write!(f, "Index failed the integrity check with {} problems:", self.problems.len()));
+
+        for problem in self.problems.iter() {
+            try!(write!(f, "\n{}", problem));
+        }
+
+        Ok(())
+    }
+}
+
+// Files in the source directory which differ from the backup at some moment,
+// relative to the source directory
+#[derive(Debug)]
+pub struct SourceDiff {
+    pub added: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+}
+
+impl SourceDiff {
+    pub fn new() -> SourceDiff {
+        SourceDiff { added: Vec::new(), modified: Vec::new(), deleted: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+}
+
+impl fmt::Display for SourceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "No changes since the backup.");
+        }
+
+        let groups = [("Added", &self.added),
+                      ("Modified", &self.modified),
+                      ("Deleted", &self.deleted)];
+        let mut first = true;
+
+        for &(title, paths) in groups.iter() {
+            if paths.is_empty() {
+                continue;
+            }
+
+            if !first {
+                try!(write!(f, "\n"));
+            }
+
+            first = false;
+            try!(write!(f, "{} ({}):", title, paths.len()));
+
+            for path in paths.iter() {
+                try!(write!(f, "\n  {}", path.display()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Blocks copied refers to blocks written to the receiving backup, while
+// skipped blocks were already present there. The bytes field counts the
+// bytes written.
+#[derive(Debug)]
+pub struct SyncSummary {
+    pub blocks_copied: u64,
+    pub blocks_skipped: u64,
+    pub bytes: u64,
+    pub files: u64,
+    pub aliases: u64,
+}
+
+impl SyncSummary {
+    pub fn new() -> SyncSummary {
+        SyncSummary { blocks_copied: 0, blocks_skipped: 0, bytes: 0, files: 0, aliases: 0 }
+    }
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Copied {} blocks containing {}, skipped {} blocks which were already present. \
+             Added {} files and {} versions of files.",
+            self.blocks_copied,
+            format_bytes(self.bytes),
+            self.blocks_skipped,
+            self.files,
+            self.aliases
+        )
+    }
+}
+
+// Outcome of checking every block referenced by the index. Corrupt blocks
+// could not be decrypted or decompressed, or did not match their hash.
+#[derive(Debug)]
+pub struct VerifySummary {
+    pub ok: u64,
+    pub corrupt: u64,
+    pub missing: u64,
+}
+
+impl VerifySummary {
+    pub fn new() -> VerifySummary {
+        VerifySummary { ok: 0, corrupt: 0, missing: 0 }
+    }
+}
+
+impl fmt::Display for VerifySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Verified {} blocks: {} intact, {} corrupt, {} missing.",
+            self.ok + self.corrupt + self.missing,
+            self.ok,
+            self.corrupt,
+            self.missing
+        )
+    }
+}
+
+// Dangling rows found in the index, along with blocks no file uses. When
+// fixed, these have been removed, as have files which missed blocks.
+#[derive(Debug)]
+pub struct FsckSummary {
+    pub fileblocks: u64,
+    pub aliases: u64,
+    pub blocks: u64,
+    pub fixed: bool,
+}
+
+impl FsckSummary {
+    pub fn is_consistent(&self) -> bool {
+        self.fileblocks + self.aliases + self.blocks == 0
+    }
+}
+
+impl fmt::Display for FsckSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_consistent() {
+            return write!(f, "Index references are consistent.");
+        }
+
+        try!(write!(f,
+                    "Found {} dangling file blocks, {} dangling aliases and {} unused blocks.",
+                    self.fileblocks,
+                    self.aliases,
+                    self.blocks));
+
+        match self.fixed {
+            true => write!(f, " These have been removed."),
+            false => write!(f, " Run with --fix to remove them."),
+        }
+    }
+}
+
+// Size of a backup. Stored bytes are the bytes taken by the blocks at the
+// destinations. Referenced bytes are the logical bytes the versions of files
+// would take up without deduplication, counting the size of a file once for
+// every alias referring to it.
+#[derive(Debug)]
+pub struct RepoStats {
+    pub blocks: u64,
+    pub files: u64,
+    pub aliases: u64,
+    pub stored_bytes: u64,
+    pub referenced_bytes: u64,
+    // Size of the contents of the distinct files, as recorded in the index.
+    // Files backed up by older releases have no recorded size, and are only
+    // counted.
+    pub file_bytes: u64,
+    pub unsized_files: u64,
+}
+
+impl RepoStats {
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.stored_bytes {
+            0 => 1.0,
+            stored => (self.referenced_bytes as f64) / (stored as f64),
+        }
+    }
+}
+
+impl fmt::Display for RepoStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(
+            f,
+            "{} versions of {} distinct files, stored in {} blocks containing {}.\n\
+             Without deduplication, the versions would take up {}.\n\
+             Deduplication ratio: {:.2}",
+            self.aliases,
+            self.files,
+            self.blocks,
+            format_bytes(self.stored_bytes),
+            format_bytes(self.referenced_bytes),
+            self.dedup_ratio()
+        ));
+
+        try!(write!(f, "\nThe distinct files hold {}", format_bytes(self.file_bytes)));
+
+        match self.unsized_files {
+            0 => write!(f, "."),
+            count => write!(f, ", besides {} files of unknown size.", count),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Summary {
     pub bytes: u64,
@@ -74,54 +349,166 @@ impl Summary {
 }
 
 // The bytes field refers to the number of bytes restored (after decryption and
-// decompression)
+// decompression). Files which were hard linked to an identical file restored
+// earlier are counted as hardlinks, not as files. Corrupted blocks are those
+// restored despite failing the integrity check. Failed decompressions are
+// blocks left out of their files because they could not be decompressed.
+// Skipped files are existing files which were left as they were.
 #[derive(Debug)]
-pub struct RestorationSummary(Summary);
+pub struct RestorationSummary {
+    pub summary: Summary,
+    pub hardlinks: u64,
+    pub corrupted_blocks: u64,
+    pub failed_decompressions: u64,
+    pub skipped: u64,
+    // Empty directories recreated
+    pub directories: u64,
+    // Symlinks recreated from their stored targets
+    pub symlinks: u64,
+}
 
 impl RestorationSummary {
     pub fn new() -> RestorationSummary {
-        RestorationSummary(Summary::new())
+        RestorationSummary {
+            summary: Summary::new(),
+            hardlinks: 0,
+            corrupted_blocks: 0,
+            failed_decompressions: 0,
+            skipped: 0,
+            directories: 0,
+            symlinks: 0,
+        }
     }
 
     pub fn add_block(&mut self, block: &[u8]) {
-        self.0.add_block(block)
+        self.summary.add_block(block)
     }
 
     pub fn add_file(&mut self) {
-        self.0.add_file()
+        self.summary.add_file()
+    }
+
+    pub fn add_hardlink(&mut self) {
+        self.hardlinks += 1;
+    }
+
+    pub fn add_corrupted_block(&mut self) {
+        self.corrupted_blocks += 1;
+    }
+
+    pub fn add_failed_decompression(&mut self) {
+        self.failed_decompressions += 1;
+    }
+
+    pub fn add_skipped_file(&mut self) {
+        self.skipped += 1;
+    }
+
+    pub fn add_directory(&mut self) {
+        self.directories += 1;
+    }
+
+    pub fn add_symlink(&mut self) {
+        self.symlinks += 1;
+    }
+
+    // Adds the counts of another summary to this one
+    pub fn add_summary(&mut self, other: &RestorationSummary) {
+        self.summary.bytes += other.summary.bytes;
+        self.summary.blocks += other.summary.blocks;
+        self.summary.files += other.summary.files;
+        self.hardlinks += other.hardlinks;
+        self.corrupted_blocks += other.corrupted_blocks;
+        self.failed_decompressions += other.failed_decompressions;
+        self.skipped += other.skipped;
+        self.directories += other.directories;
+        self.symlinks += other.symlinks;
     }
 }
 
 impl fmt::Display for RestorationSummary {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let seconds_passed = self.0.duration().as_secs();
-        let byte_desc = format_bytes(self.0.bytes);
+        let seconds_passed = self.summary.duration().as_secs();
+        let byte_desc = format_bytes(self.summary.bytes);
 
-        write!(
+        try!(write!(
             f,
             "Restored {} to {} files, from {} blocks in {} seconds.",
             byte_desc,
-            self.0.files,
-            self.0.blocks,
+            self.summary.files,
+            self.summary.blocks,
             seconds_passed
-        )
+        ));
+
+        if self.hardlinks > 0 {
+            try!(write!(f, "\nCreated {} hardlinks to identical files.", self.hardlinks));
+        }
+
+        if self.skipped > 0 {
+            try!(write!(f, "\nSkipped {} files which already existed.", self.skipped));
+        }
+
+        if self.directories > 0 {
+            try!(write!(f, "\nRecreated {} empty directories.", self.directories));
+        }
+
+        if self.symlinks > 0 {
+            try!(write!(f, "\nRecreated {} symlinks.", self.symlinks));
+        }
+
+        if self.corrupted_blocks > 0 {
+            try!(write!(
+                f,
+                "\nWarning: {} blocks failed the integrity check.",
+                self.corrupted_blocks
+            ));
+        }
+
+        if self.failed_decompressions > 0 {
+            try!(write!(
+                f,
+                "\nWarning: {} blocks could not be decompressed and were left out.",
+                self.failed_decompressions
+            ));
+        }
+
+        Ok(())
     }
 }
 
 // The bytes field refers to the number of bytes stored at the backup location
 // after compression and encryption.
 // Only newly written files and blocks will be included in this summary.
+// Skipped files are those left out for exceeding the maximum file size. For a
+// dry run, the files and blocks are those which would have been stored.
 #[derive(Debug)]
 pub struct BackupSummary {
     pub summary: Summary,
     pub cleanup: Option<CleanupSummary>,
     pub source_bytes: u64,
     pub timeout: bool,
+    pub warnings: Vec<String>,
+    pub skipped_files: u64,
+    pub dry_run: bool,
+    // Blocks which were found in the backup already, so they did not have to
+    // be written again, and the number of bytes read from the source for them
+    pub deduplicated_blocks: u64,
+    pub deduplicated_bytes: u64,
 }
 
 impl BackupSummary {
     pub fn new() -> BackupSummary {
-        BackupSummary { summary: Summary::new(), cleanup: None, source_bytes: 0, timeout: false }
+        BackupSummary {
+            summary: Summary::new(),
+            cleanup: None,
+            source_bytes: 0,
+            timeout: false,
+            warnings: Vec::new(),
+            skipped_files: 0,
+            dry_run: false,
+            deduplicated_blocks: 0,
+            deduplicated_bytes: 0,
+        }
     }
 
     pub fn add_block(&mut self, block: &[u8], source_bytes: u64) {
@@ -133,9 +520,22 @@ impl BackupSummary {
         self.summary.add_file()
     }
 
+    pub fn add_deduplicated_blocks(&mut self, blocks: u64, source_bytes: u64) {
+        self.deduplicated_blocks += blocks;
+        self.deduplicated_bytes += source_bytes;
+    }
+
     pub fn add_cleanup_summary(&mut self, summary: CleanupSummary) {
         self.cleanup = Some(summary);
     }
+
+    pub fn add_warning(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    pub fn add_skipped_file(&mut self) {
+        self.skipped_files += 1;
+    }
 }
 
 impl fmt::Display for BackupSummary {
@@ -155,10 +555,27 @@ impl fmt::Display for BackupSummary {
             compression_ratio
         ));
 
+        try!(write!(f,
+                    "\nDeduplicated {} blocks containing {}.",
+                    self.deduplicated_blocks,
+                    format_bytes(self.deduplicated_bytes)));
+
         if let Some(ref cleanup_summary) = self.cleanup {
             try!(write!(f, "\n{}", cleanup_summary.to_string()))
         }
 
+        if self.skipped_files > 0 {
+            try!(write!(f, "\nSkipped {} files exceeding the maximum size.", self.skipped_files))
+        }
+
+        for warning in self.warnings.iter() {
+            try!(write!(f, "\nWarning: {}", warning))
+        }
+
+        if self.dry_run {
+            try!(write!(f, "\nThis was a dry run. Nothing was written to the backup."))
+        }
+
         Ok(())
     }
 }
@@ -175,7 +592,7 @@ mod test {
         let mut summary = super::RestorationSummary::new();
         let now = time::get_time().sec;
 
-        let time_diff_seconds = (now - summary.0.start as i64).abs();
+        let time_diff_seconds = (now - summary.summary.start as i64).abs();
         assert!(time_diff_seconds < 10);
 
         let vec: Vec<u8> = repeat(5).take(1000).collect();
@@ -212,5 +629,11 @@ mod test {
         let re = ::regex::Regex::new(r"Compression ratio: 0\.1").unwrap();
 
         assert!(re.is_match(&representation));
+        assert!(representation.contains("Deduplicated 0 blocks containing 0 bytes."));
+
+        summary.add_deduplicated_blocks(2, 300);
+        summary.add_deduplicated_blocks(1, 100);
+
+        assert!(summary.to_string().contains("Deduplicated 3 blocks containing 400 bytes."));
     }
 }
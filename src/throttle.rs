@@ -0,0 +1,100 @@
+use std::io::{self, Write};
+use std::cell::Cell;
+use std::cmp;
+use std::thread::sleep;
+use std::time::Duration;
+
+use time::precise_time_ns;
+
+// Bytes are handed to the writer in pieces of at most this size, so that a
+// large buffer does not exceed the rate in a single burst
+static CHUNK_BYTES: usize = 64 * 1024;
+static NANOSECONDS_PER_SECOND: f64 = 1_000_000_000.0;
+
+// Limits the rate at which bytes are written using a token bucket. The bucket
+// holds at most a second's worth of bytes, so writes may briefly burst at
+// that size after a pause. Interior mutability lets it be shared by methods
+// which only borrow the backup manager.
+pub struct Throttle {
+    bytes_per_second: u64,
+    // Bytes which may be written without waiting. Negative after writing more
+    // than was allowed, until it is paid back.
+    allowance: Cell<f64>,
+    last_update: Cell<u64>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Throttle {
+        Throttle {
+            bytes_per_second: cmp::max(1, bytes_per_second),
+            allowance: Cell::new(bytes_per_second as f64),
+            last_update: Cell::new(precise_time_ns()),
+        }
+    }
+
+    // Takes the given number of bytes from the bucket, waiting until it has
+    // filled up enough when it runs dry
+    pub fn consume(&self, bytes: u64) {
+        let rate = self.bytes_per_second as f64;
+        let now = precise_time_ns();
+        let elapsed = (now - self.last_update.get()) as f64 / NANOSECONDS_PER_SECOND;
+        let refilled = (self.allowance.get() + elapsed * rate).min(rate);
+        let remaining = refilled - bytes as f64;
+
+        self.last_update.set(now);
+        self.allowance.set(remaining);
+
+        if remaining < 0.0 {
+            let nanoseconds = (-remaining / rate * NANOSECONDS_PER_SECOND) as u64;
+
+            sleep(Duration::new(nanoseconds / 1_000_000_000,
+                                (nanoseconds % 1_000_000_000) as u32));
+        }
+    }
+
+    // Writes all bytes, waiting between chunks to stay within the rate
+    pub fn write_all(&self, out: &mut Write, bytes: &[u8]) -> io::Result<()> {
+        for chunk in bytes.chunks(CHUNK_BYTES) {
+            self.consume(chunk.len() as u64);
+
+            try!(out.write_all(chunk));
+        }
+
+        Ok(())
+    }
+}
+
+// Writes the bytes, within the rate of the throttle if there is one
+pub fn write_throttled(throttle: Option<&Throttle>,
+                       out: &mut Write,
+                       bytes: &[u8])
+                       -> io::Result<()> {
+    match throttle {
+        Some(throttle) => throttle.write_all(out, bytes),
+        None => out.write_all(bytes),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::precise_time_ns;
+
+    use super::Throttle;
+
+    #[test]
+    fn limits_rate() {
+        let throttle = Throttle::new(100_000);
+        let mut sink = Vec::new();
+        let start = precise_time_ns();
+
+        // the first second's worth goes through right away, the rest takes
+        // half a second more
+        throttle.write_all(&mut sink, &[0; 150_000]).unwrap();
+
+        let elapsed_milliseconds = (precise_time_ns() - start) / 1_000_000;
+
+        assert_eq!(150_000, sink.len());
+        assert!(elapsed_milliseconds >= 400, "took {} ms", elapsed_milliseconds);
+        assert!(elapsed_milliseconds < 2000, "took {} ms", elapsed_milliseconds);
+    }
+}
@@ -0,0 +1,88 @@
+// Helpers shared by the integration tests. Not every test crate uses all of
+// them.
+#![allow(dead_code)]
+
+use std::io::{self, Read, Write};
+use std::fs::{File, OpenOptions, create_dir_all, read_dir};
+use std::collections::BTreeMap;
+use std::convert::AsRef;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use time::get_time;
+use tempdir::TempDir;
+
+use backbonzo::{AesEncrypter, DATABASE_FILENAME};
+
+// What most tests start out with: an empty source directory, a directory to
+// back up to, the test passphrase and a time limit no test should run into.
+// Tests move out the fields they need. The directories stay behind in the
+// fixture, and are removed when it goes out of scope.
+pub struct Fixture {
+    pub source_path: PathBuf,
+    pub destination_path: PathBuf,
+    pub crypto_scheme: AesEncrypter,
+    pub time_limit: Duration,
+    _source_temp: TempDir,
+    _destination_temp: TempDir,
+}
+
+impl Fixture {
+    pub fn new(name: &str) -> Fixture {
+        let source_temp = TempDir::new(&format!("{}-source", name)).unwrap();
+        let destination_temp = TempDir::new(&format!("{}-dest", name)).unwrap();
+
+        Fixture {
+            source_path: source_temp.path().to_owned(),
+            destination_path: destination_temp.path().to_owned(),
+            crypto_scheme: AesEncrypter::new("testpassword"),
+            time_limit: Duration::from_secs(1 * 60),
+            _source_temp: source_temp,
+            _destination_temp: destination_temp,
+        }
+    }
+}
+
+pub fn open_read_write<P: AsRef<Path>>(path: &P) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).append(false).open(path)
+}
+
+pub fn epoch_milliseconds() -> u64 {
+    let stamp = get_time();
+
+    stamp.nsec as u64 / 1000 / 1000 + stamp.sec as u64 * 1000
+}
+
+// Writes a file with the given contents, creating its parent directories
+pub fn write_file(path: &Path, bytes: &[u8]) {
+    create_dir_all(path.parent().unwrap()).unwrap();
+
+    let mut file = File::create(path).unwrap();
+    file.write_all(bytes).unwrap();
+    file.sync_all().unwrap();
+}
+
+// Reads every file below the given directory, keyed by its path relative to
+// that directory. The index, which lives in the source directory by default,
+// is left out.
+pub fn read_tree(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    let mut directories = vec![root.to_owned()];
+
+    while let Some(directory) = directories.pop() {
+        for entry in read_dir(&directory).unwrap() {
+            let path = entry.unwrap().path();
+
+            if path.is_dir() {
+                directories.push(path);
+            } else if path.file_name().unwrap() != DATABASE_FILENAME {
+                let mut contents = Vec::new();
+                File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+
+                files.insert(path.strip_prefix(root).unwrap().to_owned(), contents);
+            }
+        }
+    }
+
+    files
+}
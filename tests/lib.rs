@@ -1,34 +1,34 @@
 extern crate backbonzo;
 extern crate time;
 extern crate tempdir;
-
-use backbonzo::{AesEncrypter, BonzoError};
-use std::io::{self, Read, Write};
-use std::fs::{File, create_dir_all, rename, remove_file, OpenOptions, read_dir};
-use time::{Duration as NonStdDuration, get_time};
+extern crate glob;
+extern crate filetime;
+extern crate xattr;
+
+use backbonzo::{AesEncrypter, BonzoError, BackupOptions, InitOptions, RestoreOptions,
+                Overwrite, UsagePolicy, KeyParams, Compression, ChaChaEncrypter, CryptoScheme,
+                ProgressEvent, Chunking, ArchiveFormat, BonzoResult};
+use std::io::{Read, Write};
+use std::fs::{File, create_dir_all, rename, remove_file, remove_dir_all, read_dir};
 use std::time::Duration;
 use tempdir::TempDir;
-use std::convert::AsRef;
+use glob::Pattern;
 use std::borrow::ToOwned;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
+use std::iter::repeat;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 
-// FIXME: loads of code duplication here. Clean it up!
+use common::{open_read_write, epoch_milliseconds, write_file, Fixture};
 
-fn open_read_write<P: AsRef<Path>>(path: &P) -> io::Result<File> {
-    OpenOptions::new().read(true).write(true).append(false).open(path)
-}
+mod common;
 
 // Regression test for the bug where backbonzo would err when it tried to remove
 // a file during clean up which was already deleted earlier.
 #[test]
 fn cleanup_regression_test() {
-    let source_temp = TempDir::new("cleanup-source").unwrap();
-    let destination_temp = TempDir::new("cleanup-dest").unwrap();
-    let source_path = source_temp.path().to_owned();
-    let destination_path = destination_temp.path().to_owned();
-    let crypto_scheme = AesEncrypter::new("testpassword");
-    let deadline = time::now() + NonStdDuration::minutes(1);
+    let fixture = Fixture::new("cleanup");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
 
     let init_result = backbonzo::init(&source_path, &destination_path, &crypto_scheme);
 
@@ -36,14 +36,12 @@ fn cleanup_regression_test() {
 
     // write initial file
     let file_path = source_path.join("file1");
-    {
-        let mut file = File::create(&file_path).unwrap();
-        file.write_all(b"first edition!").ok().expect("Failed writing to file.");
-        assert!(file.sync_all().is_ok());
-    }
+    write_file(&file_path, b"first edition!");
 
     // run backup of file
-    backbonzo::backup(source_path.clone(), 1000000, &crypto_scheme, 0, deadline)
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
         .ok()
         .expect("First backup failed");
 
@@ -71,7 +69,9 @@ fn cleanup_regression_test() {
     assert!(deletion_counter >= 1);
 
     // rerun backup with very strict max_age parameter
-    let summary = backbonzo::backup(source_path.clone(), 1000000, &crypto_scheme, 1, deadline)
+    let summary = backbonzo::backup(source_path.clone(),
+                                    &crypto_scheme,
+                                    &BackupOptions::new(1000000, 1, time_limit))
                       .unwrap();
 
     let cleanup_summary = &summary.cleanup.unwrap();
@@ -84,12 +84,8 @@ fn cleanup_regression_test() {
 
 #[test]
 fn cleanup() {
-    let source_temp = TempDir::new("cleanup-source").unwrap();
-    let destination_temp = TempDir::new("cleanup-dest").unwrap();
-    let source_path = source_temp.path().to_owned();
-    let destination_path = destination_temp.path().to_owned();
-    let crypto_scheme = AesEncrypter::new("testpassword");
-    let deadline = time::now() + NonStdDuration::minutes(1);
+    let fixture = Fixture::new("cleanup");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
 
     let init_result = backbonzo::init(&source_path, &destination_path, &crypto_scheme);
 
@@ -97,14 +93,12 @@ fn cleanup() {
 
     // write initial file
     let file_path = source_path.join("file1");
-    {
-        let mut file = File::create(&file_path).unwrap();
-        file.write_all(b"first edition!").ok().expect("Failed writing to file.");
-        assert!(file.sync_all().is_ok());
-    }
+    write_file(&file_path, b"first edition!");
 
     // run backup of file
-    backbonzo::backup(source_path.clone(), 1000000, &crypto_scheme, 0, deadline)
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
         .ok()
         .expect("First backup failed");
 
@@ -116,7 +110,9 @@ fn cleanup() {
     remove_file(&file_path).ok().expect("Couldn't remove file");
     assert!(file_path.exists() == false);
 
-    backbonzo::backup(source_path.clone(), 1000000, &crypto_scheme, 60 * 1000, deadline)
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 60 * 1000, time_limit))
         .ok()
         .expect("Second backup failed");
 
@@ -136,7 +132,9 @@ fn cleanup() {
     assert!(file_path.exists() == false);
 
     // run backup with very strict max_age parameter
-    backbonzo::backup(source_path.clone(), 1000000, &crypto_scheme, 1, deadline)
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 1, time_limit))
         .ok()
         .expect("Third backup failed");
 
@@ -166,7 +164,7 @@ fn init() {
     let second_result = backbonzo::init(&source_dir.path(), &backup_dir.path(), &crypto_scheme);
 
     let is_expected = match second_result {
-        Err(BonzoError::Other(ref str)) => &str[..] == "Database file already exists",
+        Err(BonzoError::AlreadyInitialized) => true,
         _ => false,
     };
 
@@ -178,7 +176,7 @@ fn backup_wrong_password() {
     let dir = TempDir::new("wrong-password").unwrap();
     let source_path = dir.path().to_owned();
     let destination_path = source_path.clone();
-    let deadline = time::now();
+    let time_limit = Duration::from_secs(0);
 
     assert!(
         backbonzo::init(
@@ -189,13 +187,11 @@ fn backup_wrong_password() {
     );
 
     let backup_result = backbonzo::backup(source_path,
-                                          1000000,
                                           &AesEncrypter::new("differentpassword"),
-                                          0,
-                                          deadline);
+                                          &BackupOptions::new(1000000, 0, time_limit));
 
     let is_expected = match backup_result {
-        Err(BonzoError::Other(ref str)) => &str[..] == "Password is not the same as in database",
+        Err(BonzoError::WrongPassword) => true,
         _ => false,
     };
 
@@ -206,13 +202,11 @@ fn backup_wrong_password() {
 fn backup_no_init() {
     let dir = TempDir::new("no-init").unwrap();
     let source_path = dir.path().to_owned();
-    let deadline = time::now();
+    let time_limit = Duration::from_secs(0);
 
     let backup_result = backbonzo::backup(source_path,
-                                          1000000,
                                           &AesEncrypter::new("differentpassword"),
-                                          0,
-                                          deadline);
+                                          &BackupOptions::new(1000000, 0, time_limit));
 
     assert_eq!(&format!("{}", backup_result.unwrap_err())[..],
                "Database error: unable to open database file");
@@ -221,12 +215,8 @@ fn backup_no_init() {
 #[test]
 // tests recursive behaviour, and filters for restore
 fn backup_and_restore() {
-    let source_temp = TempDir::new("source").unwrap();
-    let destination_temp = TempDir::new("destination").unwrap();
-    let source_path = source_temp.path().to_owned();
-    let destination_path = destination_temp.path().to_owned();
-    let crypto_scheme = AesEncrypter::new("testpassword");
-    let deadline = time::now() + NonStdDuration::minutes(1);
+    let fixture = Fixture::new("backup");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
 
     assert!(create_dir_all(&source_path.join("test")).is_ok());
 
@@ -234,18 +224,10 @@ fn backup_and_restore() {
     let bytes = b"71d6e2f35502c03743f676449c503f487de29988";
 
     for filename in filenames.iter() {
-        let file_path = source_path.join(filename);
-        let mut file = File::create(&file_path).unwrap();
-        assert!(file.write_all(bytes).is_ok());
-        assert!(file.sync_all().is_ok());
+        write_file(&source_path.join(filename), bytes);
     }
 
-    {
-        let subdir_path = source_path.join("test").join("welcomg!");
-        let mut file = File::create(&subdir_path).unwrap();
-        assert!(file.write_all(bytes).is_ok());
-        assert!(file.sync_all().is_ok());
-    }
+    write_file(&source_path.join("test").join("welcomg!"), bytes);
 
     assert!(
         backbonzo::init(
@@ -256,10 +238,8 @@ fn backup_and_restore() {
     );
 
     let backup_result = backbonzo::backup(source_path.clone(),
-                                          1000000,
                                           &crypto_scheme,
-                                          0,
-                                          deadline);
+                                          &BackupOptions::new(1000000, 0, time_limit));
 
     assert!(backup_result.is_ok());
 
@@ -290,21 +270,14 @@ fn backup_and_restore() {
     assert!(restore_path.join("test").join("welcomg!").exists());
 }
 
-fn epoch_milliseconds() -> u64 {
-    let stamp = get_time();
-
-    stamp.nsec as u64 / 1000 / 1000 + stamp.sec as u64 * 1000
-}
-
 #[test]
 fn renames() {
-    let source_temp = TempDir::new("rename-source").unwrap();
-    let destination_temp = TempDir::new("first-destination").unwrap();
-    let source_path = source_temp.path().to_owned();
-    let destination_path = destination_temp.path().to_owned();
+    let fixture = Fixture::new("rename");
+    let Fixture { source_path, destination_path, .. } = fixture;
     let crypto_scheme = AesEncrypter::new("helloworld");
-    let deadline = time::now() + NonStdDuration::minutes(10);
+    let time_limit = Duration::from_secs(10 * 60);
     let max_age_milliseconds = 60 * 60 * 1000;
+    let options = BackupOptions::new(1000000, max_age_milliseconds, time_limit);
 
     assert!(
         backbonzo::init(
@@ -325,15 +298,9 @@ fn renames() {
     // create 1 file in source map
     let first_timestamp = {
         let file_path = source_path.join(first_file_name);
-        let mut file = File::create(&file_path).unwrap();
-        file.write_all(first_message).unwrap();
-        file.sync_all().unwrap();
+        write_file(&file_path, first_message);
 
-        let backup_result = backbonzo::backup(source_path.clone(),
-                                              1000000,
-                                              &crypto_scheme,
-                                              max_age_milliseconds,
-                                              deadline);
+        let backup_result = backbonzo::backup(source_path.clone(), &crypto_scheme, &options);
 
         assert!(backup_result.is_ok());
 
@@ -353,11 +320,7 @@ fn renames() {
         file.write_all(second_message).unwrap();
         file.sync_all().unwrap();
 
-        let backup_result = backbonzo::backup(source_path.clone(),
-                                              1000000,
-                                              &crypto_scheme,
-                                              max_age_milliseconds,
-                                              deadline);
+        let backup_result = backbonzo::backup(source_path.clone(), &crypto_scheme, &options);
 
         assert!(backup_result.is_ok());
 
@@ -373,11 +336,7 @@ fn renames() {
 
         rename(&second_path, &first_path).unwrap();
 
-        let backup_result = backbonzo::backup(source_path.clone(),
-                                              1000000,
-                                              &crypto_scheme,
-                                              max_age_milliseconds,
-                                              deadline);
+        let backup_result = backbonzo::backup(source_path.clone(), &crypto_scheme, &options);
 
         assert!(backup_result.is_ok());
 
@@ -392,11 +351,7 @@ fn renames() {
 
         remove_file(&first_path).unwrap();
 
-        let backup_result = backbonzo::backup(source_path.clone(),
-                                              1000000,
-                                              &crypto_scheme,
-                                              max_age_milliseconds,
-                                              deadline);
+        let backup_result = backbonzo::backup(source_path.clone(), &crypto_scheme, &options);
 
         assert!(backup_result.is_ok());
     }
@@ -519,3 +474,2839 @@ fn renames() {
         assert!(! first_path.exists());
     }
 }
+
+#[test]
+fn multiple_destinations() {
+    let source_temp = TempDir::new("multi-source").unwrap();
+    let first_temp = TempDir::new("multi-first").unwrap();
+    let second_temp = TempDir::new("multi-second").unwrap();
+    let source_path = source_temp.path().to_owned();
+    let destinations = [first_temp.path().to_owned(), second_temp.path().to_owned()];
+    let crypto_scheme = AesEncrypter::new("testpassword");
+    let time_limit = Duration::from_secs(1 * 60);
+    let mut init_options = InitOptions::new();
+    init_options.extra_destinations.push(destinations[1].clone());
+
+    backbonzo::init_with_options(&source_path, &destinations[0], &crypto_scheme, &init_options)
+        .unwrap();
+
+    for i in 0..20 {
+        let file_path = source_path.join(format!("file{}", i));
+        write_file(&file_path, format!("contents of file {}", i).as_bytes());
+    }
+
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit)).unwrap();
+
+    // both destinations should have received some blocks
+    for destination in destinations.iter() {
+        assert!(read_dir(destination).unwrap().any(|entry| entry.unwrap().path().is_dir()));
+    }
+
+    let restore_temp = TempDir::new("multi-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destinations[0].clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    for i in 0..20 {
+        let mut contents = String::new();
+        File::open(restore_path.join(format!("file{}", i)))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+
+        assert_eq!(format!("contents of file {}", i), contents);
+    }
+
+    // take the second destination offline
+    drop(second_temp);
+
+    let second_restore_temp = TempDir::new("multi-restore").unwrap();
+
+    let result = backbonzo::restore(second_restore_temp.path().to_owned(),
+                                    destinations[0].clone(),
+                                    &crypto_scheme,
+                                    epoch_milliseconds(),
+                                    "**");
+
+    let is_expected = match result {
+        Err(BonzoError::MissingBlock(..)) => true,
+        _ => false,
+    };
+
+    assert!(is_expected);
+}
+
+#[test]
+fn excessive_block_size() {
+    let fixture = Fixture::new("blocksize");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let options = BackupOptions::new(backbonzo::MAX_CHUNK_SIZE + 1, 0, time_limit);
+    let backup_result = backbonzo::backup(source_path, &crypto_scheme, &options);
+
+    let is_expected = match backup_result {
+        Err(BonzoError::InvalidInput(..)) => true,
+        _ => false,
+    };
+
+    assert!(is_expected);
+}
+
+#[test]
+fn restore_versions() {
+    let fixture = Fixture::new("versions");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    create_dir_all(source_path.join("conf")).unwrap();
+
+    let file_path = source_path.join("conf").join("settings.ini");
+    let contents = ["version one", "version two", "version three"];
+
+    let max_age_milliseconds = 60 * 60 * 1000;
+
+    for content in contents.iter() {
+        write_file(&file_path, content.as_bytes());
+
+        backbonzo::backup(source_path.clone(),
+                          &crypto_scheme,
+                          &BackupOptions::new(1000000, max_age_milliseconds, time_limit))
+            .unwrap();
+
+        sleep(Duration::from_millis(100));
+    }
+
+    let output_temp = TempDir::new("versions-output").unwrap();
+    let output_path = output_temp.path().to_owned();
+
+    backbonzo::restore_versions(destination_path.clone(),
+                                output_path.clone(),
+                                &crypto_scheme,
+                                Path::new("conf/settings.ini"),
+                                2)
+        .unwrap();
+
+    for &(suffix, expected) in [("v1", "version two"), ("v2", "version three")].iter() {
+        let mut restored = String::new();
+        File::open(output_path.join(format!("settings.ini.{}", suffix)))
+            .unwrap()
+            .read_to_string(&mut restored)
+            .unwrap();
+
+        assert_eq!(expected, restored);
+    }
+
+    assert!(!output_path.join("settings.ini.v3").exists());
+
+    // the source directory is left alone
+    let source_names: Vec<_> = read_dir(source_path.join("conf"))
+                                   .unwrap()
+                                   .map(|entry| entry.unwrap().file_name())
+                                   .collect();
+
+    assert_eq!(1, source_names.len());
+    assert_eq!(Some("settings.ini"), source_names[0].to_str());
+    assert!(!source_path.join("settings.ini.v1").exists());
+}
+
+#[test]
+fn prune_orphans_with_manifests() {
+    let fixture = Fixture::new("orphan");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    write_file(&source_path.join("file"), b"some content");
+
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit)).unwrap();
+
+    let summary = backbonzo::manifests(source_path.clone(), None, &crypto_scheme, true).unwrap();
+    assert_eq!(1, summary.blocks);
+
+    // simulate a block which was written, but never persisted to the index
+    let orphan_directory = destination_path.join("ab");
+    let orphan_hash = "ab00000000000000000000000000000000000000000000000000000000000000";
+    create_dir_all(&orphan_directory).unwrap();
+    File::create(orphan_directory.join(orphan_hash)).unwrap();
+
+    let summary = backbonzo::manifests(source_path.clone(), None, &crypto_scheme, false).unwrap();
+    assert_eq!(1, summary.missing);
+
+    backbonzo::manifests(source_path.clone(), None, &crypto_scheme, true).unwrap();
+
+    let cleanup = backbonzo::prune_orphans(source_path.clone(), None, &crypto_scheme).unwrap();
+    assert_eq!(1, cleanup.blocks);
+    assert!(!orphan_directory.join(orphan_hash).exists());
+
+    let summary = backbonzo::manifests(source_path.clone(), None, &crypto_scheme, false).unwrap();
+    assert_eq!((1, 0, 0), (summary.blocks, summary.missing, summary.stale));
+}
+
+#[test]
+fn external_index() {
+    let fixture = Fixture::new("external");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let index_temp = TempDir::new("external-index").unwrap();
+    let index_path = index_temp.path().join("index.db3");
+
+    write_file(&source_path.join("file"), b"read only content");
+
+    let mut init_options = InitOptions::new();
+    init_options.index_path = Some(index_path.clone());
+
+    backbonzo::init_with_options(&source_path, &destination_path, &crypto_scheme, &init_options)
+        .unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.index_path = Some(index_path.clone());
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    // nothing but the original file should be in the source directory
+    let source_entries: Vec<_> = read_dir(&source_path).unwrap()
+                                                       .map(|entry| entry.unwrap().path())
+                                                       .collect();
+    assert_eq!(vec![source_path.join("file")], source_entries);
+    assert!(index_path.exists());
+
+    let restore_temp = TempDir::new("external-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    assert!(restore_path.join("file").exists());
+}
+
+#[test]
+fn sync_between_backups() {
+    let fixture = Fixture::new("sync");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let other_source_temp = TempDir::new("sync-other-source").unwrap();
+    let other_destination_temp = TempDir::new("sync-other-dest").unwrap();
+    let other_source_path = other_source_temp.path().to_owned();
+    let other_destination_path = other_destination_temp.path().to_owned();
+    let other_crypto_scheme = AesEncrypter::new("otherpassword");
+    let options = BackupOptions::new(1000000, 60 * 60 * 1000, time_limit);
+
+    create_dir_all(other_source_path.join("sub")).unwrap();
+
+    let contents = [("shared", "in both backups"), ("sub/only-other", "elsewhere")];
+
+    for &(path, content) in contents.iter() {
+        write_file(&other_source_path.join(path), content.as_bytes());
+    }
+
+    write_file(&source_path.join("shared"), b"in both backups");
+
+    backbonzo::init(&other_source_path, &other_destination_path, &other_crypto_scheme).unwrap();
+    backbonzo::backup(other_source_path.clone(), &other_crypto_scheme, &options).unwrap();
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let summary = backbonzo::sync_repos(other_destination_path.clone(),
+                                        source_path.clone(),
+                                        None,
+                                        &other_crypto_scheme,
+                                        &crypto_scheme)
+                      .unwrap();
+
+    // the alias of the shared file is older than the one already present
+    assert_eq!((1, 1, 1), (summary.blocks_copied, summary.files, summary.aliases));
+
+    let restore_temp = TempDir::new("sync-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    for &(path, expected) in contents.iter() {
+        let mut restored = String::new();
+        File::open(restore_path.join(path)).unwrap().read_to_string(&mut restored).unwrap();
+
+        assert_eq!(expected, restored);
+    }
+
+    let summary = backbonzo::sync_repos(other_destination_path.clone(),
+                                        source_path.clone(),
+                                        None,
+                                        &other_crypto_scheme,
+                                        &crypto_scheme)
+                      .unwrap();
+
+    assert_eq!((0, 0), (summary.blocks_copied, summary.aliases));
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_directory() {
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("unreadable");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let locked_path = source_path.join("locked");
+
+    create_dir_all(&locked_path).unwrap();
+
+    for path in [source_path.join("readable"), locked_path.join("secret")].iter() {
+        write_file(&path, b"some content");
+    }
+
+    set_permissions(&locked_path, Permissions::from_mode(0o000)).unwrap();
+
+    // permissions are not enforced for privileged users
+    if read_dir(&locked_path).is_ok() {
+        set_permissions(&locked_path, Permissions::from_mode(0o755)).unwrap();
+        return;
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.strict = true;
+
+    assert!(backbonzo::backup(source_path.clone(), &crypto_scheme, &options).is_err());
+
+    options.strict = false;
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    set_permissions(&locked_path, Permissions::from_mode(0o755)).unwrap();
+
+    assert_eq!(1, summary.warnings.len());
+    assert!(summary.warnings[0].contains("locked"));
+
+    let restore_temp = TempDir::new("unreadable-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    assert!(restore_path.join("readable").exists());
+    assert!(!restore_path.join("locked").join("secret").exists());
+}
+
+#[test]
+fn disk_usage() {
+    let fixture = Fixture::new("du");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("one")).unwrap();
+    create_dir_all(source_path.join("two")).unwrap();
+
+    let contents = [("one/copy", "shared content"),
+                    ("two/copy", "shared content"),
+                    ("two/unique", "unique content")];
+
+    for &(path, content) in contents.iter() {
+        write_file(&source_path.join(path), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    // sum the sizes of all blocks on disk
+    let mut stored_bytes = 0;
+
+    for entry in read_dir(&destination_path).unwrap() {
+        let path = entry.unwrap().path();
+
+        if !path.is_dir() {
+            continue;
+        }
+
+        for block in read_dir(&path).unwrap() {
+            stored_bytes += block.unwrap().metadata().unwrap().len();
+        }
+    }
+
+    let now = epoch_milliseconds();
+    let unique = backbonzo::du(destination_path.clone(),
+                               &crypto_scheme,
+                               now,
+                               UsagePolicy::FirstOccurrence)
+                     .unwrap();
+    let total = backbonzo::du(destination_path.clone(), &crypto_scheme, now, UsagePolicy::Total)
+                    .unwrap();
+
+    let paths: Vec<PathBuf> = unique.iter().map(|&(ref path, _)| path.clone()).collect();
+    assert_eq!(vec![PathBuf::from("."), PathBuf::from("./one"), PathBuf::from("./two")],
+               paths);
+
+    assert_eq!(stored_bytes, unique[0].1);
+    assert_eq!(unique[1].1 + unique[2].1, unique[0].1);
+    assert_eq!(total[1].1 + total[2].1, total[0].1);
+    assert!(total[0].1 > unique[0].1);
+}
+
+#[test]
+fn index_integrity_check() {
+    let fixture = Fixture::new("check-index");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let summary = backbonzo::check_index(source_path.clone(), None).unwrap();
+    assert!(summary.problems.is_empty());
+
+    let restore_temp = TempDir::new("check-index-restore").unwrap();
+
+    backbonzo::restore_with_options(restore_temp.path().to_owned(),
+                                    destination_path.clone(),
+                                    &crypto_scheme,
+                                    epoch_milliseconds(),
+                                    "**",
+                                    &RestoreOptions::new())
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_hardlinks() {
+    use std::os::unix::fs::MetadataExt;
+
+    let fixture = Fixture::new("hardlink");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("sub")).unwrap();
+
+    let contents = [("original", "identical content"),
+                    ("sub/copy", "identical content"),
+                    ("different", "other content")];
+
+    for &(path, content) in contents.iter() {
+        write_file(&source_path.join(path), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let restore_temp = TempDir::new("hardlink-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+    let mut options = RestoreOptions::new();
+    options.hardlinks = true;
+
+    let summary = backbonzo::restore_with_options(restore_path.clone(),
+                                                  destination_path.clone(),
+                                                  &crypto_scheme,
+                                                  epoch_milliseconds(),
+                                                  "**",
+                                                  &options)
+                      .unwrap();
+
+    assert_eq!(1, summary.hardlinks);
+
+    for &(path, content) in contents.iter() {
+        let mut restored = String::new();
+        File::open(restore_path.join(path)).unwrap().read_to_string(&mut restored).unwrap();
+
+        assert_eq!(content, restored);
+    }
+
+    let nlink = |path: &str| restore_path.join(path).metadata().unwrap().nlink();
+
+    assert_eq!(2, nlink("original"));
+    assert_eq!(2, nlink("sub/copy"));
+    assert_eq!(1, nlink("different"));
+}
+
+#[cfg(unix)]
+#[test]
+fn restore_hardlinks_next_to_kept_file() {
+    use std::os::unix::fs::MetadataExt;
+
+    let fixture = Fixture::new("kept-link");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("kept-link-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    create_dir_all(source_path.join("sub")).unwrap();
+
+    for &path in ["original", "sub/copy"].iter() {
+        write_file(&source_path.join(path), b"identical content");
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    write_file(&restore_path.join("original"), b"local changes");
+
+    let mut options = RestoreOptions::new();
+    options.hardlinks = true;
+    options.overwrite = Overwrite::Never;
+
+    let summary = backbonzo::restore_with_options(restore_path.clone(),
+                                                  destination_path.clone(),
+                                                  &crypto_scheme,
+                                                  epoch_milliseconds(),
+                                                  "**",
+                                                  &options)
+                      .unwrap();
+
+    let read = |path: &str| {
+        let mut content = String::new();
+        File::open(restore_path.join(path)).unwrap().read_to_string(&mut content).unwrap();
+        content
+    };
+
+    assert_eq!((1, 1, 0), (summary.summary.files, summary.skipped, summary.hardlinks));
+    assert_eq!("local changes", read("original"));
+    assert_eq!("identical content", read("sub/copy"));
+    assert_eq!(1, restore_path.join("sub/copy").metadata().unwrap().nlink());
+}
+
+#[test]
+fn restore_to_regular_file() {
+    let temp = TempDir::new("restore-to-file").unwrap();
+    let file_path = temp.path().join("not-a-directory");
+    let crypto_scheme = AesEncrypter::new("testpassword");
+
+    write_file(&file_path, b"in the way");
+
+    // the backup does not even exist, so this must fail before reading it
+    let result = backbonzo::restore(file_path.clone(),
+                                    temp.path().join("no-backup-here"),
+                                    &crypto_scheme,
+                                    epoch_milliseconds(),
+                                    "**");
+
+    let is_expected = match result {
+        Err(BonzoError::InvalidInput(..)) => true,
+        _ => false,
+    };
+
+    assert!(is_expected);
+}
+
+// Backs up enough small files to span several batches of index changes
+#[test]
+fn many_small_files() {
+    let fixture = Fixture::new("small-files");
+    let Fixture { source_path, destination_path, crypto_scheme, .. } = fixture;
+    let time_limit = Duration::from_secs(5 * 60);
+    let file_count = 500;
+
+    for i in 0..file_count {
+        let content = format!("content of file {}", i);
+
+        write_file(&source_path.join(format!("file{}", i)), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.batch_size = 64;
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(file_count, summary.summary.files);
+
+    let restore_temp = TempDir::new("small-files-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    assert_eq!(file_count as usize, read_dir(&restore_path).unwrap().count());
+
+    let mut restored = String::new();
+    File::open(restore_path.join("file123")).unwrap().read_to_string(&mut restored).unwrap();
+
+    assert_eq!("content of file 123", restored);
+}
+
+#[test]
+fn init_and_backup() {
+    let fixture = Fixture::new("init-backup");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    write_file(&source_path.join("first"), b"backed up right away");
+
+    let summary = backbonzo::init_and_backup(&source_path,
+                                             &destination_path,
+                                             &crypto_scheme,
+                                             &InitOptions::new(),
+                                             &options)
+                      .unwrap();
+
+    assert_eq!(1, summary.summary.files);
+
+    write_file(&source_path.join("second"), b"should not be backed up");
+
+    // initialization fails as the index exists, so no backup should be made
+    assert!(backbonzo::init_and_backup(&source_path,
+                                       &destination_path,
+                                       &crypto_scheme,
+                                       &InitOptions::new(),
+                                       &options)
+                .is_err());
+
+    let restore_temp = TempDir::new("init-backup-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    assert!(restore_path.join("first").exists());
+    assert!(!restore_path.join("second").exists());
+}
+
+#[test]
+fn excluded_directories() {
+    let fixture = Fixture::new("exclude");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("kept")).unwrap();
+    create_dir_all(source_path.join("skipped/deep")).unwrap();
+    create_dir_all(source_path.join("cache")).unwrap();
+
+    let contents = [("kept/file", "kept"),
+                    ("skipped/file", "never backed up"),
+                    ("skipped/deep/file", "never backed up either"),
+                    ("cache/old", "backed up before the exclusion")];
+
+    for &(path, content) in contents.iter() {
+        write_file(&source_path.join(path), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.excluded_directories = vec![source_path.join("skipped")];
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(2, summary.summary.files);
+
+    write_file(&source_path.join("cache/new"), b"added after the exclusion");
+
+    options.excluded_directories.push(source_path.join("cache"));
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(0, summary.summary.files);
+
+    let restore_temp = TempDir::new("exclude-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    assert!(restore_path.join("kept/file").exists());
+    assert!(!restore_path.join("skipped").exists());
+
+    // files backed up before their directory was excluded are not deleted
+    assert!(restore_path.join("cache/old").exists());
+    assert!(!restore_path.join("cache/new").exists());
+}
+
+#[test]
+fn verify_blocks() {
+    let fixture = Fixture::new("verify");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    for &(name, content) in [("one", "first"), ("two", "second"), ("three", "third")].iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let summary = backbonzo::verify(destination_path.clone(), &crypto_scheme).unwrap();
+
+    assert_eq!((3, 0, 0), (summary.ok, summary.corrupt, summary.missing));
+
+    let mut blocks = Vec::new();
+
+    for entry in read_dir(&destination_path).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            blocks.extend(read_dir(&path).unwrap().map(|block| block.unwrap().path()));
+        }
+    }
+
+    assert_eq!(3, blocks.len());
+
+    write_file(&blocks[0], b"bit rot");
+
+    remove_file(&blocks[1]).unwrap();
+
+    let summary = backbonzo::verify(destination_path.clone(), &crypto_scheme).unwrap();
+
+    assert_eq!((1, 1, 1), (summary.ok, summary.corrupt, summary.missing));
+}
+
+#[test]
+fn salted_key() {
+    let fixture = Fixture::new("salt");
+    let Fixture { source_path, destination_path, time_limit, .. } = fixture;
+    let params = KeyParams::generate(1000).unwrap();
+    let crypto_scheme = AesEncrypter::with_params("testpassword", &params.salt, params.iterations);
+
+    write_file(&source_path.join("file"), b"salted");
+
+    let mut init_options = InitOptions::new();
+    init_options.key_params = Some(params);
+
+    backbonzo::init_with_options(&source_path, &destination_path, &crypto_scheme, &init_options)
+        .unwrap();
+
+    assert_eq!(params, backbonzo::source_key_params(source_path.clone(), None).unwrap());
+    assert_eq!(params, backbonzo::backup_key_params(destination_path.clone()).unwrap());
+
+    // the same passphrase without the salt derives a different key
+    let unsalted_scheme = AesEncrypter::new("testpassword");
+
+    assert!(backbonzo::backup(source_path.clone(),
+                              &unsalted_scheme,
+                              &BackupOptions::new(1000000, 0, time_limit))
+                .is_err());
+
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let stored_params = backbonzo::backup_key_params(destination_path.clone()).unwrap();
+    let restore_scheme = AesEncrypter::with_params("testpassword",
+                                                   &stored_params.salt,
+                                                   stored_params.iterations);
+    let restore_temp = TempDir::new("salt-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &restore_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    assert!(restore_path.join("file").exists());
+
+    // backups without recorded parameters use the legacy ones
+    let legacy_temp = TempDir::new("salt-legacy").unwrap();
+
+    assert_eq!(KeyParams::legacy(),
+               backbonzo::backup_key_params(legacy_temp.path().to_owned()).unwrap());
+}
+
+#[test]
+fn configurable_compression() {
+    for &compression in [Compression::Stored, Compression::Gzip].iter() {
+        let fixture = Fixture::new("compression");
+        let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+        let content: String = repeat("very compressible content ").take(100).collect();
+
+        write_file(&source_path.join("file"), content.as_bytes());
+
+        let mut init_options = InitOptions::new();
+        init_options.compression = compression;
+
+        backbonzo::init_with_options(&source_path,
+                                     &destination_path,
+                                     &crypto_scheme,
+                                     &init_options)
+            .unwrap();
+        let summary = backbonzo::backup(source_path.clone(),
+                                        &crypto_scheme,
+                                        &BackupOptions::new(1000000, 0, time_limit))
+                          .unwrap();
+
+        match compression {
+            Compression::Stored => assert!(summary.summary.bytes > content.len() as u64),
+            _ => assert!(summary.summary.bytes < content.len() as u64),
+        }
+
+        let restore_temp = TempDir::new("compression-restore").unwrap();
+        let restore_path = restore_temp.path().to_owned();
+
+        backbonzo::restore(restore_path.clone(),
+                           destination_path.clone(),
+                           &crypto_scheme,
+                           epoch_milliseconds(),
+                           "**")
+            .unwrap();
+
+        let mut restored = String::new();
+        File::open(restore_path.join("file")).unwrap().read_to_string(&mut restored).unwrap();
+
+        assert_eq!(content, restored);
+    }
+}
+
+#[test]
+fn chacha_backup() {
+    let fixture = Fixture::new("chacha");
+    let Fixture { source_path, destination_path, time_limit, .. } = fixture;
+    let crypto_scheme = ChaChaEncrypter::new("testpassword");
+    let aes_scheme = AesEncrypter::new("testpassword");
+
+    write_file(&source_path.join("file"), b"encrypted with chacha20");
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    assert_eq!(crypto_scheme.name(),
+               backbonzo::backup_cipher(destination_path.clone()).unwrap());
+
+    // the passphrase is right, but the cipher is not
+    assert!(backbonzo::backup(source_path.clone(),
+                              &aes_scheme,
+                              &BackupOptions::new(1000000, 0, time_limit))
+                .is_err());
+
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let restore_temp = TempDir::new("chacha-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    let mut restored = String::new();
+    File::open(restore_path.join("file")).unwrap().read_to_string(&mut restored).unwrap();
+
+    assert_eq!("encrypted with chacha20", restored);
+}
+
+#[test]
+fn list_files() {
+    let fixture = Fixture::new("list");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("photos")).unwrap();
+
+    for &name in ["notes.txt", "photos/one.jpg", "photos/two.jpg"].iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let now = epoch_milliseconds();
+    let all = backbonzo::list(destination_path.clone(), &crypto_scheme, now, "**").unwrap();
+
+    assert_eq!(vec![PathBuf::from("notes.txt"),
+                    PathBuf::from("photos/one.jpg"),
+                    PathBuf::from("photos/two.jpg")],
+               all);
+
+    let photos = backbonzo::list(destination_path.clone(), &crypto_scheme, now, "**/*.jpg")
+                     .unwrap();
+
+    assert_eq!(2, photos.len());
+
+    // nothing was backed up yet at the epoch
+    assert!(backbonzo::list(destination_path.clone(), &crypto_scheme, 0, "**").unwrap().is_empty());
+
+    let sizes = backbonzo::list_with_sizes(destination_path.clone(), &crypto_scheme, now, "*.txt")
+                    .unwrap();
+
+    assert_eq!(vec![(PathBuf::from("notes.txt"), Some((9, 1)))], sizes);
+}
+
+#[test]
+fn restore_existing_files() {
+    let fixture = Fixture::new("overwrite");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("overwrite-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    for &name in ["one", "two", "three"].iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let restore_with = |overwrite| {
+        let mut options = RestoreOptions::new();
+        options.overwrite = overwrite;
+
+        backbonzo::restore_with_options(restore_path.clone(),
+                                        destination_path.clone(),
+                                        &crypto_scheme,
+                                        epoch_milliseconds(),
+                                        "**",
+                                        &options)
+            .unwrap()
+    };
+
+    let summary = restore_with(Overwrite::IfDifferent);
+
+    assert_eq!((3, 0), (summary.summary.files, summary.skipped));
+
+    write_file(&restore_path.join("two"), b"changed");
+
+    let summary = restore_with(Overwrite::Never);
+    let mut content = String::new();
+    File::open(restore_path.join("two")).unwrap().read_to_string(&mut content).unwrap();
+
+    assert_eq!((0, 3), (summary.summary.files, summary.skipped));
+    assert_eq!("changed", content);
+
+    let summary = restore_with(Overwrite::IfDifferent);
+    let mut content = String::new();
+    File::open(restore_path.join("two")).unwrap().read_to_string(&mut content).unwrap();
+
+    assert_eq!((1, 2), (summary.summary.files, summary.skipped));
+    assert_eq!("two", content);
+}
+
+#[test]
+fn exclude_patterns() {
+    let fixture = Fixture::new("pattern");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("project/node_modules/dependency")).unwrap();
+    create_dir_all(source_path.join("project/.git")).unwrap();
+
+    for &name in ["project/main.js",
+                  "project/scratch.tmp",
+                  "project/node_modules/dependency/index.js",
+                  "project/.git/HEAD"]
+                     .iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.exclude_patterns = ["node_modules", "*.tmp", ".git"]
+                                   .iter()
+                                   .map(|glob| Pattern::new(glob).unwrap())
+                                   .collect();
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let paths = backbonzo::list(destination_path.clone(),
+                                &crypto_scheme,
+                                epoch_milliseconds(),
+                                "**")
+                    .unwrap();
+
+    assert_eq!(vec![PathBuf::from("project/main.js")], paths);
+}
+
+#[test]
+fn include_patterns() {
+    let fixture = Fixture::new("include");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("src/deeply/nested")).unwrap();
+    create_dir_all(source_path.join("target")).unwrap();
+
+    for &name in ["Cargo.toml",
+                  "readme.md",
+                  "src/main.rs",
+                  "src/deeply/nested/module.rs",
+                  "src/deeply/notes.txt",
+                  "target/generated.rs"]
+                     .iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    // excludes win over includes
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.include_patterns = vec![Pattern::new("*.rs").unwrap(), Pattern::new("*.toml").unwrap()];
+    options.exclude_patterns = vec![Pattern::new("target").unwrap()];
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let paths = backbonzo::list(destination_path.clone(),
+                                &crypto_scheme,
+                                epoch_milliseconds(),
+                                "**")
+                    .unwrap();
+
+    assert_eq!(vec![PathBuf::from("Cargo.toml"),
+                    PathBuf::from("src/deeply/nested/module.rs"),
+                    PathBuf::from("src/main.rs")],
+               paths);
+}
+
+#[test]
+fn ignore_files() {
+    let fixture = Fixture::new("ignore");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("project/build")).unwrap();
+    create_dir_all(source_path.join("project/src/build")).unwrap();
+
+    let contents = [(".backbonzoignore", "# scratch files\n*.tmp\n"),
+                    ("project/.backbonzoignore", "/build\n"),
+                    ("project/notes.tmp", "ignored by the root ignore file"),
+                    ("project/build/output", "ignored by the project ignore file"),
+                    ("project/src/build/input", "not anchored to the project directory")];
+
+    for &(path, content) in contents.iter() {
+        write_file(&source_path.join(path), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let paths = backbonzo::list(destination_path.clone(),
+                                &crypto_scheme,
+                                epoch_milliseconds(),
+                                "**")
+                    .unwrap();
+
+    assert_eq!(vec![PathBuf::from(".backbonzoignore"),
+                    PathBuf::from("project/.backbonzoignore"),
+                    PathBuf::from("project/src/build/input")],
+               paths);
+}
+
+#[test]
+fn max_file_size() {
+    let fixture = Fixture::new("max-size");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let large: String = repeat("x").take(2000).collect();
+
+    for &(name, content) in [("small", "tiny"), ("large", &large[..])].iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.max_file_size = Some(1000);
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(1, summary.summary.files);
+    assert_eq!(1, summary.skipped_files);
+    assert!(summary.to_string().contains("Skipped 1 files exceeding the maximum size."));
+
+    let paths = backbonzo::list(destination_path.clone(),
+                                &crypto_scheme,
+                                epoch_milliseconds(),
+                                "**")
+                    .unwrap();
+
+    assert_eq!(vec![PathBuf::from("small")], paths);
+}
+
+#[test]
+fn modification_window() {
+    use filetime::{set_file_times, FileTime};
+
+    let fixture = Fixture::new("window");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let long_ago = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+
+    for &name in ["fresh", "stale"].iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    set_file_times(&source_path.join("stale"), long_ago, long_ago).unwrap();
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let day = 24 * 60 * 60 * 1000;
+    let list = || {
+        backbonzo::list(destination_path.clone(), &crypto_scheme, epoch_milliseconds(), "**")
+            .unwrap()
+    };
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.newer_than = Some(7 * day);
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(vec![PathBuf::from("fresh")], list());
+
+    // files left out by an earlier run are not considered deleted
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.older_than = Some(7 * day);
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(vec![PathBuf::from("fresh"), PathBuf::from("stale")], list());
+}
+
+#[test]
+fn diff() {
+    use filetime::{set_file_times, FileTime};
+
+    let fixture = Fixture::new("diff");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let long_ago = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+
+    for &name in ["deleted", "modified", "touched", "unchanged"].iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+        set_file_times(&source_path.join(name), long_ago, long_ago).unwrap();
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    remove_file(source_path.join("deleted")).unwrap();
+    write_file(&source_path.join("modified"), b"changed");
+    write_file(&source_path.join("added"), b"added");
+
+    // only the modification time changes, so its hash still matches
+    let later = FileTime::from_seconds_since_1970(1_100_000_000, 0);
+    set_file_times(&source_path.join("touched"), later, later).unwrap();
+
+    // the options of the backup apply to the comparison as well
+    write_file(&source_path.join("scratch.tmp"), b"scratch");
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.exclude_patterns = vec![Pattern::new("*.tmp").unwrap()];
+
+    let diff = backbonzo::diff(source_path.clone(),
+                               destination_path.clone(),
+                               &crypto_scheme,
+                               epoch_milliseconds(),
+                               &options)
+                   .unwrap();
+
+    assert_eq!(vec![PathBuf::from("added")], diff.added);
+    assert_eq!(vec![PathBuf::from("modified")], diff.modified);
+    assert_eq!(vec![PathBuf::from("deleted")], diff.deleted);
+}
+
+// Files and directories matched by an ignore file are left out of the
+// comparison, just as they are left out of the backup
+#[test]
+fn diff_ignore_files() {
+    let fixture = Fixture::new("diff-ignore");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    write_file(&source_path.join(".backbonzoignore"), b"*.log\n/cache\n");
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    create_dir_all(source_path.join("cache")).unwrap();
+
+    for &name in ["debug.log", "cache/blob", "kept"].iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    let diff = backbonzo::diff(source_path.clone(),
+                               destination_path.clone(),
+                               &crypto_scheme,
+                               epoch_milliseconds(),
+                               &options)
+                   .unwrap();
+
+    assert_eq!(vec![PathBuf::from("kept")], diff.added);
+    assert!(diff.modified.is_empty());
+    assert!(diff.deleted.is_empty());
+}
+
+// Every backup is listed once it has completed, along with the number of
+// files it changed, even when it ran right after the previous one
+#[test]
+fn restore_points() {
+    let fixture = Fixture::new("points");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    for &name in ["first", "second"].iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+    sleep(Duration::from_millis(5));
+    write_file(&source_path.join("third"), b"third");
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let points = backbonzo::restore_points(destination_path.clone(), &crypto_scheme).unwrap();
+
+    assert_eq!(vec![2, 1], points.iter().map(|&(_, files)| files).collect::<Vec<_>>());
+
+    // restoring to the first point leaves out the file of the second backup
+    let restore_temp = TempDir::new("points-restore").unwrap();
+
+    backbonzo::restore(restore_temp.path().to_owned(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       points[0].0,
+                       "**")
+        .unwrap();
+
+    assert!(restore_temp.path().join("second").exists());
+    assert!(!restore_temp.path().join("third").exists());
+}
+
+#[test]
+fn dry_run() {
+    let fixture = Fixture::new("dry-run");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    for &(name, content) in [("one", "same"), ("two", "same"), ("three", "other")].iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    // new directories would otherwise be recorded in the index
+    create_dir_all(source_path.join("empty")).unwrap();
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let index_path = source_path.join(".backbonzo.db3");
+    let mut index_before = Vec::new();
+    File::open(&index_path).unwrap().read_to_end(&mut index_before).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.dry_run = true;
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+    let mut index_after = Vec::new();
+    File::open(&index_path).unwrap().read_to_end(&mut index_after).unwrap();
+
+    assert!(index_before == index_after);
+
+    assert_eq!(3, summary.summary.files);
+    assert_eq!(2, summary.summary.blocks);
+    assert_eq!((1, 4), (summary.deduplicated_blocks, summary.deduplicated_bytes));
+    assert!(summary.to_string().contains("This was a dry run."));
+
+    let paths = backbonzo::list(destination_path.clone(),
+                                &crypto_scheme,
+                                epoch_milliseconds(),
+                                "**")
+                    .unwrap();
+
+    assert!(paths.is_empty());
+
+    options.dry_run = false;
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(3, summary.summary.files);
+    assert_eq!(2, summary.summary.blocks);
+    assert!(!summary.to_string().contains("dry run"));
+}
+
+#[test]
+fn progress_estimate() {
+    let fixture = Fixture::new("estimate");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("sub")).unwrap();
+
+    for &(name, content) in [("one", "first file"), ("sub/two", "second file")].iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.estimate = true;
+
+    let estimate = |options: &BackupOptions| {
+        let mut events = Vec::new();
+
+        {
+            let mut callback = |event: ProgressEvent| events.push(event);
+
+            backbonzo::backup_with_progress(source_path.clone(),
+                                            &crypto_scheme,
+                                            options,
+                                            Some(&mut callback))
+                .unwrap();
+        }
+
+        // the estimate comes before anything else
+        events.into_iter().next()
+    };
+
+    assert_eq!(Some(ProgressEvent::Estimate(2, 21)), estimate(&options));
+
+    // files which are backed up already are not counted again
+    sleep(Duration::from_millis(1100));
+    write_file(&source_path.join("sub/two"), b"changed");
+
+    assert_eq!(Some(ProgressEvent::Estimate(1, 7)), estimate(&options));
+}
+
+// Files matched by an ignore file are not read, so they are not counted
+#[test]
+fn progress_estimate_ignore_files() {
+    let fixture = Fixture::new("estimate-ignore");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    create_dir_all(source_path.join("cache")).unwrap();
+
+    let contents = [(".backbonzoignore", "*.log\n/cache\n"),
+                    ("debug.log", "ignored by pattern"),
+                    ("cache/blob", "ignored with its directory"),
+                    ("kept", "kept")];
+
+    for &(name, content) in contents.iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.estimate = true;
+
+    let mut events = Vec::new();
+
+    {
+        let mut callback = |event: ProgressEvent| events.push(event);
+
+        backbonzo::backup_with_progress(source_path.clone(),
+                                        &crypto_scheme,
+                                        &options,
+                                        Some(&mut callback))
+            .unwrap();
+    }
+
+    // only the ignore file itself and the kept file are read
+    assert_eq!(Some(&ProgressEvent::Estimate(2, 17)), events.first());
+}
+
+#[test]
+fn progress_events() {
+    let fixture = Fixture::new("progress");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("progress-restore").unwrap();
+
+    let contents = [("one", "first file"), ("two", "second file"), ("copy", "first file")];
+
+    for &(name, content) in contents.iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let options = BackupOptions::new(1000000, 0, time_limit);
+    let mut events = Vec::new();
+
+    {
+        let mut callback = |event: ProgressEvent| events.push(event);
+
+        backbonzo::backup_with_progress(source_path.clone(),
+                                        &crypto_scheme,
+                                        &options,
+                                        Some(&mut callback))
+            .unwrap();
+    }
+
+    let count = |events: &[ProgressEvent], path: &Path| {
+        events.iter()
+              .filter(|event| {
+                  match **event {
+                      ProgressEvent::FileStarted(ref started) => started == path,
+                      ProgressEvent::FileCompleted(ref completed) => completed == path,
+                      ProgressEvent::BlockWritten(..) | ProgressEvent::Estimate(..) => false,
+                  }
+              })
+              .count()
+    };
+
+    assert_eq!(2, count(&events, &source_path.join("one")));
+    assert_eq!(2, count(&events, &source_path.join("two")));
+
+    // the block of the copy is not written again
+    let written_blocks = events.iter().filter(|event| {
+        match **event {
+            ProgressEvent::BlockWritten(..) => true,
+            _ => false,
+        }
+    });
+
+    assert_eq!(2, written_blocks.count());
+
+    let mut restore_events = Vec::new();
+
+    {
+        let mut callback = |event: ProgressEvent| restore_events.push(event);
+
+        backbonzo::restore_with_progress(restore_temp.path().to_owned(),
+                                         destination_path.clone(),
+                                         &crypto_scheme,
+                                         epoch_milliseconds(),
+                                         "**",
+                                         &RestoreOptions::new(),
+                                         Some(&mut callback))
+            .unwrap();
+    }
+
+    assert_eq!(2, count(&restore_events, &restore_temp.path().join("one")));
+    let restored_bytes = restore_events.iter().fold(0, |acc, event| {
+        match *event {
+            ProgressEvent::BlockWritten(bytes) => acc + bytes,
+            _ => acc,
+        }
+    });
+
+    assert_eq!(31, restored_bytes);
+}
+
+#[test]
+fn restore_single_file() {
+    let fixture = Fixture::new("single");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let output_temp = TempDir::new("single-output").unwrap();
+
+    create_dir_all(source_path.join("photos")).unwrap();
+
+    for &name in ["notes.txt", "photos/one.jpg"].iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let now = epoch_milliseconds();
+    let out_path = output_temp.path().join("extracted.jpg");
+    let summary = backbonzo::restore_single(destination_path.clone(),
+                                            &crypto_scheme,
+                                            Path::new("photos/one.jpg"),
+                                            now,
+                                            &out_path)
+                      .unwrap();
+
+    assert_eq!(1, summary.summary.files);
+
+    let mut contents = String::new();
+    File::open(&out_path).unwrap().read_to_string(&mut contents).unwrap();
+
+    assert_eq!("photos/one.jpg", contents);
+
+    // the file did not exist yet at the epoch
+    assert!(backbonzo::restore_single(destination_path.clone(),
+                                      &crypto_scheme,
+                                      Path::new("photos/one.jpg"),
+                                      0,
+                                      &out_path)
+                .is_err());
+    assert!(backbonzo::restore_single(destination_path.clone(),
+                                      &crypto_scheme,
+                                      Path::new("photos/three.jpg"),
+                                      now,
+                                      &out_path)
+                .is_err());
+}
+
+#[test]
+fn worker_count() {
+    let fixture = Fixture::new("workers");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    for i in 0..10 {
+        write_file(&source_path.join(format!("file{}", i)), format!("contents {}", i).as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.worker_count = 0;
+
+    assert!(backbonzo::backup(source_path.clone(), &crypto_scheme, &options).is_err());
+
+    options.worker_count = 1;
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(10, summary.summary.files);
+}
+
+#[test]
+fn change_password() {
+    let fixture = Fixture::new("changepw");
+    let Fixture { source_path, destination_path, time_limit, .. } = fixture;
+    let old_scheme = AesEncrypter::new("oldpassword");
+    let new_scheme = AesEncrypter::new("newpassword");
+
+    write_file(&source_path.join("file"), b"rotated");
+
+    backbonzo::init(&source_path, &destination_path, &old_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &old_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    assert!(backbonzo::change_password(source_path.clone(),
+                                       None,
+                                       &old_scheme,
+                                       &ChaChaEncrypter::new("newpassword"))
+                .is_err());
+
+    backbonzo::change_password(source_path.clone(), None, &old_scheme, &new_scheme).unwrap();
+
+    let now = epoch_milliseconds();
+
+    assert!(backbonzo::list(destination_path.clone(), &old_scheme, now, "**").is_err());
+    assert_eq!(vec![PathBuf::from("file")],
+               backbonzo::list(destination_path.clone(), &new_scheme, now, "**").unwrap());
+    assert!(backbonzo::backup(source_path.clone(),
+                              &old_scheme,
+                              &BackupOptions::new(1000000, 0, time_limit))
+                .is_err());
+}
+
+#[test]
+fn index_snapshots() {
+    let fixture = Fixture::new("snapshot");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("snapshot-restore").unwrap();
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.keep_indices = 2;
+
+    for i in 0..3 {
+        write_file(&source_path.join(format!("file{}", i)), format!("version {}", i).as_bytes());
+
+        backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+        sleep(Duration::from_millis(5));
+    }
+
+    let snapshot_count = read_dir(&destination_path)
+                             .unwrap()
+                             .filter(|entry| {
+                                 let name = entry.as_ref().unwrap().file_name();
+                                 let name = name.to_str().unwrap();
+                                 name.starts_with("index-") && name != "index-new"
+                             })
+                             .count();
+
+    assert_eq!(2, snapshot_count);
+
+    // a damaged live index is no obstacle when a snapshot is available
+    {
+        let mut index = File::create(destination_path.join("index")).unwrap();
+        index.write_all(b"garbage").unwrap();
+    }
+
+    backbonzo::restore(restore_temp.path().to_owned(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    for i in 0..3 {
+        assert!(restore_temp.path().join(format!("file{}", i)).exists());
+    }
+}
+
+#[test]
+fn parallel_restore() {
+    let fixture = Fixture::new("parallel");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("parallel-restore").unwrap();
+
+    create_dir_all(source_path.join("nested")).unwrap();
+
+    // many small blocks per file, so that their order matters
+    for i in 0..40 {
+        let contents: String = (0..50).map(|j| format!("file {} line {}\n", i, j)).collect();
+        write_file(&source_path.join("nested").join(format!("{}", i)), contents.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000, 0, time_limit))
+        .unwrap();
+
+    let mut options = RestoreOptions::new();
+    options.worker_count = 0;
+
+    assert!(backbonzo::restore_with_options(restore_temp.path().to_owned(),
+                                            destination_path.clone(),
+                                            &crypto_scheme,
+                                            epoch_milliseconds(),
+                                            "**",
+                                            &options)
+                .is_err());
+
+    options.worker_count = 4;
+
+    let summary = backbonzo::restore_with_options(restore_temp.path().to_owned(),
+                                                  destination_path.clone(),
+                                                  &crypto_scheme,
+                                                  epoch_milliseconds(),
+                                                  "**",
+                                                  &options)
+                      .unwrap();
+
+    assert_eq!(40, summary.summary.files);
+
+    for i in 0..40 {
+        let mut original = String::new();
+        let mut restored = String::new();
+        let relative = Path::new("nested").join(format!("{}", i));
+
+        File::open(source_path.join(&relative)).unwrap().read_to_string(&mut original).unwrap();
+        File::open(restore_temp.path().join(&relative))
+            .unwrap()
+            .read_to_string(&mut restored)
+            .unwrap();
+
+        assert_eq!(original, restored);
+    }
+}
+
+#[test]
+fn restore_manifest() {
+    let fixture = Fixture::new("manifest");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("manifest-restore").unwrap();
+    let manifest_temp = TempDir::new("manifest-list").unwrap();
+    let manifest_path = manifest_temp.path().join("restored");
+
+    for &(name, size) in [("small", 10), ("large", 2500), ("empty", 0)].iter() {
+        write_file(&source_path.join(name), &repeat(7u8).take(size).collect::<Vec<u8>>());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000, 0, time_limit))
+        .unwrap();
+
+    let mut options = RestoreOptions::new();
+    options.manifest = Some(manifest_path.clone());
+
+    backbonzo::restore_with_options(restore_temp.path().to_owned(),
+                                    destination_path.clone(),
+                                    &crypto_scheme,
+                                    epoch_milliseconds(),
+                                    "**",
+                                    &options)
+        .unwrap();
+
+    let mut manifest = String::new();
+    File::open(&manifest_path).unwrap().read_to_string(&mut manifest).unwrap();
+
+    let mut lines: Vec<Vec<&str>> = manifest.lines()
+                                            .map(|line| line.split('\t').collect())
+                                            .collect();
+    lines.sort();
+
+    assert_eq!(3, lines.len());
+
+    for (line, &(name, bytes, blocks)) in lines.iter()
+                                               .zip([("empty", "0", "0"),
+                                                     ("large", "2500", "3"),
+                                                     ("small", "10", "1")]
+                                                        .iter()) {
+        assert_eq!(4, line.len());
+        assert_eq!(restore_temp.path().join(name), PathBuf::from(line[0]));
+        assert_eq!(64, line[1].len());
+        assert_eq!(bytes, line[2]);
+        assert_eq!(blocks, line[3]);
+    }
+}
+
+#[test]
+fn restore_to_archive() {
+    let fixture = Fixture::new("archive");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let archive_temp = TempDir::new("archive-out").unwrap();
+    let archive_path = archive_temp.path().join("out.tar");
+    let large: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+
+    create_dir_all(source_path.join("nested")).unwrap();
+    write_file(&source_path.join("small"), b"tiny");
+    write_file(&source_path.join("nested").join("large"), &large);
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000, 0, time_limit))
+        .unwrap();
+
+    let summary = backbonzo::restore_to_archive(destination_path.clone(),
+                                                &crypto_scheme,
+                                                epoch_milliseconds(),
+                                                "**",
+                                                &RestoreOptions::new(),
+                                                ArchiveFormat::Tar,
+                                                &archive_path)
+                      .unwrap();
+
+    assert_eq!(2, summary.summary.files);
+
+    let mut archive = Vec::new();
+    File::open(&archive_path).unwrap().read_to_end(&mut archive).unwrap();
+
+    assert_eq!(0, archive.len() % 512);
+
+    // walk the headers of the archive, up to the end marker
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while archive[offset] != 0 {
+        let header = &archive[offset..offset + 512];
+        let name_length = header.iter().take(100).take_while(|&&byte| byte != 0).count();
+        let name = String::from_utf8(header[..name_length].to_vec()).unwrap();
+        let size_digits = String::from_utf8(header[124..135].to_vec()).unwrap();
+        let size = usize::from_str_radix(&size_digits, 8).unwrap();
+
+        entries.push((name, archive[offset + 512..offset + 512 + size].to_vec()));
+        offset += 512 * (1 + (size + 511) / 512);
+    }
+
+    entries.sort();
+
+    assert_eq!(vec![("nested/large".to_owned(), large), ("small".to_owned(), b"tiny".to_vec())],
+               entries);
+    assert!(archive[offset..].iter().all(|&byte| byte == 0));
+
+    // the archive is not a directory to restore into
+    assert!(backbonzo::restore_to_archive(destination_path.clone(),
+                                          &crypto_scheme,
+                                          epoch_milliseconds(),
+                                          "**",
+                                          &RestoreOptions::new(),
+                                          ArchiveFormat::TarGzip,
+                                          archive_temp.path())
+                .is_err());
+}
+
+#[test]
+fn destination_inside_source() {
+    let fixture = Fixture::new("inside");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let nested_destination = source_path.join("backup");
+
+    create_dir_all(&nested_destination).unwrap();
+
+    assert!(backbonzo::init(&source_path, &nested_destination, &crypto_scheme).is_err());
+    assert!(!source_path.join(".backbonzo.db3").exists());
+
+    // additional source directories may not contain the destination either
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000, 0, time_limit);
+    options.roots = vec![("other".to_owned(), destination_path.parent().unwrap().to_owned())];
+
+    assert!(backbonzo::backup(source_path.clone(), &crypto_scheme, &options).is_err());
+}
+
+#[test]
+fn repository_stats() {
+    let fixture = Fixture::new("stats");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    for &(name, content) in [("one", "aaaabbbb"), ("two", "aaaacccc")].iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &BackupOptions::new(4, 0, time_limit))
+        .unwrap();
+
+    let stats = backbonzo::stats(source_path.clone(), None, &crypto_scheme).unwrap();
+
+    assert_eq!(3, stats.blocks);
+    assert_eq!(2, stats.files);
+    assert_eq!(2, stats.aliases);
+    assert_eq!(16, stats.referenced_bytes);
+    assert!(stats.to_string().contains("2 versions of 2 distinct files, stored in 3 blocks"));
+}
+
+// Identical files share their blocks, so the logical bytes are at least
+// twice the bytes stored
+#[test]
+fn repository_stats_identical_files() {
+    let fixture = Fixture::new("stats-identical");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let content: Vec<u8> = repeat(b'x').take(10000).collect();
+
+    for &name in ["original", "copy"].iter() {
+        write_file(&source_path.join(name), &content);
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let stats = backbonzo::stats(source_path.clone(), None, &crypto_scheme).unwrap();
+
+    assert_eq!(1, stats.files);
+    assert_eq!(20000, stats.referenced_bytes);
+    assert!(stats.dedup_ratio() >= 2.0);
+}
+
+#[test]
+fn leftover_new_index() {
+    let fixture = Fixture::new("leftover");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let new_index = destination_path.join("index-new");
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    // an export which was interrupted right before the rename
+    rename(destination_path.join("index"), &new_index).unwrap();
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert!(summary.warnings.iter().any(|warning| warning.starts_with("Replaced the index")));
+    assert!(!new_index.exists());
+    assert!(destination_path.join("index").exists());
+
+    // an export which was interrupted while writing
+    write_file(&new_index, b"truncat");
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert!(summary.warnings.iter().any(|warning| warning.starts_with("Removed incomplete index")));
+    assert!(!new_index.exists());
+}
+
+// Needs an SSH server and an agent holding a key it accepts, so it only runs
+// when BACKBONZO_SFTP_TEST_URL points at a directory on such a server, like
+// sftp://localhost//tmp. The backup is made in a fresh subdirectory.
+#[cfg(feature = "sftp")]
+#[test]
+fn sftp_destination() {
+    use std::env;
+
+    let base_url = match env::var("BACKBONZO_SFTP_TEST_URL") {
+        Ok(url) => url,
+        Err(..) => return,
+    };
+    let source_temp = TempDir::new("sftp-source").unwrap();
+    let restore_temp = TempDir::new("sftp-restore").unwrap();
+    let source_path = source_temp.path().to_owned();
+    let destination_path = PathBuf::from(format!("{}/backbonzo-{}",
+                                                 base_url.trim_right_matches('/'),
+                                                 epoch_milliseconds()));
+    let crypto_scheme = AesEncrypter::new("testpassword");
+    let time_limit = Duration::from_secs(1 * 60);
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    // the second backup replaces the index and a changed block
+    for content in ["first version", "second version"].iter() {
+        write_file(&source_path.join("file"), content.as_bytes());
+
+        backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+        sleep(Duration::from_millis(5));
+    }
+
+    assert_eq!("aes-256-cbc", backbonzo::backup_cipher(destination_path.clone()).unwrap());
+
+    backbonzo::restore(restore_temp.path().to_owned(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    let mut restored = String::new();
+    File::open(restore_temp.path().join("file")).unwrap().read_to_string(&mut restored).unwrap();
+
+    assert_eq!("second version", restored);
+}
+
+#[cfg(not(feature = "sftp"))]
+#[test]
+fn sftp_destination_unsupported() {
+    let source_temp = TempDir::new("sftp-source").unwrap();
+    let source_path = source_temp.path().to_owned();
+    let destination_path = PathBuf::from("sftp://localhost/backup");
+    let crypto_scheme = AesEncrypter::new("testpassword");
+
+    assert!(backbonzo::init(&source_path, &destination_path, &crypto_scheme).is_err());
+    assert!(!source_path.join(".backbonzo.db3").exists());
+}
+
+// Stripping the authentication code off the index and marking it as being in
+// the older, unauthenticated format does not get it accepted
+#[test]
+fn downgraded_index() {
+    let fixture = Fixture::new("downgrade");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("downgrade-restore").unwrap();
+    let index_path = destination_path.join("index");
+
+    write_file(&source_path.join("file"), b"authenticated");
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let mut index = Vec::new();
+    File::open(&index_path).unwrap().read_to_end(&mut index).unwrap();
+
+    let length = index.len() - 32;
+    index.truncate(length);
+    index[0] = 1;
+
+    write_file(&index_path, &index);
+
+    assert!(backbonzo::restore(restore_temp.path().to_owned(),
+                               destination_path.clone(),
+                               &crypto_scheme,
+                               epoch_milliseconds(),
+                               "**")
+                .is_err());
+    assert!(!restore_temp.path().join("file").exists());
+}
+
+#[test]
+fn leftover_partial_blocks() {
+    let fixture = Fixture::new("partial");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let partial_directory = destination_path.join("partial");
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    write_file(&source_path.join("file"), b"some contents");
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    // blocks are moved out of the partial directory once written
+    assert_eq!(0, read_dir(&partial_directory).unwrap().count());
+
+    // a block write which was interrupted
+    write_file(&partial_directory.join("0a1b2c"), b"trunc");
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert!(summary.warnings.iter().any(|warning| warning.starts_with("Removed 1 partially")));
+    assert_eq!(0, read_dir(&partial_directory).unwrap().count());
+}
+
+#[test]
+fn content_defined_chunking() {
+    let fixture = Fixture::new("chunking");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    // incompressible bytes from a simple linear congruential generator
+    let mut state = 12345u32;
+    let original: Vec<u8> = (0..200000).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect();
+    let mut edited = original[..50].to_vec();
+    edited.extend(b"a few inserted bytes".iter().cloned());
+    edited.extend(original[50..].iter().cloned());
+
+    let mut init_options = InitOptions::new();
+    init_options.chunking = Chunking::ContentDefined { min: 1024, average: 4096, max: 16384 };
+
+    backbonzo::init_with_options(&source_path, &destination_path, &crypto_scheme, &init_options)
+        .unwrap();
+
+    write_file(&source_path.join("original"), &original);
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let first_blocks = backbonzo::stats(source_path.clone(), None, &crypto_scheme).unwrap().blocks;
+
+    write_file(&source_path.join("edited"), &edited);
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let second_blocks = backbonzo::stats(source_path.clone(), None, &crypto_scheme).unwrap().blocks;
+
+    // only the blocks around the insertion are new
+    assert!(first_blocks >= 10);
+    assert!(second_blocks - first_blocks <= 3);
+
+    let restore_temp = TempDir::new("chunking-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    for &(name, content) in [("original", &original), ("edited", &edited)].iter() {
+        let mut restored = Vec::new();
+        File::open(restore_path.join(name)).unwrap().read_to_end(&mut restored).unwrap();
+
+        assert_eq!(content, &restored);
+    }
+
+    init_options.chunking = Chunking::ContentDefined { min: 4096, average: 1024, max: 16384 };
+
+    let other_destination = TempDir::new("chunking-invalid").unwrap();
+    let other_source = TempDir::new("chunking-invalid-source").unwrap();
+
+    assert!(backbonzo::init_with_options(&other_source.path().to_owned(),
+                                         &other_destination.path().to_owned(),
+                                         &crypto_scheme,
+                                         &init_options)
+                .is_err());
+}
+
+#[test]
+fn prune_dry_run() {
+    let fixture = Fixture::new("prune");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let file_path = source_path.join("file");
+    let day = 24 * 60 * 60 * 1000;
+
+    write_file(&file_path, b"soon to be deleted");
+
+    // the backups themselves keep everything
+    let backup_options = BackupOptions::new(1000000, day, time_limit);
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &backup_options).unwrap();
+    remove_file(&file_path).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &backup_options).unwrap();
+
+    sleep(Duration::from_millis(10));
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.dry_run = true;
+
+    let preview = backbonzo::prune(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(2, preview.aliases);
+    assert_eq!(1, preview.blocks);
+    assert!(preview.bytes > 0);
+    assert!(preview.to_string().starts_with("Would clean up"));
+    assert_eq!(1, backbonzo::stats(source_path.clone(), None, &crypto_scheme).unwrap().blocks);
+
+    options.dry_run = false;
+
+    let summary = backbonzo::prune(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(preview.aliases, summary.aliases);
+    assert_eq!(preview.blocks, summary.blocks);
+    assert_eq!(preview.bytes, summary.bytes);
+    assert_eq!(0, backbonzo::stats(source_path.clone(), None, &crypto_scheme).unwrap().blocks);
+}
+
+#[cfg(unix)]
+#[test]
+fn file_attributes() {
+    use std::fs::{metadata, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use filetime::{set_file_times, FileTime};
+
+    let fixture = Fixture::new("attributes");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("attributes-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+    let script_path = source_path.join("script.sh");
+    let modified = FileTime::from_seconds_since_1970(1400000000, 0);
+
+    write_file(&script_path, b"#!/bin/sh\necho hello\n");
+
+    set_permissions(&script_path, Permissions::from_mode(0o754)).unwrap();
+    set_file_times(&script_path, modified, modified).unwrap();
+
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+    backbonzo::restore(restore_path.clone(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    let restored = metadata(restore_path.join("script.sh")).unwrap();
+
+    assert_eq!(0o754, restored.permissions().mode() & 0o7777);
+    assert_eq!(modified, FileTime::from_last_modification_time(&restored));
+
+    // changing nothing but the mode yields a new version as well
+    let chmod_temp = TempDir::new("attributes-chmod").unwrap();
+
+    set_permissions(&script_path, Permissions::from_mode(0o700)).unwrap();
+    sleep(Duration::from_millis(5));
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+    backbonzo::restore(chmod_temp.path().to_owned(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    let restored = metadata(chmod_temp.path().join("script.sh")).unwrap();
+
+    assert_eq!(0o700, restored.permissions().mode() & 0o7777);
+}
+
+#[test]
+fn empty_directories() {
+    let fixture = Fixture::new("empty-dirs");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    create_dir_all(source_path.join("cache/thumbnails")).unwrap();
+    create_dir_all(source_path.join("logs")).unwrap();
+    create_dir_all(source_path.join("data")).unwrap();
+
+    write_file(&source_path.join("data/file"), b"not empty");
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let restore_temp = TempDir::new("empty-dirs-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+    let summary = backbonzo::restore(restore_path.clone(),
+                                     destination_path.clone(),
+                                     &crypto_scheme,
+                                     epoch_milliseconds(),
+                                     "**")
+                      .unwrap();
+
+    assert_eq!(2, summary.directories);
+    assert!(restore_path.join("cache/thumbnails").is_dir());
+    assert!(restore_path.join("logs").is_dir());
+    assert!(restore_path.join("data/file").is_file());
+
+    // a directory which gains contents is no longer recorded as empty
+    write_file(&source_path.join("logs/today"), b"no longer empty");
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let second_restore = TempDir::new("empty-dirs-restore").unwrap();
+    let summary = backbonzo::restore(second_restore.path().to_owned(),
+                                     destination_path.clone(),
+                                     &crypto_scheme,
+                                     epoch_milliseconds(),
+                                     "**")
+                      .unwrap();
+
+    assert_eq!(1, summary.directories);
+    assert!(second_restore.path().join("logs/today").is_file());
+
+    // nor is one which was removed along with its parent
+    remove_dir_all(source_path.join("cache")).unwrap();
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let third_restore = TempDir::new("empty-dirs-restore").unwrap();
+    let summary = backbonzo::restore(third_restore.path().to_owned(),
+                                     destination_path.clone(),
+                                     &crypto_scheme,
+                                     epoch_milliseconds(),
+                                     "**")
+                      .unwrap();
+
+    assert_eq!(0, summary.directories);
+    assert!(!third_restore.path().join("cache").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks() {
+    use std::os::unix::fs::symlink;
+    use std::fs::read_link;
+    use backbonzo::SymlinkPolicy;
+
+    let fixture = Fixture::new("symlinks");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.symlinks = SymlinkPolicy::Store;
+
+    create_dir_all(source_path.join("data")).unwrap();
+
+    write_file(&source_path.join("data/large"), b"contents which should only be stored once");
+
+    symlink("data/large", source_path.join("shortcut")).unwrap();
+    symlink("data", source_path.join("folder")).unwrap();
+    symlink("nowhere", source_path.join("dangling")).unwrap();
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let restore_temp = TempDir::new("symlinks-restore").unwrap();
+    let restore_path = restore_temp.path().to_owned();
+    let summary = backbonzo::restore(restore_path.clone(),
+                                     destination_path.clone(),
+                                     &crypto_scheme,
+                                     epoch_milliseconds(),
+                                     "**")
+                      .unwrap();
+
+    // the links are recreated, rather than the files they point to
+    assert_eq!(1, summary.summary.files);
+    assert_eq!(3, summary.symlinks);
+    assert_eq!(Path::new("data/large"), read_link(restore_path.join("shortcut")).unwrap());
+    assert_eq!(Path::new("data"), read_link(restore_path.join("folder")).unwrap());
+    assert_eq!(Path::new("nowhere"), read_link(restore_path.join("dangling")).unwrap());
+
+    // a removed link is no longer restored
+    remove_file(source_path.join("shortcut")).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let second_restore = TempDir::new("symlinks-restore").unwrap();
+    let summary = backbonzo::restore(second_restore.path().to_owned(),
+                                     destination_path.clone(),
+                                     &crypto_scheme,
+                                     epoch_milliseconds(),
+                                     "**")
+                      .unwrap();
+
+    assert_eq!(2, summary.symlinks);
+    assert!(second_restore.path().join("shortcut").symlink_metadata().is_err());
+}
+
+#[test]
+fn cat_single_file() {
+    let fixture = Fixture::new("cat");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    create_dir_all(source_path.join("etc")).unwrap();
+
+    for &(name, contents) in [("etc/hosts", "127.0.0.1 localhost"),
+                              ("etc/fstab", "/dev/sda1 / ext4")]
+                                 .iter() {
+        write_file(&source_path.join(name), contents.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let mut output = Vec::new();
+    let written = backbonzo::cat(destination_path.clone(),
+                                 &crypto_scheme,
+                                 epoch_milliseconds(),
+                                 "etc/hosts",
+                                 &mut output)
+                      .unwrap();
+
+    assert_eq!(19, written);
+    assert_eq!(b"127.0.0.1 localhost", &output[..]);
+
+    // the filter has to match exactly one file
+    for filter in ["etc/*", "etc/passwd"].iter() {
+        assert!(backbonzo::cat(destination_path.clone(),
+                               &crypto_scheme,
+                               epoch_milliseconds(),
+                               *filter,
+                               &mut Vec::new())
+                    .is_err());
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_symlinks() {
+    use std::os::unix::fs::symlink;
+    use backbonzo::SymlinkPolicy;
+
+    let fixture = Fixture::new("follow");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let outside_temp = TempDir::new("follow-outside").unwrap();
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.symlinks = SymlinkPolicy::Follow;
+
+    create_dir_all(source_path.join("inside")).unwrap();
+
+    for path in [source_path.join("inside/file"), outside_temp.path().join("file")].iter() {
+        write_file(&path, b"some contents");
+    }
+
+    symlink(outside_temp.path(), source_path.join("outside")).unwrap();
+    symlink(source_path.join("inside"), source_path.join("shortcut")).unwrap();
+    symlink(&source_path, source_path.join("inside/loop")).unwrap();
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    // links into the source directory are left alone, as their targets are
+    // backed up anyway
+    let paths = backbonzo::list(destination_path.clone(),
+                                &crypto_scheme,
+                                epoch_milliseconds(),
+                                "**")
+                    .unwrap();
+
+    assert_eq!(vec![PathBuf::from("inside/file"), PathBuf::from("outside/file")], paths);
+}
+
+#[test]
+fn multiple_roots() {
+    let fixture = Fixture::new("roots");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let etc_temp = TempDir::new("roots-etc").unwrap();
+    let docs_temp = TempDir::new("roots-docs").unwrap();
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.roots = vec![("etc".to_owned(), etc_temp.path().to_owned()),
+                         ("docs".to_owned(), docs_temp.path().to_owned())];
+
+    for path in [source_path.join("main"), etc_temp.path().join("hosts"),
+                 docs_temp.path().join("letter")]
+                    .iter() {
+        write_file(&path, b"some contents");
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let paths = backbonzo::list(destination_path.clone(),
+                                &crypto_scheme,
+                                epoch_milliseconds(),
+                                "**")
+                    .unwrap();
+
+    assert_eq!(vec![PathBuf::from("docs/letter"),
+                    PathBuf::from("etc/hosts"),
+                    PathBuf::from("main")],
+               paths);
+
+    // a single root can be restored by itself
+    let restore_temp = TempDir::new("roots-restore").unwrap();
+    let summary = backbonzo::restore(restore_temp.path().to_owned(),
+                                     destination_path.clone(),
+                                     &crypto_scheme,
+                                     epoch_milliseconds(),
+                                     "**/etc/*")
+                      .unwrap();
+
+    assert_eq!(1, summary.summary.files);
+    assert!(restore_temp.path().join("etc/hosts").is_file());
+
+    // names clashing with the main source directory are refused
+    options.roots = vec![("main".to_owned(), etc_temp.path().to_owned())];
+
+    assert!(backbonzo::backup(source_path.clone(), &crypto_scheme, &options).is_err());
+}
+
+#[test]
+fn blake2b_hashing() {
+    use backbonzo::HashAlgorithm;
+
+    let fixture = Fixture::new("blake2b");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let options = BackupOptions::new(10, 0, time_limit);
+    let contents = b"hashed with blake2b rather than sha256";
+
+    let mut init_options = InitOptions::new();
+    init_options.hash_algorithm = HashAlgorithm::Blake2b;
+
+    backbonzo::init_with_options(&source_path, &destination_path, &crypto_scheme, &init_options)
+        .unwrap();
+
+    write_file(&source_path.join("file"), contents);
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    // verification and restores check blocks with the algorithm of the backup
+    let verify_summary = backbonzo::verify(destination_path.clone(), &crypto_scheme).unwrap();
+
+    assert_eq!(0, verify_summary.corrupt);
+
+    let restore_temp = TempDir::new("blake2b-restore").unwrap();
+    backbonzo::restore(restore_temp.path().to_owned(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    let mut restored = Vec::new();
+    File::open(restore_temp.path().join("file")).unwrap().read_to_end(&mut restored).unwrap();
+
+    assert_eq!(&contents[..], &restored[..]);
+}
+
+#[test]
+fn shard_depth() {
+    let fixture = Fixture::new("shard");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("shard-restore").unwrap();
+    let contents = b"spread over two levels of directories";
+    let day = 24 * 60 * 60 * 1000;
+
+    let mut init_options = InitOptions::new();
+    init_options.shard_depth = 2;
+
+    backbonzo::init_with_options(&source_path, &destination_path, &crypto_scheme, &init_options)
+        .unwrap();
+
+    write_file(&source_path.join("file"), contents);
+
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, day, time_limit))
+        .unwrap();
+
+    // the only block is found at the second level
+    let nested_shards = |path: &Path| -> Vec<PathBuf> {
+        read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap().len() == 2 && path.is_dir())
+            .flat_map(|shard| read_dir(shard).unwrap().map(|entry| entry.unwrap().path()))
+            .collect()
+    };
+    let shards = nested_shards(&destination_path);
+
+    assert_eq!(1, shards.len());
+    assert!(shards[0].is_dir());
+    assert_eq!(1, read_dir(&shards[0]).unwrap().count());
+
+    assert_eq!(0, backbonzo::verify(destination_path.clone(), &crypto_scheme).unwrap().corrupt);
+
+    backbonzo::restore(restore_temp.path().to_owned(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    let mut restored = Vec::new();
+    File::open(restore_temp.path().join("file")).unwrap().read_to_end(&mut restored).unwrap();
+
+    assert_eq!(&contents[..], &restored[..]);
+
+    // cleanup finds the block at the same place
+    remove_file(source_path.join("file")).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, day, time_limit))
+        .unwrap();
+    sleep(Duration::from_millis(10));
+
+    let summary = backbonzo::prune(source_path.clone(),
+                                   &crypto_scheme,
+                                   &BackupOptions::new(1000000, 0, time_limit))
+                      .unwrap();
+
+    assert_eq!(1, summary.blocks);
+    assert_eq!(0, read_dir(&shards[0]).unwrap().count());
+}
+
+#[test]
+fn keyfile() {
+    let fixture = Fixture::new("keyfile");
+    let Fixture { source_path, destination_path, time_limit, .. } = fixture;
+    let key_temp = TempDir::new("keyfile-key").unwrap();
+    let keyfile_path = key_temp.path().join("key");
+    let options = BackupOptions::new(1000000, 0, time_limit);
+
+    write_file(&keyfile_path, b"not so secret after all");
+
+    let crypto_scheme = AesEncrypter::from_keyfile(None, &keyfile_path).unwrap();
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    // the combination used at initialization is required from then on
+    let with_passphrase = AesEncrypter::from_keyfile(Some("testpassword"), &keyfile_path)
+                              .unwrap();
+
+    assert!(backbonzo::backup(source_path.clone(), &with_passphrase, &options).is_err());
+    assert!(backbonzo::backup(source_path.clone(),
+                              &AesEncrypter::new("testpassword"),
+                              &options)
+                .is_err());
+    assert!(backbonzo::backup(source_path.clone(),
+                              &AesEncrypter::from_keyfile(None, &keyfile_path).unwrap(),
+                              &options)
+                .is_ok());
+}
+
+#[test]
+fn fsck_consistent() {
+    let fixture = Fixture::new("fsck");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    write_file(&source_path.join("file"), b"all references in order");
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(10, 0, time_limit))
+        .unwrap();
+
+    let summary = backbonzo::fsck(source_path.clone(), None, &crypto_scheme, true).unwrap();
+
+    assert!(summary.is_consistent());
+    assert!(!summary.fixed);
+}
+
+#[test]
+fn interrupted_backup() {
+    static INTERRUPT: AtomicBool = ATOMIC_BOOL_INIT;
+
+    let fixture = Fixture::new("interrupt");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    for name in ["one", "two", "three"].iter() {
+        write_file(&source_path.join(name), name.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.interrupt = Some(&INTERRUPT);
+    INTERRUPT.store(true, Ordering::SeqCst);
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert!(summary.timeout);
+    assert_eq!(0, summary.summary.files);
+
+    // the index was exported all the same
+    assert!(backbonzo::list(destination_path.clone(),
+                            &crypto_scheme,
+                            epoch_milliseconds(),
+                            "**")
+                .unwrap()
+                .is_empty());
+
+    INTERRUPT.store(false, Ordering::SeqCst);
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert!(!summary.timeout);
+    assert_eq!(3, summary.summary.files);
+}
+
+// The time limit counts from the start of the backup, so a limit of zero
+// leaves no time at all
+#[test]
+fn time_limit() {
+    let fixture = Fixture::new("time-limit");
+    let Fixture { source_path, destination_path, crypto_scheme, .. } = fixture;
+
+    write_file(&source_path.join("file"), b"contents");
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let options = BackupOptions::new(1000000, 0, Duration::from_secs(0));
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert!(summary.timeout);
+
+    let options = BackupOptions::new(1000000, 0, Duration::from_secs(60));
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert!(!summary.timeout);
+    assert_eq!(1, summary.summary.files);
+}
+
+#[test]
+fn unsynced_blocks() {
+    let fixture = Fixture::new("unsynced");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    for &(name, content) in [("one", "first"), ("two", "second"), ("three", "third")].iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.sync_blocks = false;
+
+    let summary = backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    assert_eq!(3, summary.summary.blocks);
+
+    let summary = backbonzo::verify(destination_path.clone(), &crypto_scheme).unwrap();
+
+    assert_eq!((3, 0, 0), (summary.ok, summary.corrupt, summary.missing));
+}
+
+#[test]
+fn restore_results() {
+    let fixture = Fixture::new("results");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+
+    for &(name, content) in [("one", "first"), ("two", "second"), ("three", "third")].iter() {
+        write_file(&source_path.join(name), content.as_bytes());
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(),
+                      &crypto_scheme,
+                      &BackupOptions::new(1000000, 0, time_limit))
+        .unwrap();
+
+    let mut blocks = Vec::new();
+
+    for entry in read_dir(&destination_path).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.is_dir() {
+            blocks.extend(read_dir(&path).unwrap().map(|block| block.unwrap().path()));
+        }
+    }
+
+    write_file(&blocks[0], b"bit rot");
+
+    let restore_temp = TempDir::new("results-restore").unwrap();
+    let mut restored = Vec::new();
+    let mut failures = 0;
+
+    {
+        let mut collect = |result: BonzoResult<PathBuf>| {
+            match result {
+                Ok(path) => restored.push(path),
+                Err(..) => failures += 1,
+            }
+        };
+
+        // the damaged file does not keep the others from being restored
+        backbonzo::restore_with_results(restore_temp.path().to_owned(),
+                                        destination_path.clone(),
+                                        &crypto_scheme,
+                                        epoch_milliseconds(),
+                                        "**",
+                                        &RestoreOptions::new(),
+                                        None,
+                                        Some(&mut collect))
+            .unwrap();
+    }
+
+    assert_eq!(2, restored.len());
+    assert_eq!(1, failures);
+
+    for path in restored.iter() {
+        assert!(path.starts_with(restore_temp.path()));
+        assert!(path.exists());
+    }
+
+    assert!(backbonzo::restore(restore_temp.path().to_owned(),
+                               destination_path.clone(),
+                               &crypto_scheme,
+                               epoch_milliseconds(),
+                               "**")
+                .is_err());
+}
+
+#[test]
+fn failed_backup_exports_index() {
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("failed");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let locked_path = source_path.join("locked");
+
+    create_dir_all(&locked_path).unwrap();
+    set_permissions(&locked_path, Permissions::from_mode(0o000)).unwrap();
+
+    // permissions are not enforced for privileged users
+    if read_dir(&locked_path).is_ok() {
+        set_permissions(&locked_path, Permissions::from_mode(0o755)).unwrap();
+        return;
+    }
+
+    // newer than the locked directory, so it is backed up before the walk
+    // fails on it
+    sleep(Duration::from_millis(50));
+
+    write_file(&source_path.join("readable"), b"some content");
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.strict = true;
+    options.worker_count = 1;
+
+    let result = backbonzo::backup(source_path.clone(), &crypto_scheme, &options);
+
+    set_permissions(&locked_path, Permissions::from_mode(0o755)).unwrap();
+
+    assert!(result.is_err());
+
+    let paths = backbonzo::list(destination_path.clone(),
+                                &crypto_scheme,
+                                epoch_milliseconds(),
+                                "**")
+                    .unwrap();
+
+    assert_eq!(vec![PathBuf::from("readable")], paths);
+}
+
+#[test]
+fn extended_attributes() {
+    let fixture = Fixture::new("xattr");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let file_path = source_path.join("tagged");
+
+    write_file(&file_path, b"contents");
+
+    // not every filesystem supports user attributes
+    if xattr::set(&file_path, "user.backbonzo", b"red").is_err() {
+        return;
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.xattrs = true;
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let restore_temp = TempDir::new("xattr-restore").unwrap();
+    let restored_path = restore_temp.path().join("tagged");
+    let mut restore_options = RestoreOptions::new();
+    restore_options.xattrs = true;
+
+    backbonzo::restore_with_options(restore_temp.path().to_owned(),
+                                    destination_path.clone(),
+                                    &crypto_scheme,
+                                    epoch_milliseconds(),
+                                    "**",
+                                    &restore_options)
+        .unwrap();
+
+    assert_eq!(Some(b"red".to_vec()), xattr::get(&restored_path, "user.backbonzo").unwrap());
+
+    // attributes are left alone unless asked for
+    let plain_temp = TempDir::new("xattr-plain").unwrap();
+
+    backbonzo::restore(plain_temp.path().to_owned(),
+                       destination_path.clone(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    assert_eq!(None,
+               xattr::get(&plain_temp.path().join("tagged"), "user.backbonzo").unwrap());
+}
+
+// Attributes are set before the mode, which may forbid changing them, and are
+// left alone on files which are kept
+#[cfg(unix)]
+#[test]
+fn extended_attributes_of_read_only_files() {
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let fixture = Fixture::new("xattr");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_temp = TempDir::new("xattr-restore").unwrap();
+    let file_path = source_path.join("locked");
+    let restored_path = restore_temp.path().join("locked");
+
+    write_file(&file_path, b"contents");
+
+    if xattr::set(&file_path, "user.backbonzo", b"red").is_err() {
+        return;
+    }
+
+    set_permissions(&file_path, Permissions::from_mode(0o444)).unwrap();
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+
+    let mut options = BackupOptions::new(1000000, 0, time_limit);
+    options.xattrs = true;
+
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+
+    let restore_with = |overwrite| {
+        let mut restore_options = RestoreOptions::new();
+        restore_options.xattrs = true;
+        restore_options.overwrite = overwrite;
+
+        backbonzo::restore_with_options(restore_temp.path().to_owned(),
+                                        destination_path.clone(),
+                                        &crypto_scheme,
+                                        epoch_milliseconds(),
+                                        "**",
+                                        &restore_options)
+            .unwrap()
+    };
+
+    restore_with(Overwrite::Always);
+
+    assert_eq!(Some(b"red".to_vec()), xattr::get(&restored_path, "user.backbonzo").unwrap());
+
+    set_permissions(&restored_path, Permissions::from_mode(0o644)).unwrap();
+    xattr::set(&restored_path, "user.backbonzo", b"blue").unwrap();
+
+    restore_with(Overwrite::Never);
+
+    assert_eq!(Some(b"blue".to_vec()), xattr::get(&restored_path, "user.backbonzo").unwrap());
+}
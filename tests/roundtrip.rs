@@ -0,0 +1,109 @@
+// Property test of backups which are restored in full: whatever the shape of
+// the source directory, the restored tree should be identical to it.
+
+extern crate backbonzo;
+extern crate time;
+extern crate tempdir;
+extern crate quickcheck;
+
+use std::path::PathBuf;
+
+use backbonzo::BackupOptions;
+use quickcheck::{Arbitrary, Gen, QuickCheck};
+use tempdir::TempDir;
+
+use common::{write_file, read_tree, epoch_milliseconds, Fixture};
+
+mod common;
+
+// Blocks are kept small, so that files span many of them
+static BLOCK_BYTES: usize = 64;
+static MAX_FILE_BYTES: usize = 1000;
+static MAX_DEPTH: usize = 3;
+
+// A source directory as a list of files with their contents. Directories and
+// files are named differently, so that no path is both. Contents are drawn
+// from a small pool now and then, so that some files are identical and
+// their blocks are deduplicated.
+#[derive(Clone, Debug)]
+struct Tree {
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Arbitrary for Tree {
+    fn arbitrary<G: Gen>(g: &mut G) -> Tree {
+        let pool: Vec<Vec<u8>> = (0..3).map(|_| contents(g)).collect();
+        let size = g.size();
+        let file_count = g.gen_range(0, size + 1);
+        let mut files = Vec::with_capacity(file_count);
+
+        for index in 0..file_count {
+            let mut path = PathBuf::new();
+
+            for _ in 0..g.gen_range(0, MAX_DEPTH + 1) {
+                path.push(format!("dir{}", g.gen_range(0, 3)));
+            }
+
+            path.push(format!("file{}", index));
+
+            let bytes = match g.gen_weighted_bool(3) {
+                true => pool[g.gen_range(0, pool.len())].clone(),
+                false => contents(g),
+            };
+
+            files.push((path, bytes));
+        }
+
+        Tree { files: files }
+    }
+
+    // Leaves out one file at a time
+    fn shrink(&self) -> Box<Iterator<Item = Tree>> {
+        let files = self.files.clone();
+
+        Box::new((0..files.len()).map(move |index| {
+            let mut fewer = files.clone();
+            fewer.remove(index);
+
+            Tree { files: fewer }
+        }))
+    }
+}
+
+// Sizes cluster around multiples of the block size, where chunking is most
+// likely to go wrong
+fn contents<G: Gen>(g: &mut G) -> Vec<u8> {
+    let size = match g.gen() {
+        true => g.gen_range(0, MAX_FILE_BYTES),
+        false => BLOCK_BYTES * g.gen_range(1, 4) + g.gen_range(0, 3) - 1,
+    };
+
+    (0..size).map(|_| g.gen()).collect()
+}
+
+fn backup_and_restore(tree: Tree) -> bool {
+    let fixture = Fixture::new("roundtrip");
+    let Fixture { source_path, destination_path, crypto_scheme, time_limit, .. } = fixture;
+    let restore_dir = TempDir::new("roundtrip-restore").unwrap();
+    let options = BackupOptions::new(BLOCK_BYTES, 0, time_limit);
+
+    for &(ref path, ref bytes) in tree.files.iter() {
+        write_file(&source_path.join(path), bytes);
+    }
+
+    backbonzo::init(&source_path, &destination_path, &crypto_scheme).unwrap();
+    backbonzo::backup(source_path.clone(), &crypto_scheme, &options).unwrap();
+    backbonzo::restore(restore_dir.path(),
+                       destination_path.as_path(),
+                       &crypto_scheme,
+                       epoch_milliseconds(),
+                       "**")
+        .unwrap();
+
+    read_tree(&source_path) == read_tree(restore_dir.path())
+}
+
+#[test]
+fn restored_tree_is_identical() {
+    QuickCheck::new().tests(25).quickcheck(backup_and_restore as fn(Tree) -> bool);
+}